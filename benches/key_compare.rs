@@ -0,0 +1,73 @@
+/*
+ * Copyright (c) Peter Bjorklund. All rights reserved. https://github.com/piot/hashmap-mem
+ * Licensed under the MIT License. See LICENSE in the project root for license information.
+ */
+
+//! Time of a lookup in a small map for keys of 8 to 64 bytes
+//!
+//! Maps of up to 8 buckets are scanned, so looking up a missing key compares it with
+//! each stored key. The keys only differ in their last byte, so every compare reads
+//! the whole key. The fastest of a few runs is reported, which is the least disturbed
+//! by other work on the machine. Run it with and without the `simd` feature to compare
+//! the two:
+//!
+//! ```text
+//! cargo bench --bench key_compare
+//! cargo bench --bench key_compare --features simd
+//! ```
+
+use std::alloc::{Layout, alloc};
+use std::hint::black_box;
+use std::time::Instant;
+
+use hashmap_mem::{init, insert, layout, lookup};
+
+const ENTRIES: u8 = 4;
+const LOOKUPS: u32 = 200_000;
+const RUNS: usize = 50;
+
+fn bench_key_size(key_size: usize) -> f64 {
+    let (_, map_init) = layout(key_size as u32, 1, 4, 4, u32::from(ENTRIES));
+    let map_layout = Layout::from_size_align(map_init.total_size as usize, 8).unwrap();
+    let map_base = unsafe { alloc(map_layout) };
+    assert!(!map_base.is_null());
+
+    let keys: Vec<Vec<u8>> = (0..=ENTRIES)
+        .map(|last| {
+            let mut key = vec![0x5a; key_size];
+            key[key_size - 1] = last;
+            key
+        })
+        .collect();
+
+    unsafe {
+        init(map_base, &map_init);
+        for (value, key) in (0u32..).zip(&keys[..usize::from(ENTRIES)]) {
+            insert(map_base, key.as_ptr(), (&raw const value).cast::<u8>());
+        }
+
+        // The last key is not in the map, so it is compared with every stored key
+        let key_ptr = keys[usize::from(ENTRIES)].as_ptr();
+        (0..RUNS)
+            .map(|_| {
+                let start = Instant::now();
+                for _ in 0..LOOKUPS {
+                    black_box(lookup(black_box(map_base), black_box(key_ptr)));
+                }
+                start.elapsed().as_nanos() as f64 / f64::from(LOOKUPS)
+            })
+            .fold(f64::INFINITY, f64::min)
+    }
+}
+
+fn main() {
+    let mode = if cfg!(feature = "simd") {
+        "simd"
+    } else {
+        "scalar"
+    };
+    for key_size in [8, 12, 16, 24, 32, 48, 64] {
+        let nanos = bench_key_size(key_size);
+        println!("{mode} {key_size:>2} byte keys: {nanos:6.2} ns per lookup");
+    }
+}
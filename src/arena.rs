@@ -0,0 +1,172 @@
+/*
+ * Copyright (c) Peter Bjorklund. All rights reserved. https://github.com/piot/hashmap-mem
+ * Licensed under the MIT License. See LICENSE in the project root for license information.
+ */
+
+//! Many maps carved out of one memory block
+//!
+//! [`plan_arena`] places the maps one after another, each aligned as [`alloc_layout`]
+//! says, after a directory of their offsets. [`init_arena`] writes the directory and
+//! initializes every map, and [`map_ptr`] finds a map by its index in the plan.
+
+use crate::{MapInit, alloc_layout, init};
+use core::alloc::Layout;
+use core::ptr;
+
+/// Marks a block initialized by [`init_arena`]
+pub const ARENA_SECRET_CODE: u32 = 0x4152_3d01;
+
+/// Header at the start of an arena, followed by the `u64` offset of every map
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct ArenaDirectory {
+    pub secret_code: u32,
+    pub map_count: u32,
+}
+
+/// Where the maps of an arena go, calculated by [`plan_arena`]
+pub struct ArenaPlan<'a> {
+    configs: &'a [MapInit],
+    total_size: usize,
+    alignment: usize,
+}
+
+/// Calculate the offset of every map and the size of the arena
+///
+/// # Panics
+///
+/// If there are more than `u32::MAX` maps, or the arena does not fit in a memory layout
+#[must_use]
+pub fn plan_arena(configs: &[MapInit]) -> ArenaPlan<'_> {
+    let map_count = u32::try_from(configs.len()).expect("hashmap, too many maps in the arena");
+    let mut alignment = align_of::<ArenaDirectory>().max(align_of::<u64>());
+    let mut end = directory_len(map_count as usize);
+    for config in configs {
+        alignment = alignment.max(alloc_layout(config).align());
+        end = place_map(end, config).1;
+    }
+
+    ArenaPlan {
+        configs,
+        total_size: end,
+        alignment,
+    }
+}
+
+const fn directory_len(map_count: usize) -> usize {
+    size_of::<ArenaDirectory>() + map_count * size_of::<u64>()
+}
+
+/// Offset of the next map after the first `end` bytes of the arena, and its end
+fn place_map(end: usize, config: &MapInit) -> (usize, usize) {
+    let map_layout = alloc_layout(config);
+    let offset = end.next_multiple_of(map_layout.align());
+    (offset, offset + map_layout.size())
+}
+
+impl ArenaPlan<'_> {
+    /// Number of maps in the arena
+    #[must_use]
+    pub const fn len(&self) -> usize {
+        self.configs.len()
+    }
+
+    #[must_use]
+    pub const fn is_empty(&self) -> bool {
+        self.configs.is_empty()
+    }
+
+    /// Size of the whole arena, directory included
+    #[must_use]
+    pub const fn total_size(&self) -> usize {
+        self.total_size
+    }
+
+    /// Size and alignment of the memory block to allocate for the arena
+    ///
+    /// # Panics
+    ///
+    /// If the arena does not fit in a memory layout
+    #[must_use]
+    pub fn alloc_layout(&self) -> Layout {
+        Layout::from_size_align(self.total_size, self.alignment)
+            .expect("arena size does not fit in a memory layout")
+    }
+
+    /// Offset of a map from the start of the arena
+    ///
+    /// # Panics
+    ///
+    /// If `index` is not less than the number of maps
+    #[must_use]
+    pub fn offset(&self, index: usize) -> usize {
+        assert!(
+            index < self.configs.len(),
+            "hashmap, no such map in the arena"
+        );
+        let end = self.configs[..index]
+            .iter()
+            .fold(directory_len(self.configs.len()), |end, config| {
+                place_map(end, config).1
+            });
+        place_map(end, &self.configs[index]).0
+    }
+}
+
+/// Write the directory and initialize every map of the arena
+///
+/// # Safety
+///
+/// - `base_ptr` must point to `total_size` writable bytes, aligned as
+///   [`ArenaPlan::alloc_layout`] says
+pub unsafe fn init_arena(base_ptr: *mut u8, plan: &ArenaPlan<'_>) {
+    unsafe {
+        ptr::write(
+            base_ptr.cast::<ArenaDirectory>(),
+            ArenaDirectory {
+                secret_code: ARENA_SECRET_CODE,
+                map_count: plan.configs.len() as u32,
+            },
+        );
+        let offsets_ptr = base_ptr.add(size_of::<ArenaDirectory>()).cast::<u64>();
+        let mut end = directory_len(plan.configs.len());
+        for (index, config) in plan.configs.iter().enumerate() {
+            let (offset, map_end) = place_map(end, config);
+            offsets_ptr.add(index).write(offset as u64);
+            init(base_ptr.add(offset), config);
+            end = map_end;
+        }
+    }
+}
+
+/// Start of a map in an arena, to pass to the raw map functions
+///
+/// # Safety
+///
+/// - `base_ptr` must point to an arena initialized by [`init_arena`] with `plan`
+///
+/// # Panics
+///
+/// If `index` is not less than the number of maps, or the arena was initialized with
+/// another plan
+#[must_use]
+pub unsafe fn map_ptr(base_ptr: *mut u8, plan: &ArenaPlan<'_>, index: usize) -> *mut u8 {
+    unsafe {
+        let directory = &*base_ptr.cast::<ArenaDirectory>();
+        assert_eq!(
+            directory.secret_code, ARENA_SECRET_CODE,
+            "hashmap, arena secret code failed"
+        );
+        assert_eq!(
+            directory.map_count as usize,
+            plan.len(),
+            "hashmap, arena was initialized with another plan"
+        );
+        assert!(index < plan.len(), "hashmap, no such map in the arena");
+        let offset = *base_ptr
+            .add(size_of::<ArenaDirectory>())
+            .cast::<u64>()
+            .add(index);
+        base_ptr.add(offset as usize)
+    }
+}
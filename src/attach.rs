@@ -0,0 +1,173 @@
+/*
+ * Copyright (c) Peter Bjorklund. All rights reserved. https://github.com/piot/hashmap-mem
+ * Licensed under the MIT License. See LICENSE in the project root for license information.
+ */
+
+//! Maps in memory that the crate does not own, like a memory-mapped file shared
+//! between processes
+//!
+//! [`attach`] validates the map before handing out an [`AttachedMap`], so a corrupted
+//! or truncated file is rejected instead of being read out of bounds. Nothing is copied:
+//! every change is made directly in the mapped memory. Making the changes durable (with
+//! `msync` or `FlushViewOfFile` on [`AttachedMap::as_bytes`]) is up to the owner of the
+//! mapping, after [`AttachedMap::flush`] or [`AttachedMap::detach`].
+
+use crate::byte_map::ByteMap;
+use crate::{ValidationError, map_len, validate};
+use core::slice;
+use core::sync::atomic::{Ordering, fence};
+
+/// A validated map in memory owned by someone else
+///
+/// Created by [`attach`]. The raw primitives can still be used through
+/// [`AttachedMap::as_mut_ptr`].
+#[derive(Debug)]
+pub struct AttachedMap {
+    map: ByteMap,
+    region_len: usize,
+}
+
+/// Check the map at the start of a memory region, and give access to it
+///
+/// # Safety
+///
+/// - `base_ptr` must point to `region_len` readable and writable bytes
+/// - The region must stay mapped, and must not be changed by anyone else, until the
+///   returned map is detached or dropped
+///
+/// # Errors
+///
+/// Returns the first [`ValidationError`] found. Nothing is written to the region then.
+pub unsafe fn attach(base_ptr: *mut u8, region_len: usize) -> Result<AttachedMap, ValidationError> {
+    unsafe { validate(base_ptr, region_len)? };
+    Ok(AttachedMap {
+        map: ByteMap::new(base_ptr),
+        region_len,
+    })
+}
+
+impl AttachedMap {
+    #[must_use]
+    pub const fn as_ptr(&self) -> *const u8 {
+        self.map.as_ptr()
+    }
+
+    /// Pointer to pass to the raw map functions
+    #[must_use]
+    pub const fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.map.as_ptr()
+    }
+
+    /// Length of the memory region the map was attached to
+    #[must_use]
+    pub const fn region_len(&self) -> usize {
+        self.region_len
+    }
+
+    /// The memory of the map, header included, which may be shorter than the region
+    #[must_use]
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe {
+            slice::from_raw_parts(
+                self.map.as_ptr(),
+                map_len(self.map.as_ptr(), self.map.header()),
+            )
+        }
+    }
+
+    #[must_use]
+    pub const fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    #[must_use]
+    pub const fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    #[must_use]
+    pub const fn capacity(&self) -> usize {
+        self.map.capacity()
+    }
+
+    #[must_use]
+    pub const fn key_size(&self) -> usize {
+        self.map.key_size()
+    }
+
+    #[must_use]
+    pub const fn value_size(&self) -> usize {
+        self.map.value_size()
+    }
+
+    /// # Panics
+    ///
+    /// If `key` is not exactly `key_size` bytes long
+    #[must_use]
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        self.map.contains_key(key)
+    }
+
+    /// # Panics
+    ///
+    /// If `key` is not exactly `key_size` bytes long
+    #[must_use]
+    pub fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        self.map.get(key)
+    }
+
+    /// # Panics
+    ///
+    /// If `key` is not exactly `key_size` bytes long
+    pub fn get_mut(&mut self, key: &[u8]) -> Option<&mut [u8]> {
+        self.map.get_mut(key)
+    }
+
+    /// Insert or replace the value for `key`
+    ///
+    /// Returns `false` if the map is full.
+    ///
+    /// # Panics
+    ///
+    /// If `key` or `value` do not match the key and value sizes of the map
+    pub fn insert(&mut self, key: &[u8], value: &[u8]) -> bool {
+        self.map.insert(key, value)
+    }
+
+    /// Returns `true` if the key was present
+    ///
+    /// # Panics
+    ///
+    /// If `key` is not exactly `key_size` bytes long
+    pub fn remove(&mut self, key: &[u8]) -> bool {
+        self.map.remove(key)
+    }
+
+    /// Check the map again, after the region may have been written by someone else
+    ///
+    /// # Errors
+    ///
+    /// Returns the first [`ValidationError`] found
+    pub fn revalidate(&self) -> Result<(), ValidationError> {
+        unsafe { validate(self.map.as_ptr(), self.region_len) }
+    }
+
+    /// Order every change made so far before the writes that follow, like the release
+    /// of a lock that other processes acquire before attaching
+    ///
+    /// Changes are written to the region as they are made, this does not write them to
+    /// a backing file.
+    pub fn flush(&self) {
+        fence(Ordering::Release);
+    }
+
+    /// Stop using the map, and return the start of the region
+    ///
+    /// Flushes first, see [`AttachedMap::flush`]. The region can then be synced to
+    /// its file and unmapped.
+    #[must_use]
+    pub fn detach(self) -> *mut u8 {
+        self.flush();
+        self.map.as_ptr()
+    }
+}
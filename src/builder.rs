@@ -0,0 +1,164 @@
+/*
+ * Copyright (c) Peter Bjorklund. All rights reserved. https://github.com/piot/hashmap-mem
+ * Licensed under the MIT License. See LICENSE in the project root for license information.
+ */
+
+//! Builder that checks a map configuration before it is used
+
+use crate::{HashStrategy, LayoutError, MapInit, ProbeStrategy, layout_checked};
+
+/// Builds a [`MapInit`], taking the key and value sizes and alignments from types
+///
+/// Only the key and the logical limit have to be given. Everything else starts out as
+/// [`crate::layout`] sets it, and the other `with_` options can still be applied to the
+/// built configuration.
+#[derive(Copy, Clone, Debug)]
+pub struct MapInitBuilder {
+    key_size: u32,
+    key_alignment: usize,
+    value_size: u32,
+    value_alignment: usize,
+    logical_limit: u32,
+    probe_limit: Option<u16>,
+    probe_strategy: Option<ProbeStrategy>,
+    hash_strategy: Option<HashStrategy>,
+    seed: u64,
+}
+
+impl MapInitBuilder {
+    /// A builder without a key, for a set unless a value is given
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            key_size: 0,
+            key_alignment: 1,
+            value_size: 0,
+            value_alignment: 1,
+            logical_limit: 0,
+            probe_limit: None,
+            probe_strategy: None,
+            hash_strategy: None,
+            seed: 0,
+        }
+    }
+
+    /// Use keys of type `K`
+    ///
+    /// The map only copies and compares the bytes of the keys, so `K` should have no
+    /// padding and no pointers, see [`crate::Pod`].
+    #[must_use]
+    pub const fn key<K>(self) -> Self {
+        self.key_size(size_of::<K>(), align_of::<K>())
+    }
+
+    /// Use values of type `V`
+    #[must_use]
+    pub const fn value<V>(self) -> Self {
+        self.value_size(size_of::<V>(), align_of::<V>())
+    }
+
+    /// Use keys of `size` bytes, aligned to `alignment`
+    #[must_use]
+    pub const fn key_size(mut self, size: usize, alignment: usize) -> Self {
+        self.key_size = clamp_size(size);
+        self.key_alignment = alignment;
+        self
+    }
+
+    /// Use values of `size` bytes, aligned to `alignment`
+    #[must_use]
+    pub const fn value_size(mut self, size: usize, alignment: usize) -> Self {
+        self.value_size = clamp_size(size);
+        self.value_alignment = alignment;
+        self
+    }
+
+    /// Number of entries the map must hold. The capacity is this rounded up to a power
+    /// of two.
+    #[must_use]
+    pub const fn logical_limit(mut self, logical_limit: u32) -> Self {
+        self.logical_limit = logical_limit;
+        self
+    }
+
+    /// See [`MapInit::with_probe_limit`]
+    #[must_use]
+    pub const fn probe_limit(mut self, probe_limit: u16) -> Self {
+        self.probe_limit = Some(probe_limit);
+        self
+    }
+
+    /// See [`MapInit::with_probe_strategy`]
+    #[must_use]
+    pub const fn probe_strategy(mut self, probe_strategy: ProbeStrategy) -> Self {
+        self.probe_strategy = Some(probe_strategy);
+        self
+    }
+
+    /// See [`MapInit::with_hash_strategy`]
+    #[must_use]
+    pub const fn hash_strategy(mut self, hash_strategy: HashStrategy) -> Self {
+        self.hash_strategy = Some(hash_strategy);
+        self
+    }
+
+    /// See [`MapInit::with_seed`]
+    #[must_use]
+    pub const fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Check the configuration and calculate the layout of the map
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`LayoutError`] if no key or a zero logical limit was given, an
+    /// alignment is not a power of two or larger than 128, or the map does not fit in
+    /// a `u32`
+    pub fn build(&self) -> Result<MapInit, LayoutError> {
+        if self.key_size == 0 {
+            return Err(LayoutError::KeySizeZero);
+        }
+        if self.logical_limit == 0 {
+            return Err(LayoutError::LogicalLimitZero);
+        }
+        let key_alignment =
+            u8::try_from(self.key_alignment).map_err(|_| LayoutError::InvalidAlignment)?;
+        let value_alignment =
+            u8::try_from(self.value_alignment).map_err(|_| LayoutError::InvalidAlignment)?;
+
+        let (_, mut config) = layout_checked(
+            self.key_size,
+            key_alignment,
+            self.value_size,
+            value_alignment,
+            self.logical_limit,
+        )?;
+        if let Some(probe_limit) = self.probe_limit {
+            config = config.with_probe_limit(probe_limit);
+        }
+        if let Some(probe_strategy) = self.probe_strategy {
+            config = config.with_probe_strategy(probe_strategy);
+        }
+        if let Some(hash_strategy) = self.hash_strategy {
+            config = config.with_hash_strategy(hash_strategy);
+        }
+        Ok(config.with_seed(self.seed))
+    }
+}
+
+impl Default for MapInitBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Sizes past `u32::MAX` become `u32::MAX`, which [`layout_checked`] rejects
+const fn clamp_size(size: usize) -> u32 {
+    if size > u32::MAX as usize {
+        u32::MAX
+    } else {
+        size as u32
+    }
+}
@@ -0,0 +1,84 @@
+/*
+ * Copyright (c) Peter Bjorklund. All rights reserved. https://github.com/piot/hashmap-mem
+ * Licensed under the MIT License. See LICENSE in the project root for license information.
+ */
+
+//! Byte slice access to a map, shared by the map types that hold a map pointer
+
+use crate::{
+    InsertResult, MapHeader, capacity_of, element_count_of, has, insert, lookup, lookup_shared,
+    remove,
+};
+use core::slice;
+
+/// Pointer to an initialized map, with the methods that [`crate::OwnedMap`] and
+/// [`crate::AttachedMap`] delegate to
+///
+/// The holder is responsible for the map memory staying valid while this is used.
+#[derive(Debug)]
+pub struct ByteMap {
+    base: *mut u8,
+}
+
+impl ByteMap {
+    /// `base_ptr` must point to a valid initialized map for as long as this is used
+    pub const fn new(base_ptr: *mut u8) -> Self {
+        Self { base: base_ptr }
+    }
+
+    pub const fn as_ptr(&self) -> *mut u8 {
+        self.base
+    }
+
+    pub const fn header(&self) -> &MapHeader {
+        unsafe { &*self.base.cast::<MapHeader>() }
+    }
+
+    pub const fn len(&self) -> usize {
+        element_count_of(self.header())
+    }
+
+    pub const fn capacity(&self) -> usize {
+        capacity_of(self.header())
+    }
+
+    pub const fn key_size(&self) -> usize {
+        self.header().key_size as usize
+    }
+
+    pub const fn value_size(&self) -> usize {
+        self.header().value_size as usize
+    }
+
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        assert_eq!(key.len(), self.key_size(), "wrong key size");
+        unsafe { has(self.base, key.as_ptr()) }
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        assert_eq!(key.len(), self.key_size(), "wrong key size");
+        unsafe {
+            let value_ptr = lookup_shared(self.base, key.as_ptr());
+            (!value_ptr.is_null()).then(|| slice::from_raw_parts(value_ptr, self.value_size()))
+        }
+    }
+
+    pub fn get_mut(&mut self, key: &[u8]) -> Option<&mut [u8]> {
+        assert_eq!(key.len(), self.key_size(), "wrong key size");
+        unsafe {
+            let value_ptr = lookup(self.base, key.as_ptr());
+            (!value_ptr.is_null()).then(|| slice::from_raw_parts_mut(value_ptr, self.value_size()))
+        }
+    }
+
+    pub fn insert(&mut self, key: &[u8], value: &[u8]) -> bool {
+        assert_eq!(key.len(), self.key_size(), "wrong key size");
+        assert_eq!(value.len(), self.value_size(), "wrong value size");
+        unsafe { insert(self.base, key.as_ptr(), value.as_ptr()) != InsertResult::Full }
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> bool {
+        assert_eq!(key.len(), self.key_size(), "wrong key size");
+        unsafe { remove(self.base, key.as_ptr()) }
+    }
+}
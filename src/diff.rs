@@ -0,0 +1,322 @@
+/*
+ * Copyright (c) Peter Bjorklund. All rights reserved. https://github.com/piot/hashmap-mem
+ * Licensed under the MIT License. See LICENSE in the project root for license information.
+ */
+
+//! Patches that turn one map snapshot into another
+//!
+//! A patch starts with the key size and value size as little-endian `u32`s, followed by
+//! one record per differing key: an operation byte, the key bytes and, unless the key
+//! was removed, the new value bytes.
+//!
+//! A [`Journal`] records the inserts and removes made to a map as they happen, in the
+//! same format, so that only the mutations have to be sent to a replica.
+
+use crate::{InsertResult, MapHeader, checked_header, insert, remove};
+#[cfg(feature = "alloc")]
+use crate::{for_each_occupied_bucket, key_offset_of, lookup_shared, matches_key, value_ptr_at};
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+use core::fmt;
+use core::slice;
+
+const OPERATION_ADDED: u8 = 0;
+const OPERATION_CHANGED: u8 = 1;
+const OPERATION_REMOVED: u8 = 2;
+
+const PATCH_HEADER_SIZE: usize = 8;
+
+/// Reasons why [`apply_diff`] can fail
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum PatchError {
+    /// The patch was made for maps with other key or value sizes
+    SizeMismatch,
+    /// The patch ends in the middle of a record
+    Truncated,
+    UnknownOperation,
+    /// An added key did not fit in the map
+    Full,
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            Self::SizeMismatch => "patch key or value size does not match the map",
+            Self::Truncated => "patch is truncated",
+            Self::UnknownOperation => "patch has an unknown operation",
+            Self::Full => "map is full",
+        };
+        f.write_str(message)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for PatchError {}
+
+/// Append a patch that turns `old` into `new` to `out`
+///
+/// Removed keys come first, so that applying the patch frees buckets before it fills them.
+///
+/// # Safety
+///
+/// - `old` and `new` must point to valid initialized maps with the same key and value sizes
+#[cfg(feature = "alloc")]
+pub unsafe fn diff(old: *const u8, new: *const u8, out: &mut Vec<u8>) {
+    unsafe {
+        let old_header = checked_header(old);
+        let new_header = checked_header(new);
+        assert_eq!(
+            old_header.key_size, new_header.key_size,
+            "Incompatible key sizes"
+        );
+        assert_eq!(
+            old_header.value_size, new_header.value_size,
+            "Incompatible value sizes"
+        );
+
+        let key_size = old_header.key_size as usize;
+        let value_size = old_header.value_size as usize;
+
+        out.extend_from_slice(&old_header.key_size.to_le_bytes());
+        out.extend_from_slice(&old_header.value_size.to_le_bytes());
+
+        for_each_occupied_bucket(old, old_header, |_, bucket_ptr| {
+            let key_ptr = bucket_ptr.add(key_offset_of(old_header));
+            if lookup_shared(new, key_ptr).is_null() {
+                out.push(OPERATION_REMOVED);
+                out.extend_from_slice(slice::from_raw_parts(key_ptr, key_size));
+            }
+        });
+
+        for_each_occupied_bucket(new, new_header, |index, bucket_ptr| {
+            let key_ptr = bucket_ptr.add(key_offset_of(new_header));
+            let value_ptr = value_ptr_at(new, new_header, index, bucket_ptr);
+            let old_value_ptr = lookup_shared(old, key_ptr);
+
+            let operation = if old_value_ptr.is_null() {
+                OPERATION_ADDED
+            } else if matches_key(old_value_ptr, value_ptr, value_size) {
+                return;
+            } else {
+                OPERATION_CHANGED
+            };
+
+            out.push(operation);
+            out.extend_from_slice(slice::from_raw_parts(key_ptr, key_size));
+            out.extend_from_slice(slice::from_raw_parts(value_ptr, value_size));
+        });
+    }
+}
+
+/// Apply a patch made by [`diff`]
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+///
+/// # Errors
+///
+/// Returns a [`PatchError`] if the patch does not fit the map or is malformed. Records
+/// before the failing one have already been applied.
+pub unsafe fn apply_diff(base_ptr: *mut u8, patch: &[u8]) -> Result<(), PatchError> {
+    unsafe {
+        let header = checked_header(base_ptr);
+        let key_size = header.key_size as usize;
+        let value_size = header.value_size as usize;
+
+        let Some((sizes, mut records)) = patch.split_at_checked(PATCH_HEADER_SIZE) else {
+            return Err(PatchError::Truncated);
+        };
+        if sizes[..4] != header.key_size.to_le_bytes()
+            || sizes[4..] != header.value_size.to_le_bytes()
+        {
+            return Err(PatchError::SizeMismatch);
+        }
+
+        while let Some((&operation, rest)) = records.split_first() {
+            let Some((key, rest)) = rest.split_at_checked(key_size) else {
+                return Err(PatchError::Truncated);
+            };
+
+            records = match operation {
+                OPERATION_REMOVED => {
+                    remove(base_ptr, key.as_ptr());
+                    rest
+                }
+                OPERATION_ADDED | OPERATION_CHANGED => {
+                    let Some((value, rest)) = rest.split_at_checked(value_size) else {
+                        return Err(PatchError::Truncated);
+                    };
+                    if insert(base_ptr, key.as_ptr(), value.as_ptr()) == InsertResult::Full {
+                        return Err(PatchError::Full);
+                    }
+                    rest
+                }
+                _ => return Err(PatchError::UnknownOperation),
+            };
+        }
+
+        Ok(())
+    }
+}
+
+/// Append-only record of the mutations made through [`insert_journaled`] and
+/// [`remove_journaled`], written to a buffer owned by the caller
+///
+/// The written bytes are a patch, which [`apply_journal`] replays on another map.
+#[derive(Debug)]
+pub struct Journal<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+    overflowed: bool,
+}
+
+impl<'a> Journal<'a> {
+    /// Start an empty journal for the map at `base_ptr`
+    ///
+    /// # Safety
+    ///
+    /// - `base_ptr` must point to a valid initialized map
+    ///
+    /// # Panics
+    ///
+    /// If `buffer` is too small for the patch header
+    pub unsafe fn new(base_ptr: *const u8, buffer: &'a mut [u8]) -> Self {
+        let header = unsafe { checked_header(base_ptr) };
+        assert!(
+            buffer.len() >= PATCH_HEADER_SIZE,
+            "hashmap, journal buffer too small"
+        );
+        buffer[..4].copy_from_slice(&header.key_size.to_le_bytes());
+        buffer[4..PATCH_HEADER_SIZE].copy_from_slice(&header.value_size.to_le_bytes());
+
+        Self {
+            buffer,
+            len: PATCH_HEADER_SIZE,
+            overflowed: false,
+        }
+    }
+
+    /// The patch recorded so far
+    #[must_use]
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buffer[..self.len]
+    }
+
+    /// Whether nothing has been recorded since the journal was started or cleared
+    #[must_use]
+    pub const fn is_empty(&self) -> bool {
+        self.len == PATCH_HEADER_SIZE
+    }
+
+    /// Whether a record did not fit in the buffer
+    ///
+    /// Nothing is recorded after that, since a replay would miss a mutation. Send a
+    /// snapshot of the map instead and [`clear`](Self::clear) the journal.
+    #[must_use]
+    pub const fn overflowed(&self) -> bool {
+        self.overflowed
+    }
+
+    /// Drop every record, for example once they have been sent
+    pub const fn clear(&mut self) {
+        self.len = PATCH_HEADER_SIZE;
+        self.overflowed = false;
+    }
+
+    fn record(&mut self, operation: u8, key: &[u8], value: &[u8]) {
+        let record_len = 1 + key.len() + value.len();
+        if self.overflowed || self.buffer.len() - self.len < record_len {
+            self.overflowed = true;
+            return;
+        }
+
+        let record = &mut self.buffer[self.len..self.len + record_len];
+        record[0] = operation;
+        record[1..=key.len()].copy_from_slice(key);
+        record[1 + key.len()..].copy_from_slice(value);
+        self.len += record_len;
+    }
+}
+
+/// Insert or replace an entry, and record it in `journal`
+///
+/// Keys that do not fit are not recorded.
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+/// - `key_ptr` must point to a valid key of the size specified in the map header
+/// - `value_ptr` must point to `value_size` readable bytes that are not inside the map
+/// - `journal` must have been started for a map with the same key and value sizes
+pub unsafe fn insert_journaled(
+    base_ptr: *mut u8,
+    key_ptr: *const u8,
+    value_ptr: *const u8,
+    journal: &mut Journal<'_>,
+) -> InsertResult {
+    unsafe {
+        let result = insert(base_ptr, key_ptr, value_ptr);
+        let operation = match result {
+            InsertResult::Inserted => OPERATION_ADDED,
+            InsertResult::Replaced => OPERATION_CHANGED,
+            InsertResult::Full => return result,
+        };
+
+        let header = &*base_ptr.cast::<MapHeader>();
+        journal.record(
+            operation,
+            slice::from_raw_parts(key_ptr, header.key_size as usize),
+            slice::from_raw_parts(value_ptr, header.value_size as usize),
+        );
+        result
+    }
+}
+
+/// Remove an entry, and record it in `journal` if the key was present
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+/// - `key_ptr` must point to a valid key of the size specified in the map header
+/// - `journal` must have been started for a map with the same key and value sizes
+///
+/// # Returns
+///
+/// `true` if the key was found and removed, `false` otherwise
+pub unsafe fn remove_journaled(
+    base_ptr: *mut u8,
+    key_ptr: *const u8,
+    journal: &mut Journal<'_>,
+) -> bool {
+    unsafe {
+        if !remove(base_ptr, key_ptr) {
+            return false;
+        }
+
+        let key_size = (*base_ptr.cast::<MapHeader>()).key_size as usize;
+        journal.record(
+            OPERATION_REMOVED,
+            slice::from_raw_parts(key_ptr, key_size),
+            &[],
+        );
+        true
+    }
+}
+
+/// Replay the mutations recorded by a [`Journal`]
+///
+/// The map should hold the same entries as the journaled map had when the journal was
+/// started or cleared.
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+///
+/// # Errors
+///
+/// Returns a [`PatchError`] if the journal does not fit the map or is malformed.
+/// Records before the failing one have already been applied.
+pub unsafe fn apply_journal(base_ptr: *mut u8, journal: &[u8]) -> Result<(), PatchError> {
+    unsafe { apply_diff(base_ptr, journal) }
+}
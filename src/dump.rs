@@ -0,0 +1,254 @@
+/*
+ * Copyright (c) Peter Bjorklund. All rights reserved. https://github.com/piot/hashmap-mem
+ * Licensed under the MIT License. See LICENSE in the project root for license information.
+ */
+
+//! Human readable dumps of the internals of a map, for diagnosing clustering and
+//! corruption, and JSON exports of its entries for tooling
+
+#[cfg(feature = "alloc")]
+use crate::for_each_occupied_bucket;
+use crate::{
+    BucketStatus, HashStrategy, LayoutKind, MapHeader, ProbeStrategy, bucket_distance,
+    buckets_offset, capacity_of, checked_header, element_count_of, key_offset_of, logical_limit_of,
+    spill_bucket_ptr, spill_len_of, status_ptr, tombstone_count_of, value_ptr_at,
+};
+#[cfg(feature = "alloc")]
+use alloc::{string::String, vec::Vec};
+use core::fmt;
+#[cfg(feature = "alloc")]
+use core::fmt::Write;
+#[cfg(feature = "alloc")]
+use core::slice;
+
+/// What [`debug_dump`] writes besides the header and the bucket statuses
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct DumpOptions {
+    /// Skip empty buckets and tombstones
+    pub occupied_only: bool,
+    /// Write the key bytes of occupied buckets in hex
+    pub hex_keys: bool,
+    /// Write the value bytes of occupied buckets in hex
+    pub hex_values: bool,
+}
+
+/// Write the header, and the status and probe distance of every bucket, to `w`
+///
+/// Spilled entries follow the buckets. The exact format is meant for people and may
+/// change.
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+///
+/// # Errors
+///
+/// Returns the error of `w`
+pub unsafe fn debug_dump(
+    base_ptr: *const u8,
+    w: &mut impl fmt::Write,
+    options: DumpOptions,
+) -> fmt::Result {
+    unsafe {
+        let header = checked_header(base_ptr);
+        write_header(w, header)?;
+
+        let capacity = capacity_of(header);
+        let bucket_size = header.bucket_size as usize;
+        let buckets_ptr = base_ptr.add(buckets_offset(header));
+        for index in 0..capacity {
+            let status = *status_ptr(base_ptr, header, index);
+            if status == BucketStatus::Empty as u8 {
+                if !options.occupied_only {
+                    writeln!(w, "bucket {index:>5}: empty")?;
+                }
+            } else if status == BucketStatus::Tombstone as u8 {
+                if !options.occupied_only {
+                    writeln!(w, "bucket {index:>5}: tombstone")?;
+                }
+            } else {
+                write!(w, "bucket {index:>5}: occupied {status:#04x}")?;
+                let bucket_ptr = buckets_ptr.add(index * bucket_size);
+                write_entry(w, base_ptr, header, index, bucket_ptr, options)?;
+            }
+        }
+
+        for spill_index in 0..spill_len_of(base_ptr, header) {
+            write!(w, "spill  {spill_index:>5}: occupied")?;
+            let bucket_ptr = spill_bucket_ptr(base_ptr, header, spill_index);
+            write_entry(
+                w,
+                base_ptr,
+                header,
+                capacity + spill_index,
+                bucket_ptr,
+                options,
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Append the entries of a map to `out` as a JSON array, sorted by key bytes
+///
+/// Every entry becomes an object `{"key": ..., "value": ...}` with the strings that
+/// `key_fmt` and `value_fmt` make of the key and value bytes. Maps with the same
+/// entries give the same JSON, whatever their capacity, hash seed or bucket order.
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+#[cfg(feature = "alloc")]
+pub unsafe fn dump_json(
+    base_ptr: *const u8,
+    mut key_fmt: impl FnMut(&[u8]) -> String,
+    mut value_fmt: impl FnMut(&[u8]) -> String,
+    out: &mut String,
+) {
+    unsafe {
+        let header = checked_header(base_ptr);
+        let key_size = header.key_size as usize;
+        let value_size = header.value_size as usize;
+        let key_offset = key_offset_of(header);
+
+        let mut entries = Vec::with_capacity(element_count_of(header));
+        for_each_occupied_bucket(base_ptr, header, |index, bucket_ptr| {
+            entries.push((
+                bucket_ptr.add(key_offset),
+                value_ptr_at(base_ptr, header, index, bucket_ptr),
+            ));
+        });
+        entries.sort_unstable_by_key(|&(key_ptr, _)| slice::from_raw_parts(key_ptr, key_size));
+
+        out.push('[');
+        for (position, (key_ptr, value_ptr)) in entries.into_iter().enumerate() {
+            if position != 0 {
+                out.push(',');
+            }
+            out.push_str("{\"key\":");
+            push_json_string(out, &key_fmt(slice::from_raw_parts(key_ptr, key_size)));
+            out.push_str(",\"value\":");
+            push_json_string(
+                out,
+                &value_fmt(slice::from_raw_parts(value_ptr, value_size)),
+            );
+            out.push('}');
+        }
+        out.push(']');
+    }
+}
+
+/// Append `text` as a quoted JSON string
+#[cfg(feature = "alloc")]
+fn push_json_string(out: &mut String, text: &str) {
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c < ' ' => {
+                let _ = write!(out, "\\u{:04x}", u32::from(c));
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn write_header(w: &mut impl fmt::Write, header: &MapHeader) -> fmt::Result {
+    writeln!(
+        w,
+        "map: capacity {}, {} entries, {} tombstones, logical limit {}, probe limit {}",
+        capacity_of(header),
+        element_count_of(header),
+        tombstone_count_of(header),
+        logical_limit_of(header),
+        header.probe_limit
+    )?;
+    writeln!(
+        w,
+        "  key size {}, value size {}, bucket size {}, key offset {}, value offset {}, hash offset {}",
+        header.key_size,
+        header.value_size,
+        header.bucket_size,
+        key_offset_of(header),
+        header.value_offset,
+        header.hash_offset
+    )?;
+    writeln!(
+        w,
+        "  layout {}, probe strategy {}, hash strategy {}, flags {:#04x}, generation {}",
+        layout_kind_name(header.layout_kind),
+        probe_strategy_name(header.probe_strategy),
+        hash_strategy_name(header.hash_strategy),
+        header.flags,
+        header.mutation_generation
+    )
+}
+
+/// Write the rest of the line of an occupied bucket
+unsafe fn write_entry(
+    w: &mut impl fmt::Write,
+    base_ptr: *const u8,
+    header: &MapHeader,
+    index: usize,
+    bucket_ptr: *const u8,
+    options: DumpOptions,
+) -> fmt::Result {
+    unsafe {
+        write!(
+            w,
+            ", distance {}",
+            bucket_distance(header, bucket_ptr, index)
+        )?;
+        if options.hex_keys {
+            w.write_str(", key ")?;
+            write_hex(w, bucket_ptr.add(key_offset_of(header)), header.key_size)?;
+        }
+        if options.hex_values && header.value_size != 0 {
+            w.write_str(", value ")?;
+            let value_ptr = value_ptr_at(base_ptr, header, index, bucket_ptr.cast_mut());
+            write_hex(w, value_ptr, header.value_size)?;
+        }
+        writeln!(w)
+    }
+}
+
+unsafe fn write_hex(w: &mut impl fmt::Write, bytes_ptr: *const u8, len: u32) -> fmt::Result {
+    for offset in 0..len as usize {
+        write!(w, "{:02x}", unsafe { *bytes_ptr.add(offset) })?;
+    }
+    Ok(())
+}
+
+const fn layout_kind_name(kind: u8) -> &'static str {
+    match kind {
+        kind if kind == LayoutKind::Interleaved as u8 => "Interleaved",
+        kind if kind == LayoutKind::ControlBytes as u8 => "ControlBytes",
+        kind if kind == LayoutKind::StructOfArrays as u8 => "StructOfArrays",
+        _ => "unknown",
+    }
+}
+
+const fn probe_strategy_name(strategy: u8) -> &'static str {
+    match strategy {
+        strategy if strategy == ProbeStrategy::Linear as u8 => "Linear",
+        strategy if strategy == ProbeStrategy::RobinHood as u8 => "RobinHood",
+        strategy if strategy == ProbeStrategy::Quadratic as u8 => "Quadratic",
+        _ => "unknown",
+    }
+}
+
+const fn hash_strategy_name(strategy: u8) -> &'static str {
+    match strategy {
+        strategy if strategy == HashStrategy::Fx as u8 => "Fx",
+        strategy if strategy == HashStrategy::WyHash as u8 => "WyHash",
+        strategy if strategy == HashStrategy::SipHash13 as u8 => "SipHash13",
+        strategy if strategy == HashStrategy::Integer as u8 => "Integer",
+        _ => "unknown",
+    }
+}
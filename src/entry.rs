@@ -0,0 +1,125 @@
+/*
+ * Copyright (c) Peter Bjorklund. All rights reserved. https://github.com/piot/hashmap-mem
+ * Licensed under the MIT License. See LICENSE in the project root for license information.
+ */
+
+//! Entry API that probes for a key once, whether it is present or not
+
+use crate::instrument::{Counter, count};
+#[cfg(feature = "tracing")]
+use crate::warn_full;
+use crate::{
+    MapHeader, Slot, fill_slot, find_slot, key_hash, key_offset_of, vacate_bucket, value_ptr_of,
+};
+use core::ptr;
+
+/// A key that is either in the map or has room to be inserted
+///
+/// Created by [`entry`]. The map must not be changed in any other way while the entry
+/// is alive, since the entry remembers the bucket that was found.
+#[derive(Debug)]
+pub enum Entry {
+    Occupied(OccupiedEntry),
+    Vacant(VacantEntry),
+}
+
+/// A key that is in the map
+#[derive(Debug)]
+pub struct OccupiedEntry {
+    base_ptr: *mut u8,
+    bucket_ptr: *mut u8,
+    value_ptr: *mut u8,
+}
+
+/// A key that is not in the map, together with the bucket it will be inserted into
+#[derive(Debug)]
+pub struct VacantEntry {
+    base_ptr: *mut u8,
+    key_ptr: *const u8,
+    hash: u64,
+    slot: Slot,
+}
+
+/// Find the entry for a key, probing only once
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+/// - `key_ptr` must point to a valid key of the size specified in the map header
+/// - Both must stay valid, and the map must not be changed by anything else, until
+///   the returned entry is dropped
+///
+/// # Returns
+///
+/// The entry, or `None` if the key is not in the map and there is no room for it
+#[must_use]
+pub unsafe fn entry(base_ptr: *mut u8, key_ptr: *const u8) -> Option<Entry> {
+    unsafe {
+        let hash = key_hash(base_ptr, key_ptr);
+        match find_slot(base_ptr, key_ptr, hash) {
+            Slot::Found(bucket_ptr) => Some(Entry::Occupied(OccupiedEntry {
+                base_ptr,
+                bucket_ptr,
+                value_ptr: value_ptr_of(base_ptr, &*base_ptr.cast::<MapHeader>(), bucket_ptr),
+            })),
+            slot @ (Slot::Vacant { .. } | Slot::Spill) => Some(Entry::Vacant(VacantEntry {
+                base_ptr,
+                key_ptr,
+                hash,
+                slot,
+            })),
+            Slot::Full => {
+                count(base_ptr, Counter::FailedInserts, 1);
+                #[cfg(feature = "tracing")]
+                warn_full(base_ptr);
+                None
+            }
+        }
+    }
+}
+
+impl OccupiedEntry {
+    const fn header(&self) -> &MapHeader {
+        unsafe { &*self.base_ptr.cast::<MapHeader>() }
+    }
+
+    /// The key bytes stored in the map
+    #[must_use]
+    pub const fn key_ptr(&self) -> *const u8 {
+        unsafe { self.bucket_ptr.add(key_offset_of(self.header())) }
+    }
+
+    /// The value location of the entry, valid until the map is changed
+    #[must_use]
+    pub const fn value_ptr(&self) -> *mut u8 {
+        self.value_ptr
+    }
+
+    /// Remove the entry from the map
+    pub fn remove(self) {
+        unsafe { vacate_bucket(self.base_ptr, self.bucket_ptr) };
+    }
+}
+
+impl VacantEntry {
+    /// Insert the key with a copy of the value, without probing again
+    ///
+    /// # Safety
+    ///
+    /// - `value_ptr` must point to `value_size` readable bytes that are not inside the map
+    ///
+    /// # Returns
+    ///
+    /// The value location of the new entry
+    pub unsafe fn insert(self, value_ptr: *const u8) -> *mut u8 {
+        unsafe {
+            count(self.base_ptr, Counter::Inserts, 1);
+            #[cfg(feature = "tracing")]
+            tracing::trace!(hash = self.hash, "hashmap, key inserted");
+            let target_value_ptr = fill_slot(self.base_ptr, self.key_ptr, self.hash, self.slot);
+            let value_size = (*self.base_ptr.cast::<MapHeader>()).value_size as usize;
+            ptr::copy_nonoverlapping(value_ptr, target_value_ptr, value_size);
+            target_value_ptr
+        }
+    }
+}
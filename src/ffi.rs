@@ -0,0 +1,139 @@
+/*
+ * Copyright (c) Peter Bjorklund. All rights reserved. https://github.com/piot/hashmap-mem
+ * Licensed under the MIT License. See LICENSE in the project root for license information.
+ */
+
+//! C interface, only `repr(C)` types so that a header can be generated with cbindgen
+
+use crate::{find_next_valid_entry, get_or_reserve_entry, init, layout, lookup, overwrite, remove};
+
+/// Everything needed to allocate and initialize a map
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct HashmapMemConfig {
+    pub key_size: u32,
+    pub value_size: u32,
+    /// Bytes to allocate for the map, header included
+    pub total_size: u32,
+    pub logical_limit: u32,
+    pub key_alignment: u8,
+    pub value_alignment: u8,
+}
+
+/// Entry found by [`hashmap_mem_next_entry`]
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct HashmapMemEntry {
+    /// Null if there are no more entries
+    pub key: *const u8,
+    pub value: *mut u8,
+    /// Pass `index + 1` to find the following entry
+    pub index: u32,
+}
+
+/// Calculate the configuration of a map. `key_alignment` and `value_alignment` must be
+/// powers of two.
+#[unsafe(no_mangle)]
+pub extern "C" fn hashmap_mem_config(
+    key_size: u32,
+    key_alignment: u8,
+    value_size: u32,
+    value_alignment: u8,
+    logical_limit: u32,
+) -> HashmapMemConfig {
+    let (_, map_init) = layout(
+        key_size,
+        key_alignment,
+        value_size,
+        value_alignment,
+        logical_limit,
+    );
+
+    HashmapMemConfig {
+        key_size,
+        value_size,
+        total_size: map_init.total_size,
+        logical_limit,
+        key_alignment,
+        value_alignment,
+    }
+}
+
+/// # Safety
+///
+/// - `config` must come from [`hashmap_mem_config`]
+/// - `map_base` must point to `config.total_size` writable bytes, aligned to at least
+///   8 bytes and to the key and value alignment
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn hashmap_mem_init(map_base: *mut u8, config: *const HashmapMemConfig) {
+    unsafe {
+        let config = &*config;
+        let (_, map_init) = layout(
+            config.key_size,
+            config.key_alignment,
+            config.value_size,
+            config.value_alignment,
+            config.logical_limit,
+        );
+        init(map_base, &map_init);
+    }
+}
+
+/// Returns the value location, or null if the map is full
+///
+/// # Safety
+///
+/// Same as [`get_or_reserve_entry`]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn hashmap_mem_get_or_reserve_entry(
+    base_ptr: *mut u8,
+    key_ptr: *const u8,
+) -> *mut u8 {
+    unsafe { get_or_reserve_entry(base_ptr, key_ptr) }
+}
+
+/// Returns the value location, or null if the key is not in the map
+///
+/// # Safety
+///
+/// Same as [`lookup`]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn hashmap_mem_lookup(base_ptr: *mut u8, key_ptr: *const u8) -> *mut u8 {
+    unsafe { lookup(base_ptr, key_ptr) }
+}
+
+/// Returns `true` if the key was removed
+///
+/// # Safety
+///
+/// Same as [`remove`]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn hashmap_mem_remove(base_ptr: *mut u8, key_ptr: *const u8) -> bool {
+    unsafe { remove(base_ptr, key_ptr) }
+}
+
+/// Returns `false` if the target is too small
+///
+/// # Safety
+///
+/// Same as [`overwrite`]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn hashmap_mem_overwrite(target_base: *mut u8, source: *const u8) -> bool {
+    unsafe { overwrite(target_base, source) }
+}
+
+/// Find the first entry at or after `start_index`
+///
+/// # Safety
+///
+/// Same as [`find_next_valid_entry`]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn hashmap_mem_next_entry(
+    base_ptr: *mut u8,
+    start_index: u32,
+) -> HashmapMemEntry {
+    unsafe {
+        let (key, value, index) = find_next_valid_entry(base_ptr, start_index);
+        HashmapMemEntry { key, value, index }
+    }
+}
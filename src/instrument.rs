@@ -0,0 +1,133 @@
+/*
+ * Copyright (c) Peter Bjorklund. All rights reserved. https://github.com/piot/hashmap-mem
+ * Licensed under the MIT License. See LICENSE in the project root for license information.
+ */
+
+//! Counters of the work done by the operations on a map
+//!
+//! With the `instrument` feature, every map counts its lookups, inserts, removes,
+//! probe steps and rehashed keys in [`MapHeader::counters`]. The counters are atomics,
+//! so they can be bumped while the operation holds a `&MapHeader`. They are bumped
+//! with relaxed adds, and wrap around at `u32::MAX`. Only operations given a `*mut u8` map pointer
+//! count: reads through shared pointers, like `has`, `MapView::get` and
+//! `OwnedMap::get`, must not write to the map memory, so they are not counted. Read
+//! the counters with [`read_counters`] now and then to tune `logical_limit` and
+//! `probe_limit`, and start over with [`reset_counters`].
+
+#[cfg(feature = "instrument")]
+use crate::{MapHeader, checked_header};
+use core::ptr;
+#[cfg(feature = "instrument")]
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// Index of a counter in [`MapHeader::counters`]
+#[derive(Copy, Clone)]
+pub enum Counter {
+    Lookups = 0,
+    Inserts = 1,
+    Removes = 2,
+    ProbeSteps = 3,
+    Collisions = 4,
+    FailedInserts = 5,
+    KeyHashes = 6,
+}
+
+/// Snapshot of the counters of a map, see [`read_counters`]
+#[cfg(feature = "instrument")]
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct MapCounters {
+    /// Lookups of a key, including the ones made by `lookup_many`. Lookups through
+    /// shared pointers, like `has`, are not counted
+    pub lookups: u32,
+    /// Keys added to the map
+    pub inserts: u32,
+    /// Entries removed from the map
+    pub removes: u32,
+    /// Buckets visited while looking for a key or for room for it
+    pub probe_steps: u32,
+    /// Stored keys that were compared with a key and turned out different
+    pub collisions: u32,
+    /// Keys that found no room, because the map was full or the probe limit was reached
+    pub failed_inserts: u32,
+    /// Keys hashed again while their entries were rehashed or copied in from another
+    /// map. Maps that store hashes reuse them instead.
+    pub key_hashes: u32,
+}
+
+/// The counter in the header of the map at `base_ptr`
+#[cfg(feature = "instrument")]
+unsafe fn counter_at<'a>(base_ptr: *const u8, counter: Counter) -> &'a AtomicU32 {
+    unsafe { &(*base_ptr.cast::<MapHeader>()).counters[counter as usize] }
+}
+
+/// Add `amount` to a counter of the map
+#[inline(always)]
+pub unsafe fn count(base_ptr: *mut u8, counter: Counter, amount: u32) {
+    unsafe { Tally::map(base_ptr).count(counter, amount) }
+}
+
+/// Map that the work of a lookup is counted in, or none for lookups through shared
+/// pointers
+#[derive(Copy, Clone)]
+pub struct Tally(#[cfg_attr(not(feature = "instrument"), allow(dead_code))] *mut u8);
+
+impl Tally {
+    /// Count nothing
+    pub const NONE: Self = Self(ptr::null_mut());
+
+    /// Count in the header of the map at `base_ptr`, which must be writable
+    pub const fn map(base_ptr: *mut u8) -> Self {
+        Self(base_ptr)
+    }
+
+    /// Add `amount` to a counter, unless this is [`Tally::NONE`]
+    #[cfg(feature = "instrument")]
+    #[inline]
+    pub unsafe fn count(self, counter: Counter, amount: u32) {
+        if !self.0.is_null() {
+            unsafe { counter_at(self.0, counter).fetch_add(amount, Ordering::Relaxed) };
+        }
+    }
+
+    /// Counters are only kept with the `instrument` feature
+    #[cfg(not(feature = "instrument"))]
+    #[inline(always)]
+    pub const unsafe fn count(self, _counter: Counter, _amount: u32) {}
+}
+
+/// Read the counters of a map
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+#[cfg(feature = "instrument")]
+#[must_use]
+pub unsafe fn read_counters(base_ptr: *const u8) -> MapCounters {
+    unsafe {
+        checked_header(base_ptr);
+        let read = |counter| counter_at(base_ptr, counter).load(Ordering::Relaxed);
+        MapCounters {
+            lookups: read(Counter::Lookups),
+            inserts: read(Counter::Inserts),
+            removes: read(Counter::Removes),
+            probe_steps: read(Counter::ProbeSteps),
+            collisions: read(Counter::Collisions),
+            failed_inserts: read(Counter::FailedInserts),
+            key_hashes: read(Counter::KeyHashes),
+        }
+    }
+}
+
+/// Set every counter of a map back to zero
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+#[cfg(feature = "instrument")]
+pub unsafe fn reset_counters(base_ptr: *mut u8) {
+    unsafe {
+        for counter in &checked_header(base_ptr).counters {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+}
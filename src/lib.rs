@@ -3,12 +3,78 @@
  * Licensed under the MIT License. See LICENSE in the project root for license information.
  */
 
-use fxhash::FxHasher64;
-use std::cmp::{max, min};
-use std::hash::Hasher;
-use std::mem::size_of;
-use std::ops::Not;
-use std::{ptr, slice};
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+use core::alloc::Layout;
+use core::cmp::{max, min};
+use core::fmt;
+use core::hash::Hasher;
+use core::hint;
+use core::iter::FusedIterator;
+use core::marker::PhantomData;
+use core::mem::{align_of, size_of};
+use core::ops::{ControlFlow, Not};
+use core::ptr::{self, NonNull};
+use core::slice;
+use core::sync::atomic::AtomicU32;
+use siphasher::sip::SipHasher13;
+#[cfg(feature = "std")]
+use std::collections::hash_map::RandomState;
+#[cfg(feature = "std")]
+use std::hash::BuildHasher;
+
+mod arena;
+mod attach;
+mod builder;
+mod byte_map;
+mod diff;
+mod dump;
+mod entry;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+mod instrument;
+mod macros;
+pub mod overlay;
+#[cfg(feature = "alloc")]
+mod owned;
+mod portable;
+mod seqlock;
+pub mod sharded;
+#[cfg(feature = "simd")]
+mod simd;
+mod snapshot;
+pub mod view;
+
+pub use arena::{ArenaDirectory, ArenaPlan, init_arena, map_ptr, plan_arena};
+pub use attach::{AttachedMap, attach};
+pub use builder::MapInitBuilder;
+#[cfg(feature = "alloc")]
+pub use diff::diff;
+pub use diff::{
+    Journal, PatchError, apply_diff, apply_journal, insert_journaled, remove_journaled,
+};
+#[cfg(feature = "alloc")]
+pub use dump::dump_json;
+pub use dump::{DumpOptions, debug_dump};
+pub use entry::{Entry, OccupiedEntry, VacantEntry, entry};
+#[cfg(feature = "instrument")]
+pub use instrument::{MapCounters, read_counters, reset_counters};
+pub use overlay::{Staging, begin_staging};
+#[cfg(feature = "alloc")]
+pub use owned::OwnedMap;
+pub use portable::{load_le_image, write_le_image};
+pub use seqlock::{begin_write, end_write, len_consistent, lookup_consistent, write_consistent};
+#[cfg(feature = "alloc")]
+pub use snapshot::serialize;
+pub use snapshot::{SnapshotError, deserialize_into};
+pub use view::{MapView, MapViewMut, Pod, layout_for};
+
+use instrument::{Counter, Tally, count};
+#[cfg(all(feature = "simd", any(target_arch = "x86_64", target_arch = "aarch64")))]
+use simd::match_control_group;
 
 #[repr(u8)]
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
@@ -18,8 +84,55 @@ pub enum BucketStatus {
     Occupied = 2,
 }
 
+/// How the buckets of a map are arranged in memory
+#[repr(u8)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum LayoutKind {
+    /// Status, key and value are stored together in each bucket
+    Interleaved = 0,
+    /// The status bytes are stored one after the other in front of the buckets, so a
+    /// probe can check 16 of them at a time without loading the keys
+    ControlBytes = 1,
+    /// Status bytes, keys and values are each stored in an array of their own, so that
+    /// visiting only the values does not pull the keys into the cache
+    StructOfArrays = 2,
+}
+
+/// How keys are placed along their probe sequence
+#[repr(u8)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ProbeStrategy {
+    /// Keys take the first free bucket. Removal moves later keys of the cluster back into
+    /// the freed bucket when their probe sequence passes over it, so probe sequences stay
+    /// short under churn without [`rehash_in_place`]. Does not create tombstones.
+    Linear = 0,
+    /// Keys take buckets from keys closer to their home bucket, removal shifts the
+    /// following keys back. Never creates tombstones.
+    RobinHood = 1,
+    /// Keys take the first free bucket of a triangular sequence (home, +1, +3, +6, ...),
+    /// which breaks up the long clusters sequential keys build with linear probing.
+    /// Removal leaves a tombstone, since the probe sequences of a cluster are not
+    /// contiguous and can not be shifted back.
+    Quadratic = 2,
+}
+
+/// Hash function used for the keys of a map
+#[repr(u8)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum HashStrategy {
+    /// `FxHash`, fastest, but easy to provoke collisions with chosen keys
+    Fx = 0,
+    /// wyhash, fast and well mixed
+    WyHash = 1,
+    /// SipHash-1-3 keyed with the hash seed, for keys an attacker can influence
+    SipHash13 = 2,
+    /// Multiplicative (Fibonacci) hash of 4 and 8 byte keys read as integers, for
+    /// handles and ids. Keys of other sizes are hashed with `Fx`.
+    Integer = 3,
+}
+
 #[repr(C)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Debug)]
 pub struct MapHeader {
     // Do not change the order of the fields!
     pub capacity: u16,      // Do not change,
@@ -31,8 +144,482 @@ pub struct MapHeader {
     pub bucket_size: u32,
 
     pub logical_limit: u16,
-    pub key_offset: u8,
+    pub key_offset: u8, // Zero if the offset does not fit, see `key_offset_wide`
     pub padding_and_secret_code: u8,
+
+    pub hash_seed: u64,
+    pub layout_kind: u8,
+    pub generation: u16, // Bumped whenever an entry is removed
+    pub hash_offset: u32,
+    pub tombstone_count: u16,
+    pub flags: u8,        // `FLAG_*` bits
+    pub probe_limit: u16, // Maximum number of buckets visited for a key
+    pub probe_strategy: u8,
+    pub hash_strategy: u8,
+    pub format_version: u8, // `FORMAT_VERSION` of the layout that wrote the map
+    pub key_offset_wide: u32, // Key offset of every layout, zero in maps from before it existed
+
+    // Upper 16 bits of the counts above, zero in maps from before they existed
+    pub capacity_high: u16,
+    pub element_count_high: u16,
+    pub logical_limit_high: u16,
+    pub tombstone_count_high: u16,
+
+    pub mutation_generation: u32, // Bumped whenever an entry is added, removed or moved
+    pub scrub_byte: u8,           // Written over removed entries, see `FLAG_SCRUB_REMOVED`
+    pub value_stride: u32, // Distance between the values of a `StructOfArrays` map, else zero
+    pub counters: [AtomicU32; 7], // Operation counts kept with the `instrument` feature, else zero
+    pub sequence: u32,     // Odd while a writer changes the map, see `begin_write`
+    pub reserved: [u8; 20], // Zeroed, keeps the buckets on a cache line
+}
+
+/// Occupied status bytes carry a 7-bit hash fragment instead of `BucketStatus::Occupied`
+pub const FLAG_HASH_FRAGMENTS: u8 = 0x01;
+
+/// Home buckets are picked by Fibonacci hashing of the whole hash, instead of from a
+/// fixed range of hash bits
+pub const FLAG_FIBONACCI_INDEX: u8 = 0x02;
+
+/// A spill region follows the buckets, see [`MapInit::with_spill_capacity`]
+pub const FLAG_SPILL_REGION: u8 = 0x04;
+
+/// A dirty flag per bucket ends the map, see [`MapInit::with_dirty_flags`]
+pub const FLAG_DIRTY_FLAGS: u8 = 0x08;
+
+/// The map must not be changed, see [`freeze`]
+pub const FLAG_FROZEN: u8 = 0x10;
+
+/// Removed keys and values are overwritten with `MapHeader::scrub_byte`, see
+/// [`MapInit::with_scrub_removed`]
+pub const FLAG_SCRUB_REMOVED: u8 = 0x20;
+
+/// Keys are compared in constant time, see [`MapInit::with_constant_time_keys`]
+pub const FLAG_CONSTANT_TIME_KEYS: u8 = 0x40;
+
+/// Buckets are a multiple of a cache line long, see [`MapInit::with_cache_line_buckets`]
+pub const FLAG_CACHE_LINE_BUCKETS: u8 = 0x80;
+
+/// Size of the cache line that [`MapInit::with_cache_line_buckets`] aligns buckets to
+pub const CACHE_LINE_SIZE: u32 = 64;
+
+/// Byte pattern that makes reads of removed entries stand out, for
+/// [`MapInit::with_scrub_removed`]
+pub const POISON_BYTE: u8 = 0xdd;
+
+/// Outcome of reserving an entry for a key
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ReserveResult {
+    /// The key was inserted, the value bytes are uninitialized
+    Reserved(*mut u8),
+    /// The key was already present
+    Existing(*mut u8),
+    /// The map is full or the probe limit was exceeded
+    Full,
+}
+
+impl ReserveResult {
+    /// Value location, or null if the map is full
+    #[must_use]
+    pub const fn value_ptr(self) -> *mut u8 {
+        match self {
+            Self::Reserved(value_ptr) | Self::Existing(value_ptr) => value_ptr,
+            Self::Full => ptr::null_mut(),
+        }
+    }
+}
+
+/// Outcome of [`insert`]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum InsertResult {
+    /// The key was not present and has been added
+    Inserted,
+    /// The key was present and its value has been overwritten
+    Replaced,
+    /// The map is full or the probe limit was exceeded, nothing was written
+    Full,
+}
+
+/// Reasons why [`try_insert`] or [`try_get_or_reserve_entry`] could not add a key
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum InsertError {
+    /// Every bucket, and every spill bucket, holds an entry. Grow the map.
+    Full,
+    /// Buckets are free, but none of them within the probe limit of the key. Rehash
+    /// the map to remove tombstones, or grow it.
+    ProbeLimitExceeded,
+}
+
+impl fmt::Display for InsertError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            Self::Full => "every bucket holds an entry",
+            Self::ProbeLimitExceeded => "no free bucket within the probe limit",
+        };
+        f.write_str(message)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for InsertError {}
+
+/// Reasons why [`grow_into`], [`replace_with`] or [`merge`] can fail
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum GrowError {
+    KeySizeMismatch,
+    ValueSizeMismatch,
+    /// The target already holds entries
+    TargetNotEmpty,
+    /// The source holds more entries than the target's logical limit
+    InsufficientCapacity,
+    /// An entry could not be placed within the target's probe limit
+    ProbeLimitExceeded,
+}
+
+impl fmt::Display for GrowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            Self::KeySizeMismatch => "key sizes differ",
+            Self::ValueSizeMismatch => "value sizes differ",
+            Self::TargetNotEmpty => "target map is not empty",
+            Self::InsufficientCapacity => "target map logical limit is too small",
+            Self::ProbeLimitExceeded => "entry could not be placed within the probe limit",
+        };
+        f.write_str(message)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for GrowError {}
+
+/// Reasons why [`validate`] rejects a buffer
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ValidationError {
+    /// The buffer is shorter than the header or the buckets it describes
+    BufferTooSmall,
+    /// The buffer is not aligned for a `MapHeader`
+    Misaligned,
+    SecretCodeMismatch,
+    /// The map was written with another memory layout version
+    UnsupportedFormatVersion,
+    /// The capacity is zero or not a power of two
+    InvalidCapacity,
+    KeySizeZero,
+    UnknownLayoutKind,
+    UnknownProbeStrategy,
+    UnknownHashStrategy,
+    UnknownFlags,
+    /// The key, value or stored hash do not fit in the bucket, or overlap
+    InvalidBucketLayout,
+    LogicalLimitExceedsCapacity,
+    ProbeLimitZero,
+    /// A bucket has a status byte that the map can never write
+    InvalidBucketStatus,
+    /// `element_count` differs from the number of occupied buckets
+    ElementCountMismatch,
+    /// `tombstone_count` differs from the number of tombstones
+    TombstoneCountMismatch,
+    /// The spill region holds more entries than it has room for
+    InvalidSpillRegion,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            Self::BufferTooSmall => "buffer is too small for the map",
+            Self::Misaligned => "buffer is not aligned for the map header",
+            Self::SecretCodeMismatch => "secret code does not match",
+            Self::UnsupportedFormatVersion => "unsupported format version",
+            Self::InvalidCapacity => "capacity is not a power of two",
+            Self::KeySizeZero => "key size is zero",
+            Self::UnknownLayoutKind => "unknown layout kind",
+            Self::UnknownProbeStrategy => "unknown probe strategy",
+            Self::UnknownHashStrategy => "unknown hash strategy",
+            Self::UnknownFlags => "unknown header flags",
+            Self::InvalidBucketLayout => "bucket layout is inconsistent",
+            Self::LogicalLimitExceedsCapacity => "logical limit is larger than the capacity",
+            Self::ProbeLimitZero => "probe limit is zero",
+            Self::InvalidBucketStatus => "bucket has an invalid status",
+            Self::ElementCountMismatch => "element count does not match the occupied buckets",
+            Self::TombstoneCountMismatch => "tombstone count does not match the tombstones",
+            Self::InvalidSpillRegion => "spill region holds more entries than its capacity",
+        };
+        f.write_str(message)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ValidationError {}
+
+/// Reasons why [`layout_checked`] can not describe a map
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum LayoutError {
+    /// The key or value alignment is not a power of two
+    InvalidAlignment,
+    /// A bucket offset or the bucket size does not fit in a `u32`
+    BucketSizeOverflow,
+    /// The logical limit rounded up to a power of two does not fit in a `u32`
+    CapacityOverflow,
+    /// The total size of the map does not fit in a `u32`
+    TotalSizeOverflow,
+    /// No key size was given to a [`MapInitBuilder`]
+    KeySizeZero,
+    /// No logical limit was given to a [`MapInitBuilder`]
+    LogicalLimitZero,
+}
+
+impl fmt::Display for LayoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            Self::InvalidAlignment => "alignment is not a power of two",
+            Self::BucketSizeOverflow => "bucket size does not fit in a u32",
+            Self::CapacityOverflow => "capacity does not fit in a u32",
+            Self::TotalSizeOverflow => "total size does not fit in a u32",
+            Self::KeySizeZero => "key size is zero",
+            Self::LogicalLimitZero => "logical limit is zero",
+        };
+        f.write_str(message)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for LayoutError {}
+
+/// Reasons why [`try_init`] rejects a configuration or buffer
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum InitError {
+    /// The buffer is shorter than the header and buckets of the configuration
+    BufferTooSmall,
+    /// The buffer is not aligned for the header, keys or values
+    Misaligned,
+    KeySizeZero,
+    /// The key or value alignment is not a power of two
+    InvalidAlignment,
+    /// The capacity is zero or not a power of two
+    InvalidCapacity,
+    LogicalLimitExceedsCapacity,
+    ProbeLimitZero,
+    /// A bucket offset, the bucket size or the total size does not fit its header field
+    LayoutOverflow,
+}
+
+impl fmt::Display for InitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            Self::BufferTooSmall => "buffer is too small for the map",
+            Self::Misaligned => "buffer is not aligned for the map",
+            Self::KeySizeZero => "key size is zero",
+            Self::InvalidAlignment => "alignment is not a power of two",
+            Self::InvalidCapacity => "capacity is not a power of two",
+            Self::LogicalLimitExceedsCapacity => "logical limit is larger than the capacity",
+            Self::ProbeLimitZero => "probe limit is zero",
+            Self::LayoutOverflow => "map layout does not fit the header fields",
+        };
+        f.write_str(message)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for InitError {}
+
+/// Any error of the fallible operations, for callers that handle them in one place
+///
+/// Every error type of the crate converts into it, so `?` works across operations.
+/// [`try_overwrite`] returns it directly.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum MapError {
+    /// The buffer does not start with a map header, its secret code does not match
+    BadMagic,
+    /// The map was written with another memory layout version
+    UnsupportedVersion,
+    BufferTooSmall,
+    Misaligned,
+    /// The configuration does not describe a map that fits its header fields
+    InvalidConfig,
+    /// A header field or bucket holds a value the map can never write
+    Corrupt,
+    /// Two maps, or a map and a stream or patch, have different key or value sizes
+    LayoutMismatch,
+    /// The target already holds entries
+    TargetNotEmpty,
+    /// The map has no room for more entries. Grow it.
+    CapacityExceeded,
+    /// An entry could not be placed within the probe limit. Rehash or grow the map.
+    ProbeLimitExceeded,
+    /// A stream or patch ends in the middle of a record
+    Truncated,
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            Self::BadMagic => "secret code does not match",
+            Self::UnsupportedVersion => "unsupported format version",
+            Self::BufferTooSmall => "buffer is too small for the map",
+            Self::Misaligned => "buffer is not aligned for the map",
+            Self::InvalidConfig => "configuration does not describe a valid map",
+            Self::Corrupt => "map is corrupt",
+            Self::LayoutMismatch => "key or value sizes differ",
+            Self::TargetNotEmpty => "target map is not empty",
+            Self::CapacityExceeded => "map has no room for more entries",
+            Self::ProbeLimitExceeded => "entry could not be placed within the probe limit",
+            Self::Truncated => "input is truncated",
+        };
+        f.write_str(message)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for MapError {}
+
+impl From<ValidationError> for MapError {
+    fn from(error: ValidationError) -> Self {
+        match error {
+            ValidationError::BufferTooSmall => Self::BufferTooSmall,
+            ValidationError::Misaligned => Self::Misaligned,
+            ValidationError::SecretCodeMismatch => Self::BadMagic,
+            ValidationError::UnsupportedFormatVersion => Self::UnsupportedVersion,
+            _ => Self::Corrupt,
+        }
+    }
+}
+
+impl From<LayoutError> for MapError {
+    fn from(_: LayoutError) -> Self {
+        Self::InvalidConfig
+    }
+}
+
+impl From<InitError> for MapError {
+    fn from(error: InitError) -> Self {
+        match error {
+            InitError::BufferTooSmall => Self::BufferTooSmall,
+            InitError::Misaligned => Self::Misaligned,
+            _ => Self::InvalidConfig,
+        }
+    }
+}
+
+impl From<InsertError> for MapError {
+    fn from(error: InsertError) -> Self {
+        match error {
+            InsertError::Full => Self::CapacityExceeded,
+            InsertError::ProbeLimitExceeded => Self::ProbeLimitExceeded,
+        }
+    }
+}
+
+impl From<GrowError> for MapError {
+    fn from(error: GrowError) -> Self {
+        match error {
+            GrowError::KeySizeMismatch | GrowError::ValueSizeMismatch => Self::LayoutMismatch,
+            GrowError::TargetNotEmpty => Self::TargetNotEmpty,
+            GrowError::InsufficientCapacity => Self::CapacityExceeded,
+            GrowError::ProbeLimitExceeded => Self::ProbeLimitExceeded,
+        }
+    }
+}
+
+impl From<PatchError> for MapError {
+    fn from(error: PatchError) -> Self {
+        match error {
+            PatchError::SizeMismatch => Self::LayoutMismatch,
+            PatchError::Truncated => Self::Truncated,
+            PatchError::UnknownOperation => Self::Corrupt,
+            PatchError::Full => Self::CapacityExceeded,
+        }
+    }
+}
+
+impl From<SnapshotError> for MapError {
+    fn from(error: SnapshotError) -> Self {
+        match error {
+            SnapshotError::SizeMismatch => Self::LayoutMismatch,
+            SnapshotError::Truncated => Self::Truncated,
+            SnapshotError::Full => Self::CapacityExceeded,
+        }
+    }
+}
+
+/// What [`merge`] does with a key that is in both maps
+pub enum MergePolicy<'a> {
+    /// Keep the value of the target
+    KeepTarget,
+    /// Copy the value of the source over the value of the target
+    KeepSource,
+    /// Call `f(target_value_ptr, source_value_ptr)`, which updates the target value
+    Combine(&'a mut dyn FnMut(*mut u8, *const u8)),
+}
+
+impl fmt::Debug for MergePolicy<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::KeepTarget => f.write_str("KeepTarget"),
+            Self::KeepSource => f.write_str("KeepSource"),
+            Self::Combine(_) => f.write_str("Combine(..)"),
+        }
+    }
+}
+
+/// Overall condition of a map, combining tombstones, load and probe lengths
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum MapHealth {
+    Good,
+    /// Lookups are slower than they need to be, rebuilding would help
+    Degraded,
+    /// Inserts are about to fail even though the map is not logically full
+    Critical,
+}
+
+/// Number of distances counted by [`MapStats::distance_histogram`]
+pub const DISTANCE_HISTOGRAM_LEN: usize = 8;
+
+/// Occupancy and probe statistics of a map, from [`stats`]
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct MapStats {
+    pub element_count: u32,
+    pub tombstone_count: u32,
+    pub capacity: u32,
+    /// Occupied buckets divided by the capacity
+    pub load_factor: f32,
+    /// Longest distance of an entry from its home bucket
+    pub max_probe_distance: usize,
+    /// Mean distance of the entries from their home buckets, 0.0 for an empty map
+    pub avg_probe_distance: f32,
+    /// Longest run of buckets that are not empty, tombstones included
+    pub longest_cluster: usize,
+    /// Number of entries at each distance from their home bucket, the last slot also
+    /// counts every longer distance
+    pub distance_histogram: [u32; DISTANCE_HISTOGRAM_LEN],
+}
+
+/// Reference to an entry that stays valid until an entry is removed from the map
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Handle {
+    pub index: u32,
+    pub generation: u16,
+}
+
+/// Position to resume copying entries out of a map from, see [`next_page`]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Cursor {
+    /// Bucket index of the next entry, `u32::MAX` once every entry has been copied
+    pub index: u32,
+    /// [`generation`] of the map when the cursor was returned
+    pub generation: u32,
+}
+
+impl Cursor {
+    /// Cursor for the first page
+    pub const START: Self = Self {
+        index: 0,
+        generation: 0,
+    };
+
+    /// Check if every entry has been copied
+    #[must_use]
+    pub const fn is_done(&self) -> bool {
+        self.index == u32::MAX
+    }
 }
 
 pub struct MapInit {
@@ -40,105 +627,713 @@ pub struct MapInit {
     pub key_alignment: u8,
     pub value_size: u32,
     pub value_alignment: u8,
-    pub capacity: u16,
-    pub logical_limit: u16,
+    pub capacity: u32,
+    pub logical_limit: u32,
     pub total_size: u32,
+    pub store_hash: bool,
+    pub hash_fragments: bool,
+    pub fibonacci_index: bool,
+    pub probe_limit: u16,
+    pub probe_strategy: ProbeStrategy,
+    pub hash_strategy: HashStrategy,
+    pub layout_kind: LayoutKind,
+    pub spill_capacity: u16,
+    pub dirty_flags: bool,
+    pub scrub_removed: Option<u8>,
+    pub constant_time_keys: bool,
+    pub cache_line_buckets: bool,
+    pub seed: u64,
+}
+
+impl MapInit {
+    /// Store the 64-bit key hash in every bucket, so that entries can be migrated
+    /// to another map without rehashing their keys, and lookups only compare the key
+    /// bytes of buckets whose stored hash matches. Updates `total_size`.
+    #[must_use]
+    pub fn with_store_hash(mut self, store_hash: bool) -> Self {
+        self.store_hash = store_hash;
+        self.total_size = map_size(&self);
+        self
+    }
+
+    /// Select how the buckets are arranged in memory. [`LayoutKind::ControlBytes`]
+    /// moves the status bytes out of the buckets, and [`LayoutKind::StructOfArrays`]
+    /// the values as well, which changes the bucket layout. Updates `total_size`.
+    #[must_use]
+    pub fn with_layout_kind(mut self, layout_kind: LayoutKind) -> Self {
+        self.layout_kind = layout_kind;
+        self.total_size = map_size(&self);
+        self
+    }
+
+    /// Reserve room after the buckets for entries whose probe sequence is exhausted, so
+    /// that an insert only fails once the spill region is full as well. Spilled entries
+    /// are found by comparing them one by one, so keep the region small. Updates
+    /// `total_size`.
+    #[must_use]
+    pub fn with_spill_capacity(mut self, spill_capacity: u16) -> Self {
+        self.spill_capacity = spill_capacity;
+        self.total_size = map_size(&self);
+        self
+    }
+
+    /// Keep a dirty flag for every bucket (spill buckets included) after the buckets,
+    /// which [`insert_dirty`] and [`mark_dirty`] set, to find the entries changed since
+    /// [`clear_dirty_flags`]. Updates `total_size`.
+    #[must_use]
+    pub fn with_dirty_flags(mut self, dirty_flags: bool) -> Self {
+        self.dirty_flags = dirty_flags;
+        self.total_size = map_size(&self);
+        self
+    }
+
+    /// Overwrite the key and value bytes of removed entries with `scrub_byte`, so that
+    /// they do not linger in shared memory. Use zero to hide secrets, or [`POISON_BYTE`]
+    /// to make reads through stale pointers stand out.
+    #[must_use]
+    pub const fn with_scrub_removed(mut self, scrub_byte: Option<u8>) -> Self {
+        self.scrub_removed = scrub_byte;
+        self
+    }
+
+    /// Compare keys without stopping at the first differing byte, for keys that are
+    /// secrets like session tokens. Which buckets are compared still depends on the
+    /// hash of the key.
+    #[must_use]
+    pub const fn with_constant_time_keys(mut self, constant_time_keys: bool) -> Self {
+        self.constant_time_keys = constant_time_keys;
+        self
+    }
+
+    /// Round the bucket size up to a multiple of [`CACHE_LINE_SIZE`], so that a bucket
+    /// never straddles two cache lines and a probe touches a single one. Meant for
+    /// entries of around 48 to 64 bytes. The map memory must then be aligned to a cache
+    /// line, which [`alloc_layout`] takes care of. Updates `total_size`.
+    #[must_use]
+    pub fn with_cache_line_buckets(mut self, cache_line_buckets: bool) -> Self {
+        self.cache_line_buckets = cache_line_buckets;
+        self.total_size = map_size(&self);
+        self
+    }
+
+    /// Maximum number of buckets visited when looking for a key. Inserts fail once a
+    /// key can not be placed within this many probes, even if the map has free buckets.
+    /// The limit is never larger than the capacity.
+    #[must_use]
+    pub const fn with_probe_limit(mut self, probe_limit: u16) -> Self {
+        self.probe_limit = probe_limit;
+        self
+    }
+
+    /// Select how keys are placed. With [`ProbeStrategy::RobinHood`], inserting and
+    /// removing may move other entries, which invalidates their value pointers. With
+    /// [`ProbeStrategy::Linear`], removing may. Only [`ProbeStrategy::Quadratic`] keeps
+    /// every other entry in place on removal.
+    #[must_use]
+    pub const fn with_probe_strategy(mut self, probe_strategy: ProbeStrategy) -> Self {
+        self.probe_strategy = probe_strategy;
+        self
+    }
+
+    /// Select the hash function for the keys. Only the hashing changes, not the layout.
+    #[must_use]
+    pub const fn with_hash_strategy(mut self, hash_strategy: HashStrategy) -> Self {
+        self.hash_strategy = hash_strategy;
+        self
+    }
+
+    /// Mix a seed into the hash of every key, so that collision patterns differ between
+    /// maps. See [`random_seed`].
+    #[must_use]
+    pub const fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Keep a 7-bit fragment of the key hash in the status byte of every occupied
+    /// bucket, so that most non-matching keys are skipped without comparing them.
+    /// Does not change the layout.
+    #[must_use]
+    pub const fn with_hash_fragments(mut self, hash_fragments: bool) -> Self {
+        self.hash_fragments = hash_fragments;
+        self
+    }
+
+    /// Multiply the hash by the golden ratio before taking the home bucket from its top
+    /// bits, so that hashes that only differ in a few bits still spread over all buckets.
+    /// Does not change the layout.
+    #[must_use]
+    pub const fn with_fibonacci_index(mut self, fibonacci_index: bool) -> Self {
+        self.fibonacci_index = fibonacci_index;
+        self
+    }
 }
 
 #[derive(Clone, Copy, Debug)]
 pub struct BucketLayout {
     pub bucket_size: u32,
-    pub key_offset: u8,
+    pub key_offset: u32,
     pub value_offset: u32,
+    pub hash_offset: u32,  // Zero if the hash is not stored
+    pub value_stride: u32, // Zero unless the values are stored apart from the buckets
 }
 
 const MAP_BUCKETS_OFFSET: usize = size_of::<MapHeader>();
-const MAX_PROBE_DISTANCE: usize = 32;
+const _: () = assert!(MAP_BUCKETS_OFFSET.is_multiple_of(16));
+const DEFAULT_PROBE_LIMIT: u16 = 32;
+const SMALL_MAP_CAPACITY: usize = 8; // Maps up to this capacity are scanned instead of probed
+const BATCH_LEN: usize = 8; // Keys hashed and prefetched ahead by the batch functions
+const CONTROL_GROUP_LEN: usize = 16; // Control bytes checked at once by a probe
+
+#[inline]
+fn calculate_hash_bytes(key_bytes: &[u8], seed: u64, strategy: u8) -> u64 {
+    match strategy {
+        strategy if strategy == HashStrategy::Fx as u8 => fx_hash(key_bytes, seed),
+        strategy if strategy == HashStrategy::WyHash as u8 => wyhash::wyhash(key_bytes, seed),
+        strategy if strategy == HashStrategy::SipHash13 as u8 => {
+            let mut hasher = SipHasher13::new_with_keys(seed, 0);
+            hasher.write(key_bytes);
+            hasher.finish()
+        }
+        strategy if strategy == HashStrategy::Integer as u8 => integer_hash(key_bytes, seed),
+        strategy => panic!("hashmap, unknown hash strategy {strategy}"),
+    }
+}
+
+const FX_MULTIPLIER: u64 = 0x517c_c1b7_2722_0a95;
+
+/// 2^64 divided by the golden ratio. Multiplying by it spreads consecutive integers
+/// evenly over the high bits.
+const FIBONACCI_MULTIPLIER: u64 = 0x9E37_79B9_7F4A_7C15;
+
+#[inline]
+const fn fx_word(hash: u64, word: u64) -> u64 {
+    (hash.rotate_left(5) ^ word).wrapping_mul(FX_MULTIPLIER)
+}
+
+/// Same result as `FxHasher64` from the fxhash crate after `write_u64(seed)` and
+/// `write(key_bytes)` on little-endian targets, but without needing `std`
+///
+/// Key words are always read as little-endian, so a key hashes the same on every
+/// architecture and memory images stay valid when moved between them.
+#[inline]
+fn fx_hash(key_bytes: &[u8], seed: u64) -> u64 {
+    // A zero seed leaves the hasher state untouched, so unseeded maps hash as before
+    let mut hash = fx_word(0, seed);
+
+    let mut words = key_bytes.chunks_exact(8);
+    for word in &mut words {
+        hash = fx_word(hash, u64::from_le_bytes(word.try_into().unwrap()));
+    }
+
+    let mut rest = words.remainder();
+    if rest.len() >= 4 {
+        let (half_word, tail) = rest.split_at(4);
+        hash = fx_word(
+            hash,
+            u64::from(u32::from_le_bytes(half_word.try_into().unwrap())),
+        );
+        rest = tail;
+    }
+
+    for &byte in rest {
+        hash = fx_word(hash, u64::from(byte));
+    }
+
+    hash
+}
+
+/// Hash a 4 or 8 byte key as a little-endian integer, without going through the bytes
+/// one word at a time. Other key sizes fall back to [`fx_hash`].
+#[inline]
+fn integer_hash(key_bytes: &[u8], seed: u64) -> u64 {
+    let key = match key_bytes.len() {
+        4 => u64::from(u32::from_le_bytes(key_bytes.try_into().unwrap())),
+        8 => u64::from_le_bytes(key_bytes.try_into().unwrap()),
+        _ => return fx_hash(key_bytes, seed),
+    };
+    (key ^ seed).wrapping_mul(FIBONACCI_MULTIPLIER)
+}
+
+/// Whether two maps calculate the same hash for every key
+#[inline]
+const fn hashes_alike(a: &MapHeader, b: &MapHeader) -> bool {
+    a.hash_seed == b.hash_seed && a.hash_strategy == b.hash_strategy
+}
+
+/// Any status from `Occupied` upwards is a live entry, higher values hold a hash fragment
+#[inline]
+const fn is_occupied(status: u8) -> bool {
+    status >= BucketStatus::Occupied as u8
+}
+
+/// Status byte for an occupied bucket holding a key with `hash`
+#[inline]
+const fn occupied_status(header: &MapHeader, hash: u64) -> u8 {
+    if header.flags & FLAG_HASH_FRAGMENTS == 0 {
+        return BucketStatus::Occupied as u8;
+    }
+
+    // Bits just below the ones used for the bucket index
+    0x80 | ((hash >> 41) as u8 & 0x7f)
+}
 
+/// Number of buckets a probe sequence may visit, never more than the capacity
+#[inline]
+fn effective_probe_limit(header: &MapHeader) -> usize {
+    min(capacity_of(header), usize::from(header.probe_limit))
+}
+
+/// Bucket after `index` on a probe sequence, `probe` being the number of buckets
+/// visited before `index`
+///
+/// Quadratic probing takes triangular steps, which visit every bucket once within
+/// `capacity` probes, since the capacity is a power of two.
 #[inline]
-fn calculate_hash_bytes(key_bytes: &[u8]) -> u64 {
-    let mut hasher = FxHasher64::default();
-    hasher.write(key_bytes);
-    hasher.finish()
+fn next_probe_index(header: &MapHeader, index: usize, probe: usize) -> usize {
+    let step = if header.probe_strategy == ProbeStrategy::Quadratic as u8 {
+        probe + 1
+    } else {
+        1
+    };
+    (index + step) & (capacity_of(header) - 1)
 }
 
+/// Home bucket for a hash, see [`home_bucket`]
 #[inline]
-fn index_from_hash(hash: u64, capacity: u16) -> usize {
+fn index_from_hash(header: &MapHeader, hash: u64) -> usize {
+    let capacity = capacity_of(header);
     assert!(capacity.is_power_of_two());
 
+    if header.flags & FLAG_FIBONACCI_INDEX != 0 {
+        // Fibonacci hashing: the top bits of the product depend on every bit of the hash
+        let bits = capacity.trailing_zeros();
+        return if bits == 0 {
+            0
+        } else {
+            (hash.wrapping_mul(FIBONACCI_MULTIPLIER) >> (64 - bits)) as usize
+        };
+    }
+
     // take the top 16 bits; then mask to the actual size
     // FxHash have badly mixed lower bits
-    ((hash >> 48) as usize) & ((capacity as usize) - 1)
+    // Maps with more than 65536 buckets take their upper index bits from bits 24..40,
+    // clear of the hash fragment bits
+    (((hash >> 48) | ((hash >> 8) & 0xffff_0000)) as usize) & (capacity - 1)
+}
+
+/// Hint the CPU to load the cache line at `ptr`. Does nothing on other architectures.
+#[inline(always)]
+fn prefetch(ptr: *const u8) {
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        core::arch::x86_64::_mm_prefetch::<{ core::arch::x86_64::_MM_HINT_T0 }>(ptr.cast::<i8>());
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    let _ = ptr;
+}
+
+/// First bucket probed for a key with this hash
+#[inline]
+unsafe fn home_bucket_ptr(base_ptr: *const u8, hash: u64) -> *const u8 {
+    unsafe {
+        let header = &*base_ptr.cast::<MapHeader>();
+        let index = index_from_hash(header, hash);
+        base_ptr.add(buckets_offset(header) + index * header.bucket_size as usize)
+    }
 }
 
 /// Calculate memory layout for a map bucket
 #[inline]
 #[must_use]
-pub fn calculate_bucket_layout(
+pub const fn calculate_bucket_layout(
+    key_size: u32,
+    key_alignment: u8,
+    value_size: u32,
+    value_alignment: u8,
+) -> BucketLayout {
+    payload_layout(key_size, key_alignment, value_size, value_alignment, 1)
+}
+
+/// Layout of a bucket that starts with `status_size` bytes of status
+#[inline]
+const fn payload_layout(
     key_size: u32,
     key_alignment: u8,
     value_size: u32,
     value_alignment: u8,
+    status_size: u32,
 ) -> BucketLayout {
-    let status_size: u32 = 1;
     let mut current_offset = status_size;
 
     // Align key
-    let key_align = u32::from(key_alignment);
+    let key_align = key_alignment as u32;
     let key_offset = (current_offset + key_align - 1) & !(key_align - 1);
     current_offset = key_offset + key_size;
 
-    // Align value
-    let value_align = u32::from(value_alignment);
+    // Align value. Sets have no value, so its alignment does not matter
+    let value_align = if value_size == 0 {
+        1
+    } else {
+        value_alignment as u32
+    };
     let value_offset = (current_offset + value_align - 1) & !(value_align - 1);
     current_offset = value_offset + value_size;
 
     // Calculate final bucket size with proper alignment
-    let bucket_content_alignment = max(key_align, value_align);
+    let bucket_content_alignment = if key_align > value_align {
+        key_align
+    } else {
+        value_align
+    };
     let bucket_size =
         (current_offset + bucket_content_alignment - 1) & !(bucket_content_alignment - 1);
 
     BucketLayout {
         bucket_size,
-        key_offset: key_offset as u8,
+        key_offset,
         value_offset,
+        hash_offset: 0,
+        value_stride: 0,
     }
 }
 
+/// Calculate memory layout for the buckets of a map, including the optional stored hash
 #[must_use]
-pub const fn total_size(capacity: u16, bucket_size: u32) -> u32 {
-    (MAP_BUCKETS_OFFSET + capacity as usize * bucket_size as usize) as u32
+pub fn bucket_layout(config: &MapInit) -> BucketLayout {
+    // The buckets of a struct-of-arrays map only hold the key (and hash), the values
+    // are stored in an array of their own
+    let separate_values = config.layout_kind == LayoutKind::StructOfArrays;
+    let (value_size, value_alignment) = if separate_values {
+        (0, 1)
+    } else {
+        (config.value_size, config.value_alignment)
+    };
+    let mut bucket_layout = payload_layout(
+        config.key_size,
+        config.key_alignment,
+        value_size,
+        value_alignment,
+        bucket_status_size(config.layout_kind),
+    );
+
+    if config.store_hash {
+        // The hash goes after the value, aligned for a u64
+        let hash_align = align_of::<u64>() as u32;
+        let value_end = bucket_layout.value_offset + value_size;
+        let hash_offset = (value_end + hash_align - 1) & !(hash_align - 1);
+
+        let value_align = if value_size == 0 {
+            1
+        } else {
+            u32::from(value_alignment)
+        };
+        let bucket_content_alignment = max(
+            max(u32::from(config.key_alignment), value_align),
+            hash_align,
+        );
+        bucket_layout.hash_offset = hash_offset;
+        bucket_layout.bucket_size =
+            (hash_offset + size_of::<u64>() as u32 + bucket_content_alignment - 1)
+                & !(bucket_content_alignment - 1);
+    }
+
+    if separate_values {
+        // Sets still get a byte per value, so a value location leads back to its bucket
+        bucket_layout.value_offset = 0;
+        bucket_layout.value_stride = if config.value_size == 0 {
+            1
+        } else {
+            config
+                .value_size
+                .next_multiple_of(u32::from(config.value_alignment))
+        };
+    }
+    if config.cache_line_buckets {
+        bucket_layout.bucket_size = bucket_layout.bucket_size.next_multiple_of(CACHE_LINE_SIZE);
+    }
+
+    bucket_layout
 }
 
-#[must_use]
-pub fn layout(
+/// Same as [`bucket_layout`], but fails instead of wrapping around
+///
+/// The alignments must be powers of two.
+fn checked_bucket_layout(
     key_size: u32,
     key_alignment: u8,
     value_size: u32,
     value_alignment: u8,
-    logical_limit: u16,
-) -> (BucketLayout, MapInit) {
-    let capacity = logical_limit.next_power_of_two();
-    let bucket_layout =
-        calculate_bucket_layout(key_size, key_alignment, value_size, value_alignment);
-    (
-        bucket_layout,
-        MapInit {
-            key_size,
-            key_alignment,
-            value_size,
-            value_alignment,
-            capacity,
-            logical_limit,
-            total_size: total_size(capacity, bucket_layout.bucket_size),
-        },
-    )
-}
+    store_hash: bool,
+    layout_kind: LayoutKind,
+    cache_line_buckets: bool,
+) -> Result<BucketLayout, LayoutError> {
+    let key_align = u64::from(key_alignment);
+    let value_align = if value_size == 0 {
+        1
+    } else {
+        u64::from(value_alignment)
+    };
 
-pub const SECRET_CODE: u8 = 0x3d;
+    let key_offset = u64::from(bucket_status_size(layout_kind)).next_multiple_of(key_align);
+    let key_end = key_offset + u64::from(key_size);
+    let (value_offset, value_end, value_stride, value_align) =
+        if layout_kind == LayoutKind::StructOfArrays {
+            // Sets still get a byte per value, so a value location leads back to its bucket
+            let value_stride = max(u64::from(value_size).next_multiple_of(value_align), 1);
+            (0, key_end, value_stride, 1)
+        } else {
+            let value_offset = key_end.next_multiple_of(value_align);
+            (
+                value_offset,
+                value_offset + u64::from(value_size),
+                0,
+                value_align,
+            )
+        };
 
-/// Initialize a new hash map in pre-allocated memory
-///
+    let (hash_offset, payload_end, bucket_content_alignment) = if store_hash {
+        let hash_align = align_of::<u64>() as u64;
+        let hash_offset = value_end.next_multiple_of(hash_align);
+        (
+            hash_offset,
+            hash_offset + size_of::<u64>() as u64,
+            max(max(key_align, value_align), hash_align),
+        )
+    } else {
+        (0, value_end, max(key_align, value_align))
+    };
+    let bucket_alignment = if cache_line_buckets {
+        max(bucket_content_alignment, u64::from(CACHE_LINE_SIZE))
+    } else {
+        bucket_content_alignment
+    };
+
+    let to_u32 = |offset: u64| u32::try_from(offset).map_err(|_| LayoutError::BucketSizeOverflow);
+    Ok(BucketLayout {
+        bucket_size: to_u32(payload_end.next_multiple_of(bucket_alignment))?,
+        key_offset: to_u32(key_offset)?,
+        value_offset: to_u32(value_offset)?,
+        hash_offset: to_u32(hash_offset)?,
+        value_stride: to_u32(value_stride)?,
+    })
+}
+
+/// Alignment the map memory needs for the header and the bucket contents
+#[must_use]
+pub const fn required_alignment(key_alignment: u8, value_alignment: u8) -> usize {
+    let content_alignment = if key_alignment > value_alignment {
+        key_alignment as usize
+    } else {
+        value_alignment as usize
+    };
+    if content_alignment > align_of::<MapHeader>() {
+        content_alignment
+    } else {
+        align_of::<MapHeader>()
+    }
+}
+
+/// Size and alignment of the memory to allocate for a map
+///
+/// # Panics
+///
+/// If `config.total_size` rounded up to the alignment overflows `isize`
+#[must_use]
+pub fn alloc_layout(config: &MapInit) -> Layout {
+    Layout::from_size_align(config.total_size as usize, map_alignment(config))
+        .expect("map size does not fit in a memory layout")
+}
+
+/// Alignment of the map memory for `config`, a whole cache line for cache line buckets
+fn map_alignment(config: &MapInit) -> usize {
+    let alignment = required_alignment(config.key_alignment, config.value_alignment);
+    if config.cache_line_buckets {
+        max(alignment, CACHE_LINE_SIZE as usize)
+    } else {
+        alignment
+    }
+}
+
+#[must_use]
+pub const fn total_size(capacity: u32, bucket_size: u32) -> u32 {
+    (MAP_BUCKETS_OFFSET + capacity as usize * bucket_size as usize) as u32
+}
+
+/// Bytes of memory needed for a map made by [`layout`] with the same arguments
+///
+/// Can be used in const contexts, to size a static buffer. The buffer must also be
+/// aligned to [`required_alignment`].
+#[must_use]
+pub const fn required_size(
+    key_size: u32,
+    key_alignment: u8,
+    value_size: u32,
+    value_alignment: u8,
+    logical_limit: u32,
+) -> usize {
+    let capacity = logical_limit.next_power_of_two();
+    let bucket_layout =
+        calculate_bucket_layout(key_size, key_alignment, value_size, value_alignment);
+    total_size(capacity, bucket_layout.bucket_size) as usize
+}
+
+/// Status bytes at the start of every bucket
+const fn bucket_status_size(layout_kind: LayoutKind) -> u32 {
+    match layout_kind {
+        LayoutKind::Interleaved => 1,
+        LayoutKind::ControlBytes | LayoutKind::StructOfArrays => 0,
+    }
+}
+
+/// Bytes of memory needed for a map, including the control bytes and spill region
+fn map_size(config: &MapInit) -> u32 {
+    let control_len = control_bytes_len(config.layout_kind as u8, config.capacity as usize);
+    let layout = bucket_layout(config);
+    let buckets_end = total_size(config.capacity, layout.bucket_size) + control_len as u32;
+    map_end(
+        u64::from(buckets_end),
+        u64::from(config.capacity),
+        config.spill_capacity,
+        layout.bucket_size,
+        layout.value_stride,
+        config.dirty_flags,
+    ) as u32
+}
+
+#[must_use]
+pub fn layout(
+    key_size: u32,
+    key_alignment: u8,
+    value_size: u32,
+    value_alignment: u8,
+    logical_limit: u32,
+) -> (BucketLayout, MapInit) {
+    let capacity = logical_limit.next_power_of_two();
+    let bucket_layout =
+        calculate_bucket_layout(key_size, key_alignment, value_size, value_alignment);
+    (
+        bucket_layout,
+        default_map_init(
+            key_size,
+            key_alignment,
+            value_size,
+            value_alignment,
+            capacity,
+            logical_limit,
+            total_size(capacity, bucket_layout.bucket_size),
+        ),
+    )
+}
+
+/// Same as [`layout`], but fails instead of wrapping around on sizes that do not fit
+/// the header fields
+///
+/// # Errors
+///
+/// Returns a [`LayoutError`] naming the value that does not fit
+pub fn layout_checked(
+    key_size: u32,
+    key_alignment: u8,
+    value_size: u32,
+    value_alignment: u8,
+    logical_limit: u32,
+) -> Result<(BucketLayout, MapInit), LayoutError> {
+    if !key_alignment.is_power_of_two() || (value_size != 0 && !value_alignment.is_power_of_two()) {
+        return Err(LayoutError::InvalidAlignment);
+    }
+
+    let capacity = logical_limit
+        .checked_next_power_of_two()
+        .ok_or(LayoutError::CapacityOverflow)?;
+    let bucket_layout = checked_bucket_layout(
+        key_size,
+        key_alignment,
+        value_size,
+        value_alignment,
+        false,
+        LayoutKind::Interleaved,
+        false,
+    )?;
+    let total_size = u32::try_from(
+        MAP_BUCKETS_OFFSET as u64 + u64::from(capacity) * u64::from(bucket_layout.bucket_size),
+    )
+    .map_err(|_| LayoutError::TotalSizeOverflow)?;
+
+    Ok((
+        bucket_layout,
+        default_map_init(
+            key_size,
+            key_alignment,
+            value_size,
+            value_alignment,
+            capacity,
+            logical_limit,
+            total_size,
+        ),
+    ))
+}
+
+/// Configuration of an interleaved map with the default settings, as made by [`layout`]
+/// and [`layout_checked`]
+const fn default_map_init(
+    key_size: u32,
+    key_alignment: u8,
+    value_size: u32,
+    value_alignment: u8,
+    capacity: u32,
+    logical_limit: u32,
+    total_size: u32,
+) -> MapInit {
+    MapInit {
+        key_size,
+        key_alignment,
+        value_size,
+        value_alignment,
+        capacity,
+        logical_limit,
+        total_size,
+        store_hash: false,
+        hash_fragments: false,
+        fibonacci_index: false,
+        probe_limit: DEFAULT_PROBE_LIMIT,
+        probe_strategy: ProbeStrategy::Linear,
+        hash_strategy: HashStrategy::Fx,
+        layout_kind: LayoutKind::Interleaved,
+        spill_capacity: 0,
+        dirty_flags: false,
+        scrub_removed: None,
+        constant_time_keys: false,
+        cache_line_buckets: false,
+        seed: 0,
+    }
+}
+
+pub const SECRET_CODE: u8 = 0x3d;
+
+/// Version of the memory layout written by this crate
+///
+/// Version 1 maps have the original 24 byte header, from before the hash settings and
+/// the `format_version` field, and can be upgraded with [`migrate_v1_to_v3`]. Version 2
+/// maps come from before the header had a `mutation_generation` field and can be
+/// upgraded with [`migrate_v2_to_v3`].
+pub const FORMAT_VERSION: u8 = 3;
+
+/// Size of the version 1 header, which is where its buckets start
+const V1_BUCKETS_OFFSET: usize = 24;
+
+/// Size of the version 2 header, which is where its buckets start
+const V2_BUCKETS_OFFSET: usize = 64;
+
+/// Seed that differs between processes, for [`MapInit::with_seed`]
+#[cfg(feature = "std")]
+#[must_use]
+pub fn random_seed() -> u64 {
+    RandomState::new().hash_one(0x5eed_u64)
+}
+
+/// Initialize a new hash map in pre-allocated memory
+///
 /// # Safety
 ///
 /// - `map_base` must point to valid, properly aligned memory of sufficient size
@@ -148,56 +1343,529 @@ pub unsafe fn init(map_base: *mut u8, config: &MapInit) {
         config.capacity.is_power_of_two(),
         "Capacity must be a power of two"
     );
+    assert_ne!(config.probe_limit, 0, "Probe limit cannot be zero");
 
     let map_header = map_base.cast::<MapHeader>();
-    let layout = calculate_bucket_layout(
-        config.key_size,
-        config.key_alignment,
-        config.value_size,
-        config.value_alignment,
-    );
+    let layout = bucket_layout(config);
+    let (capacity, capacity_high) = split_count(config.capacity as usize);
+    let (logical_limit, logical_limit_high) = split_count(config.logical_limit as usize);
 
     // Initialize header
     unsafe {
         ptr::write(
             map_header,
             MapHeader {
-                capacity: config.capacity,
-                logical_limit: config.logical_limit,
+                capacity,
+                logical_limit,
                 key_size: config.key_size,
                 value_size: config.value_size,
                 bucket_size: layout.bucket_size,
-                key_offset: layout.key_offset,
+                key_offset: u8::try_from(layout.key_offset).unwrap_or(0),
                 value_offset: layout.value_offset,
                 element_count: 0,
                 padding_and_secret_code: SECRET_CODE,
+                hash_seed: config.seed,
+                layout_kind: config.layout_kind as u8,
+                generation: 0,
+                hash_offset: layout.hash_offset,
+                tombstone_count: 0,
+                flags: if config.hash_fragments {
+                    FLAG_HASH_FRAGMENTS
+                } else {
+                    0
+                } | if config.fibonacci_index {
+                    FLAG_FIBONACCI_INDEX
+                } else {
+                    0
+                } | if config.spill_capacity != 0 {
+                    FLAG_SPILL_REGION
+                } else {
+                    0
+                } | if config.dirty_flags {
+                    FLAG_DIRTY_FLAGS
+                } else {
+                    0
+                } | if config.scrub_removed.is_some() {
+                    FLAG_SCRUB_REMOVED
+                } else {
+                    0
+                } | if config.constant_time_keys {
+                    FLAG_CONSTANT_TIME_KEYS
+                } else {
+                    0
+                } | if config.cache_line_buckets {
+                    FLAG_CACHE_LINE_BUCKETS
+                } else {
+                    0
+                },
+                probe_limit: config.probe_limit,
+                probe_strategy: config.probe_strategy as u8,
+                hash_strategy: config.hash_strategy as u8,
+                format_version: FORMAT_VERSION,
+                key_offset_wide: layout.key_offset,
+                capacity_high,
+                element_count_high: 0,
+                logical_limit_high,
+                tombstone_count_high: 0,
+                mutation_generation: 0,
+                scrub_byte: config.scrub_removed.unwrap_or(0),
+                value_stride: layout.value_stride,
+                counters: [const { AtomicU32::new(0) }; 7],
+                sequence: 0,
+                reserved: [0; 20],
             },
         );
     }
 
-    // Initialize buckets to empty
-    let buckets_start_ptr = unsafe { map_base.add(MAP_BUCKETS_OFFSET) };
-    let capacity = usize::from(config.capacity);
-    let bucket_size = layout.bucket_size as usize;
-
     // Zero out all bucket status bytes (Empty = 0)
-    for i in 0..capacity {
-        unsafe {
+    unsafe {
+        let header = &*map_header;
+        for i in 0..config.capacity as usize {
+            ptr::write(status_ptr(map_base, header, i), BucketStatus::Empty as u8);
+        }
+
+        if config.spill_capacity != 0 {
             ptr::write(
-                buckets_start_ptr.add(i * bucket_size),
-                BucketStatus::Empty as u8,
+                map_base.add(spill_offset(header)).cast::<SpillHeader>(),
+                SpillHeader {
+                    capacity: config.spill_capacity,
+                    len: 0,
+                },
+            );
+        }
+
+        if config.dirty_flags {
+            let dirty_offset = dirty_flags_offset(map_base, header);
+            ptr::write_bytes(
+                map_base.add(dirty_offset),
+                0,
+                map_len(map_base, header) - dirty_offset,
             );
         }
     }
 }
 
+/// Initialize a new hash map after checking the configuration and the buffer
+///
+/// Unlike [`init`], nothing is written unless the whole map fits in `buffer_len` bytes
+/// and every header field can hold its value.
+///
+/// # Safety
+///
+/// - `map_base` must point to `buffer_len` writable bytes
+/// - The memory must remain valid for the lifetime of the map
+///
+/// # Errors
+///
+/// Returns the first [`InitError`] found
+pub unsafe fn try_init(
+    map_base: *mut u8,
+    config: &MapInit,
+    buffer_len: usize,
+) -> Result<(), InitError> {
+    if config.key_size == 0 {
+        return Err(InitError::KeySizeZero);
+    }
+    // Sets have no value, so their value alignment is never used
+    if !config.key_alignment.is_power_of_two()
+        || (config.value_size != 0 && !config.value_alignment.is_power_of_two())
+    {
+        return Err(InitError::InvalidAlignment);
+    }
+    if !config.capacity.is_power_of_two() {
+        return Err(InitError::InvalidCapacity);
+    }
+    if config.logical_limit > config.capacity {
+        return Err(InitError::LogicalLimitExceedsCapacity);
+    }
+    if config.probe_limit == 0 {
+        return Err(InitError::ProbeLimitZero);
+    }
+
+    let layout = checked_bucket_layout(
+        config.key_size,
+        config.key_alignment,
+        config.value_size,
+        config.value_alignment,
+        config.store_hash,
+        config.layout_kind,
+        config.cache_line_buckets,
+    )
+    .map_err(|_| InitError::LayoutOverflow)?;
+    let control_len = control_bytes_len(config.layout_kind as u8, config.capacity as usize);
+    let required_size = map_end(
+        (MAP_BUCKETS_OFFSET + control_len) as u64
+            + u64::from(config.capacity) * u64::from(layout.bucket_size),
+        u64::from(config.capacity),
+        config.spill_capacity,
+        layout.bucket_size,
+        layout.value_stride,
+        config.dirty_flags,
+    );
+    if required_size > u64::from(u32::MAX) {
+        return Err(InitError::LayoutOverflow);
+    }
+    if required_size > buffer_len as u64 {
+        return Err(InitError::BufferTooSmall);
+    }
+
+    if map_base.align_offset(map_alignment(config)) != 0 {
+        return Err(InitError::Misaligned);
+    }
+
+    unsafe { init(map_base, config) };
+    Ok(())
+}
+
+/// Initialize a new hash map in a byte slice
+///
+/// Safe counterpart of [`try_init`]: the slice length and alignment are checked, so a
+/// bad configuration or buffer gives an error instead of undefined behavior.
+///
+/// # Errors
+///
+/// Returns the first [`InitError`] found, the slice is untouched in that case
+pub fn init_in_slice(buffer: &mut [u8], config: &MapInit) -> Result<(), InitError> {
+    unsafe { try_init(buffer.as_mut_ptr(), config, buffer.len()) }
+}
+
+/// Check that a buffer holds a well-formed map before using it
+///
+/// Intended for maps that come from untrusted sources, like the network or a save
+/// file. Only reads from the buffer. A map that passes can be used with all the
+/// other functions without reading or writing outside of `buffer_len` bytes.
+///
+/// # Safety
+///
+/// - `base_ptr` must point to `buffer_len` readable bytes
+///
+/// # Errors
+///
+/// Returns the first [`ValidationError`] found
+pub unsafe fn validate(base_ptr: *const u8, buffer_len: usize) -> Result<(), ValidationError> {
+    if buffer_len < MAP_BUCKETS_OFFSET {
+        return Err(ValidationError::BufferTooSmall);
+    }
+    if base_ptr.align_offset(align_of::<MapHeader>()) != 0 {
+        return Err(ValidationError::Misaligned);
+    }
+
+    let header = unsafe { &*base_ptr.cast::<MapHeader>() };
+    if header.padding_and_secret_code != SECRET_CODE {
+        return Err(ValidationError::SecretCodeMismatch);
+    }
+    if header.format_version != FORMAT_VERSION {
+        return Err(ValidationError::UnsupportedFormatVersion);
+    }
+    if !capacity_of(header).is_power_of_two() {
+        return Err(ValidationError::InvalidCapacity);
+    }
+    if header.key_size == 0 {
+        return Err(ValidationError::KeySizeZero);
+    }
+    let interleaved = match header.layout_kind {
+        kind if kind == LayoutKind::Interleaved as u8 => true,
+        kind if kind == LayoutKind::ControlBytes as u8
+            || kind == LayoutKind::StructOfArrays as u8 =>
+        {
+            false
+        }
+        _ => return Err(ValidationError::UnknownLayoutKind),
+    };
+    let separate_values = header.layout_kind == LayoutKind::StructOfArrays as u8;
+    let robin_hood = match header.probe_strategy {
+        strategy
+            if strategy == ProbeStrategy::Linear as u8
+                || strategy == ProbeStrategy::Quadratic as u8 =>
+        {
+            false
+        }
+        strategy if strategy == ProbeStrategy::RobinHood as u8 => true,
+        _ => return Err(ValidationError::UnknownProbeStrategy),
+    };
+    if header.hash_strategy > HashStrategy::Integer as u8 {
+        return Err(ValidationError::UnknownHashStrategy);
+    }
+    let known_flags = FLAG_HASH_FRAGMENTS
+        | FLAG_FIBONACCI_INDEX
+        | FLAG_SPILL_REGION
+        | FLAG_DIRTY_FLAGS
+        | FLAG_FROZEN
+        | FLAG_SCRUB_REMOVED
+        | FLAG_CONSTANT_TIME_KEYS
+        | FLAG_CACHE_LINE_BUCKETS;
+    if header.flags & !known_flags != 0 {
+        return Err(ValidationError::UnknownFlags);
+    }
+    if logical_limit_of(header) > capacity_of(header) {
+        return Err(ValidationError::LogicalLimitExceedsCapacity);
+    }
+    if header.probe_limit == 0 {
+        return Err(ValidationError::ProbeLimitZero);
+    }
+
+    // Status byte (if any), key, value and optional hash must follow each other inside
+    // the bucket. Values stored apart from the buckets need a stride that fits them.
+    let bucket_size = u64::from(header.bucket_size);
+    let key_end = key_offset_of(header) as u64 + u64::from(header.key_size);
+    let value_end = if separate_values {
+        if header.value_stride == 0 || header.value_stride < header.value_size {
+            return Err(ValidationError::InvalidBucketLayout);
+        }
+        key_end
+    } else {
+        if header.value_stride != 0 {
+            return Err(ValidationError::InvalidBucketLayout);
+        }
+        u64::from(header.value_offset) + u64::from(header.value_size)
+    };
+    let payload_end = if header.hash_offset == 0 {
+        value_end
+    } else {
+        let hash_offset = u64::from(header.hash_offset);
+        let hash_align = align_of::<u64>() as u64;
+        if hash_offset < value_end
+            || !hash_offset.is_multiple_of(hash_align)
+            || !bucket_size.is_multiple_of(hash_align)
+        {
+            return Err(ValidationError::InvalidBucketLayout);
+        }
+        hash_offset + size_of::<u64>() as u64
+    };
+    if (interleaved && key_offset_of(header) == 0)
+        || (!separate_values && key_end > u64::from(header.value_offset))
+        || payload_end > bucket_size
+        || (header.flags & FLAG_CACHE_LINE_BUCKETS != 0
+            && !bucket_size.is_multiple_of(u64::from(CACHE_LINE_SIZE)))
+    {
+        return Err(ValidationError::InvalidBucketLayout);
+    }
+
+    let buckets_len = capacity_of(header) as u64 * bucket_size;
+    let buckets_end = values_end(
+        buckets_offset(header) as u64 + buckets_len,
+        capacity_of(header) as u64,
+        header.value_stride,
+    );
+    if buckets_end > buffer_len as u64 {
+        return Err(ValidationError::BufferTooSmall);
+    }
+
+    let mut spilled = 0;
+    if header.flags & FLAG_SPILL_REGION != 0 {
+        if (spill_offset(header) + SPILL_HEADER_LEN) as u64 > buffer_len as u64 {
+            return Err(ValidationError::BufferTooSmall);
+        }
+        let spill = unsafe { &*base_ptr.add(spill_offset(header)).cast::<SpillHeader>() };
+        if spill.len > spill.capacity {
+            return Err(ValidationError::InvalidSpillRegion);
+        }
+        spilled = usize::from(spill.len);
+    }
+    if unsafe { map_len(base_ptr, header) } as u64 > buffer_len as u64 {
+        return Err(ValidationError::BufferTooSmall);
+    }
+
+    let has_fragments = header.flags & FLAG_HASH_FRAGMENTS != 0;
+    let mut occupied = 0;
+    let mut tombstones = 0;
+    for index in 0..capacity_of(header) {
+        let status = unsafe { *status_ptr(base_ptr, header, index) };
+        match status {
+            status if status == BucketStatus::Empty as u8 => {}
+            status if status == BucketStatus::Tombstone as u8 && !robin_hood => tombstones += 1,
+            status if status == BucketStatus::Occupied as u8 && !has_fragments => occupied += 1,
+            status if status >= 0x80 && has_fragments => occupied += 1,
+            _ => return Err(ValidationError::InvalidBucketStatus),
+        }
+    }
+
+    if occupied + spilled != element_count_of(header) {
+        return Err(ValidationError::ElementCountMismatch);
+    }
+    if tombstones != tombstone_count_of(header) {
+        return Err(ValidationError::TombstoneCountMismatch);
+    }
+
+    Ok(())
+}
+
+/// Upgrade a version 1 map in place to the current [`FORMAT_VERSION`]
+///
+/// The header is first rewritten as a version 2 header, which [`migrate_v2_to_v3`]
+/// then upgrades the rest of the way.
+///
+/// Version 1 headers have no version field, so the caller must know that the memory
+/// holds a version 1 map. Version 1 maps were always unseeded `Fx` maps with linear
+/// probing, so those settings are written into the new fields. The buckets move up to
+/// make room for the larger header, so the buffer needs 104 bytes more than the
+/// version 1 map used.
+///
+/// # Safety
+///
+/// - `base_ptr` must point to `buffer_len` writable bytes holding a version 1 map
+///
+/// # Errors
+///
+/// Returns a [`ValidationError`] if the buffer is too small or the migrated map does
+/// not pass [`validate`]. The buffer is left untouched if it is too small.
+pub unsafe fn migrate_v1_to_v3(
+    base_ptr: *mut u8,
+    buffer_len: usize,
+) -> Result<(), ValidationError> {
+    if buffer_len < MAP_BUCKETS_OFFSET {
+        return Err(ValidationError::BufferTooSmall);
+    }
+    if base_ptr.align_offset(align_of::<MapHeader>()) != 0 {
+        return Err(ValidationError::Misaligned);
+    }
+
+    // The version 1 fields keep their offsets, the rest of the version 2 header is new
+    let header_ptr = base_ptr.cast::<MapHeader>();
+    let (secret_code, capacity, bucket_size) = unsafe {
+        (
+            (*header_ptr).padding_and_secret_code,
+            (*header_ptr).capacity,
+            (*header_ptr).bucket_size,
+        )
+    };
+    if secret_code != SECRET_CODE {
+        return Err(ValidationError::SecretCodeMismatch);
+    }
+    let buckets_len = usize::from(capacity) * bucket_size as usize;
+    if buckets_len > buffer_len - MAP_BUCKETS_OFFSET {
+        return Err(ValidationError::BufferTooSmall);
+    }
+
+    unsafe {
+        // Version 1 headers did not count tombstones
+        let mut tombstones = 0;
+        for index in 0..usize::from(capacity) {
+            let status_ptr = base_ptr.add(V1_BUCKETS_OFFSET + index * bucket_size as usize);
+            if *status_ptr == BucketStatus::Tombstone as u8 {
+                tombstones += 1;
+            }
+        }
+
+        ptr::copy(
+            base_ptr.add(V1_BUCKETS_OFFSET),
+            base_ptr.add(V2_BUCKETS_OFFSET),
+            buckets_len,
+        );
+        (*header_ptr).hash_seed = 0;
+        (*header_ptr).layout_kind = LayoutKind::Interleaved as u8;
+        (*header_ptr).generation = 0;
+        (*header_ptr).hash_offset = 0;
+        (*header_ptr).tombstone_count = tombstones;
+        (*header_ptr).flags = 0;
+        (*header_ptr).probe_limit = DEFAULT_PROBE_LIMIT;
+        (*header_ptr).probe_strategy = ProbeStrategy::Linear as u8;
+        (*header_ptr).hash_strategy = HashStrategy::Fx as u8;
+        (*header_ptr).format_version = 2;
+        (*header_ptr).key_offset_wide = u32::from((*header_ptr).key_offset);
+        (*header_ptr).capacity_high = 0;
+        (*header_ptr).element_count_high = 0;
+        (*header_ptr).logical_limit_high = 0;
+        (*header_ptr).tombstone_count_high = 0;
+
+        migrate_v2_to_v3(base_ptr, buffer_len)
+    }
+}
+
+/// Upgrade a version 2 map in place to the current [`FORMAT_VERSION`]
+///
+/// Everything after the header moves up to make room for the larger header, so the
+/// buffer needs 64 bytes more than the version 2 map used.
+///
+/// # Safety
+///
+/// - `base_ptr` must point to `buffer_len` writable bytes holding a version 2 map
+///
+/// # Errors
+///
+/// Returns a [`ValidationError`] if the map is not a version 2 map, the buffer is too
+/// small or the migrated map does not pass [`validate`]. The buffer is left untouched
+/// if it is too small.
+pub unsafe fn migrate_v2_to_v3(
+    base_ptr: *mut u8,
+    buffer_len: usize,
+) -> Result<(), ValidationError> {
+    if buffer_len < MAP_BUCKETS_OFFSET {
+        return Err(ValidationError::BufferTooSmall);
+    }
+    if base_ptr.align_offset(align_of::<MapHeader>()) != 0 {
+        return Err(ValidationError::Misaligned);
+    }
+
+    // The version 2 fields keep their offsets, and everything after them moves up by
+    // the same number of bytes, spill region and dirty flags included
+    let header_ptr = base_ptr.cast::<MapHeader>();
+    let header = unsafe { &*header_ptr };
+    if header.padding_and_secret_code != SECRET_CODE {
+        return Err(ValidationError::SecretCodeMismatch);
+    }
+    if header.format_version != 2 {
+        return Err(ValidationError::UnsupportedFormatVersion);
+    }
+    let growth = MAP_BUCKETS_OFFSET - V2_BUCKETS_OFFSET;
+    let buckets_end =
+        buckets_offset(header) as u64 + capacity_of(header) as u64 * u64::from(header.bucket_size);
+    let spill_capacity = if header.flags & FLAG_SPILL_REGION == 0 {
+        0
+    } else {
+        let old_spill_offset =
+            buckets_end.next_multiple_of(SPILL_HEADER_LEN as u64) - growth as u64;
+        if old_spill_offset + SPILL_HEADER_LEN as u64 > buffer_len as u64 {
+            return Err(ValidationError::BufferTooSmall);
+        }
+        unsafe {
+            (*base_ptr
+                .add(old_spill_offset as usize)
+                .cast::<SpillHeader>())
+            .capacity
+        }
+    };
+    let migrated_len = map_end(
+        buckets_end,
+        capacity_of(header) as u64,
+        spill_capacity,
+        header.bucket_size,
+        0,
+        header.flags & FLAG_DIRTY_FLAGS != 0,
+    );
+    if migrated_len > buffer_len as u64 {
+        return Err(ValidationError::BufferTooSmall);
+    }
+
+    unsafe {
+        ptr::copy(
+            base_ptr.add(V2_BUCKETS_OFFSET),
+            base_ptr.add(MAP_BUCKETS_OFFSET),
+            migrated_len as usize - MAP_BUCKETS_OFFSET,
+        );
+        (*header_ptr).format_version = FORMAT_VERSION;
+        (*header_ptr).mutation_generation = 0;
+        (*header_ptr).scrub_byte = 0;
+        (*header_ptr).value_stride = 0;
+        (*header_ptr).counters = [const { AtomicU32::new(0) }; 7];
+        (*header_ptr).sequence = 0;
+        (*header_ptr).reserved = [0; 20];
+    }
+
+    unsafe { validate(base_ptr, buffer_len) }
+}
+
 /// Fast key comparison helper
-// TODO: Check if the performance difference is significant
+///
+/// Keys of 8 to 16 bytes are compared as two unaligned `u64` loads, which overlap for
+/// keys shorter than 16 bytes. With the `simd` feature, longer keys are compared 16 or
+/// 32 bytes at a time.
 #[inline]
 unsafe fn matches_key(a: *const u8, b: *const u8, len: usize) -> bool {
     unsafe {
-        if len <= 16 {
-            if len == 0 { true } else {
+        match len {
+            0 => true,
+            1..8 => {
                 for i in 0..len {
                     if *a.add(i) != *b.add(i) {
                         return false;
@@ -205,381 +1873,3966 @@ unsafe fn matches_key(a: *const u8, b: *const u8, len: usize) -> bool {
                 }
                 true
             }
-        } else {
-            slice::from_raw_parts(a, len) == slice::from_raw_parts(b, len)
-        }
+            8..=16 => {
+                let word =
+                    |key: *const u8, offset: usize| key.add(offset).cast::<u64>().read_unaligned();
+                word(a, 0) == word(b, 0) && word(a, len - 8) == word(b, len - 8)
+            }
+            #[cfg(feature = "simd")]
+            _ => simd::keys_equal(a, b, len),
+            #[cfg(not(feature = "simd"))]
+            _ => slice::from_raw_parts(a, len) == slice::from_raw_parts(b, len),
+        }
+    }
+}
+
+/// Compare the stored key at `stored_key_ptr` with the key at `key_ptr`
+///
+/// Maps initialized with [`MapInit::with_constant_time_keys`] always compare every byte.
+#[inline]
+unsafe fn key_matches(header: &MapHeader, stored_key_ptr: *const u8, key_ptr: *const u8) -> bool {
+    unsafe {
+        let key_size = header.key_size as usize;
+        if header.flags & FLAG_CONSTANT_TIME_KEYS != 0 {
+            keys_equal_constant_time(stored_key_ptr, key_ptr, key_size)
+        } else {
+            matches_key(stored_key_ptr, key_ptr, key_size)
+        }
+    }
+}
+
+/// Check if the occupied bucket at `bucket_ptr` holds the key at `key_ptr`, whose hash
+/// is `hash`
+///
+/// Maps that store hashes compare them first, so the key bytes are only compared when
+/// the whole 64-bit hash matches.
+#[inline]
+unsafe fn bucket_holds_key(
+    header: &MapHeader,
+    bucket_ptr: *const u8,
+    key_ptr: *const u8,
+    hash: u64,
+) -> bool {
+    unsafe {
+        (header.hash_offset == 0
+            || *bucket_ptr.add(header.hash_offset as usize).cast::<u64>() == hash)
+            && key_matches(header, bucket_ptr.add(key_offset_of(header)), key_ptr)
+    }
+}
+
+/// Compare `len` bytes, taking the same time wherever the first difference is
+#[inline(never)]
+unsafe fn keys_equal_constant_time(a: *const u8, b: *const u8, len: usize) -> bool {
+    let mut difference = 0;
+    for i in 0..len {
+        difference |= unsafe { *a.add(i) ^ *b.add(i) };
+    }
+    // Keeps the compiler from turning the loop back into an early exit
+    hint::black_box(difference) == 0
+}
+
+/// Get or reserve an entry in the map
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+/// - `key_ptr` must point to a valid key of the size specified in the map header
+///
+/// # Returns
+///
+/// Pointer to the value location, or null if the map is full
+#[inline]
+pub unsafe fn get_or_reserve_entry(base_ptr: *mut u8, key_ptr: *const u8) -> *mut u8 {
+    unsafe { get_or_reserve_entry_hashed(base_ptr, key_ptr, key_hash(base_ptr, key_ptr)) }
+}
+
+/// Get or reserve an entry in the map for a key whose hash has already been calculated
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+/// - `key_ptr` must point to a valid key of the size specified in the map header
+/// - `hash` must be the [`key_hash`] of the key for this map
+///
+/// # Returns
+///
+/// Pointer to the value location, or null if the map is full
+#[inline]
+pub unsafe fn get_or_reserve_entry_hashed(
+    base_ptr: *mut u8,
+    key_ptr: *const u8,
+    hash: u64,
+) -> *mut u8 {
+    unsafe { reserve_hashed_entry(base_ptr, key_ptr, hash).value_ptr() }
+}
+
+/// Get or reserve an entry in the map, telling new entries apart from existing ones
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+/// - `key_ptr` must point to a valid key of the size specified in the map header
+///
+/// # Returns
+///
+/// `Reserved` with an uninitialized value location for a new entry, `Existing` with
+/// the current value location, or `Full` if the key could not be inserted
+#[inline]
+pub unsafe fn get_or_reserve_entry_ex(base_ptr: *mut u8, key_ptr: *const u8) -> ReserveResult {
+    unsafe { reserve_hashed_entry(base_ptr, key_ptr, key_hash(base_ptr, key_ptr)) }
+}
+
+/// Get or reserve an entry in the map, telling why a key could not be added
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+/// - `key_ptr` must point to a valid key of the size specified in the map header
+///
+/// # Returns
+///
+/// Pointer to the value location. The value bytes of a new entry are uninitialized.
+///
+/// # Errors
+///
+/// Returns an [`InsertError`] if the key is not in the map and there is no room for it
+#[inline]
+pub unsafe fn try_get_or_reserve_entry(
+    base_ptr: *mut u8,
+    key_ptr: *const u8,
+) -> Result<*mut u8, InsertError> {
+    unsafe {
+        match reserve_hashed_entry(base_ptr, key_ptr, key_hash(base_ptr, key_ptr)) {
+            ReserveResult::Reserved(value_ptr) | ReserveResult::Existing(value_ptr) => {
+                Ok(value_ptr)
+            }
+            ReserveResult::Full => Err(insert_error(base_ptr)),
+        }
+    }
+}
+
+/// Log that a key found no room, with the numbers needed to tune the map
+///
+/// Collects the [`stats`] of the map, which visits every bucket, so it is only called
+/// for failed inserts.
+#[cfg(feature = "tracing")]
+#[cold]
+unsafe fn warn_full(base_ptr: *const u8) {
+    unsafe {
+        let header = &*base_ptr.cast::<MapHeader>();
+        let stats = stats(base_ptr);
+        tracing::warn!(
+            capacity = stats.capacity,
+            element_count = stats.element_count,
+            tombstone_count = stats.tombstone_count,
+            logical_limit = logical_limit_of(header),
+            probe_limit = header.probe_limit,
+            spill_len = spill_len_of(base_ptr, header),
+            max_probe_distance = stats.max_probe_distance,
+            longest_cluster = stats.longest_cluster,
+            "hashmap, no room for key"
+        );
+    }
+}
+
+/// Why a key that is not in the map found no room
+#[inline]
+unsafe fn insert_error(base_ptr: *const u8) -> InsertError {
+    unsafe {
+        let header = &*base_ptr.cast::<MapHeader>();
+        let spill_room =
+            spill_header(base_ptr, header).is_some_and(|spill| spill.len < spill.capacity);
+        if !spill_room
+            && element_count_of(header) - spill_len_of(base_ptr, header) >= capacity_of(header)
+        {
+            InsertError::Full
+        } else {
+            InsertError::ProbeLimitExceeded
+        }
+    }
+}
+
+/// Get an entry, or insert one with its value bytes set to zero
+///
+/// Unlike [`get_or_reserve_entry`], the value of a new entry is never left uninitialized.
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+/// - `key_ptr` must point to a valid key of the size specified in the map header
+///
+/// # Returns
+///
+/// Pointer to the value location, or null if the map is full
+#[inline]
+pub unsafe fn get_or_insert_zeroed(base_ptr: *mut u8, key_ptr: *const u8) -> *mut u8 {
+    unsafe {
+        get_or_insert_with(base_ptr, key_ptr, |value_ptr| {
+            let value_size = (*base_ptr.cast::<MapHeader>()).value_size as usize;
+            ptr::write_bytes(value_ptr, 0, value_size);
+        })
+    }
+}
+
+/// Get an entry, or insert one and let `init_fn` write its value
+///
+/// `init_fn` is only called for new entries, with the value location of the entry, and
+/// must write all `value_size` bytes of it.
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+/// - `key_ptr` must point to a valid key of the size specified in the map header
+///
+/// # Returns
+///
+/// Pointer to the value location, or null if the map is full
+#[inline]
+pub unsafe fn get_or_insert_with(
+    base_ptr: *mut u8,
+    key_ptr: *const u8,
+    init_fn: impl FnOnce(*mut u8),
+) -> *mut u8 {
+    unsafe {
+        match reserve_hashed_entry(base_ptr, key_ptr, key_hash(base_ptr, key_ptr)) {
+            ReserveResult::Reserved(value_ptr) => {
+                init_fn(value_ptr);
+                value_ptr
+            }
+            ReserveResult::Existing(value_ptr) => value_ptr,
+            ReserveResult::Full => ptr::null_mut(),
+        }
+    }
+}
+
+/// Insert or replace an entry, copying the value into the map
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+/// - `key_ptr` must point to a valid key of the size specified in the map header
+/// - `value_ptr` must point to `value_size` readable bytes that are not inside the map
+#[inline]
+pub unsafe fn insert(base_ptr: *mut u8, key_ptr: *const u8, value_ptr: *const u8) -> InsertResult {
+    unsafe { insert_hashed(base_ptr, key_ptr, key_hash(base_ptr, key_ptr), value_ptr) }
+}
+
+/// Insert or replace an entry, telling why a key could not be added
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+/// - `key_ptr` must point to a valid key of the size specified in the map header
+/// - `value_ptr` must point to `value_size` readable bytes that are not inside the map
+///
+/// # Returns
+///
+/// Pointer to the value location the value was copied to
+///
+/// # Errors
+///
+/// Returns an [`InsertError`] if the key is not in the map and there is no room for
+/// it. Nothing is written then.
+#[inline]
+pub unsafe fn try_insert(
+    base_ptr: *mut u8,
+    key_ptr: *const u8,
+    value_ptr: *const u8,
+) -> Result<*mut u8, InsertError> {
+    unsafe {
+        let target_value_ptr = try_get_or_reserve_entry(base_ptr, key_ptr)?;
+        let value_size = (*base_ptr.cast::<MapHeader>()).value_size as usize;
+        ptr::copy_nonoverlapping(value_ptr, target_value_ptr, value_size);
+        Ok(target_value_ptr)
+    }
+}
+
+/// Insert or replace an entry for a key whose hash has already been calculated
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+/// - `key_ptr` must point to a valid key of the size specified in the map header
+/// - `hash` must be the [`key_hash`] of the key for this map
+/// - `value_ptr` must point to `value_size` readable bytes that are not inside the map
+#[inline]
+pub unsafe fn insert_hashed(
+    base_ptr: *mut u8,
+    key_ptr: *const u8,
+    hash: u64,
+    value_ptr: *const u8,
+) -> InsertResult {
+    unsafe {
+        let value_size = (*base_ptr.cast::<MapHeader>()).value_size as usize;
+        let (target_value_ptr, result) = match reserve_hashed_entry(base_ptr, key_ptr, hash) {
+            ReserveResult::Reserved(target_value_ptr) => (target_value_ptr, InsertResult::Inserted),
+            ReserveResult::Existing(target_value_ptr) => (target_value_ptr, InsertResult::Replaced),
+            ReserveResult::Full => return InsertResult::Full,
+        };
+
+        ptr::copy_nonoverlapping(value_ptr, target_value_ptr, value_size);
+        result
+    }
+}
+
+/// Insert or replace an entry, first passing the value it replaces to `on_replace`
+///
+/// Lets the caller release whatever the old value refers to, or combine it into
+/// something else, instead of losing it to the overwrite. `on_replace` is not called
+/// for new keys, or when the map is full.
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+/// - `key_ptr` must point to a valid key of the size specified in the map header
+/// - `value_ptr` must point to `value_size` readable bytes that are not inside the map
+/// - `on_replace` must not access the map other than through the pointer it is given,
+///   which is only valid until it returns
+#[inline]
+pub unsafe fn insert_or_update(
+    base_ptr: *mut u8,
+    key_ptr: *const u8,
+    value_ptr: *const u8,
+    on_replace: impl FnOnce(*const u8),
+) -> InsertResult {
+    unsafe {
+        let value_size = (*base_ptr.cast::<MapHeader>()).value_size as usize;
+        let (target_value_ptr, result) =
+            match reserve_hashed_entry(base_ptr, key_ptr, key_hash(base_ptr, key_ptr)) {
+                ReserveResult::Reserved(target_value_ptr) => {
+                    (target_value_ptr, InsertResult::Inserted)
+                }
+                ReserveResult::Existing(target_value_ptr) => {
+                    on_replace(target_value_ptr);
+                    (target_value_ptr, InsertResult::Replaced)
+                }
+                ReserveResult::Full => return InsertResult::Full,
+            };
+
+        ptr::copy_nonoverlapping(value_ptr, target_value_ptr, value_size);
+        result
+    }
+}
+
+/// Insert or replace an entry and mark it as dirty
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+/// - `key_ptr` must point to a valid key of the size specified in the map header
+/// - `value_ptr` must point to `value_size` readable bytes that are not inside the map
+///
+/// # Panics
+///
+/// If the map was not initialized with [`MapInit::with_dirty_flags`]
+pub unsafe fn insert_dirty(
+    base_ptr: *mut u8,
+    key_ptr: *const u8,
+    value_ptr: *const u8,
+) -> InsertResult {
+    unsafe {
+        let header = checked_header(base_ptr);
+        assert!(
+            header.flags & FLAG_DIRTY_FLAGS != 0,
+            "hashmap, map has no dirty flags"
+        );
+        let value_size = header.value_size as usize;
+        let hash = key_hash(base_ptr, key_ptr);
+        let (target_value_ptr, result) = match reserve_hashed_entry(base_ptr, key_ptr, hash) {
+            ReserveResult::Reserved(target_value_ptr) => (target_value_ptr, InsertResult::Inserted),
+            ReserveResult::Existing(target_value_ptr) => (target_value_ptr, InsertResult::Replaced),
+            ReserveResult::Full => return InsertResult::Full,
+        };
+
+        ptr::copy_nonoverlapping(value_ptr, target_value_ptr, value_size);
+        mark_dirty(base_ptr, target_value_ptr);
+        result
+    }
+}
+
+/// Mark the entry whose value is at `value_ptr` as dirty, after writing to the value
+///
+/// The flag moves with the entry when other entries are inserted or removed, and stays
+/// set until [`clear_dirty_flags`].
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+/// - `value_ptr` must be the value location of an entry in the map
+///
+/// # Panics
+///
+/// If the map was not initialized with [`MapInit::with_dirty_flags`]
+pub unsafe fn mark_dirty(base_ptr: *mut u8, value_ptr: *const u8) {
+    unsafe {
+        let header = &*base_ptr.cast::<MapHeader>();
+        assert!(
+            header.flags & FLAG_DIRTY_FLAGS != 0,
+            "hashmap, map has no dirty flags"
+        );
+        let index = bucket_index_of_value(base_ptr, value_ptr) as usize;
+        set_dirty_flag(base_ptr, header, index, true);
+    }
+}
+
+/// Call `f` with the key and value of every entry marked as dirty
+///
+/// Removed entries are not reported, so a replica has to learn about removals some
+/// other way, for example from a [`Journal`].
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+/// - `f` must not change the map
+///
+/// # Panics
+///
+/// If the map was not initialized with [`MapInit::with_dirty_flags`]
+pub unsafe fn for_each_dirty(base_ptr: *const u8, mut f: impl FnMut(*const u8, *const u8)) {
+    unsafe {
+        let header = checked_header(base_ptr);
+        assert!(
+            header.flags & FLAG_DIRTY_FLAGS != 0,
+            "hashmap, map has no dirty flags"
+        );
+        let key_offset = key_offset_of(header);
+
+        for_each_occupied_bucket(base_ptr, header, |index, bucket_ptr| {
+            let (flags_ptr, mask) = dirty_flag(base_ptr, header, index).unwrap_unchecked();
+            if *flags_ptr & mask != 0 {
+                f(
+                    bucket_ptr.add(key_offset),
+                    value_ptr_at(base_ptr, header, index, bucket_ptr),
+                );
+            }
+        });
+    }
+}
+
+/// Mark every entry as clean, typically after the dirty entries have been replicated
+///
+/// Does nothing if the map has no dirty flags.
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+pub unsafe fn clear_dirty_flags(base_ptr: *mut u8) {
+    unsafe {
+        let header = &*base_ptr.cast::<MapHeader>();
+        if header.flags & FLAG_DIRTY_FLAGS != 0 {
+            let dirty_offset = dirty_flags_offset(base_ptr, header);
+            ptr::write_bytes(
+                base_ptr.add(dirty_offset),
+                0,
+                map_len(base_ptr, header) - dirty_offset,
+            );
+        }
+    }
+}
+
+/// Insert a packed array of keys with a packed array of values
+///
+/// Keys are hashed a few at a time, and their home buckets prefetched, before they are
+/// inserted. Entries are inserted in order, and the batch stops at the first key that
+/// does not fit, so the keys from the returned count on are not in the map.
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+/// - `keys_ptr` must point to `count` keys of `key_size` bytes each, one after another
+/// - `values_ptr` must point to `count` values of `value_size` bytes each, one after
+///   another, that are not inside the map
+///
+/// # Returns
+///
+/// Number of entries inserted or replaced
+pub unsafe fn insert_batch(
+    base_ptr: *mut u8,
+    keys_ptr: *const u8,
+    values_ptr: *const u8,
+    count: usize,
+) -> usize {
+    unsafe {
+        let header = checked_header(base_ptr);
+        let key_size = header.key_size as usize;
+        let value_size = header.value_size as usize;
+
+        let mut hashes = [0u64; BATCH_LEN];
+        let mut start = 0;
+        while start < count {
+            let batch_len = min(BATCH_LEN, count - start);
+            for (offset, hash) in hashes[..batch_len].iter_mut().enumerate() {
+                *hash = key_hash(base_ptr, keys_ptr.add((start + offset) * key_size));
+                prefetch(home_bucket_ptr(base_ptr, *hash));
+            }
+
+            for (offset, &hash) in hashes[..batch_len].iter().enumerate() {
+                let index = start + offset;
+                let result = insert_hashed(
+                    base_ptr,
+                    keys_ptr.add(index * key_size),
+                    hash,
+                    values_ptr.add(index * value_size),
+                );
+                if result == InsertResult::Full {
+                    return index;
+                }
+            }
+
+            start += batch_len;
+        }
+
+        count
+    }
+}
+
+/// Add a key to a set, a map with a `value_size` of zero
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+/// - `key_ptr` must point to a valid key of the size specified in the map header
+///
+/// # Returns
+///
+/// `Inserted` if the key is new, `Replaced` if it was already in the set, or `Full`
+#[inline]
+pub unsafe fn insert_key(base_ptr: *mut u8, key_ptr: *const u8) -> InsertResult {
+    unsafe {
+        assert_eq!(
+            (*base_ptr.cast::<MapHeader>()).value_size,
+            0,
+            "hashmap, insert_key needs a map without values"
+        );
+        // No value bytes are copied, but the pointer still has to be non-null
+        insert(base_ptr, key_ptr, NonNull::dangling().as_ptr())
+    }
+}
+
+/// Mark the bucket at `index` as occupied by `key_ptr` and return its value location
+#[inline]
+unsafe fn occupy_bucket(base_ptr: *mut u8, index: usize, key_ptr: *const u8, hash: u64) -> *mut u8 {
+    unsafe {
+        let header_mut = &mut *base_ptr.cast::<MapHeader>();
+        let status_ptr = status_ptr(base_ptr, header_mut, index);
+        let bucket_ptr =
+            base_ptr.add(buckets_offset(header_mut) + index * header_mut.bucket_size as usize);
+
+        if *status_ptr == BucketStatus::Tombstone as u8 {
+            #[cfg(feature = "tracing")]
+            tracing::trace!(index, "hashmap, tombstone reused");
+            set_tombstone_count(header_mut, tombstone_count_of(header_mut) - 1);
+        }
+
+        // Mark as occupied and copy key, a new entry starts out clean
+        *status_ptr = occupied_status(header_mut, hash);
+        set_dirty_flag(base_ptr, header_mut, index, false);
+        let target_key_ptr = bucket_ptr.add(key_offset_of(header_mut));
+        ptr::copy_nonoverlapping(key_ptr, target_key_ptr, header_mut.key_size as usize);
+
+        if header_mut.hash_offset != 0 {
+            *bucket_ptr
+                .add(header_mut.hash_offset as usize)
+                .cast::<u64>() = hash;
+        }
+
+        // Update element count
+        set_element_count(header_mut, element_count_of(header_mut) + 1);
+        header_mut.mutation_generation = header_mut.mutation_generation.wrapping_add(1);
+
+        value_ptr_at(base_ptr, header_mut, index, bucket_ptr)
+    }
+}
+
+/// Remove the entry in an occupied bucket
+///
+/// Shifts the following entries back, or leaves a tombstone in quadratic maps. Spilled
+/// entries are replaced by the last spilled entry.
+#[inline]
+unsafe fn vacate_bucket(base_ptr: *mut u8, bucket_ptr: *mut u8) {
+    unsafe { vacate_bucket_with(base_ptr, bucket_ptr, false) }
+}
+
+/// Same as [`vacate_bucket`], but leaves a tombstone in linear maps too if
+/// `leave_tombstone` is set
+#[inline]
+unsafe fn vacate_bucket_with(base_ptr: *mut u8, bucket_ptr: *mut u8, leave_tombstone: bool) {
+    unsafe {
+        count(base_ptr, Counter::Removes, 1);
+        let header_mut = &mut *base_ptr.cast::<MapHeader>();
+        debug_assert_thawed(header_mut);
+        scrub_bucket(base_ptr, header_mut, bucket_ptr);
+        let index = bucket_index(base_ptr, header_mut, bucket_ptr);
+        #[cfg(feature = "tracing")]
+        tracing::trace!(index, "hashmap, entry removed");
+        let capacity = capacity_of(header_mut);
+        if index >= capacity {
+            vacate_spill_bucket(base_ptr, index - capacity);
+            return;
+        }
+
+        if header_mut.probe_strategy == ProbeStrategy::RobinHood as u8 {
+            shift_cluster_back(base_ptr, index);
+        } else if header_mut.probe_strategy == ProbeStrategy::Linear as u8 && !leave_tombstone {
+            shift_linear_cluster_back(base_ptr, index);
+        } else {
+            // Convert to tombstone
+            *status_ptr(base_ptr, header_mut, index) = BucketStatus::Tombstone as u8;
+            set_tombstone_count(header_mut, tombstone_count_of(header_mut) + 1);
+        }
+
+        // Update counts and invalidate handles
+        set_element_count(header_mut, element_count_of(header_mut) - 1);
+        header_mut.generation = header_mut.generation.wrapping_add(1);
+        header_mut.mutation_generation = header_mut.mutation_generation.wrapping_add(1);
+    }
+}
+
+/// Overwrite the key and value of a removed entry, if the map was initialized with
+/// [`MapInit::with_scrub_removed`]
+#[inline]
+unsafe fn scrub_bucket(base_ptr: *mut u8, header: &MapHeader, bucket_ptr: *mut u8) {
+    if header.flags & FLAG_SCRUB_REMOVED != 0 {
+        unsafe {
+            ptr::write_bytes(
+                bucket_ptr.add(key_offset_of(header)),
+                header.scrub_byte,
+                header.key_size as usize,
+            );
+            ptr::write_bytes(
+                value_ptr_of(base_ptr, header, bucket_ptr),
+                header.scrub_byte,
+                header.value_size as usize,
+            );
+        }
+    }
+}
+
+/// Copy the bucket at `from` over the bucket at `to`, status and value included
+#[inline]
+unsafe fn copy_bucket(base_ptr: *mut u8, header: &MapHeader, from: usize, to: usize) {
+    unsafe {
+        let bucket_size = header.bucket_size as usize;
+        let buckets_ptr = base_ptr.add(buckets_offset(header));
+        let from_ptr = buckets_ptr.add(from * bucket_size);
+        let to_ptr = buckets_ptr.add(to * bucket_size);
+        ptr::copy_nonoverlapping(from_ptr, to_ptr, bucket_size);
+        if has_control_bytes(header.layout_kind) {
+            *status_ptr(base_ptr, header, to) = *status_ptr(base_ptr, header, from);
+        }
+        if header.value_stride != 0 {
+            ptr::copy_nonoverlapping(
+                value_ptr_at(base_ptr, header, from, from_ptr),
+                value_ptr_at(base_ptr, header, to, to_ptr),
+                header.value_size as usize,
+            );
+        }
+        copy_dirty_flag(base_ptr, header, from, to);
+    }
+}
+
+/// Fill the bucket of a removed entry by moving the rest of its cluster back one
+/// bucket, stopping at an empty bucket or at an entry that is already home
+#[inline]
+unsafe fn shift_cluster_back(base_ptr: *mut u8, mut hole: usize) {
+    unsafe {
+        let header = &*base_ptr.cast::<MapHeader>();
+        let capacity = capacity_of(header);
+        let bucket_size = header.bucket_size as usize;
+        let buckets_ptr = base_ptr.add(buckets_offset(header));
+
+        loop {
+            let next = (hole + 1) & (capacity - 1);
+            let next_ptr = buckets_ptr.add(next * bucket_size);
+            if *status_ptr(base_ptr, header, next) == BucketStatus::Empty as u8
+                || bucket_distance(header, next_ptr, next) == 0
+            {
+                break;
+            }
+
+            copy_bucket(base_ptr, header, next, hole);
+            hole = next;
+        }
+
+        *status_ptr(base_ptr, header, hole) = BucketStatus::Empty as u8;
+    }
+}
+
+/// Fill the bucket of a removed entry in a linear map by moving back the later entries
+/// of its cluster whose probe sequence passes over it
+///
+/// Entries whose home bucket comes after the hole stay, since their lookups never visit
+/// it. Stops at an empty bucket, or once the entries are too far from the hole to have
+/// probed over it within the probe limit, and then empties the last hole. Tombstones
+/// left by older versions are stepped over. Spilled entries stay where they are, since
+/// lookups still check the spill region after reaching an empty bucket.
+#[inline]
+unsafe fn shift_linear_cluster_back(base_ptr: *mut u8, mut hole: usize) {
+    unsafe {
+        let header = &*base_ptr.cast::<MapHeader>();
+        let capacity = capacity_of(header);
+        let bucket_size = header.bucket_size as usize;
+        let buckets_ptr = base_ptr.add(buckets_offset(header));
+        let probe_limit = effective_probe_limit(header);
+
+        let mut gap = 1;
+        while gap < probe_limit {
+            let next = (hole + gap) & (capacity - 1);
+            let status = *status_ptr(base_ptr, header, next);
+            if status == BucketStatus::Empty as u8 {
+                break;
+            }
+
+            let next_ptr = buckets_ptr.add(next * bucket_size);
+            if is_occupied(status) && bucket_distance(header, next_ptr, next) >= gap {
+                copy_bucket(base_ptr, header, next, hole);
+                hole = next;
+                gap = 1;
+            } else {
+                gap += 1;
+            }
+        }
+
+        // The last hole still holds the bytes of the entry moved out of it
+        scrub_bucket(base_ptr, header, buckets_ptr.add(hole * bucket_size));
+        *status_ptr(base_ptr, header, hole) = BucketStatus::Empty as u8;
+    }
+}
+
+/// Find the empty bucket that ends the cluster beginning at `start`
+///
+/// # Returns
+///
+/// Index of the empty bucket, or `None` if there is no empty bucket to move the
+/// cluster into or a moved entry would end up outside of its probe limit
+#[inline]
+unsafe fn cluster_end(base_ptr: *const u8, header: &MapHeader, start: usize) -> Option<usize> {
+    unsafe {
+        let capacity = capacity_of(header);
+        let bucket_size = header.bucket_size as usize;
+        let probe_limit = effective_probe_limit(header);
+        let buckets_ptr = base_ptr.add(buckets_offset(header));
+
+        let mut end = start;
+        loop {
+            if *status_ptr(base_ptr, header, end) == BucketStatus::Empty as u8 {
+                return Some(end);
+            }
+            if bucket_distance(header, buckets_ptr.add(end * bucket_size), end) + 1 >= probe_limit {
+                return None;
+            }
+
+            end = (end + 1) & (capacity - 1);
+            if end == start {
+                return None;
+            }
+        }
+    }
+}
+
+/// Make room at `start` by moving the cluster that begins there forward one bucket,
+/// into the empty bucket at `end` found by [`cluster_end`]
+#[inline]
+unsafe fn shift_cluster_forward(base_ptr: *mut u8, start: usize, mut end: usize) {
+    unsafe {
+        let header_mut = &mut *base_ptr.cast::<MapHeader>();
+        let capacity = capacity_of(header_mut);
+
+        while end != start {
+            let previous = (end + capacity - 1) & (capacity - 1);
+            copy_bucket(base_ptr, header_mut, previous, end);
+            end = previous;
+        }
+
+        header_mut.generation = header_mut.generation.wrapping_add(1);
+        header_mut.mutation_generation = header_mut.mutation_generation.wrapping_add(1);
+    }
+}
+
+/// Where a key is, or where it would be inserted, in a map
+#[derive(Debug, Clone, Copy)]
+enum Slot {
+    /// Occupied bucket holding the key
+    Found(*mut u8),
+    /// Bucket the key can be inserted into. In Robin Hood maps the cluster from `index`
+    /// up to the empty bucket at `cluster_end` has to move forward first, unless they
+    /// are the same bucket.
+    Vacant { index: usize, cluster_end: usize },
+    /// No room for the key within the probe limit, but in the spill region
+    Spill,
+    /// No room for the key within the probe limit
+    Full,
+}
+
+/// Find the bucket holding a key whose hash has already been calculated, or the
+/// bucket it would be inserted into, without changing the map
+///
+/// Keys that do not fit within the probe limit go to the spill region, if there is one.
+#[inline]
+unsafe fn find_slot(base_ptr: *mut u8, key_ptr: *const u8, hash: u64) -> Slot {
+    unsafe {
+        let slot = probe_slot(base_ptr, key_ptr, hash);
+        if matches!(slot, Slot::Found(_)) {
+            return slot;
+        }
+
+        let header = &*base_ptr.cast::<MapHeader>();
+        let Some(spill) = spill_header(base_ptr, header) else {
+            return slot;
+        };
+
+        // A spilled key stays in the spill region, even if its probe sequence has room now
+        let spilled_ptr = find_in_spill(base_ptr, header, key_ptr, hash);
+        if !spilled_ptr.is_null() {
+            Slot::Found(spilled_ptr)
+        } else if matches!(slot, Slot::Full) && spill.len < spill.capacity {
+            Slot::Spill
+        } else {
+            slot
+        }
+    }
+}
+
+/// Same as [`find_slot`], without looking at the spill region
+#[inline]
+unsafe fn probe_slot(base_ptr: *mut u8, key_ptr: *const u8, hash: u64) -> Slot {
+    unsafe {
+        let header = &*base_ptr.cast::<MapHeader>();
+
+        // Validate parameters
+        let capacity = capacity_of(header);
+        let key_size = header.key_size as usize;
+        let bucket_size = header.bucket_size as usize;
+
+        assert_eq!(
+            header.padding_and_secret_code, SECRET_CODE,
+            "hashmap, secret code failed"
+        );
+        assert_ne!(key_size, 0, "Key size cannot be zero");
+        assert_ne!(capacity, 0, "Capacity cannot be zero");
+        assert!(
+            capacity.is_power_of_two(),
+            "Capacity must be a power of two"
+        );
+
+        if header.probe_strategy == ProbeStrategy::RobinHood as u8 {
+            return find_slot_robin_hood(base_ptr, key_ptr, hash);
+        }
+
+        let buckets_ptr = base_ptr.add(buckets_offset(header));
+
+        // Initial probe position
+        let mut index = index_from_hash(header, hash);
+
+        let occupied = occupied_status(header, hash);
+
+        // Track first tombstone for potential reuse
+        let mut first_tombstone = None;
+        // Never more probes than buckets, so the probe sequence can not wrap around onto
+        // a bucket it already visited (including the remembered tombstone)
+        let probe_limit = effective_probe_limit(header);
+
+        for probe in 0..probe_limit {
+            count(base_ptr, Counter::ProbeSteps, 1);
+            let bucket_ptr = buckets_ptr.add(index * bucket_size);
+            let status = *status_ptr(base_ptr, header, index);
+
+            match status {
+                status if status == BucketStatus::Empty as u8 => {
+                    // TODO: Maybe go back to BucketStatus as constants instead, this feel a bit awkward
+                    // Use tombstone if found, otherwise use current empty slot
+                    let insert_index = first_tombstone.unwrap_or(index);
+                    return Slot::Vacant {
+                        index: insert_index,
+                        cluster_end: insert_index,
+                    };
+                }
+                status if status == BucketStatus::Tombstone as u8 => {
+                    // Remember first tombstone for potential reuse
+                    if first_tombstone.is_none() {
+                        first_tombstone = Some(index);
+                    }
+                }
+                status => {
+                    // Check if keys match, a differing hash fragment rules the key out
+                    if status == occupied {
+                        if bucket_holds_key(header, bucket_ptr, key_ptr, hash) {
+                            return Slot::Found(bucket_ptr);
+                        }
+                        count(base_ptr, Counter::Collisions, 1);
+                    }
+                }
+            }
+
+            // Next bucket of the probe sequence, wrapping around
+            index = next_probe_index(header, index, probe);
+        }
+
+        // If we found a tombstone during probing, use it
+        if let Some(tombstone_index) = first_tombstone {
+            return Slot::Vacant {
+                index: tombstone_index,
+                cluster_end: tombstone_index,
+            };
+        }
+
+        // Map is full or probe limit exceeded
+        Slot::Full
+    }
+}
+
+/// Find the bucket holding a key in a Robin Hood map, or the bucket it would take
+///
+/// A key that is further from its home bucket than the resident of a bucket takes that
+/// bucket, and the rest of the cluster moves forward. Since the entries of a cluster
+/// are ordered by home bucket, the key can not be further along once that happens.
+#[inline]
+unsafe fn find_slot_robin_hood(base_ptr: *mut u8, key_ptr: *const u8, hash: u64) -> Slot {
+    unsafe {
+        let header = &*base_ptr.cast::<MapHeader>();
+        let capacity = capacity_of(header);
+        let bucket_size = header.bucket_size as usize;
+        let buckets_ptr = base_ptr.add(buckets_offset(header));
+
+        let home = index_from_hash(header, hash);
+        let occupied = occupied_status(header, hash);
+
+        for distance in 0..effective_probe_limit(header) {
+            count(base_ptr, Counter::ProbeSteps, 1);
+            let index = (home + distance) & (capacity - 1);
+            let bucket_ptr = buckets_ptr.add(index * bucket_size);
+            let status = *status_ptr(base_ptr, header, index);
+
+            if status == BucketStatus::Empty as u8 {
+                return Slot::Vacant {
+                    index,
+                    cluster_end: index,
+                };
+            }
+            if status == occupied {
+                if bucket_holds_key(header, bucket_ptr, key_ptr, hash) {
+                    return Slot::Found(bucket_ptr);
+                }
+                count(base_ptr, Counter::Collisions, 1);
+            }
+            if bucket_distance(header, bucket_ptr, index) < distance {
+                return cluster_end(base_ptr, header, index).map_or(Slot::Full, |cluster_end| {
+                    Slot::Vacant { index, cluster_end }
+                });
+            }
+        }
+
+        Slot::Full
+    }
+}
+
+/// Insert a key into a vacant or spill slot found by [`find_slot`] and return its value
+/// location
+///
+/// The map must not have changed since the slot was found.
+#[inline]
+unsafe fn fill_slot(base_ptr: *mut u8, key_ptr: *const u8, hash: u64, slot: Slot) -> *mut u8 {
+    unsafe {
+        match slot {
+            Slot::Vacant { index, cluster_end } => {
+                if cluster_end != index {
+                    shift_cluster_forward(base_ptr, index, cluster_end);
+                }
+
+                occupy_bucket(base_ptr, index, key_ptr, hash)
+            }
+            Slot::Spill => occupy_spill_bucket(base_ptr, key_ptr, hash),
+            Slot::Found(_) | Slot::Full => unreachable!("hashmap, slot has no room for a key"),
+        }
+    }
+}
+
+/// Get or reserve an entry for a key whose hash has already been calculated
+#[inline]
+unsafe fn reserve_hashed_entry(base_ptr: *mut u8, key_ptr: *const u8, hash: u64) -> ReserveResult {
+    unsafe {
+        debug_assert_thawed(&*base_ptr.cast::<MapHeader>());
+        match find_slot(base_ptr, key_ptr, hash) {
+            Slot::Found(bucket_ptr) => {
+                let header = &*base_ptr.cast::<MapHeader>();
+                ReserveResult::Existing(value_ptr_of(base_ptr, header, bucket_ptr))
+            }
+            slot @ (Slot::Vacant { .. } | Slot::Spill) => {
+                count(base_ptr, Counter::Inserts, 1);
+                #[cfg(feature = "tracing")]
+                tracing::trace!(
+                    hash,
+                    spilled = matches!(slot, Slot::Spill),
+                    "hashmap, key inserted"
+                );
+                ReserveResult::Reserved(fill_slot(base_ptr, key_ptr, hash, slot))
+            }
+            Slot::Full => {
+                count(base_ptr, Counter::FailedInserts, 1);
+                #[cfg(feature = "tracing")]
+                warn_full(base_ptr);
+                ReserveResult::Full
+            }
+        }
+    }
+}
+
+/// Check if a key exists in the map
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+/// - `key_ptr` must point to a valid key of the size specified in the map header
+#[inline]
+#[must_use]
+pub unsafe fn has(base_ptr: *const u8, key_ptr: *const u8) -> bool {
+    unsafe { lookup_shared(base_ptr, key_ptr).is_null().not() }
+}
+
+/// Check if a set (or map) contains a key
+///
+/// Same as [`has`], named for use with sets.
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+/// - `key_ptr` must point to a valid key of the size specified in the map header
+#[inline]
+#[must_use]
+pub unsafe fn contains(base_ptr: *const u8, key_ptr: *const u8) -> bool {
+    unsafe { has(base_ptr, key_ptr) }
+}
+
+/// Lookup an existing entry in the map
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+/// - `key_ptr` must point to a valid key of the size specified in the map header
+///
+/// # Returns
+///
+/// Pointer to the found value, or null if not found
+#[inline]
+pub unsafe fn lookup(base_ptr: *mut u8, key_ptr: *const u8) -> *mut u8 {
+    unsafe { find_value(base_ptr, key_ptr, Tally::map(base_ptr)) }
+}
+
+/// Same as [`lookup`] for maps that are only borrowed shared
+///
+/// Not counted with the `instrument` feature, since that would write to the header.
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+/// - `key_ptr` must point to a valid key of the size specified in the map header
+///
+/// # Returns
+///
+/// Pointer to the found value, or null if not found
+#[inline]
+pub unsafe fn lookup_shared(base_ptr: *const u8, key_ptr: *const u8) -> *const u8 {
+    unsafe { find_value(base_ptr.cast_mut(), key_ptr, Tally::NONE) }
+}
+
+#[inline]
+unsafe fn find_value(base_ptr: *mut u8, key_ptr: *const u8, tally: Tally) -> *mut u8 {
+    unsafe {
+        let header = &*base_ptr.cast::<MapHeader>();
+        if capacity_of(header) <= SMALL_MAP_CAPACITY {
+            return scan_for_key(base_ptr, key_ptr, tally);
+        }
+
+        lookup_hashed_entry(base_ptr, key_ptr, key_hash(base_ptr, key_ptr), tally)
+    }
+}
+
+/// Lookup an existing entry for a key whose hash has already been calculated
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+/// - `key_ptr` must point to a valid key of the size specified in the map header
+/// - `hash` must be the [`key_hash`] of the key for this map
+///
+/// # Returns
+///
+/// Pointer to the found value, or null if not found
+#[inline]
+pub unsafe fn lookup_hashed(base_ptr: *mut u8, key_ptr: *const u8, hash: u64) -> *mut u8 {
+    unsafe { lookup_hashed_entry(base_ptr, key_ptr, hash, Tally::map(base_ptr)) }
+}
+
+/// Look up a packed array of keys, writing the value location of each to `out_ptrs`
+///
+/// Keys are hashed a few at a time, and their home buckets prefetched, before they are
+/// looked up, which hides most of the memory latency of serial [`lookup`] calls.
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+/// - `keys_ptr` must point to `count` keys of `key_size` bytes each, one after another
+/// - `out_ptrs` must point to `count` writable pointers
+///
+/// # Returns
+///
+/// Number of keys found. Keys that are not in the map get a null pointer.
+pub unsafe fn lookup_many(
+    base_ptr: *mut u8,
+    keys_ptr: *const u8,
+    count: usize,
+    out_ptrs: *mut *mut u8,
+) -> usize {
+    unsafe {
+        let header = checked_header(base_ptr);
+        let key_size = header.key_size as usize;
+        let mut found = 0;
+
+        // Tiny maps are scanned without hashing, there is nothing to prefetch
+        if capacity_of(header) <= SMALL_MAP_CAPACITY {
+            for index in 0..count {
+                let value_ptr = scan_for_key(
+                    base_ptr,
+                    keys_ptr.add(index * key_size),
+                    Tally::map(base_ptr),
+                );
+                found += usize::from(!value_ptr.is_null());
+                *out_ptrs.add(index) = value_ptr;
+            }
+            return found;
+        }
+
+        let mut hashes = [0u64; BATCH_LEN];
+        let mut start = 0;
+        while start < count {
+            let batch_len = min(BATCH_LEN, count - start);
+            for (offset, hash) in hashes[..batch_len].iter_mut().enumerate() {
+                *hash = key_hash(base_ptr, keys_ptr.add((start + offset) * key_size));
+                prefetch(home_bucket_ptr(base_ptr, *hash));
+            }
+
+            for (offset, &hash) in hashes[..batch_len].iter().enumerate() {
+                let index = start + offset;
+                let value_ptr = lookup_hashed_entry(
+                    base_ptr,
+                    keys_ptr.add(index * key_size),
+                    hash,
+                    Tally::map(base_ptr),
+                );
+                found += usize::from(!value_ptr.is_null());
+                *out_ptrs.add(index) = value_ptr;
+            }
+
+            start += batch_len;
+        }
+
+        found
+    }
+}
+
+/// Look up several distinct keys at once, to change their values together
+///
+/// Keys are told apart by the entries they find, so no two of the returned pointers
+/// point to the same value.
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+/// - Every key pointer must point to a valid key of the size specified in the map header
+///
+/// # Returns
+///
+/// The value location of every key, or `None` if a key is not in the map or two of
+/// the keys are the same
+#[must_use]
+pub unsafe fn lookup_many_mut<const N: usize>(
+    base_ptr: *mut u8,
+    keys: [*const u8; N],
+) -> Option<[*mut u8; N]> {
+    unsafe {
+        let mut value_ptrs = [ptr::null_mut(); N];
+        for (index, key_ptr) in keys.into_iter().enumerate() {
+            let value_ptr = lookup(base_ptr, key_ptr);
+            if value_ptr.is_null() || value_ptrs[..index].contains(&value_ptr) {
+                return None;
+            }
+            value_ptrs[index] = value_ptr;
+        }
+        Some(value_ptrs)
+    }
+}
+
+/// Hash of a key as used by a map
+///
+/// Maps with the same hash seed and hash strategy give the same hash for a key, so the
+/// hash can be calculated once and passed to the `_hashed` functions of each of them.
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+/// - `key_ptr` must point to a valid key of the size specified in the map header
+#[inline]
+#[must_use]
+pub unsafe fn key_hash(base_ptr: *const u8, key_ptr: *const u8) -> u64 {
+    unsafe {
+        let header = &*base_ptr.cast::<MapHeader>();
+        let key_slice = slice::from_raw_parts(key_ptr, header.key_size as usize);
+        calculate_hash_bytes(key_slice, header.hash_seed, header.hash_strategy)
+    }
+}
+
+/// Bucket where the probe sequence for a key with this hash starts
+///
+/// Takes [`FLAG_FIBONACCI_INDEX`] into account, so it always agrees with where
+/// [`lookup`], [`insert`] and [`remove`] look for the key.
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+#[inline]
+#[must_use]
+pub unsafe fn home_bucket(base_ptr: *const u8, hash: u64) -> u32 {
+    unsafe { index_from_hash(checked_header(base_ptr), hash) as u32 }
+}
+
+/// Find a key by comparing it against every occupied bucket
+///
+/// Cheaper than hashing and probing for tiny maps
+#[inline]
+unsafe fn scan_for_key(base_ptr: *mut u8, key_ptr: *const u8, tally: Tally) -> *mut u8 {
+    unsafe {
+        let header = checked_header(base_ptr);
+        let bucket_size = header.bucket_size as usize;
+        let key_offset = key_offset_of(header);
+        let buckets_ptr = base_ptr.add(buckets_offset(header));
+        tally.count(Counter::Lookups, 1);
+
+        for index in 0..capacity_of(header) {
+            tally.count(Counter::ProbeSteps, 1);
+            let bucket_ptr = buckets_ptr.add(index * bucket_size);
+            if is_occupied(*status_ptr(base_ptr, header, index))
+                && key_matches(header, bucket_ptr.add(key_offset), key_ptr)
+            {
+                return value_ptr_at(base_ptr, header, index, bucket_ptr);
+            }
+        }
+
+        // Only spilled keys are worth hashing for
+        if spill_len_of(base_ptr, header) == 0 {
+            return ptr::null_mut();
+        }
+        let bucket_ptr = find_in_spill(base_ptr, header, key_ptr, key_hash(base_ptr, key_ptr));
+        if bucket_ptr.is_null() {
+            bucket_ptr
+        } else {
+            value_ptr_of(base_ptr, header, bucket_ptr)
+        }
+    }
+}
+
+/// Lookup an existing entry for a key whose hash has already been calculated
+#[inline]
+unsafe fn lookup_hashed_entry(
+    base_ptr: *mut u8,
+    key_ptr: *const u8,
+    hash: u64,
+    tally: Tally,
+) -> *mut u8 {
+    unsafe {
+        tally.count(Counter::Lookups, 1);
+        let bucket_ptr = find_bucket(base_ptr, key_ptr, hash, tally);
+        if bucket_ptr.is_null() {
+            bucket_ptr
+        } else {
+            value_ptr_of(base_ptr, &*base_ptr.cast::<MapHeader>(), bucket_ptr)
+        }
+    }
+}
+
+/// Remove an entry from the map
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+/// - `key_ptr` must point to a valid key of the size specified in the map header
+///
+/// # Returns
+///
+/// `true` if the key was found and removed, `false` otherwise
+#[inline]
+pub unsafe fn remove(base_ptr: *mut u8, key_ptr: *const u8) -> bool {
+    unsafe { remove_hashed(base_ptr, key_ptr, key_hash(base_ptr, key_ptr)) }
+}
+
+/// Remove an entry for a key whose hash has already been calculated
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+/// - `key_ptr` must point to a valid key of the size specified in the map header
+/// - `hash` must be the [`key_hash`] of the key for this map
+///
+/// # Returns
+///
+/// `true` if the key was found and removed, `false` otherwise
+#[inline]
+pub unsafe fn remove_hashed(base_ptr: *mut u8, key_ptr: *const u8, hash: u64) -> bool {
+    unsafe {
+        debug_assert_thawed(&*base_ptr.cast::<MapHeader>());
+        let bucket_ptr = find_bucket(base_ptr, key_ptr, hash, Tally::map(base_ptr));
+        if bucket_ptr.is_null() {
+            return false;
+        }
+
+        vacate_bucket(base_ptr, bucket_ptr);
+        true
+    }
+}
+
+/// Remove an entry from the map, copying its value out first
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+/// - `key_ptr` must point to a valid key of the size specified in the map header
+/// - `out_value` must point to `value_size` writable bytes that are not inside the map
+///
+/// # Returns
+///
+/// `true` if the key was found and removed, `false` otherwise (`out_value` is left untouched)
+#[inline]
+pub unsafe fn take(base_ptr: *mut u8, key_ptr: *const u8, out_value: *mut u8) -> bool {
+    unsafe {
+        let hash = key_hash(base_ptr, key_ptr);
+        let bucket_ptr = find_bucket(base_ptr, key_ptr, hash, Tally::map(base_ptr));
+        if bucket_ptr.is_null() {
+            return false;
+        }
+
+        let header = &*base_ptr.cast::<MapHeader>();
+        ptr::copy_nonoverlapping(
+            value_ptr_of(base_ptr, header, bucket_ptr),
+            out_value,
+            header.value_size as usize,
+        );
+        vacate_bucket(base_ptr, bucket_ptr);
+        true
+    }
+}
+
+/// Bit masks of the 16 control bytes at `controls_ptr` that equal `status`, and that
+/// are empty
+#[cfg(not(all(feature = "simd", any(target_arch = "x86_64", target_arch = "aarch64"))))]
+#[inline]
+unsafe fn match_control_group(controls_ptr: *const u8, status: u8) -> (u16, u16) {
+    let mut matches = 0;
+    let mut empties = 0;
+    for offset in 0..CONTROL_GROUP_LEN {
+        let control = unsafe { *controls_ptr.add(offset) };
+        matches |= u16::from(control == status) << offset;
+        empties |= u16::from(control == BucketStatus::Empty as u8) << offset;
+    }
+    (matches, empties)
+}
+
+/// Find the occupied bucket holding a key in a map with control bytes
+///
+/// Checks the control bytes a group at a time, and only loads the keys of buckets
+/// whose status matches the status the key would have.
+///
+/// # Returns
+///
+/// Pointer to the bucket (not the value), or null if the key is not in the map
+#[inline]
+unsafe fn find_in_control_bytes(
+    base_ptr: *mut u8,
+    header: &MapHeader,
+    key_ptr: *const u8,
+    hash: u64,
+    tally: Tally,
+) -> *mut u8 {
+    unsafe {
+        let capacity = capacity_of(header);
+        let bucket_size = header.bucket_size as usize;
+        let controls_ptr = base_ptr.add(MAP_BUCKETS_OFFSET);
+        let buckets_ptr = base_ptr.add(buckets_offset(header));
+        let occupied = occupied_status(header, hash);
+
+        let mut index = index_from_hash(header, hash);
+        let mut remaining = effective_probe_limit(header);
+        while remaining != 0 {
+            // Groups stop at the end of the control bytes instead of wrapping around. The
+            // bytes after the last control byte are still map memory, they are masked out.
+            let group_len = min(min(CONTROL_GROUP_LEN, capacity - index), remaining);
+            let group_mask = u16::MAX >> (CONTROL_GROUP_LEN - group_len);
+            let (mut matches, empties) = match_control_group(controls_ptr.add(index), occupied);
+
+            // Buckets after the first empty one are not on the probe sequence
+            let empties = empties & group_mask;
+            matches &= if empties == 0 {
+                group_mask
+            } else {
+                (1u16 << empties.trailing_zeros()).wrapping_sub(1)
+            };
+
+            while matches != 0 {
+                let offset = matches.trailing_zeros();
+                let bucket_ptr = buckets_ptr.add((index + offset as usize) * bucket_size);
+                if bucket_holds_key(header, bucket_ptr, key_ptr, hash) {
+                    tally.count(Counter::ProbeSteps, offset + 1);
+                    return bucket_ptr;
+                }
+                tally.count(Counter::Collisions, 1);
+                matches &= matches - 1;
+            }
+
+            if empties != 0 {
+                tally.count(Counter::ProbeSteps, empties.trailing_zeros() + 1);
+                return ptr::null_mut();
+            }
+            tally.count(Counter::ProbeSteps, group_len as u32);
+            remaining -= group_len;
+            index = (index + group_len) & (capacity - 1);
+        }
+
+        ptr::null_mut()
+    }
+}
+
+/// Find the occupied bucket holding a key
+///
+/// # Returns
+///
+/// Pointer to the bucket (not the value), or null if the key is not in the map
+#[inline]
+unsafe fn find_bucket(base_ptr: *mut u8, key_ptr: *const u8, hash: u64, tally: Tally) -> *mut u8 {
+    unsafe {
+        let header = &*base_ptr.cast::<MapHeader>();
+
+        let capacity = capacity_of(header);
+        let key_size = header.key_size as usize;
+        let bucket_size = header.bucket_size as usize;
+
+        assert_eq!(
+            header.padding_and_secret_code, SECRET_CODE,
+            "hashmap, secret code failed"
+        );
+        assert_ne!(key_size, 0, "Key size cannot be zero");
+        assert_ne!(capacity, 0, "Capacity cannot be zero");
+        assert!(
+            capacity.is_power_of_two(),
+            "Capacity must be a power of two"
+        );
+
+        // Quadratic probe sequences are not contiguous, so their control bytes are
+        // checked one at a time below
+        if has_control_bytes(header.layout_kind)
+            && header.probe_strategy != ProbeStrategy::Quadratic as u8
+        {
+            let bucket_ptr = find_in_control_bytes(base_ptr, header, key_ptr, hash, tally);
+            return if bucket_ptr.is_null() {
+                find_in_spill(base_ptr, header, key_ptr, hash)
+            } else {
+                bucket_ptr
+            };
+        }
+
+        let buckets_ptr = base_ptr.add(buckets_offset(header));
+
+        // Initial probe position
+        let mut index = index_from_hash(header, hash);
+        let probe_limit = effective_probe_limit(header);
+        let occupied = occupied_status(header, hash);
+
+        for probe in 0..probe_limit {
+            tally.count(Counter::ProbeSteps, 1);
+            let bucket_ptr = buckets_ptr.add(index * bucket_size);
+            let status = *status_ptr(base_ptr, header, index);
+
+            match status {
+                status if status == BucketStatus::Empty as u8 => {
+                    // Empty slot means the key is not in the buckets
+                    break;
+                }
+                // Check if keys match
+                status if status == occupied => {
+                    if bucket_holds_key(header, bucket_ptr, key_ptr, hash) {
+                        return bucket_ptr;
+                    }
+                    tally.count(Counter::Collisions, 1);
+                }
+                _ => {} // Continue probing for tombstones and other keys
+            }
+
+            index = next_probe_index(header, index, probe);
+        }
+
+        // Key not found within probe limit, it may have spilled
+        find_in_spill(base_ptr, header, key_ptr, hash)
+    }
+}
+
+/// Replace the value of an existing entry, returning the previous value bytes
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+/// - `key_ptr` must point to a valid key of the size specified in the map header
+/// - `new_value` must point to `value_size` readable bytes
+/// - `out_old` must point to `value_size` writable bytes that do not overlap `new_value`
+///
+/// # Returns
+///
+/// `true` if the key was found and its value swapped, `false` if the key is absent
+/// (nothing is inserted and `out_old` is left untouched)
+#[inline]
+pub unsafe fn swap_value(
+    base_ptr: *mut u8,
+    key_ptr: *const u8,
+    new_value: *const u8,
+    out_old: *mut u8,
+) -> bool {
+    unsafe {
+        let value_ptr = lookup(base_ptr, key_ptr);
+        if value_ptr.is_null() {
+            return false;
+        }
+
+        let value_size = (*base_ptr.cast::<MapHeader>()).value_size as usize;
+        ptr::copy_nonoverlapping(value_ptr, out_old, value_size);
+        ptr::copy_nonoverlapping(new_value, value_ptr, value_size);
+
+        true
+    }
+}
+
+/// Call `f` with the value of an existing entry, to change it in place
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+/// - `key_ptr` must point to a valid key of the size specified in the map header
+/// - `f` must not access the map other than through the value pointer it is given
+///
+/// # Returns
+///
+/// `true` if the key was found and `f` called, `false` if the key is absent (nothing
+/// is inserted)
+#[inline]
+pub unsafe fn update(base_ptr: *mut u8, key_ptr: *const u8, f: impl FnOnce(*mut u8)) -> bool {
+    unsafe {
+        let value_ptr = lookup(base_ptr, key_ptr);
+        if value_ptr.is_null() {
+            return false;
+        }
+
+        f(value_ptr);
+        true
+    }
+}
+
+/// Get or reserve an entry and return a handle to it
+///
+/// Unlike value pointers, handles can be checked for validity with [`resolve`].
+/// Handles are invalidated by removing any entry, and do not survive `init`.
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+/// - `key_ptr` must point to a valid key of the size specified in the map header
+///
+/// # Returns
+///
+/// Handle to the entry, or `None` if the map is full
+#[inline]
+pub unsafe fn reserve_handle(base_ptr: *mut u8, key_ptr: *const u8) -> Option<Handle> {
+    unsafe {
+        let value_ptr = get_or_reserve_entry(base_ptr, key_ptr);
+        if value_ptr.is_null() {
+            return None;
+        }
+
+        Some(Handle {
+            index: bucket_index_of_value(base_ptr, value_ptr),
+            generation: (*base_ptr.cast::<MapHeader>()).generation,
+        })
+    }
+}
+
+/// Index of the bucket that holds the value at `value_ptr`
+#[inline]
+unsafe fn bucket_index_of_value(base_ptr: *const u8, value_ptr: *const u8) -> u32 {
+    unsafe {
+        let header = &*base_ptr.cast::<MapHeader>();
+        if header.value_stride == 0 {
+            return bucket_index(
+                base_ptr,
+                header,
+                value_ptr.sub(header.value_offset as usize),
+            ) as u32;
+        }
+
+        // Values stored apart from the buckets are counted from the start of their array
+        let capacity = capacity_of(header);
+        let spill_values_ptr = base_ptr.add(spill_values_offset(base_ptr, header));
+        if header.flags & FLAG_SPILL_REGION != 0 && value_ptr >= spill_values_ptr {
+            return (capacity
+                + value_ptr.offset_from(spill_values_ptr) as usize / header.value_stride as usize)
+                as u32;
+        }
+        let values_ptr = base_ptr.add(values_offset(header));
+        (value_ptr.offset_from(values_ptr) as usize / header.value_stride as usize) as u32
+    }
+}
+
+/// Insert or replace an entry and return a [`Handle`] to the bucket it ended up in
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+/// - `key_ptr` must point to a valid key of the size specified in the map header
+/// - `value_ptr` must point to `value_size` readable bytes that are not inside the map
+///
+/// # Returns
+///
+/// Handle to the entry, or `None` if the map is full
+pub unsafe fn insert_slot(
+    base_ptr: *mut u8,
+    key_ptr: *const u8,
+    value_ptr: *const u8,
+) -> Option<Handle> {
+    unsafe {
+        let handle = reserve_handle(base_ptr, key_ptr)?;
+        let target_value_ptr = resolve(base_ptr, handle)?;
+        let value_size = (*base_ptr.cast::<MapHeader>()).value_size as usize;
+        ptr::copy_nonoverlapping(value_ptr, target_value_ptr, value_size);
+        Some(handle)
+    }
+}
+
+/// Get the value location of the entry for the key at `key_ptr` through a handle,
+/// without hashing the key
+///
+/// Same as [`resolve`], but the bucket must also still hold the key. The removal
+/// generation of a handle wraps around, so this catches a handle that looks current
+/// but whose bucket was emptied and filled with another key since.
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+/// - `key_ptr` must point to a valid key of the size specified in the map header
+///
+/// # Returns
+///
+/// Pointer to the value, or null if the handle is stale or its bucket holds another
+/// key. Look the key up again in that case.
+#[must_use]
+pub unsafe fn get_by_slot(base_ptr: *const u8, handle: Handle, key_ptr: *const u8) -> *mut u8 {
+    unsafe {
+        let Some(value_ptr) = resolve(base_ptr, handle) else {
+            return ptr::null_mut();
+        };
+
+        let header = checked_header(base_ptr);
+        let bucket_ptr = occupied_bucket_at(base_ptr, header, handle.index as usize);
+        if !key_matches(header, bucket_ptr.add(key_offset_of(header)), key_ptr) {
+            return ptr::null_mut();
+        }
+
+        value_ptr
+    }
+}
+
+/// Get the value location for a handle
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+///
+/// # Returns
+///
+/// Pointer to the value, or `None` if the handle is stale or its bucket is no longer occupied
+#[inline]
+#[must_use]
+pub unsafe fn resolve(base_ptr: *const u8, handle: Handle) -> Option<*mut u8> {
+    unsafe {
+        let header = checked_header(base_ptr);
+        if handle.generation != header.generation {
+            return None;
+        }
+
+        let bucket_ptr = occupied_bucket_at(base_ptr, header, handle.index as usize);
+        if bucket_ptr.is_null() {
+            return None;
+        }
+
+        Some(value_ptr_at(
+            base_ptr,
+            header,
+            handle.index as usize,
+            bucket_ptr,
+        ))
+    }
+}
+
+/// Reset a contiguous range of buckets to empty
+///
+/// Intended for partitioned maps where the caller owns the bucket range. Any handles
+/// into the map are invalidated if an entry was cleared.
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+/// - Entries outside the range whose probe sequence crosses it may become unreachable,
+///   so the caller must own every probe sequence that touches the range
+///
+/// # Returns
+///
+/// Number of occupied buckets that were cleared. `end` is clamped to the capacity, so
+/// spilled entries are never cleared.
+pub unsafe fn clear_range(base_ptr: *mut u8, start: u32, end: u32) -> u32 {
+    unsafe {
+        let header = &mut *base_ptr.cast::<MapHeader>();
+        assert_eq!(
+            header.padding_and_secret_code, SECRET_CODE,
+            "hashmap, secret code failed"
+        );
+        debug_assert_thawed(header);
+
+        let end = min(end as usize, capacity_of(header));
+        let mut cleared = 0;
+        let mut tombstones = 0;
+
+        let buckets_ptr = base_ptr.add(buckets_offset(header));
+        for index in start as usize..end {
+            let status_ptr = status_ptr(base_ptr, header, index);
+            match *status_ptr {
+                status if status == BucketStatus::Tombstone as u8 => tombstones += 1,
+                status if is_occupied(status) => {
+                    let bucket_ptr = buckets_ptr.add(index * header.bucket_size as usize);
+                    scrub_bucket(base_ptr, header, bucket_ptr);
+                    cleared += 1;
+                }
+                _ => {}
+            }
+            *status_ptr = BucketStatus::Empty as u8;
+        }
+
+        set_tombstone_count(header, tombstone_count_of(header) - tombstones);
+        if cleared != 0 {
+            set_element_count(header, element_count_of(header) - cleared);
+            header.generation = header.generation.wrapping_add(1);
+            header.mutation_generation = header.mutation_generation.wrapping_add(1);
+        }
+
+        cleared as u32
+    }
+}
+
+/// Mark the map as read-only, for example once a snapshot of it has been taken
+///
+/// Debug builds panic when a frozen map is changed by an insert, removal, `overwrite`
+/// or `clear`, until [`thaw`] is called. Release builds do not check the flag.
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+pub unsafe fn freeze(base_ptr: *mut u8) {
+    unsafe {
+        checked_header(base_ptr);
+        (*base_ptr.cast::<MapHeader>()).flags |= FLAG_FROZEN;
+    }
+}
+
+/// Allow a map frozen with [`freeze`] to be changed again
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+pub unsafe fn thaw(base_ptr: *mut u8) {
+    unsafe {
+        checked_header(base_ptr);
+        (*base_ptr.cast::<MapHeader>()).flags &= !FLAG_FROZEN;
+    }
+}
+
+/// Check if the map is frozen, see [`freeze`]
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+#[must_use]
+pub unsafe fn is_frozen(base_ptr: *const u8) -> bool {
+    unsafe { checked_header(base_ptr).flags & FLAG_FROZEN != 0 }
+}
+
+/// Catch changes to a map frozen with [`freeze`], in debug builds
+#[inline]
+fn debug_assert_thawed(header: &MapHeader) {
+    debug_assert!(header.flags & FLAG_FROZEN == 0, "hashmap, map is frozen");
+}
+
+/// Remove all entries and tombstones, keeping the layout of the map
+///
+/// Unlike `init`, this does not need the original [`MapInit`]. Any handles into the map
+/// are invalidated if it held entries.
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+pub unsafe fn clear(base_ptr: *mut u8) {
+    unsafe {
+        let header = checked_header(base_ptr);
+        clear_range(base_ptr, 0, capacity_of(header) as u32);
+
+        if let Some(spill) = spill_header(base_ptr, header)
+            && spill.len != 0
+        {
+            for spill_index in 0..usize::from(spill.len) {
+                scrub_bucket(
+                    base_ptr,
+                    header,
+                    spill_bucket_ptr(base_ptr, header, spill_index),
+                );
+            }
+            let header_mut = &mut *base_ptr.cast::<MapHeader>();
+            set_element_count(
+                header_mut,
+                element_count_of(header_mut) - usize::from(spill.len),
+            );
+            header_mut.generation = header_mut.generation.wrapping_add(1);
+            header_mut.mutation_generation = header_mut.mutation_generation.wrapping_add(1);
+            spill.len = 0;
+        }
+    }
+}
+
+/// Call `f` with the key and value of every entry, then remove all entries
+///
+/// Like [`clear`], every bucket ends up empty rather than a tombstone.
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+/// - `f` must not change the map, other than writing to the values
+pub unsafe fn drain(base_ptr: *mut u8, f: impl FnMut(*const u8, *mut u8)) {
+    unsafe {
+        for_each(base_ptr, f);
+        clear(base_ptr);
+    }
+}
+
+/// Keep only the entries for which `f(key_ptr, value_ptr)` returns `true`
+///
+/// Walks the buckets once and removes rejected entries in place, without probing for
+/// their keys. Handles into the map are invalidated if an entry was removed.
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+/// - `f` must not access the map other than through the pointers it is given
+pub unsafe fn retain(base_ptr: *mut u8, mut f: impl FnMut(*const u8, *mut u8) -> bool) {
+    unsafe {
+        let header = checked_header(base_ptr);
+        let capacity = capacity_of(header);
+        let bucket_size = header.bucket_size as usize;
+        let key_offset = key_offset_of(header);
+        let buckets_ptr = base_ptr.add(buckets_offset(header));
+
+        // Backward shifts pull later entries into the current bucket. Starting at the
+        // beginning of a cluster makes sure they never pull in an entry that was already
+        // visited.
+        let mut leave_tombstones = false;
+        let start = if header.probe_strategy == ProbeStrategy::RobinHood as u8 {
+            (0..capacity)
+                .find(|&index| {
+                    *status_ptr(base_ptr, header, index) == BucketStatus::Empty as u8
+                        || bucket_distance(header, buckets_ptr.add(index * bucket_size), index) == 0
+                })
+                .unwrap_or(0)
+        } else if header.probe_strategy == ProbeStrategy::Linear as u8 {
+            // Linear shifts can pull in entries from anywhere in the cluster, but never
+            // across an empty bucket. A full map has none, so its shifts could wrap around
+            // onto visited entries. Its removals leave tombstones instead, which are
+            // cleared once every entry has been visited.
+            let empty = (0..capacity)
+                .find(|&index| *status_ptr(base_ptr, header, index) == BucketStatus::Empty as u8);
+            leave_tombstones = empty.is_none();
+            empty.unwrap_or(0)
+        } else {
+            0
+        };
+
+        let mut tombstones_left = false;
+        let mut step = 0;
+        while step < capacity {
+            let index = (start + step) & (capacity - 1);
+            let bucket_ptr = buckets_ptr.add(index * bucket_size);
+            if is_occupied(*status_ptr(base_ptr, header, index))
+                && !f(
+                    bucket_ptr.add(key_offset),
+                    value_ptr_at(base_ptr, header, index, bucket_ptr),
+                )
+            {
+                // Visit the bucket again, another entry may have been shifted into it
+                vacate_bucket_with(base_ptr, bucket_ptr, leave_tombstones);
+                tombstones_left |= leave_tombstones;
+                continue;
+            }
+            step += 1;
+        }
+
+        // Removing a spilled entry moves the last one into its place, which is visited next
+        let mut spill_index = 0;
+        while spill_index < spill_len_of(base_ptr, header) {
+            let bucket_ptr = spill_bucket_ptr(base_ptr, header, spill_index);
+            let value_ptr = value_ptr_at(base_ptr, header, capacity + spill_index, bucket_ptr);
+            if f(bucket_ptr.add(key_offset), value_ptr) {
+                spill_index += 1;
+            } else {
+                count(base_ptr, Counter::Removes, 1);
+                #[cfg(feature = "tracing")]
+                tracing::trace!(index = capacity + spill_index, "hashmap, entry removed");
+                vacate_spill_bucket(base_ptr, spill_index);
+            }
+        }
+
+        if tombstones_left {
+            rehash_in_place(base_ptr);
+        }
+    }
+}
+
+/// Move a displaced key to the first tombstone in its probe sequence
+///
+/// A cheaper, single-key alternative to rebuilding the map. The bucket the key leaves
+/// becomes a tombstone, so the probe sequences of other keys stay intact. Handles into
+/// the map are invalidated if the key moves.
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+/// - `key_ptr` must point to a valid key of the size specified in the map header
+///
+/// # Returns
+///
+/// `true` if the key was moved closer to its home bucket
+pub unsafe fn rehome(base_ptr: *mut u8, key_ptr: *const u8) -> bool {
+    unsafe {
+        let header = checked_header(base_ptr);
+        let bucket_size = header.bucket_size as usize;
+        let key_offset = key_offset_of(header);
+        let buckets_ptr = base_ptr.add(buckets_offset(header));
+
+        let mut index = home_index(header, key_ptr);
+        let mut first_tombstone = None;
+        let probe_limit = effective_probe_limit(header);
+
+        for probe in 0..probe_limit {
+            let bucket_ptr = buckets_ptr.add(index * bucket_size);
+
+            match *status_ptr(base_ptr, header, index) {
+                status if status == BucketStatus::Empty as u8 => return false,
+                status if is_occupied(status) => {
+                    if key_matches(header, bucket_ptr.add(key_offset), key_ptr) {
+                        let Some(tombstone_index) = first_tombstone else {
+                            return false;
+                        };
+
+                        // The whole bucket moves: status (with its hash fragment), key, value
+                        // and stored hash
+                        copy_bucket(base_ptr, header, index, tombstone_index);
+                        *status_ptr(base_ptr, header, index) = BucketStatus::Tombstone as u8;
+
+                        let header_mut = &mut *base_ptr.cast::<MapHeader>();
+                        header_mut.generation = header_mut.generation.wrapping_add(1);
+                        header_mut.mutation_generation =
+                            header_mut.mutation_generation.wrapping_add(1);
+
+                        return true;
+                    }
+                }
+                _ => {
+                    if first_tombstone.is_none() {
+                        first_tombstone = Some(index);
+                    }
+                }
+            }
+
+            index = next_probe_index(header, index, probe);
+        }
+
+        false
+    }
+}
+
+/// Remove all tombstones, moving entries back towards their home buckets
+///
+/// Rebuilds the map in its own buffer without needing any scratch memory. Entries
+/// only ever move closer to their home bucket, and spilled entries move back into the
+/// buckets if their probe sequence has room now. Handles into the map are invalidated.
+///
+/// Removals only leave tombstones in quadratic maps. Linear maps have them when they
+/// were written before removals shifted entries back, see [`migrate_v1_to_v3`].
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+pub unsafe fn rehash_in_place(base_ptr: *mut u8) {
+    unsafe {
+        let header = &mut *base_ptr.cast::<MapHeader>();
+        assert_eq!(
+            header.padding_and_secret_code, SECRET_CODE,
+            "hashmap, secret code failed"
+        );
+
+        let capacity = capacity_of(header);
+        let bucket_size = header.bucket_size as usize;
+        let buckets_ptr = base_ptr.add(buckets_offset(header));
+
+        if header.probe_strategy == ProbeStrategy::Quadratic as u8 {
+            settle_quadratic(base_ptr, header);
+        } else {
+            for tombstone_index in 0..capacity {
+                let tombstone_ptr = status_ptr(base_ptr, header, tombstone_index);
+                if *tombstone_ptr != BucketStatus::Tombstone as u8 {
+                    continue;
+                }
+                *tombstone_ptr = BucketStatus::Empty as u8;
+
+                // Backward shift: pull later entries of the cluster into the hole, as long as
+                // the hole is still on their probe sequence
+                let mut hole = tombstone_index;
+                let mut index = tombstone_index;
+                loop {
+                    index = (index + 1) & (capacity - 1);
+                    let bucket_ptr = buckets_ptr.add(index * bucket_size);
+                    match *status_ptr(base_ptr, header, index) {
+                        status if status == BucketStatus::Empty as u8 => break,
+                        status if is_occupied(status) => {
+                            let home = index_from_hash(
+                                header,
+                                bucket_hash(header, bucket_ptr, Tally::map(base_ptr)),
+                            );
+                            if probe_distance(home, hole, capacity)
+                                < probe_distance(home, index, capacity)
+                            {
+                                copy_bucket(base_ptr, header, index, hole);
+                                *status_ptr(base_ptr, header, index) = BucketStatus::Empty as u8;
+                                hole = index;
+                            }
+                        }
+                        _ => {} // Tombstones are handled by their own pass
+                    }
+                }
+            }
+        }
+
+        set_tombstone_count(header, 0);
+        header.generation = header.generation.wrapping_add(1);
+        header.mutation_generation = header.mutation_generation.wrapping_add(1);
+
+        let key_offset = key_offset_of(header);
+        let value_size = header.value_size as usize;
+        let mut spill_index = 0;
+        while spill_index < spill_len_of(base_ptr, header) {
+            let spilled_ptr = spill_bucket_ptr(base_ptr, header, spill_index);
+            let hash = bucket_hash(header, spilled_ptr, Tally::map(base_ptr));
+            match probe_slot(base_ptr, spilled_ptr.add(key_offset), hash) {
+                slot @ Slot::Vacant { .. } => {
+                    let value_ptr = fill_slot(base_ptr, spilled_ptr.add(key_offset), hash, slot);
+                    let spilled_value_ptr =
+                        value_ptr_at(base_ptr, header, capacity + spill_index, spilled_ptr);
+                    ptr::copy_nonoverlapping(spilled_value_ptr, value_ptr, value_size);
+                    copy_dirty_flag(
+                        base_ptr,
+                        header,
+                        capacity + spill_index,
+                        bucket_index_of_value(base_ptr, value_ptr) as usize,
+                    );
+                    vacate_spill_bucket(base_ptr, spill_index);
+                }
+                _ => spill_index += 1,
+            }
+        }
+    }
+}
+
+/// Move entries of a quadratic probing map to the first tombstone on their probe
+/// sequence until none can move, and then empty every tombstone
+///
+/// Triangular probe sequences do not form clusters that could be shifted back. Once no
+/// entry can move, no tombstone is left in front of an entry on its probe sequence.
+unsafe fn settle_quadratic(base_ptr: *mut u8, header: &MapHeader) {
+    unsafe {
+        let capacity = capacity_of(header);
+        let bucket_size = header.bucket_size as usize;
+        let buckets_ptr = base_ptr.add(buckets_offset(header));
+        let tombstone = BucketStatus::Tombstone as u8;
+
+        // Every move brings an entry closer to its home bucket, so this ends
+        let mut moved = true;
+        while moved {
+            moved = false;
+            for index in 0..capacity {
+                if !is_occupied(*status_ptr(base_ptr, header, index)) {
+                    continue;
+                }
+
+                let bucket_ptr = buckets_ptr.add(index * bucket_size);
+                let mut probe_index = index_from_hash(
+                    header,
+                    bucket_hash(header, bucket_ptr, Tally::map(base_ptr)),
+                );
+                let mut probe = 0;
+                while probe_index != index {
+                    if *status_ptr(base_ptr, header, probe_index) == tombstone {
+                        copy_bucket(base_ptr, header, index, probe_index);
+                        *status_ptr(base_ptr, header, index) = tombstone;
+                        moved = true;
+                        break;
+                    }
+                    probe_index = next_probe_index(header, probe_index, probe);
+                    probe += 1;
+                }
+            }
+        }
+
+        for index in 0..capacity {
+            let status_ptr = status_ptr(base_ptr, header, index);
+            if *status_ptr == tombstone {
+                *status_ptr = BucketStatus::Empty as u8;
+            }
+        }
+    }
+}
+
+/// Copy all entries from source map to target map
+///
+/// Merges into the target: entries of the target whose key is not in the source are
+/// kept. Use [`replace_with`] to end up with exactly the entries of the source.
+///
+/// # Safety
+///
+/// - Both maps must be properly initialized. The capacity, alignment padding, stored
+///   hashes and layout kind can differ.
+/// - Target map must have sufficient capacity
+///
+/// # Returns
+///
+/// `true` if the operation succeeded, `false` if the target has insufficient capacity.
+/// The target never grows past its `logical_limit`, even when it has free buckets left.
+///
+/// # Panics
+///
+/// If the key or value sizes of the maps differ
+#[inline]
+pub unsafe fn overwrite(target_base: *mut u8, source: *const u8) -> bool {
+    unsafe {
+        let target_header = &*target_base.cast::<MapHeader>();
+        let source_header = &*source.cast::<MapHeader>();
+        assert_eq!(
+            target_header.padding_and_secret_code, SECRET_CODE,
+            "hashmap, secret code failed"
+        );
+        assert_eq!(
+            source_header.padding_and_secret_code, SECRET_CODE,
+            "hashmap, secret code failed"
+        );
+        debug_assert_thawed(target_header);
+        // Check if target has enough capacity
+        if logical_limit_of(target_header) < element_count_of(source_header) {
+            return false;
+        }
+
+        // Keys and values are copied through the offsets of each map, so only their sizes
+        // have to match
+        assert_eq!(
+            target_header.key_size, source_header.key_size,
+            "Incompatible key sizes"
+        );
+        assert_eq!(
+            target_header.value_size, source_header.value_size,
+            "Incompatible value sizes"
+        );
+
+        merge(target_base, source, MergePolicy::KeepSource).is_ok()
+    }
+}
+
+/// Replace all entries of a map with the entries of another map
+///
+/// Unlike [`overwrite`], the target is cleared first, so it ends up holding exactly the
+/// entries of the source, as when restoring a snapshot. The bucket layouts may differ
+/// like for [`grow_into`]. Handles into the target are invalidated.
+///
+/// # Safety
+///
+/// - `target_base` and `source` must point to valid initialized maps that do not overlap
+///
+/// # Errors
+///
+/// Returns a [`GrowError`] if the maps are incompatible or the source holds more entries
+/// than the logical limit of the target, the target is left untouched in that case. If
+/// placing an entry fails, the target holds some of the entries.
+pub unsafe fn replace_with(target_base: *mut u8, source: *const u8) -> Result<(), GrowError> {
+    unsafe {
+        let source_header = checked_header(source);
+        let target_header = checked_header(target_base);
+
+        if target_header.key_size != source_header.key_size {
+            return Err(GrowError::KeySizeMismatch);
+        }
+        if target_header.value_size != source_header.value_size {
+            return Err(GrowError::ValueSizeMismatch);
+        }
+        if logical_limit_of(target_header) < element_count_of(source_header) {
+            return Err(GrowError::InsufficientCapacity);
+        }
+
+        clear(target_base);
+        grow_into(target_base, source)
+    }
+}
+
+/// Copy all entries from source map to target map, reporting failures as a [`MapError`]
+///
+/// Same as [`overwrite`], but maps that are not initialized or do not fit together
+/// give an error instead of a panic.
+///
+/// # Safety
+///
+/// - `target_base` and `source` must point to at least a header's worth of readable
+///   bytes, and must not overlap
+/// - A buffer whose secret code matches must hold a valid initialized map
+///
+/// # Errors
+///
+/// - [`MapError::BadMagic`] if either map is not initialized
+/// - [`MapError::LayoutMismatch`] if the key or value sizes differ
+/// - [`MapError::CapacityExceeded`] or [`MapError::ProbeLimitExceeded`] if the entries
+///   do not fit. The entries copied before that stay in the target.
+pub unsafe fn try_overwrite(target_base: *mut u8, source: *const u8) -> Result<(), MapError> {
+    unsafe {
+        let target_header = &*target_base.cast::<MapHeader>();
+        let source_header = &*source.cast::<MapHeader>();
+        if target_header.padding_and_secret_code != SECRET_CODE
+            || source_header.padding_and_secret_code != SECRET_CODE
+        {
+            return Err(MapError::BadMagic);
+        }
+        if target_header.key_size != source_header.key_size
+            || target_header.value_size != source_header.value_size
+        {
+            return Err(MapError::LayoutMismatch);
+        }
+        if logical_limit_of(target_header) < element_count_of(source_header) {
+            return Err(MapError::CapacityExceeded);
+        }
+
+        merge(target_base, source, MergePolicy::KeepSource)?;
+        Ok(())
+    }
+}
+
+/// Add all entries of a map to another map, resolving keys that are in both with `policy`
+///
+/// Meant for combining partial results, like per-thread maps, into one map. The bucket
+/// layouts may differ like for [`grow_into`].
+///
+/// # Safety
+///
+/// - `target_base` and `source` must point to valid initialized maps that do not overlap
+/// - A [`MergePolicy::Combine`] callback must not access the maps other than through
+///   the pointers it is given
+///
+/// # Errors
+///
+/// Returns a [`GrowError`] if the maps are incompatible, or if a new key would take the
+/// target past its logical limit or probe limit. The entries merged before that stay
+/// in the target.
+pub unsafe fn merge(
+    target_base: *mut u8,
+    source: *const u8,
+    mut policy: MergePolicy<'_>,
+) -> Result<(), GrowError> {
+    unsafe {
+        let source_header = checked_header(source);
+        let target_header = checked_header(target_base);
+
+        if target_header.key_size != source_header.key_size {
+            return Err(GrowError::KeySizeMismatch);
+        }
+        if target_header.value_size != source_header.value_size {
+            return Err(GrowError::ValueSizeMismatch);
+        }
+
+        let value_size = source_header.value_size as usize;
+        let key_offset = key_offset_of(source_header);
+        let logical_limit = logical_limit_of(target_header);
+        let mut result = Ok(());
+
+        for_each_occupied_bucket(source, source_header, |index, bucket_ptr| {
+            if result.is_err() {
+                return;
+            }
+
+            let key_ptr = bucket_ptr.add(key_offset);
+            let source_value_ptr = value_ptr_at(source, source_header, index, bucket_ptr);
+            let hash = copied_bucket_hash(source_header, bucket_ptr, target_base);
+
+            // Only keys already in the target may be merged once it is logically full
+            let target_count = element_count_of(&*target_base.cast::<MapHeader>());
+            if target_count >= logical_limit
+                && lookup_hashed_entry(target_base, key_ptr, hash, Tally::map(target_base))
+                    .is_null()
+            {
+                result = Err(GrowError::InsufficientCapacity);
+                return;
+            }
+
+            match reserve_hashed_entry(target_base, key_ptr, hash) {
+                ReserveResult::Reserved(target_value_ptr) => {
+                    ptr::copy_nonoverlapping(source_value_ptr, target_value_ptr, value_size);
+                }
+                ReserveResult::Existing(target_value_ptr) => match &mut policy {
+                    MergePolicy::KeepTarget => {}
+                    MergePolicy::KeepSource => {
+                        ptr::copy_nonoverlapping(source_value_ptr, target_value_ptr, value_size);
+                    }
+                    MergePolicy::Combine(f) => f(target_value_ptr, source_value_ptr),
+                },
+                ReserveResult::Full => result = Err(GrowError::ProbeLimitExceeded),
+            }
+        });
+
+        result
+    }
+}
+
+/// Move all entries of a map into a freshly initialized map, rehashing them for the
+/// target's capacity
+///
+/// The bucket layouts may differ (e.g. different capacity, alignment padding or stored
+/// hashes), only the key and value sizes have to match. The source is left untouched.
+///
+/// # Safety
+///
+/// - `target_base` and `source` must point to valid initialized maps
+///
+/// # Errors
+///
+/// Returns a [`GrowError`] if the maps are incompatible or the entries do not fit.
+/// The target may hold some of the entries if placing one of them failed.
+pub unsafe fn grow_into(target_base: *mut u8, source: *const u8) -> Result<(), GrowError> {
+    unsafe {
+        let source_header = checked_header(source);
+        let target_header = checked_header(target_base);
+
+        if target_header.key_size != source_header.key_size {
+            return Err(GrowError::KeySizeMismatch);
+        }
+        if target_header.value_size != source_header.value_size {
+            return Err(GrowError::ValueSizeMismatch);
+        }
+        if element_count_of(target_header) != 0 {
+            return Err(GrowError::TargetNotEmpty);
+        }
+        if logical_limit_of(target_header) < element_count_of(source_header) {
+            return Err(GrowError::InsufficientCapacity);
+        }
+
+        let value_size = source_header.value_size as usize;
+        let key_offset = key_offset_of(source_header);
+        let mut result = Ok(());
+
+        for_each_occupied_bucket(source, source_header, |index, bucket_ptr| {
+            if result.is_err() {
+                return;
+            }
+
+            let key_ptr = bucket_ptr.add(key_offset);
+            let hash = copied_bucket_hash(source_header, bucket_ptr, target_base);
+
+            let target_value_ptr = reserve_hashed_entry(target_base, key_ptr, hash).value_ptr();
+            if target_value_ptr.is_null() {
+                result = Err(GrowError::ProbeLimitExceeded);
+            } else {
+                ptr::copy_nonoverlapping(
+                    value_ptr_at(source, source_header, index, bucket_ptr),
+                    target_value_ptr,
+                    value_size,
+                );
+            }
+        });
+
+        result
+    }
+}
+
+/// Initialize a smaller map and move all entries of a map into it, to give back the
+/// memory of a map that holds far fewer entries than it was made for
+///
+/// Nothing is written unless the sizes of `target_init` match the source and its
+/// logical limit holds all entries of the source. The source is left untouched.
+///
+/// # Safety
+///
+/// - `target_base` must point to writable memory as [`alloc_layout`] describes for
+///   `target_init`, that does not overlap the source
+/// - `source` must point to a valid initialized map
+///
+/// # Errors
+///
+/// Returns [`MapError::LayoutMismatch`] if the key or value sizes differ,
+/// [`MapError::CapacityExceeded`] if the source holds more entries than the logical
+/// limit of `target_init`, and [`MapError::ProbeLimitExceeded`] if an entry could not be
+/// placed (the target then holds some of the entries).
+pub unsafe fn shrink_into(
+    target_base: *mut u8,
+    target_init: &MapInit,
+    source: *const u8,
+) -> Result<(), MapError> {
+    unsafe {
+        let source_header = checked_header(source);
+        if target_init.key_size != source_header.key_size
+            || target_init.value_size != source_header.value_size
+        {
+            return Err(MapError::LayoutMismatch);
+        }
+        if (target_init.logical_limit as usize) < element_count_of(source_header) {
+            return Err(MapError::CapacityExceeded);
+        }
+
+        init(target_base, target_init);
+        grow_into(target_base, source)?;
+        Ok(())
+    }
+}
+
+/// Call `f` with the key and value of every entry
+///
+/// Walks the buckets directly, so it is faster than calling [`find_next_valid_entry`]
+/// in a loop.
+///
+/// # Safety
+///
+/// - `base` must point to a valid initialized map
+/// - `f` must not change the map, other than writing to the values
+pub unsafe fn for_each(base: *mut u8, mut f: impl FnMut(*const u8, *mut u8)) {
+    unsafe {
+        let header = checked_header(base);
+        let key_offset = key_offset_of(header);
+
+        for_each_occupied_bucket(base, header, |index, bucket_ptr| {
+            f(
+                bucket_ptr.add(key_offset),
+                value_ptr_at(base, header, index, bucket_ptr),
+            );
+        });
+    }
+}
+
+/// Call `f` with the key and value of every entry, until it returns `Break`
+///
+/// # Safety
+///
+/// - `base` must point to a valid initialized map
+/// - `f` must not change the map, other than writing to the values
+///
+/// # Returns
+///
+/// The `Break` returned by `f`, or `Continue` if every entry was visited
+pub unsafe fn try_for_each<B>(
+    base: *mut u8,
+    mut f: impl FnMut(*const u8, *mut u8) -> ControlFlow<B>,
+) -> ControlFlow<B> {
+    unsafe {
+        let header = checked_header(base);
+        let key_offset = key_offset_of(header);
+
+        try_for_each_occupied_bucket(base, header, |index, bucket_ptr| {
+            f(
+                bucket_ptr.add(key_offset),
+                value_ptr_at(base, header, index, bucket_ptr),
+            )
+        })
+    }
+}
+
+/// Find the first entry whose value equals the `value_size` bytes at `value_ptr`
+///
+/// Scans every occupied bucket, so it is meant for debugging and small maps.
+///
+/// # Safety
+///
+/// - `base` must point to a valid initialized map
+/// - `value_ptr` must point to `value_size` readable bytes
+///
+/// # Returns
+///
+/// Key pointer and bucket index of the entry, or (null, `u32::MAX`) if no entry has
+/// the value. Spilled entries have indices from the capacity on.
+#[must_use]
+pub unsafe fn find_by_value(base: *const u8, value_ptr: *const u8) -> (*const u8, u32) {
+    unsafe {
+        let header = checked_header(base);
+        let key_offset = key_offset_of(header);
+        let value_size = header.value_size as usize;
+
+        let found = try_for_each_occupied_bucket(base, header, |index, bucket_ptr| {
+            let stored_value_ptr = value_ptr_at(base, header, index, bucket_ptr);
+            if matches_key(stored_value_ptr, value_ptr, value_size) {
+                ControlFlow::Break((bucket_ptr.add(key_offset).cast_const(), index as u32))
+            } else {
+                ControlFlow::Continue(())
+            }
+        });
+        match found {
+            ControlFlow::Break(found) => found,
+            ControlFlow::Continue(()) => (ptr::null(), u32::MAX),
+        }
+    }
+}
+
+/// Pick an entry at random, using `random` as the source of randomness
+///
+/// The random number is scaled to an entry number, and the buckets are walked up to
+/// that entry, so every entry is equally likely no matter how the entries are spread
+/// over the buckets. Each entry gets `2^32 / len` of the random numbers, rounded up or
+/// down, so any bias is too small to matter for maps with far fewer than `2^32` entries.
+///
+/// # Safety
+///
+/// - `base` must point to a valid initialized map
+///
+/// # Returns
+///
+/// Key and value pointers of the entry, or nulls if the map is empty
+#[must_use]
+pub unsafe fn sample(base: *mut u8, random: u32) -> (*const u8, *mut u8) {
+    unsafe {
+        let header = checked_header(base);
+        let key_offset = key_offset_of(header);
+        let count = element_count_of(header) as u64;
+        let mut remaining = (u64::from(random) * count) >> 32;
+
+        let found = try_for_each_occupied_bucket(base, header, |index, bucket_ptr| {
+            if remaining == 0 {
+                ControlFlow::Break((
+                    bucket_ptr.add(key_offset).cast_const(),
+                    value_ptr_at(base, header, index, bucket_ptr),
+                ))
+            } else {
+                remaining -= 1;
+                ControlFlow::Continue(())
+            }
+        });
+        match found {
+            ControlFlow::Break(found) => found,
+            ControlFlow::Continue(()) => (ptr::null(), ptr::null_mut()),
+        }
+    }
+}
+
+/// Find the next valid entry in the map
+///
+/// # Safety
+///
+/// - `base` must point to a valid initialized map
+///
+/// # Returns
+///
+/// Tuple of (`key_ptr`, `value_ptr`, index) of the next valid entry,
+/// or (null, null, `u32::MAX`) if no more entries exist. Spilled entries come after
+/// the buckets, with indices from the capacity on.
+#[inline]
+pub unsafe fn find_next_valid_entry(base: *mut u8, start_index: u32) -> (*const u8, *mut u8, u32) {
+    unsafe {
+        let map_header = &*base.cast::<MapHeader>();
+        let key_offset = key_offset_of(map_header);
+        assert_eq!(
+            map_header.padding_and_secret_code, SECRET_CODE,
+            "hashmap, secret code failed"
+        );
+
+        let mut index = start_index as usize;
+        let end = capacity_of(map_header) + spill_len_of(base, map_header);
+
+        while index < end {
+            let entry_ptr = occupied_bucket_at(base, map_header, index);
+
+            if !entry_ptr.is_null() {
+                let key_addr = entry_ptr.add(key_offset);
+                let value_addr = value_ptr_at(base, map_header, index, entry_ptr);
+
+                return (key_addr, value_addr, index as u32);
+            }
+
+            index += 1;
+        }
+
+        (ptr::null(), ptr::null_mut(), u32::MAX)
+    }
+}
+
+/// Iterator over the key and value bytes of every occupied bucket
+///
+/// Created by [`entries`]. Wraps [`find_next_valid_entry`] and tracks the cursor itself.
+/// Debug builds panic if entries are added or removed while iterating.
+#[derive(Debug)]
+pub struct Entries<'a> {
+    base: *mut u8,
+    next_index: usize,
+    generation: u32,
+    _marker: PhantomData<&'a mut [u8]>,
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = (&'a [u8], &'a mut [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        unsafe {
+            let header = &*self.base.cast::<MapHeader>();
+            debug_assert_eq!(
+                header.mutation_generation, self.generation,
+                "hashmap, map changed while iterating"
+            );
+            let end = capacity_of(header) + spill_len_of(self.base, header);
+            if self.next_index >= end {
+                return None;
+            }
+
+            let (key_ptr, value_ptr, index) =
+                find_next_valid_entry(self.base, self.next_index as u32);
+            if key_ptr.is_null() {
+                self.next_index = end;
+                return None;
+            }
+            self.next_index = index as usize + 1;
+
+            Some((
+                slice::from_raw_parts(key_ptr, header.key_size as usize),
+                slice::from_raw_parts_mut(value_ptr, header.value_size as usize),
+            ))
+        }
+    }
+}
+
+impl FusedIterator for Entries<'_> {}
+
+/// Iterate over the key and value bytes of all entries in the map
+///
+/// # Safety
+///
+/// - `base` must point to a valid initialized map
+/// - The map must not be accessed in any other way while the iterator or the slices it
+///   returns are alive
+pub const unsafe fn entries<'a>(base: *mut u8) -> Entries<'a> {
+    Entries {
+        base,
+        next_index: 0,
+        generation: unsafe { (*base.cast::<MapHeader>()).mutation_generation },
+        _marker: PhantomData,
+    }
+}
+
+/// Copy up to `max_entries` entries into `out`, starting at `cursor`
+///
+/// Each entry is written as its key bytes followed by its value bytes, and no more
+/// entries are copied than fit in `out`. Pass the returned cursor to get the next page,
+/// until it [`Cursor::is_done`].
+///
+/// Removing entries between pages is fine for quadratic probing maps, since the other
+/// entries stay in their buckets: every entry that is in the map for the whole stream is
+/// copied exactly once. Any other change invalidates the cursor. Linear and Robin Hood
+/// removals shift the later entries of a cluster back, which can move an entry from
+/// ahead of the cursor to behind it, or from the start of the buckets around to their
+/// end. Inserts and removals of spilled entries can move entries too. Those entries are
+/// skipped or copied twice. The cursor keeps the [`generation`] of the map, so the
+/// caller can tell when that may have happened and start over.
+///
+/// # Safety
+///
+/// - `base` must point to a valid initialized map
+///
+/// # Returns
+///
+/// Number of entries copied, and the cursor for the next page
+pub unsafe fn next_page(
+    base: *const u8,
+    cursor: Cursor,
+    max_entries: usize,
+    out: &mut [u8],
+) -> (usize, Cursor) {
+    unsafe {
+        let header = checked_header(base);
+        let key_size = header.key_size as usize;
+        let value_size = header.value_size as usize;
+        let key_offset = key_offset_of(header);
+        let end = capacity_of(header) + spill_len_of(base, header);
+        let max_entries = min(max_entries, out.len() / (key_size + value_size));
+
+        let mut index = cursor.index as usize;
+        let mut count = 0;
+        while index < end && count < max_entries {
+            let bucket_ptr = occupied_bucket_at(base, header, index);
+            if !bucket_ptr.is_null() {
+                let entry_out = &mut out[count * (key_size + value_size)..];
+                ptr::copy_nonoverlapping(
+                    bucket_ptr.add(key_offset),
+                    entry_out.as_mut_ptr(),
+                    key_size,
+                );
+                ptr::copy_nonoverlapping(
+                    value_ptr_at(base, header, index, bucket_ptr),
+                    entry_out.as_mut_ptr().add(key_size),
+                    value_size,
+                );
+                count += 1;
+            }
+            index += 1;
+        }
+
+        // Skip the empty buckets at the end, so that the last page says it is the last
+        while index < end && occupied_bucket_at(base, header, index).is_null() {
+            index += 1;
+        }
+
+        let next = Cursor {
+            index: if index >= end { u32::MAX } else { index as u32 },
+            generation: header.mutation_generation,
+        };
+        (count, next)
+    }
+}
+
+/// Offset of the key inside a bucket
+///
+/// The `u8` header field is used when it is set, so maps from before `key_offset_wide`
+/// existed keep working.
+#[inline]
+const fn key_offset_of(header: &MapHeader) -> usize {
+    if header.key_offset != 0 {
+        header.key_offset as usize
+    } else {
+        header.key_offset_wide as usize
+    }
+}
+
+/// Control bytes are padded to whole cache lines, so the buckets after them are as
+/// aligned as the buckets of an interleaved map
+const CONTROL_BYTES_ALIGN: usize = 64;
+
+/// Check if the status bytes are stored in front of the buckets instead of in them
+#[inline]
+const fn has_control_bytes(layout_kind: u8) -> bool {
+    layout_kind == LayoutKind::ControlBytes as u8 || layout_kind == LayoutKind::StructOfArrays as u8
+}
+
+/// Bytes between the header and the first bucket, taken by the status bytes of a
+/// [`LayoutKind::ControlBytes`] or [`LayoutKind::StructOfArrays`] map
+#[inline]
+const fn control_bytes_len(layout_kind: u8, capacity: usize) -> usize {
+    if has_control_bytes(layout_kind) {
+        capacity.next_multiple_of(CONTROL_BYTES_ALIGN)
+    } else {
+        0
+    }
+}
+
+/// Offset of the first bucket from the start of the map
+#[inline]
+const fn buckets_offset(header: &MapHeader) -> usize {
+    MAP_BUCKETS_OFFSET + control_bytes_len(header.layout_kind, capacity_of(header))
+}
+
+/// Distance between the status bytes of two neighbouring buckets
+///
+/// The status byte of the first bucket is right after the header in both layouts.
+#[inline]
+const fn status_stride(header: &MapHeader) -> usize {
+    if has_control_bytes(header.layout_kind) {
+        1
+    } else {
+        header.bucket_size as usize
+    }
+}
+
+/// Status byte of the bucket at `index`
+#[inline]
+const unsafe fn status_ptr(base_ptr: *const u8, header: &MapHeader, index: usize) -> *mut u8 {
+    unsafe {
+        base_ptr
+            .cast_mut()
+            .add(MAP_BUCKETS_OFFSET + index * status_stride(header))
+    }
+}
+
+/// Index of the bucket at `bucket_ptr`, spilled entries count from the capacity on
+#[inline]
+unsafe fn bucket_index(base_ptr: *const u8, header: &MapHeader, bucket_ptr: *const u8) -> usize {
+    unsafe {
+        let spill_buckets_ptr = spill_bucket_ptr(base_ptr, header, 0);
+        if header.flags & FLAG_SPILL_REGION != 0 && bucket_ptr >= spill_buckets_ptr.cast_const() {
+            return capacity_of(header)
+                + bucket_ptr.offset_from(spill_buckets_ptr) as usize / header.bucket_size as usize;
+        }
+
+        bucket_ptr.offset_from(base_ptr.add(buckets_offset(header))) as usize
+            / header.bucket_size as usize
+    }
+}
+
+/// Bucket at `index` if it holds an entry, or null
+///
+/// Indices from the capacity on are the spilled entries, in the order of the spill region.
+#[inline]
+unsafe fn occupied_bucket_at(base_ptr: *const u8, header: &MapHeader, index: usize) -> *mut u8 {
+    unsafe {
+        let capacity = capacity_of(header);
+        if index < capacity {
+            if is_occupied(*status_ptr(base_ptr, header, index)) {
+                base_ptr
+                    .cast_mut()
+                    .add(buckets_offset(header) + index * header.bucket_size as usize)
+            } else {
+                ptr::null_mut()
+            }
+        } else if index - capacity < spill_len_of(base_ptr, header) {
+            spill_bucket_ptr(base_ptr, header, index - capacity)
+        } else {
+            ptr::null_mut()
+        }
+    }
+}
+
+/// The spill region starts on a cache line of its own, and its buckets one cache line
+/// later, so that they are as aligned as the buckets of the map
+const SPILL_HEADER_LEN: usize = 64;
+
+/// Start of the spill region of a map initialized with [`MapInit::with_spill_capacity`]
+///
+/// Spilled entries are packed at the start of the spill buckets, in no particular order.
+/// They have no status byte (the one of an interleaved bucket is left unused).
+#[repr(C)]
+struct SpillHeader {
+    capacity: u16,
+    len: u16,
+}
+
+/// End of a map whose buckets end at `buckets_end`, including its value array, spill
+/// region and dirty flags
+#[inline]
+const fn map_end(
+    buckets_end: u64,
+    capacity: u64,
+    spill_capacity: u16,
+    bucket_size: u32,
+    value_stride: u32,
+    dirty_flags: bool,
+) -> u64 {
+    let buckets_end = values_end(buckets_end, capacity, value_stride);
+    let spill_end = if spill_capacity == 0 {
+        buckets_end
+    } else {
+        values_end(
+            buckets_end.next_multiple_of(SPILL_HEADER_LEN as u64)
+                + SPILL_HEADER_LEN as u64
+                + spill_capacity as u64 * bucket_size as u64,
+            spill_capacity as u64,
+            value_stride,
+        )
+    };
+
+    if dirty_flags {
+        spill_end + (capacity + spill_capacity as u64).div_ceil(8)
+    } else {
+        spill_end
+    }
+}
+
+/// Offset of the spill region from the start of the map
+#[inline]
+const fn spill_offset(header: &MapHeader) -> usize {
+    values_end(
+        (buckets_offset(header) + capacity_of(header) * header.bucket_size as usize) as u64,
+        capacity_of(header) as u64,
+        header.value_stride,
+    )
+    .next_multiple_of(SPILL_HEADER_LEN as u64) as usize
+}
+
+/// Bytes of memory used by a map, from the header to the end of the last bucket or of
+/// the dirty flags
+#[inline]
+unsafe fn map_len(base_ptr: *const u8, header: &MapHeader) -> usize {
+    unsafe { map_len_with(base_ptr, header, header.flags & FLAG_DIRTY_FLAGS != 0) }
+}
+
+/// Offset of the dirty flags, right after the last bucket
+#[inline]
+unsafe fn dirty_flags_offset(base_ptr: *const u8, header: &MapHeader) -> usize {
+    unsafe { map_len_with(base_ptr, header, false) }
+}
+
+#[inline]
+unsafe fn map_len_with(base_ptr: *const u8, header: &MapHeader, dirty_flags: bool) -> usize {
+    unsafe {
+        let buckets_end =
+            buckets_offset(header) + capacity_of(header) * header.bucket_size as usize;
+        let spill_capacity = spill_header(base_ptr, header).map_or(0, |spill| spill.capacity);
+        map_end(
+            buckets_end as u64,
+            capacity_of(header) as u64,
+            spill_capacity,
+            header.bucket_size,
+            header.value_stride,
+            dirty_flags,
+        ) as usize
+    }
+}
+
+/// Byte and bit mask of the dirty flag of the bucket at `index`, counting spill buckets
+/// from the capacity on, if the map has dirty flags
+#[inline]
+unsafe fn dirty_flag(
+    base_ptr: *const u8,
+    header: &MapHeader,
+    index: usize,
+) -> Option<(*mut u8, u8)> {
+    if header.flags & FLAG_DIRTY_FLAGS == 0 {
+        return None;
+    }
+    unsafe {
+        let flags_ptr = base_ptr
+            .cast_mut()
+            .add(dirty_flags_offset(base_ptr, header));
+        Some((flags_ptr.add(index / 8), 1 << (index % 8)))
+    }
+}
+
+/// Set or clear the dirty flag of the bucket at `index`, if the map has dirty flags
+#[inline]
+unsafe fn set_dirty_flag(base_ptr: *mut u8, header: &MapHeader, index: usize, dirty: bool) {
+    unsafe {
+        if let Some((flags_ptr, mask)) = dirty_flag(base_ptr, header, index) {
+            if dirty {
+                *flags_ptr |= mask;
+            } else {
+                *flags_ptr &= !mask;
+            }
+        }
+    }
+}
+
+/// Move the dirty flag of a bucket along with its entry
+#[inline]
+unsafe fn copy_dirty_flag(base_ptr: *mut u8, header: &MapHeader, from: usize, to: usize) {
+    unsafe {
+        if let Some((flags_ptr, mask)) = dirty_flag(base_ptr, header, from) {
+            set_dirty_flag(base_ptr, header, to, *flags_ptr & mask != 0);
+        }
+    }
+}
+
+/// The value arrays of a [`LayoutKind::StructOfArrays`] map start on a multiple of the
+/// largest alignment a value can have
+const VALUES_ALIGN: u64 = 128;
+
+/// End of an array of `count` values placed after `end`, or `end` itself if the values
+/// are stored in the buckets (`value_stride` is zero)
+#[inline]
+const fn values_end(end: u64, count: u64, value_stride: u32) -> u64 {
+    if value_stride == 0 {
+        end
+    } else {
+        end.next_multiple_of(VALUES_ALIGN) + count * value_stride as u64
+    }
+}
+
+/// Offset of the value of the first bucket of a [`LayoutKind::StructOfArrays`] map,
+/// which follows the buckets
+#[inline]
+const fn values_offset(header: &MapHeader) -> usize {
+    let buckets_end = buckets_offset(header) + capacity_of(header) * header.bucket_size as usize;
+    values_end(buckets_end as u64, 0, header.value_stride) as usize
+}
+
+/// Value location of the entry in the bucket at `index`, which is at `bucket_ptr`
+///
+/// Cheaper than [`value_ptr_of`] when the index is known anyway.
+#[inline]
+unsafe fn value_ptr_at(
+    base_ptr: *const u8,
+    header: &MapHeader,
+    index: usize,
+    bucket_ptr: *mut u8,
+) -> *mut u8 {
+    unsafe {
+        if header.value_stride == 0 {
+            return bucket_ptr.add(header.value_offset as usize);
+        }
+
+        let capacity = capacity_of(header);
+        let (values_offset, value_index) = if index < capacity {
+            (values_offset(header), index)
+        } else {
+            (spill_values_offset(base_ptr, header), index - capacity)
+        };
+        base_ptr
+            .cast_mut()
+            .add(values_offset + value_index * header.value_stride as usize)
+    }
+}
+
+/// Value location of the entry in the bucket at `bucket_ptr`
+#[inline]
+unsafe fn value_ptr_of(base_ptr: *const u8, header: &MapHeader, bucket_ptr: *mut u8) -> *mut u8 {
+    unsafe {
+        if header.value_stride == 0 {
+            bucket_ptr.add(header.value_offset as usize)
+        } else {
+            let index = bucket_index(base_ptr, header, bucket_ptr);
+            value_ptr_at(base_ptr, header, index, bucket_ptr)
+        }
+    }
+}
+
+/// Offset of the value of the first spilled entry of a [`LayoutKind::StructOfArrays`]
+/// map, which follows the spill buckets
+#[inline]
+unsafe fn spill_values_offset(base_ptr: *const u8, header: &MapHeader) -> usize {
+    unsafe {
+        let spill_capacity = spill_header(base_ptr, header).map_or(0, |spill| spill.capacity);
+        let spill_buckets_end = spill_offset(header)
+            + SPILL_HEADER_LEN
+            + usize::from(spill_capacity) * header.bucket_size as usize;
+        values_end(spill_buckets_end as u64, 0, header.value_stride) as usize
+    }
+}
+
+/// Distance between the values of two neighbouring buckets
+#[inline]
+const fn value_stride_of(header: &MapHeader) -> usize {
+    if header.value_stride == 0 {
+        header.bucket_size as usize
+    } else {
+        header.value_stride as usize
+    }
+}
+
+/// Spill region of the map, if it has one
+#[inline]
+unsafe fn spill_header<'a>(base_ptr: *const u8, header: &MapHeader) -> Option<&'a mut SpillHeader> {
+    if header.flags & FLAG_SPILL_REGION == 0 {
+        return None;
+    }
+    unsafe {
+        Some(
+            &mut *base_ptr
+                .cast_mut()
+                .add(spill_offset(header))
+                .cast::<SpillHeader>(),
+        )
+    }
+}
+
+/// Number of spilled entries
+#[inline]
+unsafe fn spill_len_of(base_ptr: *const u8, header: &MapHeader) -> usize {
+    unsafe { spill_header(base_ptr, header).map_or(0, |spill| usize::from(spill.len)) }
+}
+
+/// Bucket at `spill_index` in the spill region
+#[inline]
+const unsafe fn spill_bucket_ptr(
+    base_ptr: *const u8,
+    header: &MapHeader,
+    spill_index: usize,
+) -> *mut u8 {
+    unsafe {
+        base_ptr.cast_mut().add(
+            spill_offset(header) + SPILL_HEADER_LEN + spill_index * header.bucket_size as usize,
+        )
+    }
+}
+
+/// Find a spilled key by comparing it against every spilled entry
+///
+/// # Returns
+///
+/// Pointer to the bucket (not the value), or null if the key is not spilled
+#[inline]
+unsafe fn find_in_spill(
+    base_ptr: *const u8,
+    header: &MapHeader,
+    key_ptr: *const u8,
+    hash: u64,
+) -> *mut u8 {
+    unsafe {
+        for spill_index in 0..spill_len_of(base_ptr, header) {
+            let bucket_ptr = spill_bucket_ptr(base_ptr, header, spill_index);
+            if bucket_holds_key(header, bucket_ptr, key_ptr, hash) {
+                return bucket_ptr;
+            }
+        }
+
+        ptr::null_mut()
+    }
+}
+
+/// Add a key after the last spilled entry and return its value location
+///
+/// The spill region must have room for it.
+#[inline]
+unsafe fn occupy_spill_bucket(base_ptr: *mut u8, key_ptr: *const u8, hash: u64) -> *mut u8 {
+    unsafe {
+        let header_mut = &mut *base_ptr.cast::<MapHeader>();
+        let spill = spill_header(base_ptr, header_mut).expect("hashmap, map has no spill region");
+        debug_assert!(spill.len < spill.capacity);
+
+        let bucket_ptr = spill_bucket_ptr(base_ptr, header_mut, usize::from(spill.len));
+        set_dirty_flag(
+            base_ptr,
+            header_mut,
+            capacity_of(header_mut) + usize::from(spill.len),
+            false,
+        );
+        ptr::copy_nonoverlapping(
+            key_ptr,
+            bucket_ptr.add(key_offset_of(header_mut)),
+            header_mut.key_size as usize,
+        );
+        if header_mut.hash_offset != 0 {
+            *bucket_ptr
+                .add(header_mut.hash_offset as usize)
+                .cast::<u64>() = hash;
+        }
+
+        spill.len += 1;
+        set_element_count(header_mut, element_count_of(header_mut) + 1);
+        header_mut.mutation_generation = header_mut.mutation_generation.wrapping_add(1);
+
+        value_ptr_of(base_ptr, header_mut, bucket_ptr)
+    }
+}
+
+/// Remove a spilled entry by moving the last spilled entry into its place
+#[inline]
+unsafe fn vacate_spill_bucket(base_ptr: *mut u8, spill_index: usize) {
+    unsafe {
+        let header_mut = &mut *base_ptr.cast::<MapHeader>();
+        let spill = spill_header(base_ptr, header_mut).expect("hashmap, map has no spill region");
+
+        spill.len -= 1;
+        let last_index = usize::from(spill.len);
+        if spill_index != last_index {
+            let capacity = capacity_of(header_mut);
+            let last_ptr = spill_bucket_ptr(base_ptr, header_mut, last_index);
+            let hole_ptr = spill_bucket_ptr(base_ptr, header_mut, spill_index);
+            ptr::copy_nonoverlapping(last_ptr, hole_ptr, header_mut.bucket_size as usize);
+            if header_mut.value_stride != 0 {
+                ptr::copy_nonoverlapping(
+                    value_ptr_at(base_ptr, header_mut, capacity + last_index, last_ptr),
+                    value_ptr_at(base_ptr, header_mut, capacity + spill_index, hole_ptr),
+                    header_mut.value_size as usize,
+                );
+            }
+            copy_dirty_flag(
+                base_ptr,
+                header_mut,
+                capacity + last_index,
+                capacity + spill_index,
+            );
+            scrub_bucket(base_ptr, header_mut, last_ptr);
+        }
+
+        // Update counts and invalidate handles
+        set_element_count(header_mut, element_count_of(header_mut) - 1);
+        header_mut.generation = header_mut.generation.wrapping_add(1);
+        header_mut.mutation_generation = header_mut.mutation_generation.wrapping_add(1);
+    }
+}
+
+/// Join a `u16` header count with its `_high` field
+#[inline]
+const fn join_count(low: u16, high: u16) -> usize {
+    low as usize | (high as usize) << 16
+}
+
+/// Split a count into a `u16` header field and its `_high` field
+#[inline]
+const fn split_count(count: usize) -> (u16, u16) {
+    (count as u16, (count >> 16) as u16)
+}
+
+#[inline]
+const fn capacity_of(header: &MapHeader) -> usize {
+    join_count(header.capacity, header.capacity_high)
+}
+
+#[inline]
+const fn element_count_of(header: &MapHeader) -> usize {
+    join_count(header.element_count, header.element_count_high)
+}
+
+#[inline]
+const fn logical_limit_of(header: &MapHeader) -> usize {
+    join_count(header.logical_limit, header.logical_limit_high)
+}
+
+#[inline]
+const fn tombstone_count_of(header: &MapHeader) -> usize {
+    join_count(header.tombstone_count, header.tombstone_count_high)
+}
+
+#[inline]
+const fn set_element_count(header: &mut MapHeader, count: usize) {
+    (header.element_count, header.element_count_high) = split_count(count);
+}
+
+#[inline]
+const fn set_tombstone_count(header: &mut MapHeader, count: usize) {
+    (header.tombstone_count, header.tombstone_count_high) = split_count(count);
+}
+
+/// Read the header of an initialized map
+#[inline]
+unsafe fn checked_header<'a>(base_ptr: *const u8) -> &'a MapHeader {
+    let header = unsafe { &*base_ptr.cast::<MapHeader>() };
+    assert_eq!(
+        header.padding_and_secret_code, SECRET_CODE,
+        "hashmap, secret code failed"
+    );
+    header
+}
+
+/// Bucket index where the probe sequence for a key starts
+#[inline]
+unsafe fn home_index(header: &MapHeader, key_ptr: *const u8) -> usize {
+    let key_slice = unsafe { slice::from_raw_parts(key_ptr, header.key_size as usize) };
+    index_from_hash(
+        header,
+        calculate_hash_bytes(key_slice, header.hash_seed, header.hash_strategy),
+    )
+}
+
+/// Hash of the key in an occupied bucket, read from the bucket if the map stores hashes
+#[inline]
+unsafe fn bucket_hash(header: &MapHeader, bucket_ptr: *const u8, tally: Tally) -> u64 {
+    unsafe {
+        if header.hash_offset != 0 {
+            *bucket_ptr.add(header.hash_offset as usize).cast::<u64>()
+        } else {
+            tally.count(Counter::KeyHashes, 1);
+            let key_ptr = bucket_ptr.add(key_offset_of(header));
+            let key_slice = slice::from_raw_parts(key_ptr, header.key_size as usize);
+            calculate_hash_bytes(key_slice, header.hash_seed, header.hash_strategy)
+        }
+    }
+}
+
+/// Hash for the map at `target_base` of the key in an occupied bucket of another map
+///
+/// A stored hash can only be reused if both maps hash the same way, otherwise the key
+/// is hashed again and counted in the target.
+#[inline]
+unsafe fn copied_bucket_hash(
+    source_header: &MapHeader,
+    bucket_ptr: *const u8,
+    target_base: *mut u8,
+) -> u64 {
+    unsafe {
+        let target_header = &*target_base.cast::<MapHeader>();
+        if hashes_alike(source_header, target_header) {
+            return bucket_hash(source_header, bucket_ptr, Tally::map(target_base));
+        }
+
+        count(target_base, Counter::KeyHashes, 1);
+        let key_ptr = bucket_ptr.add(key_offset_of(source_header));
+        let key_slice = slice::from_raw_parts(key_ptr, source_header.key_size as usize);
+        calculate_hash_bytes(
+            key_slice,
+            target_header.hash_seed,
+            target_header.hash_strategy,
+        )
+    }
+}
+
+/// Call `f` with the index and pointer of every occupied bucket, and then of every
+/// spilled entry (with indices from the capacity on)
+#[inline]
+unsafe fn for_each_occupied_bucket(
+    base_ptr: *const u8,
+    header: &MapHeader,
+    mut f: impl FnMut(usize, *mut u8),
+) {
+    unsafe {
+        let _ = try_for_each_occupied_bucket(base_ptr, header, |index, bucket_ptr| {
+            f(index, bucket_ptr);
+            ControlFlow::<()>::Continue(())
+        });
+    }
+}
+
+/// Like [`for_each_occupied_bucket`], stopping at the first bucket for which `f`
+/// returns `Break`
+#[inline]
+unsafe fn try_for_each_occupied_bucket<B>(
+    base_ptr: *const u8,
+    header: &MapHeader,
+    mut f: impl FnMut(usize, *mut u8) -> ControlFlow<B>,
+) -> ControlFlow<B> {
+    unsafe {
+        let buckets_ptr = base_ptr.cast_mut().add(buckets_offset(header));
+        let bucket_size = header.bucket_size as usize;
+        let capacity = capacity_of(header);
+
+        for index in 0..capacity {
+            if is_occupied(*status_ptr(base_ptr, header, index)) {
+                f(index, buckets_ptr.add(index * bucket_size))?;
+            }
+        }
+
+        for spill_index in 0..spill_len_of(base_ptr, header) {
+            f(
+                capacity + spill_index,
+                spill_bucket_ptr(base_ptr, header, spill_index),
+            )?;
+        }
+
+        ControlFlow::Continue(())
+    }
+}
+
+/// Distance of the entry in an occupied bucket from its home bucket
+///
+/// A spilled entry is a whole probe sequence plus its place in the spill region away.
+#[inline]
+unsafe fn bucket_distance(header: &MapHeader, bucket_ptr: *const u8, index: usize) -> usize {
+    let capacity = capacity_of(header);
+    if index >= capacity {
+        return effective_probe_limit(header) + index - capacity;
+    }
+
+    let home = index_from_hash(header, unsafe {
+        bucket_hash(header, bucket_ptr, Tally::NONE)
+    });
+    if header.probe_strategy == ProbeStrategy::Quadratic as u8 {
+        // Triangular steps can not be undone modulo the capacity, so they are retraced
+        let mut probe_index = home;
+        let mut distance = 0;
+        while probe_index != index {
+            probe_index = next_probe_index(header, probe_index, distance);
+            distance += 1;
+        }
+        return distance;
+    }
+    probe_distance(home, index, capacity)
+}
+
+#[inline]
+const fn probe_distance(home: usize, index: usize, capacity: usize) -> usize {
+    index.wrapping_sub(home) & (capacity - 1)
+}
+
+#[inline]
+const fn seed_for_attempt(seed: u64, attempt: u32) -> u64 {
+    // Attempt zero keeps the configured seed
+    seed.wrapping_add((attempt as u64).wrapping_mul(FIBONACCI_MULTIPLIER))
+}
+
+/// Place every key directly in its home bucket, failing on the first collision
+unsafe fn place_in_home_buckets(base_ptr: *mut u8, keys_values: &[(*const u8, *const u8)]) -> bool {
+    unsafe {
+        let header = &*base_ptr.cast::<MapHeader>();
+        let key_size = header.key_size as usize;
+        let value_size = header.value_size as usize;
+        let bucket_size = header.bucket_size as usize;
+        let key_offset = key_offset_of(header);
+        let buckets_ptr = base_ptr.add(buckets_offset(header));
+
+        for &(key_ptr, value_ptr) in keys_values {
+            let key_slice = slice::from_raw_parts(key_ptr, key_size);
+            let hash = calculate_hash_bytes(key_slice, header.hash_seed, header.hash_strategy);
+            let index = index_from_hash(header, hash);
+            let bucket_ptr = buckets_ptr.add(index * bucket_size);
+
+            if is_occupied(*status_ptr(base_ptr, header, index)) {
+                // Duplicate keys are allowed, the last value wins
+                if !key_matches(header, bucket_ptr.add(key_offset), key_ptr) {
+                    return false;
+                }
+            } else {
+                occupy_bucket(base_ptr, index, key_ptr, hash);
+            }
+
+            let target_value_ptr = value_ptr_at(base_ptr, header, index, bucket_ptr);
+            ptr::copy_nonoverlapping(value_ptr, target_value_ptr, value_size);
+        }
+
+        true
+    }
+}
+
+/// Build a read-only map from a fixed key set
+///
+/// Searches for a hash seed that places every key in its home bucket, so that
+/// every lookup is resolved by the first probe. The seed is stored in the header
+/// and used by all later operations on the map.
+///
+/// # Safety
+///
+/// - `base_ptr` must point to valid, properly aligned memory of at least `config.total_size` bytes
+/// - Every key pointer must point to `config.key_size` readable bytes
+/// - Every value pointer must point to `config.value_size` readable bytes
+///
+/// # Returns
+///
+/// `true` if a collision-free seed was found within `max_seed_tries` attempts.
+/// On failure the map is left initialized and empty.
+pub unsafe fn build_static(
+    base_ptr: *mut u8,
+    config: &MapInit,
+    keys_values: &[(*const u8, *const u8)],
+    max_seed_tries: u32,
+) -> bool {
+    unsafe {
+        if keys_values.len() <= config.logical_limit as usize {
+            for attempt in 0..max_seed_tries {
+                init(base_ptr, config);
+                (*base_ptr.cast::<MapHeader>()).hash_seed = seed_for_attempt(config.seed, attempt);
+
+                if place_in_home_buckets(base_ptr, keys_values) {
+                    return true;
+                }
+            }
+        }
+
+        init(base_ptr, config);
+        false
+    }
+}
+
+/// Average number of probes needed to find each stored key
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+///
+/// # Returns
+///
+/// The mean probe count over all occupied buckets (1.0 means every key sits in its
+/// home bucket), or 0.0 for an empty map
+#[must_use]
+pub unsafe fn avg_lookup_probes(base_ptr: *const u8) -> f32 {
+    unsafe {
+        let header = checked_header(base_ptr);
+
+        let mut total_probes = 0usize;
+        let mut occupied = 0usize;
+
+        for_each_occupied_bucket(base_ptr, header, |index, bucket_ptr| {
+            total_probes += bucket_distance(header, bucket_ptr, index) + 1;
+            occupied += 1;
+        });
+
+        if occupied == 0 {
+            0.0
+        } else {
+            total_probes as f32 / occupied as f32
+        }
+    }
+}
+
+/// Read which bucket layout a map was initialized with
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+#[must_use]
+pub unsafe fn layout_kind(base_ptr: *const u8) -> LayoutKind {
+    unsafe {
+        let header = checked_header(base_ptr);
+
+        match header.layout_kind {
+            kind if kind == LayoutKind::Interleaved as u8 => LayoutKind::Interleaved,
+            kind if kind == LayoutKind::ControlBytes as u8 => LayoutKind::ControlBytes,
+            kind if kind == LayoutKind::StructOfArrays as u8 => LayoutKind::StructOfArrays,
+            kind => panic!("hashmap, unknown layout kind {kind}"),
+        }
+    }
+}
+
+/// Read how many buckets a probe sequence may visit
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+///
+/// # Returns
+///
+/// The configured probe limit, capped to the capacity of the map
+#[must_use]
+pub unsafe fn probe_limit(base_ptr: *const u8) -> u16 {
+    unsafe { effective_probe_limit(checked_header(base_ptr)) as u16 }
+}
+
+/// Number of entries in the map
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+#[must_use]
+pub unsafe fn len(base_ptr: *const u8) -> u32 {
+    unsafe { element_count_of(checked_header(base_ptr)) as u32 }
+}
+
+/// Counter bumped whenever an entry is added, removed or moved
+///
+/// Writing a value in place does not change it. Compare two readings to find out
+/// whether pointers into the map, or anything cached from it, might be stale.
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+#[must_use]
+pub unsafe fn generation(base_ptr: *const u8) -> u32 {
+    unsafe { checked_header(base_ptr).mutation_generation }
+}
+
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+#[must_use]
+pub unsafe fn is_empty(base_ptr: *const u8) -> bool {
+    unsafe { len(base_ptr) == 0 }
+}
+
+/// Number of buckets in the map
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+#[must_use]
+pub unsafe fn capacity(base_ptr: *const u8) -> u32 {
+    unsafe { capacity_of(checked_header(base_ptr)) as u32 }
+}
+
+/// Maximum number of entries the map is meant to hold
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+#[must_use]
+pub unsafe fn logical_limit(base_ptr: *const u8) -> u32 {
+    unsafe { logical_limit_of(checked_header(base_ptr)) as u32 }
+}
+
+/// Whether the map holds as many entries as its logical limit allows
+///
+/// The insert functions only stop at the capacity, so callers that enforce the logical
+/// limit check this first.
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+#[must_use]
+pub unsafe fn is_full(base_ptr: *const u8) -> bool {
+    unsafe { remaining(base_ptr) == 0 }
+}
+
+/// Number of entries that can still be added before reaching the logical limit
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+#[must_use]
+pub unsafe fn remaining(base_ptr: *const u8) -> u32 {
+    unsafe {
+        let header = checked_header(base_ptr);
+        logical_limit_of(header).saturating_sub(element_count_of(header)) as u32
+    }
+}
+
+/// Count the keys present in both maps
+///
+/// # Safety
+///
+/// - `a` and `b` must point to valid initialized maps with the same key size
+#[must_use]
+pub unsafe fn count_common_keys(a: *const u8, b: *const u8) -> usize {
+    unsafe {
+        let a_header = checked_header(a);
+        let b_header = checked_header(b);
+        assert_eq!(
+            a_header.key_size, b_header.key_size,
+            "Incompatible key sizes"
+        );
+
+        let key_offset = key_offset_of(a_header);
+        let mut common = 0;
+
+        for_each_occupied_bucket(a, a_header, |_, bucket_ptr| {
+            if has(b, bucket_ptr.add(key_offset)) {
+                common += 1;
+            }
+        });
+
+        common
     }
 }
 
-/// Get or reserve an entry in the map
+/// Call `f(key_ptr, value_ptr)` for every entry of `a` whose key is not in `b`
+///
+/// Probes `b` once per entry of `a`. The pointers point into `a`.
 ///
 /// # Safety
 ///
-/// - `base_ptr` must point to a valid initialized map
-/// - `key_ptr` must point to a valid key of the size specified in the map header
+/// - `a` and `b` must point to valid initialized maps with the same key size
+/// - `f` must not change either map
 ///
-/// # Returns
+/// # Panics
 ///
-/// Pointer to the value location, or null if the map is full
-#[inline]
-pub unsafe fn get_or_reserve_entry(base_ptr: *mut u8, key_ptr: *const u8) -> *mut u8 {
+/// If the key sizes of the maps differ
+pub unsafe fn for_each_missing_in(
+    a: *const u8,
+    b: *const u8,
+    mut f: impl FnMut(*const u8, *mut u8),
+) {
     unsafe {
-        let header = &*base_ptr.cast::<MapHeader>();
-
-        // Validate parameters
-        let capacity = header.capacity as usize;
-        let key_size = header.key_size as usize;
-        let bucket_size = header.bucket_size as usize;
-        let key_offset = header.key_offset as usize;
-        let value_offset = header.value_offset as usize;
-
+        let a_header = checked_header(a);
+        let b_header = checked_header(b);
         assert_eq!(
-            header.padding_and_secret_code, SECRET_CODE,
-            "hashmap, secret code failed"
-        );
-        assert_ne!(key_size, 0, "Key size cannot be zero");
-        assert_ne!(capacity, 0, "Capacity cannot be zero");
-        assert!(
-            capacity.is_power_of_two(),
-            "Capacity must be a power of two"
+            a_header.key_size, b_header.key_size,
+            "Incompatible key sizes"
         );
 
-        let buckets_ptr = base_ptr.add(MAP_BUCKETS_OFFSET);
-        let key_slice = slice::from_raw_parts(key_ptr, key_size);
-        let hash = calculate_hash_bytes(key_slice);
-
-        // Initial probe position
-        let mut index = index_from_hash(hash, header.capacity);
-
-        // Track first tombstone for potential reuse
-        let mut first_tombstone = None;
-        let probe_limit = min(capacity, MAX_PROBE_DISTANCE);
-
-        for _ in 0..probe_limit {
-            let bucket_ptr = buckets_ptr.add(index * bucket_size);
-            let status = *bucket_ptr;
-
-            match status {
-                status if status == BucketStatus::Empty as u8 => {
-                    // TODO: Maybe go back to BucketStatus as constants instead, this feel a bit awkward
-                    // Use tombstone if found, otherwise use current empty slot
-                    let insert_index = first_tombstone.unwrap_or(index);
-                    let target_bucket = buckets_ptr.add(insert_index * bucket_size);
-
-                    // Mark as occupied and copy key
-                    *target_bucket = BucketStatus::Occupied as u8;
-                    let target_key_ptr = target_bucket.add(key_offset);
-                    ptr::copy_nonoverlapping(key_ptr, target_key_ptr, key_size);
-
-                    // Update element count
-                    let header_mut = &mut *base_ptr.cast::<MapHeader>();
-                    header_mut.element_count += 1;
+        let key_offset = key_offset_of(a_header);
 
-                    return target_bucket.add(value_offset);
-                }
-                status if status == BucketStatus::Occupied as u8 => {
-                    // Check if keys match
-                    let existing_key_ptr = bucket_ptr.add(key_offset);
-                    if matches_key(existing_key_ptr, key_ptr, key_size) {
-                        return bucket_ptr.add(value_offset);
-                    }
-                }
-                status if status == BucketStatus::Tombstone as u8 => {
-                    // Remember first tombstone for potential reuse
-                    if first_tombstone.is_none() {
-                        first_tombstone = Some(index);
-                    }
-                }
-                _ => unreachable!(),
+        for_each_occupied_bucket(a, a_header, |index, bucket_ptr| {
+            if !has(b, bucket_ptr.add(key_offset)) {
+                f(
+                    bucket_ptr.add(key_offset),
+                    value_ptr_at(a, a_header, index, bucket_ptr),
+                );
             }
+        });
+    }
+}
 
-            // Linear probing with wraparound using bitmask
-            index = (index + 1) & (capacity - 1);
-        }
-
-        // If we found a tombstone during probing, use it
-        if let Some(tombstone_index) = first_tombstone {
-            let target_bucket = buckets_ptr.add(tombstone_index * bucket_size);
-
-            // Mark as occupied and copy key
-            *target_bucket = BucketStatus::Occupied as u8;
-            let target_key_ptr = target_bucket.add(key_offset);
-            ptr::copy_nonoverlapping(key_ptr, target_key_ptr, key_size);
-
-            // Update element count
-            let header_mut = &mut *base_ptr.cast::<MapHeader>();
-            header_mut.element_count += 1;
+/// Call `f(key_ptr, a_value_ptr, b_value_ptr)` for every key that is in both maps
+///
+/// Probes `b` once per entry of `a`. The key pointer points into `a`.
+///
+/// # Safety
+///
+/// - `a` and `b` must point to valid initialized maps with the same key size
+/// - `f` must not change either map, other than writing to the values
+///
+/// # Panics
+///
+/// If the key sizes of the maps differ
+pub unsafe fn for_each_common(
+    a: *const u8,
+    b: *const u8,
+    mut f: impl FnMut(*const u8, *mut u8, *mut u8),
+) {
+    unsafe {
+        let a_header = checked_header(a);
+        let b_header = checked_header(b);
+        assert_eq!(
+            a_header.key_size, b_header.key_size,
+            "Incompatible key sizes"
+        );
 
-            return target_bucket.add(value_offset);
-        }
+        let key_offset = key_offset_of(a_header);
 
-        // Map is full or probe limit exceeded
-        ptr::null_mut()
+        for_each_occupied_bucket(a, a_header, |index, bucket_ptr| {
+            let b_value_ptr = lookup_shared(b, bucket_ptr.add(key_offset)).cast_mut();
+            if !b_value_ptr.is_null() {
+                f(
+                    bucket_ptr.add(key_offset),
+                    value_ptr_at(a, a_header, index, bucket_ptr),
+                    b_value_ptr,
+                );
+            }
+        });
     }
 }
 
-/// Check if a key exists in the map
+/// Count the distinct keys present in either map
 ///
 /// # Safety
 ///
-/// - `base_ptr` must point to a valid initialized map
-/// - `key_ptr` must point to a valid key of the size specified in the map header
-#[inline]
+/// - `a` and `b` must point to valid initialized maps with the same key size
 #[must_use]
-pub unsafe fn has(base_ptr: *const u8, key_ptr: *const u8) -> bool {
-    unsafe { lookup(base_ptr.cast_mut(), key_ptr).is_null().not() }
+pub unsafe fn count_union_keys(a: *const u8, b: *const u8) -> usize {
+    unsafe {
+        let a_count = element_count_of(checked_header(a));
+        let b_count = element_count_of(checked_header(b));
+        a_count + b_count - count_common_keys(a, b)
+    }
 }
 
-/// Lookup an existing entry in the map
+/// Jaccard similarity of the key sets of two maps
 ///
 /// # Safety
 ///
-/// - `base_ptr` must point to a valid initialized map
-/// - `key_ptr` must point to a valid key of the size specified in the map header
+/// - `a` and `b` must point to valid initialized maps with the same key size
 ///
 /// # Returns
 ///
-/// Pointer to the found value, or null if not found
-#[inline]
-pub unsafe fn lookup(base_ptr: *mut u8, key_ptr: *const u8) -> *mut u8 {
+/// `intersection / union` of the key sets. Two empty maps have identical key sets
+/// and return 1.0.
+#[must_use]
+pub unsafe fn key_jaccard(a: *const u8, b: *const u8) -> f32 {
     unsafe {
-        let header = &*base_ptr.cast::<MapHeader>();
-
-        let capacity = header.capacity as usize;
-        let key_size = header.key_size as usize;
-        let bucket_size = header.bucket_size as usize;
-        let key_offset = header.key_offset as usize;
-        let value_offset = header.value_offset as usize;
-
-        assert_eq!(
-            header.padding_and_secret_code, SECRET_CODE,
-            "hashmap, secret code failed"
-        );
-        assert_ne!(key_size, 0, "Key size cannot be zero");
-        assert_ne!(capacity, 0, "Capacity cannot be zero");
-        assert!(
-            capacity.is_power_of_two(),
-            "Capacity must be a power of two {capacity}"
-        );
+        let union = count_union_keys(a, b);
+        if union == 0 {
+            return 1.0;
+        }
 
-        let buckets_ptr = base_ptr.add(MAP_BUCKETS_OFFSET);
-        let key_slice = slice::from_raw_parts(key_ptr, key_size);
-        let hash = calculate_hash_bytes(key_slice);
+        count_common_keys(a, b) as f32 / union as f32
+    }
+}
 
-        // Initial probe position
-        let mut index = index_from_hash(hash, header.capacity);
-        let probe_limit = min(capacity, MAX_PROBE_DISTANCE);
+/// Check if two maps hold exactly the same keys with the same value bytes
+///
+/// Capacity, hash seed, bucket order and tombstones do not matter.
+///
+/// # Safety
+///
+/// - `a` and `b` must point to valid initialized maps
+#[must_use]
+pub unsafe fn equals(a: *const u8, b: *const u8) -> bool {
+    unsafe {
+        let a_header = checked_header(a);
+        let b_header = checked_header(b);
+        if a_header.key_size != b_header.key_size
+            || a_header.value_size != b_header.value_size
+            || element_count_of(a_header) != element_count_of(b_header)
+        {
+            return false;
+        }
 
-        for _ in 0..probe_limit {
-            let bucket_ptr = buckets_ptr.add(index * bucket_size);
-            let status = *bucket_ptr;
+        let key_offset = key_offset_of(a_header);
+        let value_size = a_header.value_size as usize;
+        let mut equal = true;
 
-            match status {
-                status if status == BucketStatus::Empty as u8 => {
-                    // TODO: Maybe go back to constant
-                    // Empty slot means the key is not in the map
-                    return ptr::null_mut();
-                }
-                status if status == BucketStatus::Occupied as u8 => {
-                    // Check if keys match
-                    let existing_key_ptr = bucket_ptr.add(key_offset);
-                    if matches_key(existing_key_ptr, key_ptr, key_size) {
-                        return bucket_ptr.add(value_offset);
-                    }
-                }
-                _ => {} // Continue probing for tombstones
+        // Same count and every entry of `a` in `b` means there is nothing else in `b`
+        for_each_occupied_bucket(a, a_header, |index, bucket_ptr| {
+            if equal {
+                let b_value_ptr = lookup_shared(b, bucket_ptr.add(key_offset));
+                equal = !b_value_ptr.is_null()
+                    && matches_key(
+                        value_ptr_at(a, a_header, index, bucket_ptr),
+                        b_value_ptr,
+                        value_size,
+                    );
             }
+        });
 
-            index = (index + 1) & (capacity - 1);
-        }
-
-        // Key not found within probe limit
-        ptr::null_mut()
+        equal
     }
 }
 
-/// Remove an entry from the map
+/// Order-independent digest of all key and value bytes in the map
+///
+/// Maps with the same entries give the same digest, whatever their capacity, hash seed,
+/// hash strategy or bucket order. Meant for detecting desyncs, not for security.
 ///
 /// # Safety
 ///
 /// - `base_ptr` must point to a valid initialized map
-/// - `key_ptr` must point to a valid key of the size specified in the map header
-///
-/// # Returns
-///
-/// `true` if the key was found and removed, `false` otherwise
-#[inline]
-pub unsafe fn remove(base_ptr: *mut u8, key_ptr: *const u8) -> bool {
+#[must_use]
+pub unsafe fn content_hash(base_ptr: *const u8) -> u64 {
     unsafe {
-        let header = &*base_ptr.cast::<MapHeader>();
-
-        let capacity = header.capacity as usize;
+        let header = checked_header(base_ptr);
         let key_size = header.key_size as usize;
-        let bucket_size = header.bucket_size as usize;
-        let key_offset = header.key_offset as usize;
-
-        assert_eq!(
-            header.padding_and_secret_code, SECRET_CODE,
-            "hashmap, secret code failed"
-        );
-        assert_ne!(key_size, 0, "Key size cannot be zero");
-        assert_ne!(capacity, 0, "Capacity cannot be zero");
-        assert!(
-            capacity.is_power_of_two(),
-            "Capacity must be a power of two"
-        );
+        let value_size = header.value_size as usize;
+        let key_offset = key_offset_of(header);
 
-        let buckets_ptr = base_ptr.add(MAP_BUCKETS_OFFSET);
-        let key_slice = slice::from_raw_parts(key_ptr, key_size);
-        let hash = calculate_hash_bytes(key_slice);
+        let mut digest = 0u64;
+        for_each_occupied_bucket(base_ptr, header, |index, bucket_ptr| {
+            let key_slice = slice::from_raw_parts(bucket_ptr.add(key_offset), key_size);
+            let value_ptr = value_ptr_at(base_ptr, header, index, bucket_ptr);
+            let value_slice = slice::from_raw_parts(value_ptr, value_size);
+            let entry_hash = fx_hash(value_slice, fx_hash(key_slice, 0));
+            // Summing makes the digest independent of the bucket order
+            digest = digest.wrapping_add(finalize_hash(entry_hash));
+        });
 
-        // Initial probe position
-        let mut index = index_from_hash(hash, header.capacity);
-        let probe_limit = min(capacity, MAX_PROBE_DISTANCE);
+        digest
+    }
+}
 
-        for _ in 0..probe_limit {
-            let bucket_ptr = buckets_ptr.add(index * bucket_size);
-            let status = *bucket_ptr;
+/// Spread every input bit over the whole hash (the `SplitMix64` finalizer)
+#[inline]
+const fn finalize_hash(mut hash: u64) -> u64 {
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^ (hash >> 31)
+}
 
-            match status {
-                status if status == BucketStatus::Empty as u8 => {
-                    // Empty slot means the key is not in the map
-                    return false;
-                }
-                status if status == BucketStatus::Occupied as u8 => {
-                    // Check if keys match
-                    let existing_key_ptr = bucket_ptr.add(key_offset);
-                    if matches_key(existing_key_ptr, key_ptr, key_size) {
-                        // Convert to tombstone
-                        *bucket_ptr = BucketStatus::Tombstone as u8;
-
-                        // Update count
-                        let header_mut = &mut *base_ptr.cast::<MapHeader>();
-                        header_mut.element_count -= 1;
+/// Report every stored key whose probe sequence starts at bucket `home`
+///
+/// Calls `f(key_ptr, actual_index)` for each such key, which reveals the contents of
+/// a single collision chain.
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+pub unsafe fn keys_at_home(base_ptr: *const u8, home: u32, mut f: impl FnMut(*const u8, u32)) {
+    unsafe {
+        let header = checked_header(base_ptr);
+        let key_offset = key_offset_of(header);
 
-                        return true;
-                    }
-                }
-                _ => {} // Continue probing for tombstones
+        for_each_occupied_bucket(base_ptr, header, |index, bucket_ptr| {
+            let hash = bucket_hash(header, bucket_ptr, Tally::NONE);
+            if index_from_hash(header, hash) == home as usize {
+                f(bucket_ptr.add(key_offset), index as u32);
             }
-
-            index = (index + 1) & (capacity - 1);
-        }
-
-        // Key not found within probe limit
-        false
+        });
     }
 }
 
-/// Copy all entries from source map to target map
+/// Number of key and value bytes stored in the map
+///
+/// Excludes status bytes, padding, stored hashes, empty buckets and tombstones.
 ///
 /// # Safety
 ///
-/// - Both maps must be properly initialized with compatible layouts (capacity can differ)
-/// - Target map must have sufficient capacity
+/// - `base_ptr` must point to a valid initialized map
+#[must_use]
+pub unsafe fn live_payload_bytes(base_ptr: *const u8) -> u64 {
+    unsafe {
+        let header = checked_header(base_ptr);
+        element_count_of(header) as u64
+            * (u64::from(header.key_size) + u64::from(header.value_size))
+    }
+}
+
+/// Classify whether a map needs to be rebuilt
 ///
-/// # Returns
+/// - `Critical` when at least 90% of the buckets are occupied or tombstoned, or when
+///   the longest probe sequence reaches the probe limit
+/// - `Degraded` when at least 25% of the buckets are tombstones, at least 75% are
+///   occupied or tombstoned, or the longest probe sequence reaches half the probe limit
+/// - `Good` otherwise
 ///
-/// `true` if the operation succeeded, `false` if the target has insufficient capacity
-#[inline]
-pub unsafe fn overwrite(target_base: *mut u8, source: *const u8) -> bool {
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+#[must_use]
+pub unsafe fn health(base_ptr: *const u8) -> MapHealth {
     unsafe {
-        let target_header = &mut *target_base.cast::<MapHeader>();
-        let source_header = &*source.cast::<MapHeader>();
-        assert_eq!(
-            target_header.padding_and_secret_code, SECRET_CODE,
-            "hashmap, secret code failed"
-        );
-        assert_eq!(
-            source_header.padding_and_secret_code, SECRET_CODE,
-            "hashmap, secret code failed"
-        );
-        // Check if target has enough capacity
-        if target_header.logical_limit < source_header.element_count {
-            return false;
-        }
-
-        // Validate compatible layouts
-        assert_eq!(
-            target_header.bucket_size, source_header.bucket_size,
-            "Incompatible bucket sizes"
-        );
-        assert_eq!(
-            target_header.key_size, source_header.key_size,
-            "Incompatible key sizes"
-        );
-        assert_eq!(
-            target_header.value_size, source_header.value_size,
-            "Incompatible value sizes"
-        );
+        let header = checked_header(base_ptr);
+        let capacity = capacity_of(header);
+        let probe_limit = effective_probe_limit(header);
 
-        let source_buckets_ptr = source.add(MAP_BUCKETS_OFFSET);
-        let bucket_size = source_header.bucket_size as usize;
-        let key_offset = source_header.key_offset as usize;
-        let value_offset = source_header.value_offset as usize;
-        let value_size = source_header.value_size as usize;
-
-        // Copy each occupied bucket
-        for i in 0..source_header.capacity as usize {
-            let source_bucket = source_buckets_ptr.add(i * bucket_size);
-
-            if *source_bucket == BucketStatus::Occupied as u8 {
-                let source_key_ptr = source_bucket.add(key_offset);
-                let source_value_ptr = source_bucket.add(value_offset);
-
-                let target_value_ptr = get_or_reserve_entry(target_base, source_key_ptr);
+        let mut longest_probe = 0;
+        for_each_occupied_bucket(base_ptr, header, |index, bucket_ptr| {
+            longest_probe = max(
+                longest_probe,
+                bucket_distance(header, bucket_ptr, index) + 1,
+            );
+        });
 
-                if target_value_ptr.is_null() {
-                    return false;
-                }
+        let tombstones = tombstone_count_of(header);
+        let used = element_count_of(header) + tombstones;
 
-                ptr::copy_nonoverlapping(source_value_ptr, target_value_ptr, value_size);
-            }
+        if used * 10 >= capacity * 9 || longest_probe >= probe_limit {
+            MapHealth::Critical
+        } else if tombstones * 4 >= capacity
+            || used * 4 >= capacity * 3
+            || longest_probe * 2 >= probe_limit
+        {
+            MapHealth::Degraded
+        } else {
+            MapHealth::Good
         }
-
-        true
     }
 }
 
-/// Find the next valid entry in the map
+/// Check whether enough of the buckets are tombstones to be worth a [`rehash_in_place`]
+///
+/// Only reads the tombstone count that removals and inserts keep in the header, so it
+/// is cheap enough to call after every batch of changes.
 ///
 /// # Safety
 ///
-/// - `base` must point to a valid initialized map
+/// - `base_ptr` must point to a valid initialized map
 ///
 /// # Returns
 ///
-/// Tuple of (`key_ptr`, `value_ptr`, index) of the next valid entry,
-/// or (null, null, 0xFFFF) if no more entries exist
-#[inline]
-pub unsafe fn find_next_valid_entry(base: *mut u8, start_index: u16) -> (*const u8, *mut u8, u16) {
+/// `true` if more than `max_tombstone_ratio` (0.0 to 1.0) of the buckets are tombstones
+#[must_use]
+pub unsafe fn needs_rehash(base_ptr: *const u8, max_tombstone_ratio: f32) -> bool {
     unsafe {
-        let map_header = &*base.cast::<MapHeader>();
-        let bucket_size = map_header.bucket_size as usize;
-        let buckets_start = base.add(MAP_BUCKETS_OFFSET);
-        let key_offset = map_header.key_offset as usize;
-        let value_offset = map_header.value_offset as usize;
-        assert_eq!(
-            map_header.padding_and_secret_code, SECRET_CODE,
-            "hashmap, secret code failed"
-        );
-
-        let mut index = start_index as usize;
+        let header = checked_header(base_ptr);
+        tombstone_count_of(header) as f32 > capacity_of(header) as f32 * max_tombstone_ratio
+    }
+}
 
-        while index < map_header.capacity as usize {
-            let entry_ptr = buckets_start.add(index * bucket_size);
+/// Gather occupancy and probe statistics, to tune the capacity and probe limit
+///
+/// Visits every bucket, so it is meant for diagnostics rather than hot paths.
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+#[must_use]
+pub unsafe fn stats(base_ptr: *const u8) -> MapStats {
+    unsafe {
+        let header = checked_header(base_ptr);
+        let capacity = capacity_of(header);
 
-            // Properly use the enum instead of magic number
-            if *entry_ptr == BucketStatus::Occupied as u8 {
-                let key_addr = entry_ptr.add(key_offset);
-                let value_addr = entry_ptr.add(value_offset);
+        let mut max_probe_distance = 0;
+        let mut total_distance = 0usize;
+        let mut distance_histogram = [0u32; DISTANCE_HISTOGRAM_LEN];
+        for_each_occupied_bucket(base_ptr, header, |index, bucket_ptr| {
+            let distance = bucket_distance(header, bucket_ptr, index);
+            max_probe_distance = max(max_probe_distance, distance);
+            total_distance += distance;
+            distance_histogram[min(distance, DISTANCE_HISTOGRAM_LEN - 1)] += 1;
+        });
 
-                return (key_addr, value_addr, index as u16);
+        // Start right after an empty bucket so no cluster is split by the wrap-around
+        let is_empty =
+            |index: usize| *status_ptr(base_ptr, header, index) == BucketStatus::Empty as u8;
+        let longest_cluster = match (0..capacity).find(|&index| is_empty(index)) {
+            None => capacity,
+            Some(empty_index) => {
+                let mut longest = 0;
+                let mut current = 0;
+                for offset in 1..=capacity {
+                    if is_empty((empty_index + offset) & (capacity - 1)) {
+                        current = 0;
+                    } else {
+                        current += 1;
+                        longest = max(longest, current);
+                    }
+                }
+                longest
             }
+        };
 
-            index += 1;
+        let element_count = element_count_of(header);
+        MapStats {
+            element_count: element_count as u32,
+            tombstone_count: tombstone_count_of(header) as u32,
+            capacity: capacity as u32,
+            load_factor: element_count as f32 / capacity as f32,
+            max_probe_distance,
+            avg_probe_distance: if element_count == 0 {
+                0.0
+            } else {
+                total_distance as f32 / element_count as f32
+            },
+            longest_cluster,
+            distance_histogram,
         }
-
-        (ptr::null(), ptr::null_mut(), 0xFFFF)
     }
 }
@@ -0,0 +1,142 @@
+/*
+ * Copyright (c) Peter Bjorklund. All rights reserved. https://github.com/piot/hashmap-mem
+ * Licensed under the MIT License. See LICENSE in the project root for license information.
+ */
+
+//! Macros that generate typed code around the raw functions
+
+/// Define a module of typed functions for maps with one key type, value type and
+/// logical limit
+///
+/// The module gets `Key`, `Value`, `LOGICAL_LIMIT` and `REQUIRED_SIZE`, `config` and
+/// `alloc_layout` to allocate a map, and `init`, `insert`, `get`, `get_mut`, `remove`,
+/// `len` and `iter` over the map pointer. They call the raw functions directly, with the sizes and
+/// alignments taken from the types by [`crate::layout_for`], so a map can not be made
+/// with the wrong ones. Keys and values must implement [`crate::Pod`].
+///
+/// `define_map! { pub mod health { key: u32, value: u16, logical_limit: 64 } }` defines
+/// `health::init`, `health::insert` and so on.
+#[macro_export]
+macro_rules! define_map {
+    (
+        $(#[$meta:meta])*
+        $vis:vis mod $name:ident {
+            key: $key:ty,
+            value: $value:ty,
+            logical_limit: $logical_limit:expr $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        $vis mod $name {
+            #[allow(unused_imports)]
+            use super::*;
+
+            pub type Key = $key;
+            pub type Value = $value;
+
+            /// Number of entries the maps hold
+            pub const LOGICAL_LIMIT: u32 = $logical_limit;
+
+            /// Bytes of memory needed for a map, for sizing static buffers
+            pub const REQUIRED_SIZE: usize = $crate::required_size(
+                ::core::mem::size_of::<Key>() as u32,
+                ::core::mem::align_of::<Key>() as u8,
+                ::core::mem::size_of::<Value>() as u32,
+                ::core::mem::align_of::<Value>() as u8,
+                LOGICAL_LIMIT,
+            );
+
+            /// Configuration of the maps
+            #[must_use]
+            pub fn config() -> $crate::MapInit {
+                $crate::layout_for::<Key, Value>(LOGICAL_LIMIT).1
+            }
+
+            /// Size and alignment of the memory to allocate for a map
+            #[must_use]
+            pub fn alloc_layout() -> ::core::alloc::Layout {
+                $crate::alloc_layout(&config())
+            }
+
+            /// # Safety
+            ///
+            /// - `base_ptr` must point to writable memory as [`alloc_layout`] describes
+            pub unsafe fn init(base_ptr: *mut u8) {
+                unsafe { $crate::init(base_ptr, &config()) }
+            }
+
+            /// # Safety
+            ///
+            /// - `base_ptr` must point to a map initialized by [`init`]
+            pub unsafe fn insert(base_ptr: *mut u8, key: &Key, value: &Value) -> $crate::InsertResult {
+                unsafe {
+                    $crate::insert(
+                        base_ptr,
+                        ::core::ptr::from_ref(key).cast::<u8>(),
+                        ::core::ptr::from_ref(value).cast::<u8>(),
+                    )
+                }
+            }
+
+            /// # Safety
+            ///
+            /// - `base_ptr` must point to a map initialized by [`init`], that is not
+            ///   changed while the returned reference is alive
+            #[must_use]
+            pub unsafe fn get<'a>(base_ptr: *const u8, key: &Key) -> Option<&'a Value> {
+                unsafe {
+                    let value_ptr =
+                        $crate::lookup_shared(base_ptr, ::core::ptr::from_ref(key).cast::<u8>());
+                    value_ptr.cast::<Value>().as_ref()
+                }
+            }
+
+            /// # Safety
+            ///
+            /// - `base_ptr` must point to a map initialized by [`init`], that is not
+            ///   used in any other way while the returned reference is alive
+            #[must_use]
+            pub unsafe fn get_mut<'a>(base_ptr: *mut u8, key: &Key) -> Option<&'a mut Value> {
+                unsafe {
+                    let value_ptr =
+                        $crate::lookup(base_ptr, ::core::ptr::from_ref(key).cast::<u8>());
+                    value_ptr.cast::<Value>().as_mut()
+                }
+            }
+
+            /// # Safety
+            ///
+            /// - `base_ptr` must point to a map initialized by [`init`]
+            pub unsafe fn remove(base_ptr: *mut u8, key: &Key) -> Option<Value> {
+                unsafe {
+                    let key_ptr = ::core::ptr::from_ref(key).cast::<u8>();
+                    let mut value = ::core::mem::MaybeUninit::<Value>::uninit();
+                    $crate::take(base_ptr, key_ptr, value.as_mut_ptr().cast::<u8>())
+                        .then(|| value.assume_init())
+                }
+            }
+
+            /// # Safety
+            ///
+            /// - `base_ptr` must point to a map initialized by [`init`]
+            #[must_use]
+            pub unsafe fn len(base_ptr: *const u8) -> u32 {
+                unsafe { $crate::len(base_ptr) }
+            }
+
+            /// # Safety
+            ///
+            /// - `base_ptr` must point to a map initialized by [`init`], that is not
+            ///   changed while the iterator is alive
+            #[must_use]
+            pub unsafe fn iter<'a>(base_ptr: *const u8) -> $crate::view::Iter<'a, Key, Value> {
+                unsafe {
+                    let bytes = ::core::slice::from_raw_parts(base_ptr, config().total_size as usize);
+                    $crate::MapView::<'a, Key, Value>::new(bytes)
+                        .expect("hashmap, map was not initialized by this module")
+                        .iter()
+                }
+            }
+        }
+    };
+}
@@ -0,0 +1,341 @@
+/*
+ * Copyright (c) Peter Bjorklund. All rights reserved. https://github.com/piot/hashmap-mem
+ * Licensed under the MIT License. See LICENSE in the project root for license information.
+ */
+
+//! Speculative changes layered over a map without copying it
+//!
+//! An overlay is an ordinary map with the same key size as its base map, whose values
+//! are one byte longer than the base values. The extra byte after the value marks the
+//! key as inserted or removed, so that removals can be recorded as tombstones that hide
+//! the key in the base map. Create its configuration with [`layout`].
+//!
+//! Meant for client-side prediction: speculative changes go into the overlay, which is
+//! [`commit`]ted once the authoritative state agrees with it, or [`discard`]ed to roll
+//! back. A [`Staging`] wraps an overlay and its base map to apply a batch of changes
+//! all at once, or not at all.
+
+use crate::{
+    BucketLayout, GrowError, MapHeader, MapInit, ReserveResult, checked_header, clear,
+    element_count_of, for_each_occupied_bucket, get_or_reserve_entry, get_or_reserve_entry_ex,
+    key_offset_of, logical_limit_of, lookup, lookup_shared, remove, value_ptr_at,
+};
+use core::ptr;
+
+const MARKER_INSERTED: u8 = 1;
+const MARKER_REMOVED: u8 = 2;
+const MARKER_RESERVED: u8 = 3; // Inserted, and reserved in the base map by `commit`
+
+/// Calculate the memory layout of an overlay for a base map with these sizes
+///
+/// Takes the same arguments as [`crate::layout`] for the base map. `logical_limit` is
+/// the number of keys the overlay can record changes for.
+#[must_use]
+pub fn layout(
+    key_size: u32,
+    key_alignment: u8,
+    value_size: u32,
+    value_alignment: u8,
+    logical_limit: u32,
+) -> (BucketLayout, MapInit) {
+    crate::layout(
+        key_size,
+        key_alignment,
+        value_size + 1,
+        value_alignment,
+        logical_limit,
+    )
+}
+
+/// Pointer to the marker byte after the value of an overlay entry
+#[inline]
+unsafe fn marker_ptr(overlay: *const u8, value_ptr: *mut u8) -> *mut u8 {
+    unsafe { value_ptr.add((*overlay.cast::<MapHeader>()).value_size as usize - 1) }
+}
+
+/// Panic unless `overlay` records changes for maps like `base`
+fn check_sizes(overlay: &MapHeader, base: &MapHeader) {
+    assert_eq!(overlay.key_size, base.key_size, "Incompatible key sizes");
+    assert_eq!(
+        overlay.value_size,
+        base.value_size + 1,
+        "Incompatible value sizes"
+    );
+}
+
+/// Record an insert or replace of a key in the overlay
+///
+/// # Safety
+///
+/// - `overlay` must point to a valid initialized map created from [`layout`]
+/// - `key_ptr` must point to a valid key of the size specified in the map header
+/// - `value_ptr` must point to a value of the base map that is not inside the overlay
+///
+/// # Returns
+///
+/// `false` if the overlay is full
+pub unsafe fn insert_overlaid(overlay: *mut u8, key_ptr: *const u8, value_ptr: *const u8) -> bool {
+    unsafe {
+        let target_value_ptr = get_or_reserve_entry(overlay, key_ptr);
+        if target_value_ptr.is_null() {
+            return false;
+        }
+
+        let value_size = (*overlay.cast::<MapHeader>()).value_size as usize - 1;
+        ptr::copy_nonoverlapping(value_ptr, target_value_ptr, value_size);
+        *marker_ptr(overlay, target_value_ptr) = MARKER_INSERTED;
+        true
+    }
+}
+
+/// Record the removal of a key in the overlay, hiding it in the base map
+///
+/// # Safety
+///
+/// - `overlay` must point to a valid initialized map created from [`layout`] for `base`
+/// - `base` must point to a valid initialized map
+/// - `key_ptr` must point to a valid key of the size specified in the map header
+///
+/// # Returns
+///
+/// `true` if the key was visible through the overlay before. A key that was visible
+/// is not hidden if the overlay is full.
+///
+/// # Panics
+///
+/// If the key or value sizes of the maps do not match
+pub unsafe fn remove_overlaid(overlay: *mut u8, base: *const u8, key_ptr: *const u8) -> bool {
+    unsafe {
+        if lookup_overlaid(overlay, base, key_ptr).is_null() {
+            return false;
+        }
+
+        // A key the base map does not have only has to be forgotten by the overlay
+        if lookup_shared(base, key_ptr).is_null() {
+            return remove(overlay, key_ptr);
+        }
+
+        let value_ptr = get_or_reserve_entry(overlay, key_ptr);
+        if value_ptr.is_null() {
+            return false;
+        }
+        *marker_ptr(overlay, value_ptr) = MARKER_REMOVED;
+        true
+    }
+}
+
+/// Lookup a key in the overlay first, and in the base map if the overlay has no record
+/// of it
+///
+/// # Safety
+///
+/// - `overlay` must point to a valid initialized map created from [`layout`] for `base`
+/// - `base` must point to a valid initialized map
+/// - `key_ptr` must point to a valid key of the size specified in the map header
+///
+/// # Returns
+///
+/// Pointer to the value, in either map, or null if the key is missing or removed by
+/// the overlay
+///
+/// # Panics
+///
+/// If the key or value sizes of the maps do not match
+#[must_use]
+pub unsafe fn lookup_overlaid(
+    overlay: *const u8,
+    base: *const u8,
+    key_ptr: *const u8,
+) -> *const u8 {
+    unsafe {
+        check_sizes(checked_header(overlay), checked_header(base));
+
+        let value_ptr = lookup_shared(overlay, key_ptr);
+        if value_ptr.is_null() {
+            return lookup_shared(base, key_ptr);
+        }
+        if *marker_ptr(overlay, value_ptr.cast_mut()) == MARKER_REMOVED {
+            return ptr::null();
+        }
+        value_ptr
+    }
+}
+
+/// Apply the changes recorded in the overlay to the base map and clear the overlay
+///
+/// Commits in two phases: the inserted keys are reserved in the base map first, and
+/// only once all of them fit are the values written and the removals made. If a key
+/// does not fit, the keys reserved so far are removed again, so the base map keeps
+/// the entries it had.
+///
+/// # Safety
+///
+/// - `overlay` must point to a valid initialized map created from [`layout`] for `base`
+/// - `base` must point to a valid initialized map that does not overlap the overlay
+///
+/// # Errors
+///
+/// Returns a [`GrowError`] if the maps are incompatible, or if an inserted key would
+/// take the base map past its logical limit or probe limit. The overlay is left as it
+/// is, so it can be committed again once the base map has room.
+pub unsafe fn commit(overlay: *mut u8, base: *mut u8) -> Result<(), GrowError> {
+    unsafe {
+        let overlay_header = checked_header(overlay);
+        let base_header = checked_header(base);
+        if overlay_header.key_size != base_header.key_size {
+            return Err(GrowError::KeySizeMismatch);
+        }
+        if overlay_header.value_size != base_header.value_size + 1 {
+            return Err(GrowError::ValueSizeMismatch);
+        }
+
+        let key_offset = key_offset_of(overlay_header);
+        let value_size = base_header.value_size as usize;
+        let logical_limit = logical_limit_of(base_header);
+        let mut result = Ok(());
+
+        // Phase one: reserve the inserted keys that are new to the base map
+        for_each_occupied_bucket(overlay, overlay_header, |index, bucket_ptr| {
+            let value_ptr = value_ptr_at(overlay, overlay_header, index, bucket_ptr);
+            let marker_ptr = marker_ptr(overlay, value_ptr);
+            if result.is_err() || *marker_ptr != MARKER_INSERTED {
+                return;
+            }
+
+            let key_ptr = bucket_ptr.add(key_offset);
+            if lookup(base, key_ptr).is_null() {
+                if element_count_of(&*base.cast::<MapHeader>()) >= logical_limit {
+                    result = Err(GrowError::InsufficientCapacity);
+                    return;
+                }
+                match get_or_reserve_entry_ex(base, key_ptr) {
+                    ReserveResult::Reserved(_) => *marker_ptr = MARKER_RESERVED,
+                    ReserveResult::Existing(_) => {}
+                    ReserveResult::Full => result = Err(GrowError::ProbeLimitExceeded),
+                }
+            }
+        });
+
+        // Phase two: fill in the values and remove keys, or undo the reservations
+        for_each_occupied_bucket(overlay, overlay_header, |index, bucket_ptr| {
+            let key_ptr = bucket_ptr.add(key_offset);
+            let value_ptr = value_ptr_at(overlay, overlay_header, index, bucket_ptr);
+            let marker_ptr = marker_ptr(overlay, value_ptr);
+            if result.is_err() {
+                if *marker_ptr == MARKER_RESERVED {
+                    remove(base, key_ptr);
+                    *marker_ptr = MARKER_INSERTED;
+                }
+            } else if *marker_ptr == MARKER_REMOVED {
+                remove(base, key_ptr);
+            } else {
+                ptr::copy_nonoverlapping(value_ptr, lookup(base, key_ptr), value_size);
+            }
+        });
+
+        if result.is_ok() {
+            clear(overlay);
+        }
+        result
+    }
+}
+
+/// Throw away the changes recorded in the overlay
+///
+/// # Safety
+///
+/// - `overlay` must point to a valid initialized map
+pub unsafe fn discard(overlay: *mut u8) {
+    unsafe { clear(overlay) }
+}
+
+/// A batch of changes to a map that is applied all at once, or not at all
+///
+/// Created by [`begin_staging`]. Inserts and removes are staged in a scratch map made
+/// with [`layout`], and the live map is only changed by [`Staging::commit`].
+#[derive(Debug)]
+pub struct Staging {
+    base: *mut u8,
+    scratch: *mut u8,
+}
+
+/// Start a batch of changes to `base`, staged in `scratch`
+///
+/// Anything left in the scratch map is thrown away.
+///
+/// # Safety
+///
+/// - `base` must point to a valid initialized map
+/// - `scratch` must point to a valid initialized map created from [`layout`] for `base`,
+///   that does not overlap it
+/// - Both must stay valid until the returned staging is committed or aborted, and
+///   `base` must only be read in the meantime
+///
+/// # Panics
+///
+/// If the key or value sizes of the maps do not match
+pub unsafe fn begin_staging(base: *mut u8, scratch: *mut u8) -> Staging {
+    unsafe {
+        check_sizes(checked_header(scratch), checked_header(base));
+        clear(scratch);
+    }
+    Staging { base, scratch }
+}
+
+impl Staging {
+    /// Stage an insert or replace of a key
+    ///
+    /// # Safety
+    ///
+    /// - `key_ptr` must point to a valid key of the size specified in the map header
+    /// - `value_ptr` must point to a value of the live map that is not inside either map
+    ///
+    /// # Returns
+    ///
+    /// `false` if the scratch map is full
+    pub unsafe fn insert(&mut self, key_ptr: *const u8, value_ptr: *const u8) -> bool {
+        unsafe { insert_overlaid(self.scratch, key_ptr, value_ptr) }
+    }
+
+    /// Stage the removal of a key
+    ///
+    /// # Safety
+    ///
+    /// - `key_ptr` must point to a valid key of the size specified in the map header
+    ///
+    /// # Returns
+    ///
+    /// `true` if the key is in the map with the changes staged so far
+    pub unsafe fn remove(&mut self, key_ptr: *const u8) -> bool {
+        unsafe { remove_overlaid(self.scratch, self.base, key_ptr) }
+    }
+
+    /// Lookup a key in the map as it would be after the commit
+    ///
+    /// # Safety
+    ///
+    /// - `key_ptr` must point to a valid key of the size specified in the map header
+    #[must_use]
+    pub unsafe fn lookup(&self, key_ptr: *const u8) -> *const u8 {
+        unsafe { lookup_overlaid(self.scratch, self.base, key_ptr) }
+    }
+
+    /// Apply every staged change to the live map
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`GrowError`] if an inserted key does not fit in the live map, which
+    /// is then left with the entries it had. The staged changes are dropped either way.
+    pub fn commit(self) -> Result<(), GrowError> {
+        unsafe {
+            let result = commit(self.scratch, self.base);
+            discard(self.scratch);
+            result
+        }
+    }
+
+    /// Drop every staged change, leaving the live map as it is
+    pub fn abort(self) {
+        unsafe { discard(self.scratch) };
+    }
+}
@@ -0,0 +1,184 @@
+/*
+ * Copyright (c) Peter Bjorklund. All rights reserved. https://github.com/piot/hashmap-mem
+ * Licensed under the MIT License. See LICENSE in the project root for license information.
+ */
+
+//! Map that owns its memory
+
+use crate::byte_map::ByteMap;
+use crate::{MapInit, MapView, MapViewMut, Pod, alloc_layout, init, layout};
+use alloc::alloc::{Layout, alloc, alloc_zeroed, dealloc, handle_alloc_error};
+use core::{ptr, slice};
+
+/// Fixed-capacity map that allocates, initializes and frees its own memory
+///
+/// The raw primitives can still be used through [`OwnedMap::as_mut_ptr`].
+#[derive(Debug)]
+pub struct OwnedMap {
+    map: ByteMap,
+    memory_layout: Layout,
+}
+
+// The map exclusively owns its allocation, just like a `Vec<u8>`
+unsafe impl Send for OwnedMap {}
+unsafe impl Sync for OwnedMap {}
+
+impl OwnedMap {
+    /// Allocate and initialize a map for up to `logical_limit` entries
+    #[must_use]
+    pub fn new(
+        key_size: u32,
+        key_alignment: u8,
+        value_size: u32,
+        value_alignment: u8,
+        logical_limit: u32,
+    ) -> Self {
+        let (_, config) = layout(
+            key_size,
+            key_alignment,
+            value_size,
+            value_alignment,
+            logical_limit,
+        );
+        Self::with_config(&config)
+    }
+
+    /// Allocate and initialize a map from an explicit configuration
+    #[must_use]
+    pub fn with_config(config: &MapInit) -> Self {
+        let memory_layout = alloc_layout(config);
+
+        // Zeroed, so that the whole buffer can be handed out as a byte slice
+        let base = unsafe { alloc_zeroed(memory_layout) };
+        if base.is_null() {
+            handle_alloc_error(memory_layout);
+        }
+        unsafe { init(base, config) };
+
+        Self {
+            map: ByteMap::new(base),
+            memory_layout,
+        }
+    }
+
+    #[must_use]
+    pub const fn as_ptr(&self) -> *const u8 {
+        self.map.as_ptr()
+    }
+
+    /// Pointer to pass to the raw map functions
+    #[must_use]
+    pub const fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.map.as_ptr()
+    }
+
+    /// The whole map memory, header included
+    #[must_use]
+    pub const fn as_bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.map.as_ptr(), self.memory_layout.size()) }
+    }
+
+    pub const fn as_bytes_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.map.as_ptr(), self.memory_layout.size()) }
+    }
+
+    #[must_use]
+    pub const fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    #[must_use]
+    pub const fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    #[must_use]
+    pub const fn capacity(&self) -> usize {
+        self.map.capacity()
+    }
+
+    #[must_use]
+    pub const fn key_size(&self) -> usize {
+        self.map.key_size()
+    }
+
+    #[must_use]
+    pub const fn value_size(&self) -> usize {
+        self.map.value_size()
+    }
+
+    /// # Panics
+    ///
+    /// If `key` is not exactly `key_size` bytes long
+    #[must_use]
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        self.map.contains_key(key)
+    }
+
+    /// # Panics
+    ///
+    /// If `key` is not exactly `key_size` bytes long
+    #[must_use]
+    pub fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        self.map.get(key)
+    }
+
+    /// # Panics
+    ///
+    /// If `key` is not exactly `key_size` bytes long
+    pub fn get_mut(&mut self, key: &[u8]) -> Option<&mut [u8]> {
+        self.map.get_mut(key)
+    }
+
+    /// Insert or replace the value for `key`
+    ///
+    /// Returns `false` if the map is full.
+    ///
+    /// # Panics
+    ///
+    /// If `key` or `value` do not match the key and value sizes of the map
+    pub fn insert(&mut self, key: &[u8], value: &[u8]) -> bool {
+        self.map.insert(key, value)
+    }
+
+    /// Returns `true` if the key was present
+    ///
+    /// # Panics
+    ///
+    /// If `key` is not exactly `key_size` bytes long
+    pub fn remove(&mut self, key: &[u8]) -> bool {
+        self.map.remove(key)
+    }
+
+    /// Typed view, or `None` if `K` and `V` do not match the map
+    #[must_use]
+    pub fn view<K: Pod, V: Pod>(&self) -> Option<MapView<'_, K, V>> {
+        MapView::new(self.as_bytes())
+    }
+
+    /// Mutable typed view, or `None` if `K` and `V` do not match the map
+    pub fn view_mut<K: Pod, V: Pod>(&mut self) -> Option<MapViewMut<'_, K, V>> {
+        MapViewMut::new(self.as_bytes_mut())
+    }
+}
+
+impl Clone for OwnedMap {
+    fn clone(&self) -> Self {
+        let base = unsafe { alloc(self.memory_layout) };
+        if base.is_null() {
+            handle_alloc_error(self.memory_layout);
+        }
+        unsafe { ptr::copy_nonoverlapping(self.map.as_ptr(), base, self.memory_layout.size()) };
+
+        Self {
+            map: ByteMap::new(base),
+            memory_layout: self.memory_layout,
+        }
+    }
+}
+
+impl Drop for OwnedMap {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.map.as_ptr(), self.memory_layout) };
+    }
+}
@@ -0,0 +1,177 @@
+/*
+ * Copyright (c) Peter Bjorklund. All rights reserved. https://github.com/piot/hashmap-mem
+ * Licensed under the MIT License. See LICENSE in the project root for license information.
+ */
+
+//! Memory images that can move between little- and big-endian machines
+//!
+//! Map memory holds the header fields and stored hashes in native byte order. An image
+//! made by [`write_le_image`] holds them as little-endian instead, and [`load_le_image`]
+//! turns them back into native order. Key and value bytes are copied as they are, so
+//! multi-byte keys and values must be encoded in a fixed byte order by the caller.
+
+use crate::{
+    FLAG_SPILL_REGION, MAP_BUCKETS_OFFSET, MapHeader, SPILL_HEADER_LEN, SpillHeader,
+    ValidationError, buckets_offset, capacity_of, checked_header, map_len, spill_bucket_ptr,
+    spill_header, spill_offset, validate, values_end,
+};
+use core::mem::offset_of;
+use core::ptr;
+
+/// Convert the value at `field_ptr` between native and little-endian byte order
+macro_rules! convert_le {
+    ($t:ty, $field_ptr:expr) => {{
+        let field_ptr = $field_ptr.cast::<$t>();
+        field_ptr.write_unaligned(<$t>::to_le(field_ptr.read_unaligned()));
+    }};
+}
+
+/// Convert every multi-byte header field between native and little-endian byte order
+///
+/// Converting twice gives back the original bytes, so this works in both directions.
+unsafe fn convert_header(image_ptr: *mut u8) {
+    unsafe {
+        convert_le!(u16, image_ptr.add(offset_of!(MapHeader, capacity)));
+        convert_le!(u16, image_ptr.add(offset_of!(MapHeader, element_count)));
+        convert_le!(u32, image_ptr.add(offset_of!(MapHeader, key_size)));
+        convert_le!(u32, image_ptr.add(offset_of!(MapHeader, value_size)));
+        convert_le!(u32, image_ptr.add(offset_of!(MapHeader, value_offset)));
+        convert_le!(u32, image_ptr.add(offset_of!(MapHeader, bucket_size)));
+        convert_le!(u16, image_ptr.add(offset_of!(MapHeader, logical_limit)));
+        convert_le!(u64, image_ptr.add(offset_of!(MapHeader, hash_seed)));
+        convert_le!(u16, image_ptr.add(offset_of!(MapHeader, generation)));
+        convert_le!(u32, image_ptr.add(offset_of!(MapHeader, hash_offset)));
+        convert_le!(u16, image_ptr.add(offset_of!(MapHeader, tombstone_count)));
+        convert_le!(u16, image_ptr.add(offset_of!(MapHeader, probe_limit)));
+        convert_le!(u32, image_ptr.add(offset_of!(MapHeader, key_offset_wide)));
+        convert_le!(u16, image_ptr.add(offset_of!(MapHeader, capacity_high)));
+        convert_le!(
+            u16,
+            image_ptr.add(offset_of!(MapHeader, element_count_high))
+        );
+        convert_le!(
+            u16,
+            image_ptr.add(offset_of!(MapHeader, logical_limit_high))
+        );
+        convert_le!(
+            u16,
+            image_ptr.add(offset_of!(MapHeader, tombstone_count_high))
+        );
+        convert_le!(
+            u32,
+            image_ptr.add(offset_of!(MapHeader, mutation_generation))
+        );
+        convert_le!(u32, image_ptr.add(offset_of!(MapHeader, value_stride)));
+        let counters_ptr = image_ptr.add(offset_of!(MapHeader, counters));
+        for counter in 0..7 {
+            convert_le!(u32, counters_ptr.add(counter * size_of::<u32>()));
+        }
+        convert_le!(u32, image_ptr.add(offset_of!(MapHeader, sequence)));
+    }
+}
+
+/// Convert the fields of the spill region header, if it is inside the first
+/// `image_len` bytes
+///
+/// `header` must already be in native byte order.
+unsafe fn convert_spill_header(image_ptr: *mut u8, header: &MapHeader, image_len: usize) {
+    let buckets_end = values_end(
+        buckets_offset(header) as u64 + capacity_of(header) as u64 * u64::from(header.bucket_size),
+        capacity_of(header) as u64,
+        header.value_stride,
+    );
+    let spill_end = buckets_end.next_multiple_of(SPILL_HEADER_LEN as u64) + SPILL_HEADER_LEN as u64;
+    if header.flags & FLAG_SPILL_REGION == 0 || spill_end > image_len as u64 {
+        return;
+    }
+    unsafe {
+        let spill_ptr = image_ptr.add(spill_offset(header));
+        convert_le!(u16, spill_ptr.add(offset_of!(SpillHeader, capacity)));
+        convert_le!(u16, spill_ptr.add(offset_of!(SpillHeader, len)));
+    }
+}
+
+/// Convert the stored hash of every bucket between native and little-endian byte order
+///
+/// The spill region header must be in native byte order.
+unsafe fn convert_stored_hashes(image_ptr: *mut u8, header: &MapHeader) {
+    if header.hash_offset == 0 {
+        return;
+    }
+    unsafe {
+        let bucket_size = header.bucket_size as usize;
+        for index in 0..capacity_of(header) {
+            let bucket_ptr = image_ptr.add(buckets_offset(header) + index * bucket_size);
+            convert_le!(u64, bucket_ptr.add(header.hash_offset as usize));
+        }
+
+        let spill_capacity = spill_header(image_ptr, header).map_or(0, |spill| spill.capacity);
+        for spill_index in 0..usize::from(spill_capacity) {
+            let bucket_ptr = spill_bucket_ptr(image_ptr, header, spill_index);
+            convert_le!(u64, bucket_ptr.add(header.hash_offset as usize));
+        }
+    }
+}
+
+/// Copy the memory of a map to `out` with the header and stored hashes as little-endian
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+///
+/// # Returns
+///
+/// Number of bytes written to `out`
+///
+/// # Panics
+///
+/// If `out` is shorter than the map memory
+pub unsafe fn write_le_image(base_ptr: *const u8, out: &mut [u8]) -> usize {
+    unsafe {
+        let header = checked_header(base_ptr);
+        let image_len = map_len(base_ptr, header);
+        assert!(out.len() >= image_len, "hashmap, image buffer too small");
+
+        let image_ptr = out.as_mut_ptr();
+        ptr::copy_nonoverlapping(base_ptr, image_ptr, image_len);
+        convert_stored_hashes(image_ptr, header);
+        convert_spill_header(image_ptr, header, image_len);
+        convert_header(image_ptr);
+
+        image_len
+    }
+}
+
+/// Load an image made by [`write_le_image`] on any architecture into map memory
+///
+/// The image is copied to `base_ptr`, converted to native byte order and validated
+/// with [`validate`](crate::validate).
+///
+/// # Safety
+///
+/// - `base_ptr` must point to `buffer_len` writable bytes
+///
+/// # Errors
+///
+/// Returns the [`ValidationError`] if the image does not hold a well-formed map. The
+/// memory at `base_ptr` must not be used as a map in that case.
+pub unsafe fn load_le_image(
+    base_ptr: *mut u8,
+    buffer_len: usize,
+    image: &[u8],
+) -> Result<(), ValidationError> {
+    if image.len() < MAP_BUCKETS_OFFSET || image.len() > buffer_len {
+        return Err(ValidationError::BufferTooSmall);
+    }
+
+    unsafe {
+        ptr::copy_nonoverlapping(image.as_ptr(), base_ptr, image.len());
+        convert_header(base_ptr);
+        let header = &*base_ptr.cast::<MapHeader>();
+        convert_spill_header(base_ptr, header, image.len());
+        validate(base_ptr, image.len())?;
+        convert_stored_hashes(base_ptr, header);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,147 @@
+/*
+ * Copyright (c) Peter Bjorklund. All rights reserved. https://github.com/piot/hashmap-mem
+ * Licensed under the MIT License. See LICENSE in the project root for license information.
+ */
+
+//! Reads of a map that one writer changes while others read it, like a map in memory
+//! shared between processes
+//!
+//! The writer wraps every change, including writing the value behind a returned value
+//! pointer, in [`begin_write`] and [`end_write`] (or [`write_consistent`]). That makes
+//! [`MapHeader::sequence`] odd while the change is made. Readers use
+//! [`lookup_consistent`] and [`len_consistent`], which copy what they read and start
+//! over if the sequence was odd or changed in the meantime, so they never return torn
+//! state. Maps that are never shared this way can ignore the sequence.
+
+use crate::{MapHeader, checked_header, element_count_of, lookup_shared};
+use core::hint;
+use core::mem::offset_of;
+use core::ptr;
+use core::sync::atomic::{AtomicU32, Ordering, fence};
+
+/// The sequence in the header of the map at `base_ptr`
+unsafe fn sequence_at<'a>(base_ptr: *const u8) -> &'a AtomicU32 {
+    unsafe {
+        let sequence_ptr = base_ptr.add(offset_of!(MapHeader, sequence)).cast::<u32>();
+        AtomicU32::from_ptr(sequence_ptr.cast_mut())
+    }
+}
+
+/// Mark the map as being changed, readers retry until [`end_write`]
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+/// - Only one writer may change the map, and it must not already be writing
+pub unsafe fn begin_write(base_ptr: *mut u8) {
+    unsafe {
+        checked_header(base_ptr);
+        let sequence = sequence_at(base_ptr);
+        debug_assert!(
+            sequence.load(Ordering::Relaxed) & 1 == 0,
+            "hashmap, write already begun"
+        );
+        sequence.fetch_add(1, Ordering::Relaxed);
+    }
+    fence(Ordering::Release);
+}
+
+/// Mark the change started by [`begin_write`] as done
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map that [`begin_write`] was called on
+pub unsafe fn end_write(base_ptr: *mut u8) {
+    unsafe {
+        let sequence = sequence_at(base_ptr);
+        debug_assert!(
+            sequence.load(Ordering::Relaxed) & 1 == 1,
+            "hashmap, write not begun"
+        );
+        sequence.fetch_add(1, Ordering::Release);
+    }
+}
+
+/// Make changes to the map in `f`, between [`begin_write`] and [`end_write`]
+///
+/// If `f` panics, the write is never ended and readers spin forever.
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+/// - Only one writer may change the map, and it must not already be writing
+pub unsafe fn write_consistent<R>(base_ptr: *mut u8, f: impl FnOnce(*mut u8) -> R) -> R {
+    unsafe {
+        begin_write(base_ptr);
+        let result = f(base_ptr);
+        end_write(base_ptr);
+        result
+    }
+}
+
+/// Read from the map in `read` until it was not changed in the meantime
+///
+/// `read` may see torn state, and must only copy what it finds.
+unsafe fn read_consistent<R>(base_ptr: *const u8, mut read: impl FnMut() -> R) -> R {
+    let sequence = unsafe { sequence_at(base_ptr) };
+    loop {
+        let start = sequence.load(Ordering::Acquire);
+        if start & 1 == 0 {
+            let result = read();
+            fence(Ordering::Acquire);
+            if sequence.load(Ordering::Relaxed) == start {
+                return result;
+            }
+        }
+        hint::spin_loop();
+    }
+}
+
+/// Lookup a key and copy its value, while a writer may be changing the map
+///
+/// Spins while a write is in progress.
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map, whose writer uses [`begin_write`]
+///   and [`end_write`]
+/// - `key_ptr` must point to a valid key of the size specified in the map header
+/// - `out_value` must point to `value_size` writable bytes that are not inside the map
+///
+/// # Returns
+///
+/// `true` if the key was found and its value copied, `false` otherwise (`out_value`
+/// may have been written then)
+pub unsafe fn lookup_consistent(
+    base_ptr: *const u8,
+    key_ptr: *const u8,
+    out_value: *mut u8,
+) -> bool {
+    unsafe {
+        let value_size = checked_header(base_ptr).value_size as usize;
+        read_consistent(base_ptr, || {
+            let value_ptr = lookup_shared(base_ptr, key_ptr);
+            if value_ptr.is_null() {
+                return false;
+            }
+            ptr::copy_nonoverlapping(value_ptr, out_value, value_size);
+            true
+        })
+    }
+}
+
+/// Number of entries, while a writer may be changing the map
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map, whose writer uses [`begin_write`]
+///   and [`end_write`]
+#[must_use]
+pub unsafe fn len_consistent(base_ptr: *const u8) -> usize {
+    unsafe {
+        checked_header(base_ptr);
+        read_consistent(base_ptr, || {
+            element_count_of(&ptr::read_volatile(base_ptr.cast::<MapHeader>()))
+        })
+    }
+}
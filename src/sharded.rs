@@ -0,0 +1,334 @@
+/*
+ * Copyright (c) Peter Bjorklund. All rights reserved. https://github.com/piot/hashmap-mem
+ * Licensed under the MIT License. See LICENSE in the project root for license information.
+ */
+
+//! Independent maps in one buffer, so that several threads can write at once
+//!
+//! A sharded buffer starts with a [`ShardDirectory`], followed by a lock per shard, and
+//! then the shards: ordinary maps with the same configuration and hash seed, one after
+//! another. A key is hashed once, and the high bits of the mixed hash pick its shard, so
+//! they do not line up with the bits the shard picks a bucket from. [`insert`],
+//! [`lookup`] and [`remove`] only lock the shard of the key, and [`with_shard`] runs any
+//! of the raw functions on it. The locks are spin locks in the buffer itself, so they
+//! also work for buffers shared between processes.
+
+use crate::{
+    InsertResult, MapHeader, MapInit, alloc_layout as map_alloc_layout, capacity_of,
+    element_count_of, finalize_hash, init as init_map, insert_hashed, key_hash, lookup_hashed,
+    remove_hashed,
+};
+use core::alloc::Layout;
+use core::hint;
+use core::ptr;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// Marks a buffer initialized by [`init`]
+pub const DIRECTORY_SECRET_CODE: u32 = 0x5348_3d01;
+
+/// Header at the start of a sharded buffer
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct ShardDirectory {
+    pub secret_code: u32,
+    pub shard_count: u32, // Power of two
+    pub shards_offset: u32,
+    pub shard_stride: u32, // Distance between the starts of two shards
+}
+
+/// Sizes and offsets of a sharded buffer, calculated by [`layout`]
+pub struct ShardedInit {
+    /// Configuration of every shard
+    pub config: MapInit,
+    pub shard_count: u32,
+    pub shards_offset: u32,
+    pub shard_stride: u32,
+    pub total_size: usize,
+}
+
+/// Calculate the memory layout of `shard_count` shards made from `config`
+///
+/// Each shard holds up to `config.logical_limit` entries. Keys are spread evenly over
+/// the shards, so leave some room in each of them.
+///
+/// # Panics
+///
+/// If `shard_count` is zero or not a power of two, or the buffer does not fit in a
+/// memory layout
+#[must_use]
+pub fn layout(shard_count: u32, config: MapInit) -> ShardedInit {
+    assert!(
+        shard_count.is_power_of_two(),
+        "hashmap, shard count must be a power of two"
+    );
+    let map_layout = map_alloc_layout(&config);
+    let locks_end = size_of::<ShardDirectory>() + shard_count as usize * size_of::<AtomicU32>();
+    let shards_offset = locks_end.next_multiple_of(map_layout.align());
+    let shard_stride = map_layout.size().next_multiple_of(map_layout.align());
+    let total_size = shards_offset + shard_count as usize * shard_stride;
+
+    ShardedInit {
+        config,
+        shard_count,
+        shards_offset: u32::try_from(shards_offset).expect("hashmap, too many shards"),
+        shard_stride: u32::try_from(shard_stride).expect("hashmap, shards are too large"),
+        total_size,
+    }
+}
+
+/// Size and alignment of the memory to allocate for a sharded buffer
+#[must_use]
+pub fn alloc_layout(sharded: &ShardedInit) -> Layout {
+    let alignment = map_alloc_layout(&sharded.config)
+        .align()
+        .max(align_of::<ShardDirectory>());
+    Layout::from_size_align(sharded.total_size, alignment)
+        .expect("sharded size does not fit in a memory layout")
+}
+
+/// Initialize the directory, the locks and every shard
+///
+/// # Safety
+///
+/// - `base_ptr` must point to `total_size` writable bytes, aligned as
+///   [`alloc_layout`] says
+/// - No other thread may use the buffer until this returns
+pub unsafe fn init(base_ptr: *mut u8, sharded: &ShardedInit) {
+    unsafe {
+        ptr::write(
+            base_ptr.cast::<ShardDirectory>(),
+            ShardDirectory {
+                secret_code: DIRECTORY_SECRET_CODE,
+                shard_count: sharded.shard_count,
+                shards_offset: sharded.shards_offset,
+                shard_stride: sharded.shard_stride,
+            },
+        );
+        for shard in 0..sharded.shard_count as usize {
+            lock_ptr(base_ptr, shard).write(0);
+            init_map(shard_ptr(base_ptr, shard as u32), &sharded.config);
+        }
+    }
+}
+
+unsafe fn checked_directory<'a>(base_ptr: *const u8) -> &'a ShardDirectory {
+    let directory = unsafe { &*base_ptr.cast::<ShardDirectory>() };
+    assert_eq!(
+        directory.secret_code, DIRECTORY_SECRET_CODE,
+        "hashmap, shard directory secret code failed"
+    );
+    directory
+}
+
+const unsafe fn lock_ptr(base_ptr: *const u8, shard: usize) -> *mut u32 {
+    unsafe {
+        base_ptr
+            .add(size_of::<ShardDirectory>())
+            .cast::<u32>()
+            .add(shard)
+            .cast_mut()
+    }
+}
+
+/// Number of shards in a sharded buffer
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a buffer initialized by [`init`]
+#[must_use]
+pub unsafe fn shard_count(base_ptr: *const u8) -> u32 {
+    unsafe { checked_directory(base_ptr).shard_count }
+}
+
+/// Start of the map of a shard, to use with the raw functions while holding its lock
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a buffer initialized by [`init`]
+///
+/// # Panics
+///
+/// If `shard` is not less than the shard count
+#[must_use]
+pub unsafe fn shard_ptr(base_ptr: *mut u8, shard: u32) -> *mut u8 {
+    unsafe {
+        let directory = checked_directory(base_ptr);
+        assert!(shard < directory.shard_count, "hashmap, no such shard");
+        base_ptr.add(
+            directory.shards_offset as usize + shard as usize * directory.shard_stride as usize,
+        )
+    }
+}
+
+/// Shard that a key belongs to, and the hash of the key for the shard maps
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a buffer initialized by [`init`]
+/// - `key_ptr` must point to a valid key of the key size of the shards
+#[must_use]
+pub unsafe fn shard_of(base_ptr: *mut u8, key_ptr: *const u8) -> (u32, u64) {
+    unsafe {
+        let bits = checked_directory(base_ptr).shard_count.trailing_zeros();
+        // Every shard has the same hash seed and strategy
+        let hash = key_hash(shard_ptr(base_ptr, 0), key_ptr);
+        let shard = if bits == 0 {
+            0
+        } else {
+            (finalize_hash(hash) >> (64 - bits)) as u32
+        };
+        (shard, hash)
+    }
+}
+
+/// Run `f` on the map of a shard while holding its lock
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a buffer initialized by [`init`]
+/// - `f` must not lock the same shard again, and must not keep pointers into the shard
+///   after it returns
+///
+/// # Panics
+///
+/// If `shard` is not less than the shard count
+pub unsafe fn with_shard_index<R>(
+    base_ptr: *mut u8,
+    shard: u32,
+    f: impl FnOnce(*mut u8) -> R,
+) -> R {
+    unsafe {
+        let map_ptr = shard_ptr(base_ptr, shard);
+        let lock = AtomicU32::from_ptr(lock_ptr(base_ptr, shard as usize));
+        while lock
+            .compare_exchange_weak(0, 1, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            hint::spin_loop();
+        }
+
+        // Unlock even if `f` panics, so that the other threads do not spin forever
+        struct Unlock<'a>(&'a AtomicU32);
+        impl Drop for Unlock<'_> {
+            fn drop(&mut self) {
+                self.0.store(0, Ordering::Release);
+            }
+        }
+        let _unlock = Unlock(lock);
+        f(map_ptr)
+    }
+}
+
+/// Run `f` on the shard of a key while holding its lock
+///
+/// `f` gets the map of the shard and the hash of the key, for the `_hashed` functions.
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a buffer initialized by [`init`]
+/// - `key_ptr` must point to a valid key of the key size of the shards
+/// - `f` must not lock the same shard again, and must not keep pointers into the shard
+///   after it returns
+pub unsafe fn with_shard<R>(
+    base_ptr: *mut u8,
+    key_ptr: *const u8,
+    f: impl FnOnce(*mut u8, u64) -> R,
+) -> R {
+    unsafe {
+        let (shard, hash) = shard_of(base_ptr, key_ptr);
+        with_shard_index(base_ptr, shard, |map_ptr| f(map_ptr, hash))
+    }
+}
+
+/// Insert or replace an entry, locking only the shard of the key
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a buffer initialized by [`init`]
+/// - `key_ptr` must point to a valid key of the key size of the shards
+/// - `value_ptr` must point to `value_size` readable bytes that are not inside the buffer
+pub unsafe fn insert(base_ptr: *mut u8, key_ptr: *const u8, value_ptr: *const u8) -> InsertResult {
+    unsafe {
+        with_shard(base_ptr, key_ptr, |map_ptr, hash| {
+            insert_hashed(map_ptr, key_ptr, hash, value_ptr)
+        })
+    }
+}
+
+/// Lookup a key and copy its value, locking only the shard of the key
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a buffer initialized by [`init`]
+/// - `key_ptr` must point to a valid key of the key size of the shards
+/// - `out_value` must point to `value_size` writable bytes that are not inside the buffer
+///
+/// # Returns
+///
+/// `true` if the key was found and its value copied, `false` otherwise (`out_value` is
+/// left untouched)
+pub unsafe fn lookup(base_ptr: *mut u8, key_ptr: *const u8, out_value: *mut u8) -> bool {
+    unsafe {
+        with_shard(base_ptr, key_ptr, |map_ptr, hash| {
+            let value_ptr = lookup_hashed(map_ptr, key_ptr, hash);
+            if value_ptr.is_null() {
+                return false;
+            }
+            let value_size = (*map_ptr.cast::<MapHeader>()).value_size as usize;
+            ptr::copy_nonoverlapping(value_ptr, out_value, value_size);
+            true
+        })
+    }
+}
+
+/// Remove an entry, locking only the shard of the key
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a buffer initialized by [`init`]
+/// - `key_ptr` must point to a valid key of the key size of the shards
+///
+/// # Returns
+///
+/// `true` if the key was found and removed, `false` otherwise
+pub unsafe fn remove(base_ptr: *mut u8, key_ptr: *const u8) -> bool {
+    unsafe {
+        with_shard(base_ptr, key_ptr, |map_ptr, hash| {
+            remove_hashed(map_ptr, key_ptr, hash)
+        })
+    }
+}
+
+/// Number of entries in all shards, locking one shard at a time
+///
+/// The shards are counted one after another, so entries that other threads insert or
+/// remove meanwhile may or may not be counted.
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a buffer initialized by [`init`]
+#[must_use]
+pub unsafe fn len(base_ptr: *mut u8) -> usize {
+    unsafe {
+        (0..shard_count(base_ptr))
+            .map(|shard| {
+                with_shard_index(base_ptr, shard, |map_ptr| {
+                    element_count_of(&*map_ptr.cast::<MapHeader>())
+                })
+            })
+            .sum()
+    }
+}
+
+/// Number of buckets in all shards
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a buffer initialized by [`init`]
+#[must_use]
+pub unsafe fn capacity(base_ptr: *mut u8) -> usize {
+    unsafe {
+        let count = shard_count(base_ptr) as usize;
+        count * capacity_of(&*shard_ptr(base_ptr, 0).cast::<MapHeader>())
+    }
+}
@@ -0,0 +1,132 @@
+/*
+ * Copyright (c) Peter Bjorklund. All rights reserved. https://github.com/piot/hashmap-mem
+ * Licensed under the MIT License. See LICENSE in the project root for license information.
+ */
+
+//! Key comparison 16 and 32 bytes at a time with SSE2 or NEON
+//!
+//! Other architectures compare the keys as slices.
+
+/// Compare `len` bytes, `len` being at least 16
+///
+/// The bytes after the last full chunk are compared by one more chunk that overlaps
+/// the previous one, so nothing is read outside of the keys.
+#[cfg(target_arch = "x86_64")]
+#[inline]
+pub unsafe fn keys_equal(a: *const u8, b: *const u8, len: usize) -> bool {
+    use core::arch::x86_64::{
+        __m128i, _mm_and_si128, _mm_cmpeq_epi8, _mm_loadu_si128, _mm_movemask_epi8,
+    };
+
+    debug_assert!(len >= 16);
+    unsafe {
+        let chunk_equal = |offset: usize| {
+            _mm_cmpeq_epi8(
+                _mm_loadu_si128(a.add(offset).cast::<__m128i>()),
+                _mm_loadu_si128(b.add(offset).cast::<__m128i>()),
+            )
+        };
+
+        let mut offset = 0;
+        while offset + 32 <= len {
+            let equal = _mm_and_si128(chunk_equal(offset), chunk_equal(offset + 16));
+            if _mm_movemask_epi8(equal) != 0xffff {
+                return false;
+            }
+            offset += 32;
+        }
+        if offset + 16 <= len {
+            if _mm_movemask_epi8(chunk_equal(offset)) != 0xffff {
+                return false;
+            }
+            offset += 16;
+        }
+
+        offset == len || _mm_movemask_epi8(chunk_equal(len - 16)) == 0xffff
+    }
+}
+
+/// Compare `len` bytes, `len` being at least 16
+///
+/// The bytes after the last full chunk are compared by one more chunk that overlaps
+/// the previous one, so nothing is read outside of the keys.
+#[cfg(target_arch = "aarch64")]
+#[inline]
+pub unsafe fn keys_equal(a: *const u8, b: *const u8, len: usize) -> bool {
+    use core::arch::aarch64::{uint8x16_t, vandq_u8, vceqq_u8, vld1q_u8, vminvq_u8};
+
+    debug_assert!(len >= 16);
+    unsafe {
+        let chunk_equal = |offset: usize| -> uint8x16_t {
+            vceqq_u8(vld1q_u8(a.add(offset)), vld1q_u8(b.add(offset)))
+        };
+
+        let mut offset = 0;
+        while offset + 32 <= len {
+            if vminvq_u8(vandq_u8(chunk_equal(offset), chunk_equal(offset + 16))) != 0xff {
+                return false;
+            }
+            offset += 32;
+        }
+        if offset + 16 <= len {
+            if vminvq_u8(chunk_equal(offset)) != 0xff {
+                return false;
+            }
+            offset += 16;
+        }
+
+        offset == len || vminvq_u8(chunk_equal(len - 16)) == 0xff
+    }
+}
+
+/// Compare `len` bytes as slices
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+#[inline]
+pub unsafe fn keys_equal(a: *const u8, b: *const u8, len: usize) -> bool {
+    unsafe { core::slice::from_raw_parts(a, len) == core::slice::from_raw_parts(b, len) }
+}
+
+/// Bit masks of the 16 control bytes at `controls_ptr` that equal `status`, and that
+/// are empty
+#[cfg(target_arch = "x86_64")]
+#[inline]
+pub unsafe fn match_control_group(controls_ptr: *const u8, status: u8) -> (u16, u16) {
+    use core::arch::x86_64::{
+        __m128i, _mm_cmpeq_epi8, _mm_loadu_si128, _mm_movemask_epi8, _mm_set1_epi8,
+        _mm_setzero_si128,
+    };
+
+    unsafe {
+        let controls = _mm_loadu_si128(controls_ptr.cast::<__m128i>());
+        let matches = _mm_movemask_epi8(_mm_cmpeq_epi8(controls, _mm_set1_epi8(status as i8)));
+        let empties = _mm_movemask_epi8(_mm_cmpeq_epi8(controls, _mm_setzero_si128()));
+        (matches as u16, empties as u16)
+    }
+}
+
+/// Bit masks of the 16 control bytes at `controls_ptr` that equal `status`, and that
+/// are empty
+#[cfg(target_arch = "aarch64")]
+#[inline]
+pub unsafe fn match_control_group(controls_ptr: *const u8, status: u8) -> (u16, u16) {
+    use core::arch::aarch64::{
+        uint8x16_t, vaddv_u8, vandq_u8, vceqq_u8, vdupq_n_u8, vget_high_u8, vget_low_u8, vld1q_u8,
+    };
+
+    // NEON has no movemask, so every lane keeps its own bit and the halves are summed
+    const LANE_BITS: [u8; 16] = [1, 2, 4, 8, 16, 32, 64, 128, 1, 2, 4, 8, 16, 32, 64, 128];
+
+    unsafe {
+        let lane_bits = vld1q_u8(LANE_BITS.as_ptr());
+        let to_mask = |equal: uint8x16_t| {
+            let bits = vandq_u8(equal, lane_bits);
+            u16::from(vaddv_u8(vget_low_u8(bits))) | u16::from(vaddv_u8(vget_high_u8(bits))) << 8
+        };
+
+        let controls = vld1q_u8(controls_ptr);
+        (
+            to_mask(vceqq_u8(controls, vdupq_n_u8(status))),
+            to_mask(vceqq_u8(controls, vdupq_n_u8(0))),
+        )
+    }
+}
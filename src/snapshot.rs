@@ -0,0 +1,128 @@
+/*
+ * Copyright (c) Peter Bjorklund. All rights reserved. https://github.com/piot/hashmap-mem
+ * Licensed under the MIT License. See LICENSE in the project root for license information.
+ */
+
+//! Compact, canonical byte streams of the entries of a map
+//!
+//! A stream holds the key size and value size as little-endian `u32`s and the entry
+//! count as a little-endian `u32`, followed by the key and value bytes of every entry
+//! sorted by key bytes. Maps with the same entries give the same stream, whatever
+//! their capacity, hash seed or bucket order.
+
+use crate::{InsertResult, checked_header, clear, insert, logical_limit_of};
+#[cfg(feature = "alloc")]
+use crate::{element_count_of, for_each_occupied_bucket, key_offset_of, value_ptr_at};
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+use core::fmt;
+#[cfg(feature = "alloc")]
+use core::slice;
+
+const STREAM_HEADER_SIZE: usize = 12;
+
+/// Reasons why [`deserialize_into`] can fail
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum SnapshotError {
+    /// The stream was made for maps with other key or value sizes
+    SizeMismatch,
+    /// The stream is shorter than its entry count says
+    Truncated,
+    /// The entries do not fit in the map
+    Full,
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            Self::SizeMismatch => "stream key or value size does not match the map",
+            Self::Truncated => "stream is truncated",
+            Self::Full => "map is full",
+        };
+        f.write_str(message)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for SnapshotError {}
+
+/// Append the entries of a map to `out` in canonical order
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+#[cfg(feature = "alloc")]
+pub unsafe fn serialize(base_ptr: *const u8, out: &mut Vec<u8>) {
+    unsafe {
+        let header = checked_header(base_ptr);
+        let key_size = header.key_size as usize;
+        let value_size = header.value_size as usize;
+        let key_offset = key_offset_of(header);
+
+        let mut entries = Vec::with_capacity(element_count_of(header));
+        for_each_occupied_bucket(base_ptr, header, |index, bucket_ptr| {
+            entries.push((
+                bucket_ptr.add(key_offset),
+                value_ptr_at(base_ptr, header, index, bucket_ptr),
+            ));
+        });
+        entries.sort_unstable_by_key(|&(key_ptr, _)| slice::from_raw_parts(key_ptr, key_size));
+
+        out.extend_from_slice(&header.key_size.to_le_bytes());
+        out.extend_from_slice(&header.value_size.to_le_bytes());
+        out.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+        for (key_ptr, value_ptr) in entries {
+            out.extend_from_slice(slice::from_raw_parts(key_ptr, key_size));
+            out.extend_from_slice(slice::from_raw_parts(value_ptr, value_size));
+        }
+    }
+}
+
+/// Replace the entries of a map with the entries of a stream made by [`serialize`]
+///
+/// The map keeps its own layout, capacity and hash seed.
+///
+/// # Safety
+///
+/// - `base_ptr` must point to a valid initialized map
+///
+/// # Errors
+///
+/// Returns a [`SnapshotError`] if the stream does not fit the map or is malformed.
+/// The map may hold some of the entries in that case.
+pub unsafe fn deserialize_into(base_ptr: *mut u8, stream: &[u8]) -> Result<(), SnapshotError> {
+    unsafe {
+        let header = checked_header(base_ptr);
+        let key_size = header.key_size as usize;
+        let value_size = header.value_size as usize;
+
+        let Some((stream_header, mut entries)) = stream.split_at_checked(STREAM_HEADER_SIZE) else {
+            return Err(SnapshotError::Truncated);
+        };
+        if stream_header[..4] != header.key_size.to_le_bytes()
+            || stream_header[4..8] != header.value_size.to_le_bytes()
+        {
+            return Err(SnapshotError::SizeMismatch);
+        }
+        let count = u32::from_le_bytes(stream_header[8..].try_into().unwrap());
+        if count as usize > logical_limit_of(header) {
+            return Err(SnapshotError::Full);
+        }
+
+        clear(base_ptr);
+        for _ in 0..count {
+            let Some((key, rest)) = entries.split_at_checked(key_size) else {
+                return Err(SnapshotError::Truncated);
+            };
+            let Some((value, rest)) = rest.split_at_checked(value_size) else {
+                return Err(SnapshotError::Truncated);
+            };
+            if insert(base_ptr, key.as_ptr(), value.as_ptr()) == InsertResult::Full {
+                return Err(SnapshotError::Full);
+            }
+            entries = rest;
+        }
+
+        Ok(())
+    }
+}
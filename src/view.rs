@@ -0,0 +1,418 @@
+/*
+ * Copyright (c) Peter Bjorklund. All rights reserved. https://github.com/piot/hashmap-mem
+ * Licensed under the MIT License. See LICENSE in the project root for license information.
+ */
+
+//! Safe typed access to map memory
+//!
+//! With the `bytemuck` feature, keys and values must be `bytemuck::Pod` types as
+//! well, and the conversions from and to map memory go through `bytemuck`, which
+//! checks their size and alignment. With the `serde` feature, views serialize as a map from key to
+//! value, and [`MapViewMut::deserialize_entries`] rebuilds a map from one.
+
+#[cfg(feature = "serde")]
+use crate::clear;
+use crate::{
+    BucketLayout, MapHeader, MapInit, ReserveResult, buckets_offset, capacity_of, element_count_of,
+    find_next_valid_entry, get_or_reserve_entry_ex, key_offset_of, layout, lookup, lookup_shared,
+    take, validate, value_ptr_at, value_stride_of,
+};
+#[cfg(feature = "serde")]
+use core::fmt;
+use core::iter::FusedIterator;
+use core::marker::PhantomData;
+use core::mem::{self, MaybeUninit, align_of, size_of};
+#[cfg(not(feature = "bytemuck"))]
+use core::ptr;
+#[cfg(feature = "bytemuck")]
+use core::slice;
+#[cfg(feature = "serde")]
+use serde::de::{self, Deserialize, Deserializer, MapAccess, Visitor};
+#[cfg(feature = "serde")]
+use serde::{Serialize, Serializer};
+
+/// Plain old data that can be stored in a map as raw bytes
+///
+/// # Safety
+///
+/// - The type must not contain any padding bytes, since keys are hashed and compared as bytes
+/// - Every bit pattern must be a valid value of the type
+#[cfg(not(feature = "bytemuck"))]
+pub unsafe trait Pod: Copy + 'static {}
+
+/// Plain old data that can be stored in a map as raw bytes
+///
+/// With the `bytemuck` feature, the type must also implement `bytemuck::Pod`, which
+/// the views use to convert it from and to map memory.
+///
+/// # Safety
+///
+/// - The type must not contain any padding bytes, since keys are hashed and compared as bytes
+/// - Every bit pattern must be a valid value of the type
+#[cfg(feature = "bytemuck")]
+pub unsafe trait Pod: bytemuck::Pod {}
+
+macro_rules! impl_pod {
+    ($($t:ty),*) => {
+        $(unsafe impl Pod for $t {})*
+    };
+}
+
+impl_pod!(
+    (),
+    u8,
+    u16,
+    u32,
+    u64,
+    u128,
+    usize,
+    i8,
+    i16,
+    i32,
+    i64,
+    i128,
+    isize,
+    f32,
+    f64
+);
+
+unsafe impl<T: Pod, const N: usize> Pod for [T; N] {}
+
+/// Calculate the memory layout of a map with `K` keys and `V` values
+///
+/// Same as [`crate::layout`] with the sizes and alignments of the types, so the map
+/// is always aligned for them. Types aligned to more than 128 bytes fail to compile.
+#[must_use]
+pub fn layout_for<K: Pod, V: Pod>(logical_limit: u32) -> (BucketLayout, MapInit) {
+    const {
+        assert!(
+            align_of::<K>() <= 128 && align_of::<V>() <= 128,
+            "hashmap, alignment does not fit in a u8"
+        );
+    }
+    layout(
+        size_of::<K>() as u32,
+        align_of::<K>() as u8,
+        size_of::<V>() as u32,
+        align_of::<V>() as u8,
+        logical_limit,
+    )
+}
+
+/// Pointer to the bytes of a key or value, to hand to the raw functions
+#[inline]
+fn bytes_ptr<T: Pod>(item: &T) -> *const u8 {
+    #[cfg(feature = "bytemuck")]
+    return bytemuck::bytes_of(item).as_ptr();
+    #[cfg(not(feature = "bytemuck"))]
+    return ptr::from_ref(item).cast::<u8>();
+}
+
+/// Reference to the `T` in map memory at `item_ptr`, which must be aligned for it
+#[inline]
+unsafe fn item_ref<'a, T: Pod>(item_ptr: *const u8) -> &'a T {
+    #[cfg(feature = "bytemuck")]
+    return bytemuck::from_bytes(unsafe { slice::from_raw_parts(item_ptr, size_of::<T>()) });
+    #[cfg(not(feature = "bytemuck"))]
+    return unsafe { &*item_ptr.cast::<T>() };
+}
+
+/// Mutable reference to the `T` in map memory at `item_ptr`, which must be aligned for it
+#[inline]
+unsafe fn item_mut<'a, T: Pod>(item_ptr: *mut u8) -> &'a mut T {
+    #[cfg(feature = "bytemuck")]
+    return bytemuck::from_bytes_mut(unsafe {
+        slice::from_raw_parts_mut(item_ptr, size_of::<T>())
+    });
+    #[cfg(not(feature = "bytemuck"))]
+    return unsafe { &mut *item_ptr.cast::<T>() };
+}
+
+/// Check that `bytes` holds a well-formed map storing `K` keys and `V` values
+fn is_compatible<K: Pod, V: Pod>(bytes: &[u8]) -> bool {
+    // Every header field the map functions use must be in range, the buffer may come
+    // from anywhere
+    let base = bytes.as_ptr();
+    if unsafe { validate(base, bytes.len()) }.is_err() {
+        return false;
+    }
+
+    let header = unsafe { &*base.cast::<MapHeader>() };
+    if header.key_size as usize != size_of::<K>() || header.value_size as usize != size_of::<V>() {
+        return false;
+    }
+    let bucket_size = header.bucket_size as usize;
+
+    // Every key and value must be properly aligned to hand out references to them. The
+    // value arrays of struct-of-arrays maps are aligned for any value.
+    let buckets_ptr = unsafe { base.add(buckets_offset(header)).cast_mut() };
+    let key_addr = buckets_ptr as usize + key_offset_of(header);
+    let value_addr = unsafe { value_ptr_at(base, header, 0, buckets_ptr) } as usize;
+    key_addr.is_multiple_of(align_of::<K>())
+        && value_addr.is_multiple_of(align_of::<V>())
+        && bucket_size.is_multiple_of(align_of::<K>())
+        && value_stride_of(header).is_multiple_of(align_of::<V>())
+}
+
+/// Read-only typed view of an initialized map
+#[derive(Debug)]
+pub struct MapView<'a, K: Pod, V: Pod> {
+    base: *const u8,
+    _marker: PhantomData<(&'a [u8], K, V)>,
+}
+
+impl<K: Pod, V: Pod> Clone for MapView<'_, K, V> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<K: Pod, V: Pod> Copy for MapView<'_, K, V> {}
+
+impl<'a, K: Pod, V: Pod> MapView<'a, K, V> {
+    /// Create a view over an initialized map
+    ///
+    /// Returns `None` if `bytes` does not hold a map that passes [`crate::validate`],
+    /// with `K` sized keys and `V` sized values, aligned for `K` and `V`.
+    #[must_use]
+    pub fn new(bytes: &'a [u8]) -> Option<Self> {
+        is_compatible::<K, V>(bytes).then_some(Self {
+            base: bytes.as_ptr(),
+            _marker: PhantomData,
+        })
+    }
+
+    fn header(&self) -> &MapHeader {
+        unsafe { &*self.base.cast::<MapHeader>() }
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        element_count_of(self.header())
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    #[must_use]
+    pub fn capacity(&self) -> usize {
+        capacity_of(self.header())
+    }
+
+    #[must_use]
+    pub fn get(&self, key: &K) -> Option<&'a V> {
+        unsafe {
+            let value_ptr = lookup_shared(self.base, bytes_ptr(key));
+            (!value_ptr.is_null()).then(|| item_ref(value_ptr))
+        }
+    }
+
+    #[must_use]
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.get(key).is_some()
+    }
+
+    #[must_use]
+    pub fn iter(&self) -> Iter<'a, K, V> {
+        Iter {
+            base: self.base,
+            next_index: 0,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<'a, K: Pod, V: Pod> IntoIterator for MapView<'a, K, V> {
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// Mutable typed view of an initialized map
+#[derive(Debug)]
+pub struct MapViewMut<'a, K: Pod, V: Pod> {
+    base: *mut u8,
+    _marker: PhantomData<(&'a mut [u8], K, V)>,
+}
+
+impl<'a, K: Pod, V: Pod> MapViewMut<'a, K, V> {
+    /// Create a mutable view over an initialized map
+    ///
+    /// Returns `None` under the same conditions as [`MapView::new`].
+    #[must_use]
+    pub fn new(bytes: &'a mut [u8]) -> Option<Self> {
+        is_compatible::<K, V>(bytes).then_some(Self {
+            base: bytes.as_mut_ptr(),
+            _marker: PhantomData,
+        })
+    }
+
+    #[must_use]
+    pub fn as_view(&self) -> MapView<'_, K, V> {
+        MapView {
+            base: self.base,
+            _marker: PhantomData,
+        }
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.as_view().len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.as_view().is_empty()
+    }
+
+    #[must_use]
+    pub fn capacity(&self) -> usize {
+        self.as_view().capacity()
+    }
+
+    #[must_use]
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.as_view().get(key)
+    }
+
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        unsafe {
+            let value_ptr = lookup(self.base, bytes_ptr(key));
+            (!value_ptr.is_null()).then(|| item_mut(value_ptr))
+        }
+    }
+
+    #[must_use]
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.as_view().contains_key(key)
+    }
+
+    /// Insert or replace the value for `key`
+    ///
+    /// # Errors
+    ///
+    /// Gives `value` back if the map is full
+    pub fn insert(&mut self, key: K, value: V) -> Result<Option<V>, V> {
+        unsafe {
+            match get_or_reserve_entry_ex(self.base, bytes_ptr(&key)) {
+                ReserveResult::Reserved(value_ptr) => {
+                    value_ptr.cast::<V>().write(value);
+                    Ok(None)
+                }
+                ReserveResult::Existing(value_ptr) => {
+                    Ok(Some(mem::replace(item_mut(value_ptr), value)))
+                }
+                ReserveResult::Full => Err(value),
+            }
+        }
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let mut value = MaybeUninit::<V>::uninit();
+        unsafe {
+            take(self.base, bytes_ptr(key), value.as_mut_ptr().cast::<u8>())
+                .then(|| value.assume_init())
+        }
+    }
+
+    #[must_use]
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        self.as_view().iter()
+    }
+}
+
+/// Iterator over the entries of a [`MapView`]
+///
+/// Walks the buckets with [`find_next_valid_entry`] and only reads through the pointers
+/// it gets, since the map is borrowed shared.
+#[derive(Debug)]
+pub struct Iter<'a, K: Pod, V: Pod> {
+    base: *const u8,
+    next_index: u32,
+    _marker: PhantomData<(&'a [u8], K, V)>,
+}
+
+impl<'a, K: Pod, V: Pod> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        unsafe {
+            let (key_ptr, value_ptr, index) =
+                find_next_valid_entry(self.base.cast_mut(), self.next_index);
+            if key_ptr.is_null() {
+                self.next_index = u32::MAX;
+                return None;
+            }
+            self.next_index = index + 1;
+            Some((item_ref(key_ptr), item_ref(value_ptr)))
+        }
+    }
+}
+
+impl<K: Pod, V: Pod> FusedIterator for Iter<'_, K, V> {}
+
+/// Serializes the entries as a map from key to value
+#[cfg(feature = "serde")]
+impl<K: Pod + Serialize, V: Pod + Serialize> Serialize for MapView<'_, K, V> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(self.iter())
+    }
+}
+
+/// Serializes the entries as a map from key to value
+#[cfg(feature = "serde")]
+impl<K: Pod + Serialize, V: Pod + Serialize> Serialize for MapViewMut<'_, K, V> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.as_view().serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<K: Pod, V: Pod> MapViewMut<'_, K, V> {
+    /// Replace the entries with the ones of a map serialized from a [`MapView`]
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of the deserializer, or a custom error if the entries do not
+    /// fit. The map keeps the entries inserted up to that point.
+    pub fn deserialize_entries<'de, D: Deserializer<'de>>(
+        &mut self,
+        deserializer: D,
+    ) -> Result<(), D::Error>
+    where
+        K: Deserialize<'de>,
+        V: Deserialize<'de>,
+    {
+        unsafe { clear(self.base) };
+        deserializer.deserialize_map(EntriesVisitor(self))
+    }
+}
+
+/// Inserts the entries of a serialized map into a [`MapViewMut`]
+#[cfg(feature = "serde")]
+struct EntriesVisitor<'v, 'a, K: Pod, V: Pod>(&'v mut MapViewMut<'a, K, V>);
+
+#[cfg(feature = "serde")]
+impl<'de, K: Pod + Deserialize<'de>, V: Pod + Deserialize<'de>> Visitor<'de>
+    for EntriesVisitor<'_, '_, K, V>
+{
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter.write_str("a map of keys to values")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut access: A) -> Result<(), A::Error> {
+        while let Some((key, value)) = access.next_entry::<K, V>()? {
+            if self.0.insert(key, value).is_err() {
+                return Err(de::Error::custom("map is full"));
+            }
+        }
+        Ok(())
+    }
+}
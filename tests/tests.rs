@@ -3,9 +3,48 @@
  * Licensed under the MIT License. See LICENSE in the project root for license information.
  */
 
-use std::alloc::{Layout, alloc};
+use std::alloc::{Layout, alloc, alloc_zeroed};
+use std::mem::size_of;
+use std::ops::ControlFlow;
+use std::ptr;
 
-use hashmap_mem::{MapHeader, get_or_reserve_entry, init, layout, lookup, overwrite, remove};
+use hashmap_mem::{
+    BucketLayout, CACHE_LINE_SIZE, Cursor, DumpOptions, Entry, FLAG_CACHE_LINE_BUCKETS,
+    FLAG_CONSTANT_TIME_KEYS, FLAG_HASH_FRAGMENTS, FORMAT_VERSION, GrowError, Handle, HashStrategy,
+    InitError, InsertError, InsertResult, Journal, LayoutError, LayoutKind, MapError, MapHeader,
+    MapHealth, MapInit, MapInitBuilder, MapView, MapViewMut, MergePolicy, POISON_BYTE,
+    ProbeStrategy, ReserveResult, SnapshotError, ValidationError, alloc_layout, apply_journal,
+    attach, avg_lookup_probes, begin_staging, bucket_layout, build_static, calculate_bucket_layout,
+    capacity, clear, clear_dirty_flags, clear_range, contains, content_hash, debug_dump, drain,
+    entries, entry, equals, find_by_value, find_next_valid_entry, for_each, for_each_common,
+    for_each_dirty, for_each_missing_in, freeze, generation, get_by_slot, get_or_insert_with,
+    get_or_insert_zeroed, get_or_reserve_entry, get_or_reserve_entry_ex,
+    get_or_reserve_entry_hashed, grow_into, has, health, home_bucket, init, init_arena,
+    init_in_slice, insert, insert_batch, insert_dirty, insert_hashed, insert_journaled, insert_key,
+    insert_or_update, insert_slot, is_empty, is_frozen, is_full, key_hash, key_jaccard,
+    keys_at_home, layout, layout_checked, layout_for, layout_kind, len, len_consistent,
+    live_payload_bytes, load_le_image, logical_limit, lookup, lookup_consistent, lookup_hashed,
+    lookup_many, lookup_many_mut, map_ptr, mark_dirty, merge, migrate_v1_to_v3, migrate_v2_to_v3,
+    needs_rehash, next_page, overlay, overwrite, plan_arena, probe_limit, rehash_in_place, rehome,
+    remaining, remove, remove_hashed, remove_journaled, replace_with, required_alignment,
+    required_size, reserve_handle, resolve, retain, sample, sharded, shrink_into, stats,
+    swap_value, take, thaw, try_for_each, try_get_or_reserve_entry, try_init, try_insert,
+    try_overwrite, update, validate, write_consistent, write_le_image,
+};
+#[cfg(feature = "instrument")]
+use hashmap_mem::{MapCounters, read_counters, reset_counters};
+#[cfg(feature = "alloc")]
+use hashmap_mem::{PatchError, apply_diff, deserialize_into, diff, dump_json, serialize};
+
+/// Home bucket of a `u32` key, found by inserting it alone into a scratch map
+unsafe fn home_of(scratch_base: *mut u8, map_init: &MapInit, key: u32) -> u32 {
+    unsafe {
+        init(scratch_base, map_init);
+        reserve_handle(scratch_base, (&raw const key).cast::<u8>())
+            .unwrap()
+            .index
+    }
+}
 
 #[test]
 fn test_basic_insert_lookup() {
@@ -139,3 +178,4988 @@ fn test_overwrite() {
         }
     }
 }
+
+#[test]
+fn test_build_static() {
+    let (_, map_init) = layout(4, 4, 4, 4, 16);
+
+    let layout = Layout::from_size_align(map_init.total_size as usize, 8).unwrap();
+    let map_base = unsafe { alloc(layout) };
+    assert!(!map_base.is_null());
+
+    let keys: [u32; 6] = [3, 17, 42, 1000, 0xBEEF, 0x1234_5678];
+    let values: [u32; 6] = [30, 170, 420, 10000, 0xBEEF0, 0x1234];
+    let keys_values: Vec<(*const u8, *const u8)> = keys
+        .iter()
+        .zip(values.iter())
+        .map(|(key, value)| {
+            (
+                (key as *const u32).cast::<u8>(),
+                (value as *const u32).cast::<u8>(),
+            )
+        })
+        .collect();
+
+    unsafe {
+        assert!(build_static(map_base, &map_init, &keys_values, 1000));
+
+        let header = &*(map_base as *const MapHeader);
+        assert_eq!(header.element_count, 6);
+
+        // Every key is in its home bucket
+        assert_eq!(avg_lookup_probes(map_base), 1.0);
+
+        for (key, value) in keys.iter().zip(values.iter()) {
+            let found_ptr = lookup(map_base, (key as *const u32).cast::<u8>());
+            assert!(!found_ptr.is_null());
+            assert_eq!(*(found_ptr as *const u32), *value);
+        }
+    }
+}
+
+#[test]
+fn test_layout_kind() {
+    let (_, map_init) = layout(4, 4, 4, 4, 8);
+
+    let layout = Layout::from_size_align(map_init.total_size as usize, 8).unwrap();
+    let map_base = unsafe { alloc(layout) };
+    assert!(!map_base.is_null());
+
+    unsafe {
+        init(map_base, &map_init);
+        assert_eq!(layout_kind(map_base), LayoutKind::Interleaved);
+
+        let key: u32 = 7;
+        let value: u32 = 70;
+        let keys_values = [(
+            (&raw const key).cast::<u8>(),
+            (&raw const value).cast::<u8>(),
+        )];
+        assert!(build_static(map_base, &map_init, &keys_values, 16));
+        assert_eq!(layout_kind(map_base), LayoutKind::Interleaved);
+    }
+}
+
+#[test]
+fn test_overwrite_into_zero_logical_limit() {
+    let (_, source_init) = layout(4, 4, 4, 4, 8);
+    let (_, mut target_init) = layout(4, 4, 4, 4, 8);
+    // Hand-built config: physical buckets but no logical room
+    target_init.logical_limit = 0;
+
+    let source_layout = Layout::from_size_align(source_init.total_size as usize, 8).unwrap();
+    let source_base = unsafe { alloc(source_layout) };
+    let target_layout = Layout::from_size_align(target_init.total_size as usize, 8).unwrap();
+    let target_base = unsafe { alloc(target_layout) };
+    assert!(!source_base.is_null() && !target_base.is_null());
+
+    unsafe {
+        init(source_base, &source_init);
+        init(target_base, &target_init);
+
+        // An empty source is trivially copied
+        assert!(overwrite(target_base, source_base));
+
+        let key: u32 = 5;
+        let value_ptr = get_or_reserve_entry(source_base, (&raw const key).cast::<u8>());
+        *value_ptr.cast::<u32>() = 50;
+
+        assert!(!overwrite(target_base, source_base));
+
+        let target_header = &*(target_base as *const MapHeader);
+        assert_eq!(target_header.element_count, 0);
+        assert!(lookup(target_base, (&raw const key).cast::<u8>()).is_null());
+    }
+}
+
+#[test]
+fn test_key_jaccard() {
+    let (_, map_init) = layout(4, 4, 4, 4, 16);
+
+    let map_layout = Layout::from_size_align(map_init.total_size as usize, 8).unwrap();
+    let a_base = unsafe { alloc(map_layout) };
+    let b_base = unsafe { alloc(map_layout) };
+    assert!(!a_base.is_null() && !b_base.is_null());
+
+    unsafe {
+        init(a_base, &map_init);
+        init(b_base, &map_init);
+
+        // Two empty maps have identical (empty) key sets
+        assert_eq!(key_jaccard(a_base, b_base), 1.0);
+
+        // a = {0..4}, b = {2..6}: two shared keys out of six distinct keys
+        for key in 0u32..4 {
+            get_or_reserve_entry(a_base, (&raw const key).cast::<u8>());
+        }
+        for key in 2u32..6 {
+            get_or_reserve_entry(b_base, (&raw const key).cast::<u8>());
+        }
+
+        assert_eq!(key_jaccard(a_base, b_base), 2.0 / 6.0);
+        assert_eq!(key_jaccard(b_base, a_base), 2.0 / 6.0);
+    }
+}
+
+#[test]
+fn test_store_hash_grow() {
+    for store_hash in [false, true] {
+        let (_, source_init) = layout(8, 8, 4, 4, 8);
+        let (_, target_init) = layout(8, 8, 4, 4, 32);
+        let source_init = source_init.with_store_hash(store_hash);
+        let target_init = target_init.with_store_hash(store_hash);
+
+        let source_layout = Layout::from_size_align(source_init.total_size as usize, 8).unwrap();
+        let source_base = unsafe { alloc(source_layout) };
+        let target_layout = Layout::from_size_align(target_init.total_size as usize, 8).unwrap();
+        let target_base = unsafe { alloc(target_layout) };
+        assert!(!source_base.is_null() && !target_base.is_null());
+
+        unsafe {
+            init(source_base, &source_init);
+            init(target_base, &target_init);
+
+            let source_header = ptr::read(source_base as *const MapHeader);
+            assert_eq!(source_header.hash_offset != 0, store_hash);
+
+            for i in 0..8u64 {
+                let key = i * 1000;
+                let value_ptr = get_or_reserve_entry(source_base, (&raw const key).cast::<u8>());
+                *value_ptr.cast::<u32>() = i as u32;
+            }
+
+            assert!(overwrite(target_base, source_base));
+
+            let target_header = ptr::read(target_base as *const MapHeader);
+            assert_eq!(target_header.element_count, 8);
+
+            for i in 0..8u64 {
+                let key = i * 1000;
+                let found_ptr = lookup(target_base, (&raw const key).cast::<u8>());
+                assert!(!found_ptr.is_null());
+                assert_eq!(*(found_ptr as *const u32), i as u32);
+            }
+
+            // Stored hashes are copied over, so no key is hashed again
+            #[cfg(feature = "instrument")]
+            assert_eq!(
+                read_counters(target_base).key_hashes,
+                if store_hash { 0 } else { 8 }
+            );
+        }
+    }
+}
+
+#[test]
+fn test_stored_hash_compared_first() {
+    for layout_kind in [LayoutKind::Interleaved, LayoutKind::ControlBytes] {
+        // Big enough to be probed rather than scanned
+        let (_, map_init) = layout(8, 8, 4, 4, 32);
+        let map_init = map_init.with_store_hash(true).with_layout_kind(layout_kind);
+
+        let map_base = unsafe { alloc(alloc_layout(&map_init)) };
+        assert!(!map_base.is_null());
+
+        unsafe {
+            init(map_base, &map_init);
+            let header = ptr::read(map_base as *const MapHeader);
+
+            for i in 0..8u64 {
+                let key = i * 1000;
+                let value_ptr = get_or_reserve_entry(map_base, (&raw const key).cast::<u8>());
+                *value_ptr.cast::<u32>() = i as u32;
+            }
+
+            // A bucket whose stored hash differs is passed over, even though its key
+            // bytes are equal
+            let key: u64 = 3000;
+            let key_ptr = (&raw const key).cast::<u8>();
+            let hash_ptr = lookup(map_base, key_ptr)
+                .sub(header.value_offset as usize)
+                .add(header.hash_offset as usize)
+                .cast::<u64>();
+            *hash_ptr ^= 1;
+            assert!(lookup(map_base, key_ptr).is_null());
+
+            *hash_ptr ^= 1;
+            assert_eq!(*lookup(map_base, key_ptr).cast::<u32>(), 3);
+
+            // Rehashing in place reuses the stored hashes
+            rehash_in_place(map_base);
+            for i in 0..8u64 {
+                let key = i * 1000;
+                let value_ptr = lookup(map_base, (&raw const key).cast::<u8>());
+                assert_eq!(*value_ptr.cast::<u32>(), i as u32);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_try_insert() {
+    let (_, map_init) = layout(4, 4, 4, 4, 8);
+    let map_base = unsafe { alloc(alloc_layout(&map_init)) };
+    assert!(!map_base.is_null());
+
+    unsafe {
+        init(map_base, &map_init);
+        for key in 0..8u32 {
+            let value = key * 10;
+            let value_ptr = try_insert(
+                map_base,
+                (&raw const key).cast::<u8>(),
+                (&raw const value).cast::<u8>(),
+            )
+            .unwrap();
+            assert_eq!(*value_ptr.cast::<u32>(), value);
+        }
+
+        // Existing keys are still found once every bucket is taken
+        let key: u32 = 3;
+        let value_ptr = try_get_or_reserve_entry(map_base, (&raw const key).cast::<u8>());
+        assert_eq!(*value_ptr.unwrap().cast::<u32>(), 30);
+
+        let key: u32 = 8;
+        let value: u32 = 80;
+        assert_eq!(
+            try_insert(
+                map_base,
+                (&raw const key).cast::<u8>(),
+                (&raw const value).cast::<u8>(),
+            ),
+            Err(InsertError::Full)
+        );
+        assert_eq!(
+            try_get_or_reserve_entry(map_base, (&raw const key).cast::<u8>()),
+            Err(InsertError::Full)
+        );
+    }
+
+    // With a probe limit of one, a key whose home bucket is taken has no room, even
+    // though other buckets are free
+    let (_, map_init) = layout(4, 4, 4, 4, 16);
+    let map_init = map_init.with_probe_limit(1);
+    let map_base = unsafe { alloc(alloc_layout(&map_init)) };
+    assert!(!map_base.is_null());
+
+    unsafe {
+        init(map_base, &map_init);
+        let mut rejected = 0;
+        for key in 0..16u32 {
+            match try_get_or_reserve_entry(map_base, (&raw const key).cast::<u8>()) {
+                Ok(_) => {}
+                Err(error) => {
+                    assert_eq!(error, InsertError::ProbeLimitExceeded);
+                    rejected += 1;
+                }
+            }
+        }
+        assert_ne!(rejected, 0);
+        assert_eq!(len(map_base) + rejected, 16);
+    }
+}
+
+#[test]
+fn test_try_overwrite() {
+    let (_, source_init) = layout(4, 4, 4, 4, 16);
+    let (_, small_init) = layout(4, 4, 4, 4, 4);
+    let (_, other_init) = layout(4, 4, 8, 8, 16);
+
+    let source_base = unsafe { alloc(alloc_layout(&source_init)) };
+    let target_base = unsafe { alloc(alloc_layout(&source_init)) };
+    let small_base = unsafe { alloc(alloc_layout(&small_init)) };
+    let other_base = unsafe { alloc(alloc_layout(&other_init)) };
+    assert!(!source_base.is_null() && !target_base.is_null());
+    assert!(!small_base.is_null() && !other_base.is_null());
+
+    unsafe {
+        init(source_base, &source_init);
+        init(target_base, &source_init);
+        init(small_base, &small_init);
+        init(other_base, &other_init);
+        for key in 0..10u32 {
+            *get_or_reserve_entry(source_base, (&raw const key).cast::<u8>()).cast::<u32>() = key;
+        }
+
+        assert_eq!(try_overwrite(target_base, source_base), Ok(()));
+        assert_eq!(len(target_base), 10);
+        assert_eq!(
+            try_overwrite(other_base, source_base),
+            Err(MapError::LayoutMismatch)
+        );
+        assert_eq!(
+            try_overwrite(small_base, source_base),
+            Err(MapError::CapacityExceeded)
+        );
+
+        let garbage = [0u64; 16];
+        assert_eq!(
+            try_overwrite(target_base, garbage.as_ptr().cast::<u8>()),
+            Err(MapError::BadMagic)
+        );
+    }
+
+    // The other error types convert into a `MapError`
+    assert_eq!(
+        MapError::from(ValidationError::UnsupportedFormatVersion),
+        MapError::UnsupportedVersion
+    );
+    assert_eq!(
+        MapError::from(InitError::BufferTooSmall),
+        MapError::BufferTooSmall
+    );
+    assert_eq!(
+        MapError::from(InsertError::ProbeLimitExceeded),
+        MapError::ProbeLimitExceeded
+    );
+    assert_eq!(
+        MapError::from(GrowError::ValueSizeMismatch),
+        MapError::LayoutMismatch
+    );
+    assert_eq!(
+        MapError::from(SnapshotError::Truncated),
+        MapError::Truncated
+    );
+}
+
+#[test]
+fn test_debug_dump() {
+    // Quadratic maps leave a tombstone on removal
+    let (_, map_init) = layout(4, 4, 2, 2, 4);
+    let map_init = map_init.with_probe_strategy(ProbeStrategy::Quadratic);
+    let map_base = unsafe { alloc(alloc_layout(&map_init)) };
+    assert!(!map_base.is_null());
+
+    unsafe {
+        init(map_base, &map_init);
+        for key in [0x11u32, 0x22, 0x33] {
+            let value = key as u16 * 2;
+            insert(
+                map_base,
+                (&raw const key).cast::<u8>(),
+                (&raw const value).cast::<u8>(),
+            );
+        }
+        let key: u32 = 0x22;
+        remove(map_base, (&raw const key).cast::<u8>());
+
+        let mut text = String::new();
+        debug_dump(map_base, &mut text, DumpOptions::default()).unwrap();
+        assert!(text.starts_with("map: capacity 4, 2 entries, 1 tombstones"));
+        assert!(text.contains("layout Interleaved, probe strategy Quadratic, hash strategy Fx"));
+        assert_eq!(text.matches(": empty").count(), 1);
+        assert_eq!(text.matches(": tombstone").count(), 1);
+        assert_eq!(text.matches(": occupied").count(), 2);
+        assert!(!text.contains("11000000"));
+
+        let mut text = String::new();
+        let options = DumpOptions {
+            occupied_only: true,
+            hex_keys: true,
+            hex_values: true,
+        };
+        debug_dump(map_base, &mut text, options).unwrap();
+        assert_eq!(text.lines().count(), 3 + 2);
+        assert!(!text.contains("empty") && !text.contains(": tombstone"));
+        assert!(text.contains("key 11000000, value 2200"));
+        assert!(text.contains("key 33000000, value 6600"));
+    }
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn test_dump_json() {
+    let (_, map_init) = layout(4, 4, 2, 2, 32);
+    let map_base = unsafe { alloc(alloc_layout(&map_init)) };
+    assert!(!map_base.is_null());
+
+    unsafe {
+        init(map_base, &map_init);
+        for key in [30u32, 10, 20] {
+            let value = key as u16 + 1;
+            insert(
+                map_base,
+                (&raw const key).cast::<u8>(),
+                (&raw const value).cast::<u8>(),
+            );
+        }
+
+        let key_fmt = |bytes: &[u8]| u32::from_ne_bytes(bytes.try_into().unwrap()).to_string();
+        let value_fmt =
+            |bytes: &[u8]| format!("\"{}\"\n", u16::from_ne_bytes(bytes.try_into().unwrap()));
+        let mut json = String::new();
+        dump_json(map_base, key_fmt, value_fmt, &mut json);
+
+        // Sorted by key bytes, with the formatted strings escaped
+        let entry = |key: u32| format!(r#"{{"key":"{key}","value":"\"{}\"\n"}}"#, key + 1);
+        let keys = [10u32, 20, 30];
+        let mut sorted = keys;
+        sorted.sort_by_key(|key| key.to_ne_bytes());
+        let expected: Vec<String> = sorted.into_iter().map(entry).collect();
+        assert_eq!(json, format!("[{}]", expected.join(",")));
+
+        let mut empty = String::new();
+        clear(map_base);
+        dump_json(map_base, key_fmt, value_fmt, &mut empty);
+        assert_eq!(empty, "[]");
+    }
+}
+
+#[cfg(feature = "instrument")]
+#[test]
+fn test_instrument_counters() {
+    let (_, map_init) = layout(4, 4, 4, 4, 32);
+    let map_init = map_init.with_probe_limit(2);
+    let map_base = unsafe { alloc_zeroed(alloc_layout(&map_init)) };
+    assert!(!map_base.is_null());
+
+    unsafe {
+        init(map_base, &map_init);
+        assert_eq!(read_counters(map_base), MapCounters::default());
+
+        let mut inserted = 0;
+        let mut failed = 0;
+        for key in 0u32..24 {
+            let value = key * 10;
+            let key_ptr = (&raw const key).cast::<u8>();
+            match try_insert(map_base, key_ptr, (&raw const value).cast::<u8>()) {
+                Ok(_) => inserted += 1,
+                Err(_) => failed += 1,
+            }
+        }
+        assert_ne!(failed, 0);
+
+        for key in 0u32..4 {
+            let _ = lookup(map_base, (&raw const key).cast::<u8>());
+        }
+
+        // Reads through shared pointers must not write to the map, so they do not count
+        let counters = read_counters(map_base);
+        let key: u32 = 0;
+        assert!(has(map_base, (&raw const key).cast::<u8>()));
+        let bytes = std::slice::from_raw_parts(map_base, map_init.total_size as usize);
+        let view = MapView::<u32, u32>::new(bytes).unwrap();
+        assert_eq!(view.get(&0), Some(&0));
+        assert_eq!(read_counters(map_base), counters);
+
+        let key: u32 = 100;
+        assert!(!remove(map_base, (&raw const key).cast::<u8>()));
+        let mut removed = 0;
+        for key in 0u32..24 {
+            removed += u32::from(remove(map_base, (&raw const key).cast::<u8>()));
+        }
+        assert_eq!(removed, inserted);
+
+        let counters = read_counters(map_base);
+        assert_eq!(counters.inserts, inserted);
+        assert_eq!(counters.failed_inserts, failed);
+        assert_eq!(counters.removes, removed);
+        assert_eq!(counters.lookups, 4);
+        // Every insert, lookup and remove visits at least one bucket, and failed
+        // inserts visit the whole probe sequence
+        assert!(counters.probe_steps >= 24 + 4 + 1 + 24 + failed);
+        assert_ne!(counters.collisions, 0);
+
+        reset_counters(map_base);
+        assert_eq!(read_counters(map_base), MapCounters::default());
+    }
+}
+
+/// Subscriber that keeps the level and message of every event
+#[cfg(feature = "tracing")]
+struct EventLog(std::sync::Mutex<Vec<(tracing::Level, String)>>);
+
+#[cfg(feature = "tracing")]
+impl tracing::Subscriber for EventLog {
+    fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, _: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+        tracing::span::Id::from_u64(1)
+    }
+
+    fn record(&self, _: &tracing::span::Id, _: &tracing::span::Record<'_>) {}
+
+    fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+
+    fn event(&self, event: &tracing::Event<'_>) {
+        struct Message(String);
+        impl tracing::field::Visit for Message {
+            fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+                if field.name() == "message" {
+                    self.0 = format!("{value:?}");
+                }
+            }
+        }
+
+        let mut message = Message(String::new());
+        event.record(&mut message);
+        self.0
+            .lock()
+            .unwrap()
+            .push((*event.metadata().level(), message.0));
+    }
+
+    fn enter(&self, _: &tracing::span::Id) {}
+
+    fn exit(&self, _: &tracing::span::Id) {}
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+fn test_tracing_events() {
+    // Quadratic maps leave a tombstone to reuse
+    let (_, map_init) = layout(4, 4, 4, 4, 32);
+    let map_init = map_init
+        .with_probe_limit(2)
+        .with_probe_strategy(ProbeStrategy::Quadratic);
+    let map_base = unsafe { alloc_zeroed(alloc_layout(&map_init)) };
+    assert!(!map_base.is_null());
+
+    let log = std::sync::Arc::new(EventLog(std::sync::Mutex::new(Vec::new())));
+    tracing::subscriber::with_default(log.clone(), || unsafe {
+        init(map_base, &map_init);
+        for key in 0u32..24 {
+            let _ = get_or_reserve_entry(map_base, (&raw const key).cast::<u8>());
+        }
+        let key: u32 = 0;
+        assert!(remove(map_base, (&raw const key).cast::<u8>()));
+        assert!(!get_or_reserve_entry(map_base, (&raw const key).cast::<u8>()).is_null());
+    });
+
+    let events = log.0.lock().unwrap();
+    let messages = |level, message: &str| {
+        events
+            .iter()
+            .filter(|event| event.0 == level && event.1 == message)
+            .count()
+    };
+    let failed = messages(tracing::Level::WARN, "hashmap, no room for key");
+    assert_ne!(failed, 0);
+    assert_eq!(
+        messages(tracing::Level::TRACE, "hashmap, key inserted"),
+        24 - failed + 1
+    );
+    assert_eq!(messages(tracing::Level::TRACE, "hashmap, entry removed"), 1);
+    assert_eq!(
+        messages(tracing::Level::TRACE, "hashmap, tombstone reused"),
+        1
+    );
+}
+
+#[test]
+fn test_swap_value() {
+    let (_, map_init) = layout(4, 4, 8, 8, 8);
+
+    let layout = Layout::from_size_align(map_init.total_size as usize, 8).unwrap();
+    let map_base = unsafe { alloc(layout) };
+    assert!(!map_base.is_null());
+
+    unsafe {
+        init(map_base, &map_init);
+
+        let key: u32 = 42;
+        let key_ptr = (&raw const key).cast::<u8>();
+        let new_value: u64 = 0x2222;
+        let mut old_value: u64 = 0;
+
+        // Absent keys are not inserted
+        assert!(!swap_value(
+            map_base,
+            key_ptr,
+            (&raw const new_value).cast::<u8>(),
+            (&raw mut old_value).cast::<u8>()
+        ));
+        assert!(lookup(map_base, key_ptr).is_null());
+
+        *get_or_reserve_entry(map_base, key_ptr).cast::<u64>() = 0x1111;
+
+        assert!(swap_value(
+            map_base,
+            key_ptr,
+            (&raw const new_value).cast::<u8>(),
+            (&raw mut old_value).cast::<u8>()
+        ));
+        assert_eq!(old_value, 0x1111);
+        assert_eq!(*lookup(map_base, key_ptr).cast::<u64>(), 0x2222);
+    }
+}
+
+#[test]
+fn test_update() {
+    let (_, map_init) = layout(4, 4, 8, 8, 64);
+
+    let map_base = unsafe { alloc(alloc_layout(&map_init)) };
+    assert!(!map_base.is_null());
+
+    unsafe {
+        init(map_base, &map_init);
+
+        let key: u32 = 42;
+        let key_ptr = (&raw const key).cast::<u8>();
+
+        // Absent keys are not inserted, and the closure is not called
+        let mut called = false;
+        assert!(!update(map_base, key_ptr, |_| called = true));
+        assert!(!called);
+        assert!(lookup(map_base, key_ptr).is_null());
+
+        *get_or_reserve_entry(map_base, key_ptr).cast::<u64>() = 10;
+        assert!(update(map_base, key_ptr, |value_ptr| {
+            *value_ptr.cast::<u64>() += 5;
+        }));
+        assert_eq!(*lookup(map_base, key_ptr).cast::<u64>(), 15);
+        assert_eq!(len(map_base), 1);
+    }
+}
+
+#[test]
+fn test_insert_or_update() {
+    let (_, map_init) = layout(4, 4, 8, 8, 64);
+
+    let map_base = unsafe { alloc(alloc_layout(&map_init)) };
+    assert!(!map_base.is_null());
+
+    unsafe {
+        init(map_base, &map_init);
+
+        let key: u32 = 7;
+        let key_ptr = (&raw const key).cast::<u8>();
+        let mut replaced = Vec::new();
+
+        for value in [100u64, 200, 300] {
+            let result = insert_or_update(
+                map_base,
+                key_ptr,
+                (&raw const value).cast::<u8>(),
+                |old_ptr| replaced.push(*old_ptr.cast::<u64>()),
+            );
+            let expected = if value == 100 {
+                InsertResult::Inserted
+            } else {
+                InsertResult::Replaced
+            };
+            assert_eq!(result, expected);
+        }
+
+        // The first insert had nothing to replace
+        assert_eq!(replaced, [100, 200]);
+        assert_eq!(*lookup(map_base, key_ptr).cast::<u64>(), 300);
+    }
+}
+
+#[test]
+fn test_lookup_many_mut() {
+    let (_, map_init) = layout(4, 4, 4, 4, 64);
+
+    let map_base = unsafe { alloc(alloc_layout(&map_init)) };
+    assert!(!map_base.is_null());
+
+    unsafe {
+        init(map_base, &map_init);
+        for key in 0..10u32 {
+            *get_or_reserve_entry(map_base, (&raw const key).cast::<u8>()).cast::<u32>() = key;
+        }
+
+        let keys = [2u32, 5, 9, 5, 40];
+        let key_ptr = |index: usize| (&raw const keys[index]).cast::<u8>();
+
+        // Swap the values of two entries
+        let [a, b] = lookup_many_mut(map_base, [key_ptr(0), key_ptr(1)]).unwrap();
+        ptr::swap(a.cast::<u32>(), b.cast::<u32>());
+        assert_eq!(*lookup(map_base, key_ptr(0)).cast::<u32>(), 5);
+        assert_eq!(*lookup(map_base, key_ptr(1)).cast::<u32>(), 2);
+
+        let values = lookup_many_mut(map_base, [key_ptr(0), key_ptr(1), key_ptr(2)]).unwrap();
+        assert_eq!(values.map(|value_ptr| *value_ptr.cast::<u32>()), [5, 2, 9]);
+
+        // A repeated key, or one that is missing, gives nothing
+        assert!(lookup_many_mut(map_base, [key_ptr(1), key_ptr(2), key_ptr(3)]).is_none());
+        assert!(lookup_many_mut(map_base, [key_ptr(0), key_ptr(4)]).is_none());
+    }
+}
+
+#[test]
+fn test_reinsert_into_tombstone_saturated_map() {
+    for logical_limit in [2u32, 4] {
+        let (_, map_init) = layout(4, 4, 4, 4, logical_limit);
+
+        let layout = Layout::from_size_align(map_init.total_size as usize, 8).unwrap();
+        let map_base = unsafe { alloc(layout) };
+        assert!(!map_base.is_null());
+
+        unsafe {
+            init(map_base, &map_init);
+            let capacity = map_init.capacity;
+
+            // Fill every bucket and then turn all of them into tombstones
+            for key in 0..capacity {
+                assert!(!get_or_reserve_entry(map_base, (&raw const key).cast::<u8>()).is_null());
+            }
+            for key in 0..capacity {
+                assert!(remove(map_base, (&raw const key).cast::<u8>()));
+            }
+
+            // New keys reuse the tombstones, each one in a bucket of its own
+            let mut value_ptrs = Vec::new();
+            for key in 100..100 + capacity {
+                let value_ptr = get_or_reserve_entry(map_base, (&raw const key).cast::<u8>());
+                assert!(!value_ptr.is_null());
+                assert!(!value_ptrs.contains(&value_ptr));
+                *value_ptr.cast::<u32>() = key * 2;
+                value_ptrs.push(value_ptr);
+            }
+
+            let header = &*(map_base as *const MapHeader);
+            assert_eq!(u32::from(header.element_count), capacity);
+
+            for key in 100..100 + capacity {
+                let found_ptr = lookup(map_base, (&raw const key).cast::<u8>());
+                assert!(!found_ptr.is_null());
+                assert_eq!(*(found_ptr as *const u32), key * 2);
+            }
+
+            // Every bucket is occupied again
+            let key: u32 = 999;
+            assert!(get_or_reserve_entry(map_base, (&raw const key).cast::<u8>()).is_null());
+        }
+    }
+}
+
+#[test]
+fn test_small_map_lookup() {
+    let (_, map_init) = layout(4, 4, 4, 4, 4);
+    assert_eq!(map_init.capacity, 4);
+
+    let layout = Layout::from_size_align(map_init.total_size as usize, 8).unwrap();
+    let map_base = unsafe { alloc(layout) };
+    assert!(!map_base.is_null());
+
+    unsafe {
+        init(map_base, &map_init);
+
+        let mut reserved = Vec::new();
+        for key in [11u32, 22, 33, 44] {
+            let value_ptr = get_or_reserve_entry(map_base, (&raw const key).cast::<u8>());
+            assert!(!value_ptr.is_null());
+            reserved.push((key, value_ptr));
+        }
+        // The scanning lookup finds the same buckets the hash probing reserved
+        for &(key, value_ptr) in &reserved {
+            assert_eq!(lookup(map_base, (&raw const key).cast::<u8>()), value_ptr);
+            *value_ptr.cast::<u32>() = key;
+        }
+
+        let key: u32 = 22;
+        assert!(remove(map_base, (&raw const key).cast::<u8>()));
+        for (key, _) in reserved {
+            let key_ptr = (&raw const key).cast::<u8>();
+            if key == 22 {
+                assert!(lookup(map_base, key_ptr).is_null());
+                assert!(!has(map_base, key_ptr));
+            } else {
+                assert_eq!(*lookup(map_base, key_ptr).cast::<u32>(), key);
+                assert!(has(map_base, key_ptr));
+            }
+        }
+
+        let missing: u32 = 55;
+        assert!(!has(map_base, (&raw const missing).cast::<u8>()));
+    }
+}
+
+#[test]
+fn test_handles() {
+    let (_, map_init) = layout(4, 4, 4, 4, 16);
+
+    let layout = Layout::from_size_align(map_init.total_size as usize, 8).unwrap();
+    let map_base = unsafe { alloc(layout) };
+    assert!(!map_base.is_null());
+
+    unsafe {
+        init(map_base, &map_init);
+
+        let key: u32 = 12;
+        let key_ptr = (&raw const key).cast::<u8>();
+        let handle = reserve_handle(map_base, key_ptr).unwrap();
+        let value_ptr = resolve(map_base, handle).unwrap();
+        *value_ptr.cast::<u32>() = 120;
+        assert_eq!(value_ptr, lookup(map_base, key_ptr));
+
+        // Reserving an existing key hands out the same handle
+        assert_eq!(reserve_handle(map_base, key_ptr), Some(handle));
+
+        // Inserting other keys keeps the handle valid
+        let other: u32 = 13;
+        get_or_reserve_entry(map_base, (&raw const other).cast::<u8>());
+        assert_eq!(*resolve(map_base, handle).unwrap().cast::<u32>(), 120);
+
+        assert!(remove(map_base, key_ptr));
+        assert!(resolve(map_base, handle).is_none());
+
+        // Even if the key comes back in the same bucket
+        get_or_reserve_entry(map_base, key_ptr);
+        assert!(resolve(map_base, handle).is_none());
+    }
+}
+
+#[test]
+fn test_clear_range() {
+    let (_, map_init) = layout(4, 4, 4, 4, 64);
+
+    let layout = Layout::from_size_align(map_init.total_size as usize, 8).unwrap();
+    let map_base = unsafe { alloc(layout) };
+    assert!(!map_base.is_null());
+
+    unsafe {
+        init(map_base, &map_init);
+
+        let mut entries = Vec::new();
+        for key in 0..12u32 {
+            let handle = reserve_handle(map_base, (&raw const key).cast::<u8>()).unwrap();
+            *resolve(map_base, handle).unwrap().cast::<u32>() = key + 100;
+            entries.push((key, handle.index));
+        }
+
+        let upper_count = entries.iter().filter(|(_, index)| *index >= 32).count();
+        assert!(upper_count > 0 && upper_count < 12);
+
+        // The end is clamped to the capacity
+        let cleared = clear_range(map_base, 32, 1000);
+        assert_eq!(cleared as usize, upper_count);
+
+        let header = &*(map_base as *const MapHeader);
+        assert_eq!(usize::from(header.element_count), 12 - upper_count);
+
+        for (key, index) in entries {
+            let found_ptr = lookup(map_base, (&raw const key).cast::<u8>());
+            if index >= 32 {
+                assert!(found_ptr.is_null());
+            } else {
+                assert_eq!(*(found_ptr as *const u32), key + 100);
+            }
+        }
+
+        // Clearing an already empty range changes nothing
+        assert_eq!(clear_range(map_base, 32, 64), 0);
+    }
+}
+
+#[test]
+fn test_keys_at_home() {
+    let (_, map_init) = layout(4, 4, 4, 4, 16);
+
+    let map_layout = Layout::from_size_align(map_init.total_size as usize, 8).unwrap();
+    let map_base = unsafe { alloc(map_layout) };
+    let scratch_base = unsafe { alloc(map_layout) };
+    assert!(!map_base.is_null() && !scratch_base.is_null());
+
+    unsafe {
+        let first: u32 = 1;
+        let home = home_of(scratch_base, &map_init, first);
+        let second = (2..)
+            .find(|&key| home_of(scratch_base, &map_init, key) == home)
+            .unwrap();
+
+        init(map_base, &map_init);
+        let first_index = reserve_handle(map_base, (&raw const first).cast::<u8>())
+            .unwrap()
+            .index;
+        let second_index = reserve_handle(map_base, (&raw const second).cast::<u8>())
+            .unwrap()
+            .index;
+        assert_eq!(first_index, home);
+        assert_eq!(second_index, (home + 1) % map_init.capacity);
+
+        let mut found = Vec::new();
+        keys_at_home(map_base, home, |key_ptr, index| {
+            found.push((*key_ptr.cast::<u32>(), index));
+        });
+        assert_eq!(found.len(), 2);
+        assert!(found.contains(&(first, first_index)));
+        assert!(found.contains(&(second, second_index)));
+    }
+}
+
+#[test]
+fn test_live_payload_bytes() {
+    let (_, map_init) = layout(2, 2, 12, 4, 16);
+
+    let layout = Layout::from_size_align(map_init.total_size as usize, 8).unwrap();
+    let map_base = unsafe { alloc(layout) };
+    assert!(!map_base.is_null());
+
+    unsafe {
+        init(map_base, &map_init);
+        assert_eq!(live_payload_bytes(map_base), 0);
+
+        for key in 0..6u16 {
+            get_or_reserve_entry(map_base, (&raw const key).cast::<u8>());
+        }
+        for key in 0..2u16 {
+            remove(map_base, (&raw const key).cast::<u8>());
+        }
+
+        assert_eq!(live_payload_bytes(map_base), 4 * (2 + 12));
+    }
+}
+
+#[test]
+fn test_rehome() {
+    let (_, map_init) = layout(4, 4, 4, 4, 16);
+    // Quadratic maps leave a tombstone to move into
+    let map_init = map_init.with_probe_strategy(ProbeStrategy::Quadratic);
+
+    let map_layout = Layout::from_size_align(map_init.total_size as usize, 8).unwrap();
+    let map_base = unsafe { alloc(map_layout) };
+    let scratch_base = unsafe { alloc(map_layout) };
+    assert!(!map_base.is_null() && !scratch_base.is_null());
+
+    unsafe {
+        // Three keys sharing a home bucket end up in consecutive buckets
+        let home = home_of(scratch_base, &map_init, 0);
+        let mut keys = (0..).filter(|&key| home_of(scratch_base, &map_init, key) == home);
+        let (first, second, third) = (
+            keys.next().unwrap(),
+            keys.next().unwrap(),
+            keys.next().unwrap(),
+        );
+
+        init(map_base, &map_init);
+        for key in [first, second, third] {
+            *get_or_reserve_entry(map_base, (&raw const key).cast::<u8>()).cast::<u32>() = key;
+        }
+        let third_ptr = (&raw const third).cast::<u8>();
+        let home_value_ptr = lookup(map_base, (&raw const first).cast::<u8>());
+
+        // Nothing to move to yet
+        assert!(!rehome(map_base, third_ptr));
+
+        remove(map_base, (&raw const first).cast::<u8>());
+        assert!(rehome(map_base, third_ptr));
+
+        // Moved into the home bucket that the first key left behind
+        let found_ptr = lookup(map_base, third_ptr);
+        assert_eq!(found_ptr, home_value_ptr);
+        assert_eq!(*found_ptr.cast::<u32>(), third);
+        assert_eq!(
+            *lookup(map_base, (&raw const second).cast::<u8>()).cast::<u32>(),
+            second
+        );
+
+        // Already in its home bucket
+        assert!(!rehome(map_base, third_ptr));
+        let header = &*(map_base as *const MapHeader);
+        assert_eq!(header.element_count, 2);
+    }
+}
+
+#[test]
+fn test_health() {
+    let (_, map_init) = layout(4, 4, 4, 4, 64);
+    // Quadratic maps leave tombstones on removal
+    let map_init = map_init.with_probe_strategy(ProbeStrategy::Quadratic);
+
+    let layout = Layout::from_size_align(map_init.total_size as usize, 8).unwrap();
+    let map_base = unsafe { alloc(layout) };
+    assert!(!map_base.is_null());
+
+    unsafe {
+        init(map_base, &map_init);
+        assert_eq!(health(map_base), MapHealth::Good);
+
+        for key in 0..8u32 {
+            get_or_reserve_entry(map_base, (&raw const key).cast::<u8>());
+        }
+        assert_eq!(health(map_base), MapHealth::Good);
+
+        // Heavy churn leaves lots of tombstones behind
+        for key in 8..24u32 {
+            get_or_reserve_entry(map_base, (&raw const key).cast::<u8>());
+        }
+        for key in 4..24u32 {
+            remove(map_base, (&raw const key).cast::<u8>());
+        }
+        assert_eq!(health(map_base), MapHealth::Degraded);
+
+        // Nearly every bucket in use
+        init(map_base, &map_init);
+        for key in 0..60u32 {
+            get_or_reserve_entry(map_base, (&raw const key).cast::<u8>());
+        }
+        assert_eq!(health(map_base), MapHealth::Critical);
+    }
+}
+
+#[test]
+fn test_needs_rehash() {
+    let (_, map_init) = layout(4, 4, 4, 4, 64);
+    // Quadratic maps leave tombstones on removal
+    let map_init = map_init.with_probe_strategy(ProbeStrategy::Quadratic);
+
+    let map_base = unsafe { alloc(alloc_layout(&map_init)) };
+    assert!(!map_base.is_null());
+
+    unsafe {
+        init(map_base, &map_init);
+        for key in 0..32u32 {
+            get_or_reserve_entry(map_base, (&raw const key).cast::<u8>());
+        }
+        assert!(!needs_rehash(map_base, 0.0));
+
+        // 16 tombstones out of 64 buckets
+        for key in 0..16u32 {
+            assert!(remove(map_base, (&raw const key).cast::<u8>()));
+        }
+        assert_eq!(stats(map_base).tombstone_count, 16);
+        assert!(needs_rehash(map_base, 0.2));
+        assert!(!needs_rehash(map_base, 0.25));
+
+        // Inserting into a tombstone takes it out of the count
+        let key: u32 = 3;
+        get_or_reserve_entry(map_base, (&raw const key).cast::<u8>());
+        assert_eq!(stats(map_base).tombstone_count, 15);
+
+        rehash_in_place(map_base);
+        assert!(!needs_rehash(map_base, 0.0));
+    }
+}
+
+#[test]
+fn test_map_view() {
+    let (_, map_init) = layout(4, 4, 8, 8, 16);
+
+    // u64 backing storage keeps the buffer aligned for the header
+    let mut storage = vec![0u64; (map_init.total_size as usize).div_ceil(8)];
+    let bytes = unsafe {
+        std::slice::from_raw_parts_mut(
+            storage.as_mut_ptr().cast::<u8>(),
+            map_init.total_size as usize,
+        )
+    };
+    unsafe { init(bytes.as_mut_ptr(), &map_init) };
+
+    // Sizes must match the map
+    assert!(MapViewMut::<u64, u64>::new(bytes).is_none());
+    assert!(MapView::<u32, u64>::new(&bytes[..8]).is_none());
+
+    let mut map = MapViewMut::<u32, u64>::new(bytes).unwrap();
+    assert!(map.is_empty());
+    assert_eq!(map.capacity(), 16);
+
+    assert_eq!(map.insert(1, 100), Ok(None));
+    assert_eq!(map.insert(2, 200), Ok(None));
+    assert_eq!(map.insert(1, 111), Ok(Some(100)));
+    assert_eq!(map.len(), 2);
+
+    *map.get_mut(&2).unwrap() += 2;
+    assert_eq!(map.get(&2), Some(&202));
+    assert!(map.contains_key(&1));
+    assert!(!map.contains_key(&3));
+
+    let mut entries: Vec<(u32, u64)> = map.iter().map(|(k, v)| (*k, *v)).collect();
+    entries.sort_unstable();
+    assert_eq!(entries, [(1, 111), (2, 202)]);
+
+    assert_eq!(map.remove(&1), Some(111));
+    assert_eq!(map.remove(&1), None);
+    assert_eq!(map.len(), 1);
+
+    // Fill it up until inserts are rejected
+    let mut key = 10;
+    while map.insert(key, u64::from(key)).is_ok() {
+        key += 1;
+    }
+    assert_eq!(map.insert(key, 7), Err(7));
+
+    let view = MapView::<u32, u64>::new(bytes).unwrap();
+    assert_eq!(view.get(&2), Some(&202));
+    assert_eq!(view.iter().count(), view.len());
+
+    // Headers that the map functions can not use safely are rejected
+    for tamper in [
+        |header: &mut MapHeader| {
+            header.key_offset = 0;
+            header.key_offset_wide = 0x4000_0000;
+        },
+        |header: &mut MapHeader| header.hash_strategy = 0xff,
+    ] {
+        let mut tampered = storage.clone();
+        let tampered_bytes = unsafe {
+            std::slice::from_raw_parts_mut(
+                tampered.as_mut_ptr().cast::<u8>(),
+                map_init.total_size as usize,
+            )
+        };
+        tamper(unsafe { &mut *tampered_bytes.as_mut_ptr().cast::<MapHeader>() });
+        assert!(MapView::<u32, u64>::new(tampered_bytes).is_none());
+    }
+}
+
+#[repr(C, align(16))]
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct Position {
+    x: f32,
+    y: f32,
+    z: f32,
+    w: f32,
+}
+
+unsafe impl hashmap_mem::Pod for Position {}
+#[cfg(feature = "bytemuck")]
+unsafe impl bytemuck::Zeroable for Position {}
+#[cfg(feature = "bytemuck")]
+unsafe impl bytemuck::Pod for Position {}
+
+#[test]
+fn test_layout_for() {
+    let (_, map_init) = layout_for::<u64, Position>(16);
+    assert_eq!(map_init.key_size, 8);
+    assert_eq!(map_init.key_alignment, 8);
+    assert_eq!(map_init.value_size, 16);
+    assert_eq!(map_init.value_alignment, 16);
+
+    let map_layout = alloc_layout(&map_init);
+    let map_base = unsafe { alloc(map_layout) };
+    assert!(!map_base.is_null());
+    unsafe { init(map_base, &map_init) };
+
+    let bytes = unsafe { std::slice::from_raw_parts_mut(map_base, map_layout.size()) };
+    let mut map = MapViewMut::<u64, Position>::new(bytes).unwrap();
+    let position = Position {
+        x: 1.0,
+        y: 2.0,
+        z: 3.0,
+        w: 4.0,
+    };
+    assert_eq!(map.insert(7, position), Ok(None));
+    map.get_mut(&7).unwrap().y = 20.0;
+    assert_eq!(map.get(&7).map(|position| position.y), Some(20.0));
+    assert_eq!(
+        map.iter().next(),
+        Some((
+            &7,
+            &Position {
+                y: 20.0,
+                ..position
+            }
+        ))
+    );
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_serde_view() {
+    let (_, map_init) = layout_for::<u32, u64>(16);
+    let (_, small_init) = layout_for::<u32, u64>(2);
+    let source_base = unsafe { alloc(alloc_layout(&map_init)) };
+    let target_base = unsafe { alloc(alloc_layout(&map_init)) };
+    let small_base = unsafe { alloc(alloc_layout(&small_init)) };
+    assert!(!source_base.is_null() && !target_base.is_null() && !small_base.is_null());
+
+    unsafe {
+        init(source_base, &map_init);
+        init(target_base, &map_init);
+        init(small_base, &small_init);
+        let size = map_init.total_size as usize;
+
+        let mut source =
+            MapViewMut::<u32, u64>::new(std::slice::from_raw_parts_mut(source_base, size)).unwrap();
+        for key in 0..5u32 {
+            source.insert(key, u64::from(key) * 100).unwrap();
+        }
+        let json = serde_json::to_string(&source.as_view()).unwrap();
+        assert_eq!(serde_json::to_string(&source).unwrap(), json);
+        let parsed: std::collections::HashMap<u32, u64> = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.len(), 5);
+        assert_eq!(parsed[&3], 300);
+
+        // Entries already in the target are replaced
+        let mut target =
+            MapViewMut::<u32, u64>::new(std::slice::from_raw_parts_mut(target_base, size)).unwrap();
+        target.insert(99, 1).unwrap();
+        target
+            .deserialize_entries(&mut serde_json::Deserializer::from_str(&json))
+            .unwrap();
+        assert_eq!(target.len(), 5);
+        assert_eq!(target.get(&99), None);
+        for key in 0..5u32 {
+            assert_eq!(target.get(&key), Some(&(u64::from(key) * 100)));
+        }
+
+        let small_size = small_init.total_size as usize;
+        let mut small =
+            MapViewMut::<u32, u64>::new(std::slice::from_raw_parts_mut(small_base, small_size))
+                .unwrap();
+        let error = small
+            .deserialize_entries(&mut serde_json::Deserializer::from_str(&json))
+            .unwrap_err();
+        assert!(error.to_string().contains("map is full"));
+    }
+}
+
+#[test]
+fn test_get_or_reserve_entry_ex() {
+    let (_, map_init) = layout(4, 4, 4, 4, 2);
+
+    let layout = Layout::from_size_align(map_init.total_size as usize, 8).unwrap();
+    let map_base = unsafe { alloc(layout) };
+    assert!(!map_base.is_null());
+
+    unsafe {
+        init(map_base, &map_init);
+
+        let key: u32 = 1;
+        let key_ptr = (&raw const key).cast::<u8>();
+        let ReserveResult::Reserved(value_ptr) = get_or_reserve_entry_ex(map_base, key_ptr) else {
+            panic!("expected a new entry");
+        };
+        *value_ptr.cast::<u32>() = 10;
+
+        assert_eq!(
+            get_or_reserve_entry_ex(map_base, key_ptr),
+            ReserveResult::Existing(value_ptr)
+        );
+
+        let other: u32 = 2;
+        assert!(matches!(
+            get_or_reserve_entry_ex(map_base, (&raw const other).cast::<u8>()),
+            ReserveResult::Reserved(_)
+        ));
+
+        let overflow: u32 = 3;
+        let result = get_or_reserve_entry_ex(map_base, (&raw const overflow).cast::<u8>());
+        assert_eq!(result, ReserveResult::Full);
+        assert!(result.value_ptr().is_null());
+    }
+}
+
+#[test]
+fn test_rehash_in_place() {
+    let (_, map_init) = layout(4, 4, 4, 4, 32);
+    // Quadratic maps leave tombstones on removal
+    let map_init = map_init.with_probe_strategy(ProbeStrategy::Quadratic);
+
+    let layout = Layout::from_size_align(map_init.total_size as usize, 8).unwrap();
+    let map_base = unsafe { alloc(layout) };
+    assert!(!map_base.is_null());
+
+    unsafe {
+        init(map_base, &map_init);
+
+        for key in 0..28u32 {
+            *get_or_reserve_entry(map_base, (&raw const key).cast::<u8>()).cast::<u32>() = key * 3;
+        }
+        for key in (0..28u32).filter(|key| key % 3 != 0) {
+            remove(map_base, (&raw const key).cast::<u8>());
+        }
+
+        let header = ptr::read(map_base as *const MapHeader);
+        assert_eq!(header.element_count, 10);
+        assert_eq!(header.tombstone_count, 18);
+        let probes_before = avg_lookup_probes(map_base);
+
+        rehash_in_place(map_base);
+
+        let header = ptr::read(map_base as *const MapHeader);
+        assert_eq!(header.element_count, 10);
+        assert_eq!(header.tombstone_count, 0);
+        let statuses = (0..usize::from(header.capacity)).map(|index| {
+            *map_base.add(size_of::<MapHeader>() + index * header.bucket_size as usize)
+        });
+        assert_eq!(statuses.filter(|&status| status == 1).count(), 0);
+        assert!(avg_lookup_probes(map_base) <= probes_before);
+
+        for key in 0..28u32 {
+            let found_ptr = lookup(map_base, (&raw const key).cast::<u8>());
+            if key % 3 == 0 {
+                assert_eq!(*(found_ptr as *const u32), key * 3);
+            } else {
+                assert!(found_ptr.is_null());
+            }
+        }
+
+        // Reusing a tombstone keeps the count in sync
+        let key: u32 = 3;
+        remove(map_base, (&raw const key).cast::<u8>());
+        assert_eq!((*(map_base as *const MapHeader)).tombstone_count, 1);
+        get_or_reserve_entry(map_base, (&raw const key).cast::<u8>());
+        assert_eq!((*(map_base as *const MapHeader)).tombstone_count, 0);
+    }
+}
+
+#[test]
+fn test_grow_into() {
+    // Tightly packed source, padded target that also stores hashes
+    let (_, source_init) = layout(2, 2, 4, 4, 8);
+    let (_, target_init) = layout(2, 2, 4, 8, 32);
+    let target_init = target_init.with_store_hash(true);
+    let (_, mismatched_init) = layout(4, 4, 4, 4, 32);
+
+    let source_layout = Layout::from_size_align(source_init.total_size as usize, 8).unwrap();
+    let source_base = unsafe { alloc(source_layout) };
+    let target_layout = Layout::from_size_align(target_init.total_size as usize, 8).unwrap();
+    let target_base = unsafe { alloc(target_layout) };
+    let mismatched_layout =
+        Layout::from_size_align(mismatched_init.total_size as usize, 8).unwrap();
+    let mismatched_base = unsafe { alloc(mismatched_layout) };
+    assert!(!source_base.is_null() && !target_base.is_null() && !mismatched_base.is_null());
+
+    unsafe {
+        init(source_base, &source_init);
+        init(target_base, &target_init);
+        init(mismatched_base, &mismatched_init);
+
+        for key in 0..8u16 {
+            *get_or_reserve_entry(source_base, (&raw const key).cast::<u8>()).cast::<u32>() =
+                u32::from(key) + 500;
+        }
+
+        assert_eq!(
+            grow_into(mismatched_base, source_base),
+            Err(GrowError::KeySizeMismatch)
+        );
+
+        assert_eq!(grow_into(target_base, source_base), Ok(()));
+        let target_header = &*(target_base as *const MapHeader);
+        assert_eq!(target_header.element_count, 8);
+
+        for key in 0..8u16 {
+            let found_ptr = lookup(target_base, (&raw const key).cast::<u8>());
+            assert_eq!(*(found_ptr as *const u32), u32::from(key) + 500);
+        }
+
+        assert_eq!(
+            grow_into(target_base, source_base),
+            Err(GrowError::TargetNotEmpty)
+        );
+    }
+}
+
+#[test]
+fn test_shrink_into() {
+    let (_, source_init) = layout(4, 4, 8, 8, 1024);
+    let (_, target_init) = layout(4, 4, 8, 8, 16);
+    let (_, tiny_init) = layout(4, 4, 8, 8, 4);
+    let (_, mismatched_init) = layout(4, 4, 4, 4, 16);
+
+    let source_base = unsafe { alloc(alloc_layout(&source_init)) };
+    let target_base = unsafe { alloc_zeroed(alloc_layout(&target_init)) };
+    assert!(!source_base.is_null() && !target_base.is_null());
+
+    unsafe {
+        init(source_base, &source_init);
+        for key in 0..600u32 {
+            *get_or_reserve_entry(source_base, (&raw const key).cast::<u8>()).cast::<u64>() =
+                u64::from(key) * 3;
+        }
+        for key in 10..600u32 {
+            remove(source_base, (&raw const key).cast::<u8>());
+        }
+
+        // Nothing is written when the entries do not fit
+        assert_eq!(
+            shrink_into(target_base, &tiny_init, source_base),
+            Err(MapError::CapacityExceeded)
+        );
+        assert_eq!(
+            shrink_into(target_base, &mismatched_init, source_base),
+            Err(MapError::LayoutMismatch)
+        );
+        assert!(*target_base == 0);
+
+        assert_eq!(shrink_into(target_base, &target_init, source_base), Ok(()));
+        assert_eq!(len(target_base), 10);
+        assert_eq!(capacity(target_base), 16);
+        for key in 0..10u32 {
+            let found_ptr = lookup(target_base, (&raw const key).cast::<u8>());
+            assert_eq!(*(found_ptr as *const u64), u64::from(key) * 3);
+        }
+        assert_eq!(len(source_base), 10);
+    }
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn test_owned_map() {
+    use hashmap_mem::OwnedMap;
+
+    let mut map = OwnedMap::new(4, 4, 8, 8, 16);
+    assert!(map.is_empty());
+    assert_eq!(map.capacity(), 16);
+
+    assert!(map.insert(&7u32.to_ne_bytes(), &70u64.to_ne_bytes()));
+    assert!(map.contains_key(&7u32.to_ne_bytes()));
+    assert_eq!(map.get(&7u32.to_ne_bytes()), Some(&70u64.to_ne_bytes()[..]));
+
+    map.get_mut(&7u32.to_ne_bytes())
+        .unwrap()
+        .copy_from_slice(&71u64.to_ne_bytes());
+
+    let copy = map.clone();
+    assert!(map.remove(&7u32.to_ne_bytes()));
+    assert!(map.get(&7u32.to_ne_bytes()).is_none());
+
+    // The clone has its own memory
+    assert_eq!(copy.len(), 1);
+    assert_eq!(copy.view::<u32, u64>().unwrap().get(&7), Some(&71));
+
+    // The raw primitives work on the owned memory too
+    unsafe {
+        let key: u32 = 9;
+        let value_ptr = get_or_reserve_entry(map.as_mut_ptr(), (&raw const key).cast::<u8>());
+        *value_ptr.cast::<u64>() = 90;
+    }
+    assert_eq!(map.view_mut::<u32, u64>().unwrap().remove(&9), Some(90));
+    assert!(map.view::<u16, u64>().is_none());
+}
+
+#[test]
+fn test_entries() {
+    let (_, map_init) = layout(4, 4, 4, 4, 16);
+
+    let layout = Layout::from_size_align(map_init.total_size as usize, 8).unwrap();
+    let map_base = unsafe { alloc(layout) };
+    assert!(!map_base.is_null());
+
+    unsafe {
+        init(map_base, &map_init);
+        assert_eq!(entries(map_base).count(), 0);
+
+        for key in 0..10u32 {
+            *get_or_reserve_entry(map_base, (&raw const key).cast::<u8>()).cast::<u32>() = key;
+        }
+        for key in 0..3u32 {
+            remove(map_base, (&raw const key).cast::<u8>());
+        }
+
+        for (key, value) in entries(map_base) {
+            assert_eq!(key, value);
+            value
+                .copy_from_slice(&(u32::from_ne_bytes(key.try_into().unwrap()) * 10).to_ne_bytes());
+        }
+
+        let mut keys: Vec<u32> = entries(map_base)
+            .map(|(key, _)| u32::from_ne_bytes(key.try_into().unwrap()))
+            .collect();
+        keys.sort_unstable();
+        assert_eq!(keys, (3..10).collect::<Vec<_>>());
+
+        for key in 3..10u32 {
+            assert_eq!(
+                *lookup(map_base, (&raw const key).cast::<u8>()).cast::<u32>(),
+                key * 10
+            );
+        }
+
+        let mut iter = entries(map_base);
+        assert_eq!(iter.by_ref().count(), 7);
+        assert!(iter.next().is_none());
+    }
+}
+
+#[test]
+fn test_hash_fragments() {
+    let (_, map_init) = layout(32, 4, 4, 4, 64);
+    let map_init = map_init.with_hash_fragments(true);
+    let (_, plain_init) = layout(32, 4, 4, 4, 128);
+
+    let layout = Layout::from_size_align(map_init.total_size as usize, 8).unwrap();
+    let map_base = unsafe { alloc(layout) };
+    let plain_layout = Layout::from_size_align(plain_init.total_size as usize, 8).unwrap();
+    let plain_base = unsafe { alloc(plain_layout) };
+    assert!(!map_base.is_null() && !plain_base.is_null());
+
+    let key_of = |index: u32| {
+        let mut key = [0u32; 8];
+        key[7] = index;
+        key
+    };
+
+    unsafe {
+        init(map_base, &map_init);
+        let header = ptr::read(map_base as *const MapHeader);
+        assert_eq!(header.flags, FLAG_HASH_FRAGMENTS);
+
+        for index in 0..48u32 {
+            let key = key_of(index);
+            *get_or_reserve_entry(map_base, key.as_ptr().cast::<u8>()).cast::<u32>() = index;
+        }
+        for index in (0..48u32).filter(|index| index % 4 == 0) {
+            assert!(remove(map_base, key_of(index).as_ptr().cast::<u8>()));
+        }
+
+        let statuses = (0..usize::from(header.capacity)).map(|index| {
+            *map_base.add(size_of::<MapHeader>() + index * header.bucket_size as usize)
+        });
+        assert_eq!(statuses.filter(|&status| status >= 0x80).count(), 36);
+        assert_eq!(entries(map_base).count(), 36);
+
+        rehash_in_place(map_base);
+        for index in 0..48u32 {
+            let found_ptr = lookup(map_base, key_of(index).as_ptr().cast::<u8>());
+            if index % 4 == 0 {
+                assert!(found_ptr.is_null());
+            } else {
+                assert_eq!(*(found_ptr as *const u32), index);
+            }
+        }
+
+        // Moving into a map without fragments writes plain occupied statuses
+        init(plain_base, &plain_init);
+        assert_eq!(grow_into(plain_base, map_base), Ok(()));
+        let plain_header = ptr::read(plain_base as *const MapHeader);
+        let plain_statuses = (0..usize::from(plain_header.capacity)).map(|index| {
+            *plain_base.add(size_of::<MapHeader>() + index * plain_header.bucket_size as usize)
+        });
+        assert_eq!(plain_statuses.filter(|&status| status == 2).count(), 36);
+        let found_ptr = lookup(plain_base, key_of(47).as_ptr().cast::<u8>());
+        assert_eq!(*(found_ptr as *const u32), 47);
+    }
+}
+
+#[test]
+fn test_attach() {
+    let (_, map_init) = layout(4, 4, 4, 4, 16);
+    let mut region = vec![0u64; (map_init.total_size as usize).div_ceil(8) + 4];
+    let region_len = region.len() * 8;
+    let region_ptr = region.as_mut_ptr().cast::<u8>();
+
+    unsafe {
+        init(region_ptr, &map_init);
+
+        let mut map = attach(region_ptr, region_len).unwrap();
+        assert_eq!(map.region_len(), region_len);
+        assert_eq!(map.as_bytes().len(), map_init.total_size as usize);
+        assert!(map.insert(&7u32.to_ne_bytes(), &70u32.to_ne_bytes()));
+        assert_eq!(map.get(&7u32.to_ne_bytes()), Some(&70u32.to_ne_bytes()[..]));
+        assert_eq!(map.revalidate(), Ok(()));
+        assert_eq!(map.detach(), region_ptr);
+
+        // Attaching again sees the entry written through the region
+        let map = attach(region_ptr, region_len).unwrap();
+        assert_eq!(map.len(), 1);
+        assert!(map.contains_key(&7u32.to_ne_bytes()));
+        let _ = map.detach();
+
+        // Truncated and corrupted regions are rejected
+        assert_eq!(
+            attach(region_ptr, map_init.total_size as usize - 1).unwrap_err(),
+            ValidationError::BufferTooSmall
+        );
+        (*region_ptr.cast::<MapHeader>()).format_version = FORMAT_VERSION + 1;
+        assert_eq!(
+            attach(region_ptr, region_len).unwrap_err(),
+            ValidationError::UnsupportedFormatVersion
+        );
+    }
+}
+
+#[test]
+fn test_lookup_consistent() {
+    let (_, map_init) = layout(4, 4, 16, 8, 64);
+    let map_base = unsafe { alloc_zeroed(alloc_layout(&map_init)) };
+    assert!(!map_base.is_null());
+    unsafe { init(map_base, &map_init) };
+
+    // Raw pointers are not `Send`, the threads share the address instead
+    let address = map_base as usize;
+    let writer = std::thread::spawn(move || {
+        let map_base = address as *mut u8;
+        for round in 0u64..2000 {
+            for key in 0u32..8 {
+                let value = [round, round];
+                unsafe {
+                    write_consistent(map_base, |map_base| {
+                        if round % 3 == 2 && key % 2 == 0 {
+                            remove(map_base, (&raw const key).cast::<u8>());
+                        } else {
+                            insert(
+                                map_base,
+                                (&raw const key).cast::<u8>(),
+                                (&raw const value).cast::<u8>(),
+                            );
+                        }
+                    });
+                }
+            }
+        }
+    });
+
+    let readers: Vec<_> = (0..2)
+        .map(|_| {
+            std::thread::spawn(move || {
+                let map_base = address as *const u8;
+                for _ in 0..4000 {
+                    for key in 0u32..8 {
+                        let mut value = [0u64; 2];
+                        if unsafe {
+                            lookup_consistent(
+                                map_base,
+                                (&raw const key).cast::<u8>(),
+                                (&raw mut value).cast::<u8>(),
+                            )
+                        } {
+                            // Both halves are written in the same change, so are never torn
+                            assert_eq!(value[0], value[1]);
+                        }
+                    }
+                    assert!(unsafe { len_consistent(map_base) } <= 8);
+                }
+            })
+        })
+        .collect();
+
+    writer.join().unwrap();
+    for reader in readers {
+        reader.join().unwrap();
+    }
+
+    unsafe {
+        assert_eq!(len_consistent(map_base), 8);
+        let key: u32 = 3;
+        let mut value = [0u64; 2];
+        assert!(lookup_consistent(
+            map_base,
+            (&raw const key).cast::<u8>(),
+            (&raw mut value).cast::<u8>()
+        ));
+        assert_eq!(value, [1999, 1999]);
+    }
+}
+
+#[test]
+fn test_sharded() {
+    let (_, map_init) = layout(4, 4, 8, 8, 256);
+    let sharded_init = sharded::layout(4, map_init);
+    let memory_layout = sharded::alloc_layout(&sharded_init);
+    let base = unsafe { alloc_zeroed(memory_layout) };
+    assert!(!base.is_null());
+    unsafe { sharded::init(base, &sharded_init) };
+    assert_eq!(unsafe { sharded::shard_count(base) }, 4);
+
+    // Four writers at once, each with its own keys
+    let address = base as usize;
+    let writers: Vec<_> = (0u32..4)
+        .map(|writer| {
+            std::thread::spawn(move || {
+                let base = address as *mut u8;
+                for key in (0u32..400).filter(|key| key % 4 == writer) {
+                    let value = u64::from(key) * 3;
+                    let result = unsafe {
+                        sharded::insert(
+                            base,
+                            (&raw const key).cast::<u8>(),
+                            (&raw const value).cast::<u8>(),
+                        )
+                    };
+                    assert_eq!(result, InsertResult::Inserted);
+                }
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap();
+    }
+
+    unsafe {
+        assert_eq!(sharded::len(base), 400);
+        assert_eq!(
+            sharded::capacity(base),
+            4 * sharded_init.config.capacity as usize
+        );
+
+        // Every shard got a fair share of the keys
+        for shard in 0..4 {
+            let shard_len = sharded::with_shard_index(base, shard, |map_ptr| len(map_ptr));
+            assert!(shard_len > 50, "shard {shard} has {shard_len} keys");
+        }
+
+        for key in 0u32..400 {
+            let mut value = 0u64;
+            assert!(sharded::lookup(
+                base,
+                (&raw const key).cast::<u8>(),
+                (&raw mut value).cast::<u8>()
+            ));
+            assert_eq!(value, u64::from(key) * 3);
+
+            let (shard, _) = sharded::shard_of(base, (&raw const key).cast::<u8>());
+            let map_ptr = sharded::shard_ptr(base, shard);
+            assert!(!lookup(map_ptr, (&raw const key).cast::<u8>()).is_null());
+        }
+
+        let key: u32 = 17;
+        assert!(sharded::remove(base, (&raw const key).cast::<u8>()));
+        assert!(!sharded::remove(base, (&raw const key).cast::<u8>()));
+        let mut value = 0u64;
+        assert!(!sharded::lookup(
+            base,
+            (&raw const key).cast::<u8>(),
+            (&raw mut value).cast::<u8>()
+        ));
+        assert_eq!(sharded::len(base), 399);
+    }
+}
+
+#[test]
+fn test_arena() {
+    let configs = [
+        layout(4, 4, 2, 2, 16).1,
+        layout(8, 8, 24, 8, 64).1.with_cache_line_buckets(true),
+        layout(1, 1, 1, 1, 4).1,
+    ];
+    let plan = plan_arena(&configs);
+    assert_eq!(plan.len(), 3);
+    let memory_layout = plan.alloc_layout();
+    assert_eq!(memory_layout.align(), CACHE_LINE_SIZE as usize);
+    let base = unsafe { alloc_zeroed(memory_layout) };
+    assert!(!base.is_null());
+
+    unsafe {
+        init_arena(base, &plan);
+
+        let mut previous_end = 0;
+        for (index, config) in configs.iter().enumerate() {
+            let map_base = map_ptr(base, &plan, index);
+            let offset = map_base.offset_from(base) as usize;
+            assert_eq!(offset, plan.offset(index));
+            assert_eq!(offset % alloc_layout(config).align(), 0);
+            assert!(offset >= previous_end);
+            previous_end = offset + config.total_size as usize;
+            assert!(validate(map_base, plan.total_size() - offset).is_ok());
+        }
+        assert!(previous_end <= plan.total_size());
+
+        // The maps do not overlap
+        for index in 0..3u64 {
+            let map_base = map_ptr(base, &plan, index as usize);
+            let key = [index as u8; 8];
+            let value = [index as u8 + 1; 24];
+            assert_ne!(
+                insert(map_base, key.as_ptr(), value.as_ptr()),
+                InsertResult::Full
+            );
+        }
+        for index in 0..3 {
+            let map_base = map_ptr(base, &plan, index);
+            assert_eq!(len(map_base), 1);
+            assert!(!lookup(map_base, [index as u8; 8].as_ptr()).is_null());
+        }
+    }
+}
+
+#[test]
+fn test_map_init_builder() {
+    let config = MapInitBuilder::new()
+        .key::<u32>()
+        .value::<Position>()
+        .logical_limit(100)
+        .probe_strategy(ProbeStrategy::RobinHood)
+        .seed(7)
+        .build()
+        .unwrap();
+    let (_, expected) = layout(4, 4, 16, 16, 100);
+    assert_eq!(config.key_size, 4);
+    assert_eq!(config.value_size, 16);
+    assert_eq!(config.value_alignment, 16);
+    assert_eq!(config.capacity, 128);
+    assert_eq!(config.total_size, expected.total_size);
+    assert_eq!(config.probe_strategy, ProbeStrategy::RobinHood);
+    assert_eq!(config.seed, 7);
+
+    let map_base = unsafe { alloc_zeroed(alloc_layout(&config)) };
+    assert!(!map_base.is_null());
+    unsafe {
+        init(map_base, &config);
+        assert!(validate(map_base, config.total_size as usize).is_ok());
+    }
+
+    // Sets only need a key
+    assert_eq!(
+        MapInitBuilder::new()
+            .key::<u64>()
+            .logical_limit(8)
+            .build()
+            .unwrap()
+            .value_size,
+        0
+    );
+
+    assert_eq!(
+        MapInitBuilder::new().logical_limit(8).build().err(),
+        Some(LayoutError::KeySizeZero)
+    );
+    assert_eq!(
+        MapInitBuilder::new().key::<u32>().build().err(),
+        Some(LayoutError::LogicalLimitZero)
+    );
+    assert_eq!(
+        MapInitBuilder::new()
+            .key_size(4, 3)
+            .logical_limit(8)
+            .build()
+            .err(),
+        Some(LayoutError::InvalidAlignment)
+    );
+    assert_eq!(
+        MapInitBuilder::new()
+            .key::<u32>()
+            .value_size(4, 256)
+            .logical_limit(8)
+            .build()
+            .err(),
+        Some(LayoutError::InvalidAlignment)
+    );
+}
+
+hashmap_mem::define_map! {
+    /// Positions by entity id
+    mod positions { key: u32, value: Position, logical_limit: 16 }
+}
+
+#[test]
+fn test_define_map() {
+    let memory_layout = positions::alloc_layout();
+    assert_eq!(memory_layout.align(), 16);
+    let map_base = unsafe { alloc_zeroed(memory_layout) };
+    assert!(!map_base.is_null());
+
+    unsafe {
+        positions::init(map_base);
+        assert_eq!(capacity(map_base), 16);
+
+        let position = |id: u32| Position {
+            x: id as f32,
+            y: 0.0,
+            z: 0.0,
+            w: 1.0,
+        };
+        for id in 0..10 {
+            assert_eq!(
+                positions::insert(map_base, &id, &position(id)),
+                InsertResult::Inserted
+            );
+        }
+        assert_eq!(positions::len(map_base), 10);
+        assert_eq!(positions::get(map_base, &3), Some(&position(3)));
+        assert_eq!(positions::get(map_base, &30), None);
+
+        positions::get_mut(map_base, &4).unwrap().y = 2.0;
+        assert_eq!(positions::get(map_base, &4).unwrap().y, 2.0);
+
+        assert_eq!(positions::remove(map_base, &5), Some(position(5)));
+        assert_eq!(positions::remove(map_base, &5), None);
+
+        let mut ids: Vec<u32> = positions::iter(map_base).map(|(id, _)| *id).collect();
+        ids.sort_unstable();
+        assert_eq!(ids, [0, 1, 2, 3, 4, 6, 7, 8, 9]);
+    }
+}
+
+#[test]
+fn test_required_size() {
+    const REQUIRED: usize = required_size(4, 4, 16, 16, 100);
+    const ALIGNMENT: usize = required_alignment(4, 16);
+
+    #[repr(C, align(16))]
+    struct StaticBuffer([u8; REQUIRED]);
+    static mut MAP_BUF: StaticBuffer = StaticBuffer([0; REQUIRED]);
+
+    let (bucket_layout, config) = layout(4, 4, 16, 16, 100);
+    assert_eq!(REQUIRED, config.total_size as usize);
+    assert_eq!(ALIGNMENT, alloc_layout(&config).align());
+    const BUCKET: BucketLayout = calculate_bucket_layout(4, 4, 16, 16);
+    assert_eq!(BUCKET.bucket_size, bucket_layout.bucket_size);
+    assert_eq!(
+        positions::REQUIRED_SIZE,
+        positions::config().total_size as usize
+    );
+
+    unsafe {
+        let map_base = (&raw mut MAP_BUF).cast::<u8>();
+        init(map_base, &config);
+        let key = 9u32.to_ne_bytes();
+        let position = Position {
+            x: 1.0,
+            y: 2.0,
+            z: 3.0,
+            w: 4.0,
+        };
+        assert_eq!(
+            insert(map_base, key.as_ptr(), (&raw const position).cast::<u8>()),
+            InsertResult::Inserted
+        );
+        assert_eq!(*lookup(map_base, key.as_ptr()).cast::<Position>(), position);
+    }
+}
+
+#[test]
+fn test_validate() {
+    let (_, map_init) = layout(4, 4, 8, 8, 16);
+    let map_init = map_init.with_store_hash(true).with_hash_fragments(true);
+    let size = map_init.total_size as usize;
+
+    let layout = Layout::from_size_align(size, 8).unwrap();
+    let map_base = unsafe { alloc(layout) };
+    assert!(!map_base.is_null());
+
+    unsafe {
+        init(map_base, &map_init);
+        for key in 0..10u32 {
+            get_or_reserve_entry(map_base, (&raw const key).cast::<u8>());
+        }
+        let key: u32 = 3;
+        remove(map_base, (&raw const key).cast::<u8>());
+
+        assert_eq!(validate(map_base, size), Ok(()));
+        assert_eq!(
+            validate(map_base, size - 1),
+            Err(ValidationError::BufferTooSmall)
+        );
+        assert_eq!(
+            validate(map_base.add(1), size - 1),
+            Err(ValidationError::Misaligned)
+        );
+
+        let header = map_base as *mut MapHeader;
+        let original = ptr::read(header);
+        let corrupt = |f: &dyn Fn(&mut MapHeader)| {
+            ptr::copy_nonoverlapping(&original, header, 1);
+            f(&mut *header);
+            let result = validate(map_base, size);
+            ptr::copy_nonoverlapping(&original, header, 1);
+            result
+        };
+
+        assert_eq!(
+            corrupt(&|h| h.padding_and_secret_code = 0),
+            Err(ValidationError::SecretCodeMismatch)
+        );
+        assert_eq!(
+            corrupt(&|h| h.capacity = 12),
+            Err(ValidationError::InvalidCapacity)
+        );
+        assert_eq!(
+            corrupt(&|h| h.capacity = 32),
+            Err(ValidationError::BufferTooSmall)
+        );
+        assert_eq!(
+            corrupt(&|h| h.value_offset = 2),
+            Err(ValidationError::InvalidBucketLayout)
+        );
+        assert_eq!(
+            corrupt(&|h| h.hash_offset = 4),
+            Err(ValidationError::InvalidBucketLayout)
+        );
+        assert_eq!(
+            corrupt(&|h| h.logical_limit = 17),
+            Err(ValidationError::LogicalLimitExceedsCapacity)
+        );
+        assert_eq!(
+            corrupt(&|h| h.element_count = 10),
+            Err(ValidationError::ElementCountMismatch)
+        );
+        assert_eq!(
+            corrupt(&|h| h.tombstone_count = 1),
+            Err(ValidationError::TombstoneCountMismatch)
+        );
+        assert_eq!(
+            corrupt(&|h| h.flags = 0),
+            Err(ValidationError::InvalidBucketStatus)
+        );
+    }
+}
+
+#[test]
+fn test_clear() {
+    let (_, map_init) = layout(4, 4, 4, 4, 16);
+
+    let layout = Layout::from_size_align(map_init.total_size as usize, 8).unwrap();
+    let map_base = unsafe { alloc(layout) };
+    assert!(!map_base.is_null());
+
+    unsafe {
+        init(map_base, &map_init);
+        for key in 0..10u32 {
+            get_or_reserve_entry(map_base, (&raw const key).cast::<u8>());
+        }
+        let key: u32 = 4;
+        remove(map_base, (&raw const key).cast::<u8>());
+
+        clear(map_base);
+
+        let header = ptr::read(map_base as *const MapHeader);
+        assert_eq!(header.element_count, 0);
+        assert_eq!(header.tombstone_count, 0);
+        assert_eq!(capacity(map_base), map_init.capacity);
+        assert_eq!(entries(map_base).count(), 0);
+        assert!(!has(map_base, (&raw const key).cast::<u8>()));
+
+        *get_or_reserve_entry(map_base, (&raw const key).cast::<u8>()).cast::<u32>() = 7;
+        assert_eq!(
+            *(lookup(map_base, (&raw const key).cast::<u8>()) as *const u32),
+            7
+        );
+    }
+}
+
+#[test]
+fn test_insert() {
+    let (_, map_init) = layout(4, 4, 8, 8, 2);
+
+    let layout = Layout::from_size_align(map_init.total_size as usize, 8).unwrap();
+    let map_base = unsafe { alloc(layout) };
+    assert!(!map_base.is_null());
+
+    unsafe {
+        init(map_base, &map_init);
+
+        let key: u32 = 1;
+        let value: u64 = 100;
+        assert_eq!(
+            insert(
+                map_base,
+                (&raw const key).cast::<u8>(),
+                (&raw const value).cast::<u8>()
+            ),
+            InsertResult::Inserted
+        );
+        let value: u64 = 200;
+        assert_eq!(
+            insert(
+                map_base,
+                (&raw const key).cast::<u8>(),
+                (&raw const value).cast::<u8>()
+            ),
+            InsertResult::Replaced
+        );
+        assert_eq!(
+            *(lookup(map_base, (&raw const key).cast::<u8>()) as *const u64),
+            200
+        );
+
+        let key: u32 = 2;
+        insert(
+            map_base,
+            (&raw const key).cast::<u8>(),
+            (&raw const value).cast::<u8>(),
+        );
+        let key: u32 = 3;
+        assert_eq!(
+            insert(
+                map_base,
+                (&raw const key).cast::<u8>(),
+                (&raw const value).cast::<u8>()
+            ),
+            InsertResult::Full
+        );
+        assert_eq!((*(map_base as *const MapHeader)).element_count, 2);
+    }
+}
+
+#[test]
+fn test_take() {
+    let (_, map_init) = layout(4, 4, 8, 8, 16);
+
+    let layout = Layout::from_size_align(map_init.total_size as usize, 8).unwrap();
+    let map_base = unsafe { alloc(layout) };
+    assert!(!map_base.is_null());
+
+    unsafe {
+        init(map_base, &map_init);
+
+        let key: u32 = 9;
+        *get_or_reserve_entry(map_base, (&raw const key).cast::<u8>()).cast::<u64>() = 0xDEAD;
+
+        let mut out: u64 = 0;
+        assert!(take(
+            map_base,
+            (&raw const key).cast::<u8>(),
+            (&raw mut out).cast::<u8>()
+        ));
+        assert_eq!(out, 0xDEAD);
+        assert!(!has(map_base, (&raw const key).cast::<u8>()));
+        assert_eq!((*(map_base as *const MapHeader)).element_count, 0);
+
+        out = 1;
+        assert!(!take(
+            map_base,
+            (&raw const key).cast::<u8>(),
+            (&raw mut out).cast::<u8>()
+        ));
+        assert_eq!(out, 1);
+    }
+}
+
+#[test]
+fn test_probe_limit() {
+    let (_, map_init) = layout(4, 4, 4, 4, 64);
+    let (_, wide_init) = layout(4, 4, 4, 4, 64);
+
+    let layout = Layout::from_size_align(map_init.total_size as usize, 8).unwrap();
+    let map_base = unsafe { alloc(layout) };
+    assert!(!map_base.is_null());
+
+    unsafe {
+        init(map_base, &map_init);
+        assert_eq!(probe_limit(map_base), 32);
+
+        // A limit above the capacity lets every bucket be filled
+        init(map_base, &wide_init.with_probe_limit(1000));
+        assert_eq!(probe_limit(map_base), 64);
+        for key in 0..64u32 {
+            assert!(!get_or_reserve_entry(map_base, (&raw const key).cast::<u8>()).is_null());
+        }
+
+        // With a single probe, keys only ever go to their home bucket
+        init(map_base, &map_init.with_probe_limit(1));
+        let inserted = (0..64u32)
+            .filter(|key| !get_or_reserve_entry(map_base, (&raw const *key).cast::<u8>()).is_null())
+            .count();
+        assert!(inserted < 64);
+        assert_eq!(avg_lookup_probes(map_base), 1.0);
+    }
+}
+
+#[test]
+fn test_robin_hood() {
+    let (_, map_init) = layout(4, 4, 4, 4, 64);
+    let map_init = map_init
+        .with_probe_strategy(ProbeStrategy::RobinHood)
+        .with_hash_fragments(true);
+    let size = map_init.total_size as usize;
+
+    let layout = Layout::from_size_align(size, 8).unwrap();
+    let map_base = unsafe { alloc(layout) };
+    assert!(!map_base.is_null());
+
+    unsafe {
+        init(map_base, &map_init);
+
+        let mut expected = std::collections::HashMap::new();
+        for round in 0..20u32 {
+            for key in (round * 7)..(round * 7 + 40) {
+                let value = key ^ round;
+                if insert(
+                    map_base,
+                    (&raw const key).cast::<u8>(),
+                    (&raw const value).cast::<u8>(),
+                ) != InsertResult::Full
+                {
+                    expected.insert(key, value);
+                }
+            }
+            for key in (round * 7)..(round * 7 + 30) {
+                if expected.remove(&key).is_some() {
+                    assert!(remove(map_base, (&raw const key).cast::<u8>()));
+                }
+            }
+
+            let header = ptr::read(map_base as *const MapHeader);
+            assert_eq!(header.tombstone_count, 0);
+            assert_eq!(usize::from(header.element_count), expected.len());
+            assert_eq!(validate(map_base, size), Ok(()));
+            for (key, value) in &expected {
+                let found_ptr = lookup(map_base, (&raw const *key).cast::<u8>());
+                assert_eq!(*(found_ptr as *const u32), *value);
+            }
+        }
+
+        // Backward shift deletion keeps every key close to home
+        assert!(avg_lookup_probes(map_base) < 2.0);
+    }
+}
+
+#[test]
+fn test_linear_shift() {
+    for layout_kind in [
+        LayoutKind::Interleaved,
+        LayoutKind::ControlBytes,
+        LayoutKind::StructOfArrays,
+    ] {
+        let (_, map_init) = layout(4, 4, 4, 4, 64);
+        let map_init = map_init.with_layout_kind(layout_kind).with_probe_limit(16);
+        let size = map_init.total_size as usize;
+
+        let map_base = unsafe { alloc(alloc_layout(&map_init)) };
+        assert!(!map_base.is_null());
+
+        unsafe {
+            init(map_base, &map_init);
+
+            let mut expected = std::collections::HashMap::new();
+            for round in 0..20u32 {
+                for key in (round * 7)..(round * 7 + 40) {
+                    let value = key ^ round;
+                    if insert(
+                        map_base,
+                        (&raw const key).cast::<u8>(),
+                        (&raw const value).cast::<u8>(),
+                    ) != InsertResult::Full
+                    {
+                        expected.insert(key, value);
+                    }
+                }
+                for key in (round * 7)..(round * 7 + 30) {
+                    if expected.remove(&key).is_some() {
+                        assert!(remove(map_base, (&raw const key).cast::<u8>()));
+                    }
+                }
+
+                let header = ptr::read(map_base as *const MapHeader);
+                assert_eq!(header.tombstone_count, 0);
+                assert_eq!(usize::from(header.element_count), expected.len());
+                assert_eq!(validate(map_base, size), Ok(()));
+                for (key, value) in &expected {
+                    let found_ptr = lookup(map_base, (&raw const *key).cast::<u8>());
+                    assert_eq!(*(found_ptr as *const u32), *value);
+                }
+            }
+
+            // Shifting entries back does not make retain skip or repeat any of them
+            let mut visited = Vec::new();
+            retain(map_base, |key_ptr, _| {
+                visited.push(*key_ptr.cast::<u32>());
+                *key_ptr.cast::<u32>() % 2 == 0
+            });
+            visited.sort_unstable();
+            let mut all_keys: Vec<u32> = expected.keys().copied().collect();
+            all_keys.sort_unstable();
+            assert_eq!(visited, all_keys);
+            expected.retain(|key, _| key % 2 == 0);
+            assert_eq!(len(map_base) as usize, expected.len());
+            assert_eq!(stats(map_base).tombstone_count, 0);
+            for key in expected.keys() {
+                assert!(has(map_base, (&raw const *key).cast::<u8>()));
+            }
+        }
+    }
+}
+
+#[test]
+fn test_set() {
+    let (bucket_layout, set_init) = layout(8, 8, 0, 8, 16);
+    assert_eq!(bucket_layout.bucket_size, 16);
+    let (_, copy_init) = layout(8, 8, 0, 8, 16);
+
+    let layout = Layout::from_size_align(set_init.total_size as usize, 8).unwrap();
+    let set_base = unsafe { alloc(layout) };
+    let copy_base = unsafe { alloc(layout) };
+    assert!(!set_base.is_null() && !copy_base.is_null());
+
+    unsafe {
+        init(set_base, &set_init);
+        for key in [3u64, 5, 8, 13] {
+            assert_eq!(
+                insert_key(set_base, (&raw const key).cast::<u8>()),
+                InsertResult::Inserted
+            );
+        }
+        let key: u64 = 5;
+        assert_eq!(
+            insert_key(set_base, (&raw const key).cast::<u8>()),
+            InsertResult::Replaced
+        );
+        assert!(contains(set_base, (&raw const key).cast::<u8>()));
+        assert!(remove(set_base, (&raw const key).cast::<u8>()));
+        assert!(!contains(set_base, (&raw const key).cast::<u8>()));
+
+        init(copy_base, &copy_init);
+        assert!(overwrite(copy_base, set_base));
+        assert_eq!(
+            entries(copy_base)
+                .filter(|(_, value)| value.is_empty())
+                .count(),
+            3
+        );
+
+        let bytes = std::slice::from_raw_parts(set_base, set_init.total_size as usize);
+        let view = MapView::<u64, ()>::new(bytes).unwrap();
+        let mut keys: Vec<u64> = view.iter().map(|(key, _)| *key).collect();
+        keys.sort_unstable();
+        assert_eq!(keys, [3, 8, 13]);
+    }
+}
+
+#[test]
+fn test_precomputed_hash() {
+    let (_, small_init) = layout(4, 4, 4, 4, 16);
+    let (_, large_init) = layout(4, 4, 4, 4, 256);
+
+    let small_layout = Layout::from_size_align(small_init.total_size as usize, 8).unwrap();
+    let large_layout = Layout::from_size_align(large_init.total_size as usize, 8).unwrap();
+    let small_base = unsafe { alloc(small_layout) };
+    let large_base = unsafe { alloc(large_layout) };
+    assert!(!small_base.is_null() && !large_base.is_null());
+
+    unsafe {
+        init(small_base, &small_init);
+        init(large_base, &large_init);
+
+        let key: u32 = 77;
+        let key_ptr = (&raw const key).cast::<u8>();
+        let hash = key_hash(small_base, key_ptr);
+        assert_eq!(hash, key_hash(large_base, key_ptr));
+
+        let value: u32 = 1;
+        assert_eq!(
+            insert_hashed(small_base, key_ptr, hash, (&raw const value).cast::<u8>()),
+            InsertResult::Inserted
+        );
+        *get_or_reserve_entry_hashed(large_base, key_ptr, hash).cast::<u32>() = 2;
+
+        assert_eq!(*(lookup_hashed(small_base, key_ptr, hash) as *const u32), 1);
+        assert_eq!(*(lookup(large_base, key_ptr) as *const u32), 2);
+
+        assert!(remove_hashed(large_base, key_ptr, hash));
+        assert!(lookup_hashed(large_base, key_ptr, hash).is_null());
+        assert!(has(small_base, key_ptr));
+    }
+}
+
+#[test]
+fn test_hash_strategy() {
+    let strategies = [
+        HashStrategy::Fx,
+        HashStrategy::WyHash,
+        HashStrategy::SipHash13,
+    ];
+    let (_, map_init) = layout(4, 4, 4, 4, 64);
+
+    let map_layout = Layout::from_size_align(map_init.total_size as usize, 8).unwrap();
+    let source_base = unsafe { alloc(map_layout) };
+    assert!(!source_base.is_null());
+
+    unsafe {
+        init(source_base, &map_init);
+        for key in 0..40u32 {
+            *get_or_reserve_entry(source_base, (&raw const key).cast::<u8>()).cast::<u32>() = key;
+        }
+
+        let mut hashes = Vec::new();
+        for strategy in strategies {
+            let (_, target_init) = layout(4, 4, 4, 4, 64);
+            let target_base = alloc(map_layout);
+            init(target_base, &target_init.with_hash_strategy(strategy));
+            assert_eq!(
+                (*(target_base as *const MapHeader)).hash_strategy,
+                strategy as u8
+            );
+
+            assert_eq!(grow_into(target_base, source_base), Ok(()));
+            for key in 0..40u32 {
+                let found_ptr = lookup(target_base, (&raw const key).cast::<u8>());
+                assert_eq!(*(found_ptr as *const u32), key);
+            }
+
+            let key: u32 = 7;
+            hashes.push(key_hash(target_base, (&raw const key).cast::<u8>()));
+        }
+
+        assert_ne!(hashes[0], hashes[1]);
+        assert_ne!(hashes[1], hashes[2]);
+    }
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_seed() {
+    let (_, plain_init) = layout(4, 4, 4, 4, 32);
+    let (_, seeded_init) = layout(4, 4, 4, 4, 32);
+    let seed = hashmap_mem::random_seed();
+    assert_ne!(seed, hashmap_mem::random_seed());
+    let seeded_init = seeded_init.with_seed(seed);
+
+    let map_layout = Layout::from_size_align(plain_init.total_size as usize, 8).unwrap();
+    let plain_base = unsafe { alloc(map_layout) };
+    let seeded_base = unsafe { alloc(map_layout) };
+    assert!(!plain_base.is_null() && !seeded_base.is_null());
+
+    unsafe {
+        init(plain_base, &plain_init);
+        init(seeded_base, &seeded_init);
+        assert_eq!((*(seeded_base as *const MapHeader)).hash_seed, seed);
+
+        let key: u32 = 12;
+        let key_ptr = (&raw const key).cast::<u8>();
+        assert_ne!(
+            key_hash(plain_base, key_ptr),
+            key_hash(seeded_base, key_ptr)
+        );
+
+        for key in 0..20u32 {
+            *get_or_reserve_entry(plain_base, (&raw const key).cast::<u8>()).cast::<u32>() = key;
+        }
+        assert!(overwrite(seeded_base, plain_base));
+        for key in 0..20u32 {
+            let found_ptr = lookup(seeded_base, (&raw const key).cast::<u8>());
+            assert_eq!(*(found_ptr as *const u32), key);
+        }
+    }
+}
+
+#[test]
+fn test_fx_hash_matches_fxhash() {
+    use std::hash::Hasher;
+
+    let key_bytes: Vec<u8> = (0..23u8).map(|byte| byte.wrapping_mul(37)).collect();
+
+    for key_size in 1..=23u32 {
+        let (_, map_init) = layout(key_size, 1, 0, 1, 8);
+        let map_init = map_init.with_seed(0x1234_5678_9abc_def0);
+        let map_layout = Layout::from_size_align(map_init.total_size as usize, 8).unwrap();
+        let map_base = unsafe { alloc(map_layout) };
+        assert!(!map_base.is_null());
+
+        let mut hasher = fxhash::FxHasher64::default();
+        hasher.write_u64(0x1234_5678_9abc_def0);
+        hasher.write(&key_bytes[..key_size as usize]);
+
+        unsafe {
+            init(map_base, &map_init);
+            assert_eq!(key_hash(map_base, key_bytes.as_ptr()), hasher.finish());
+        }
+    }
+}
+
+#[cfg(feature = "ffi")]
+#[test]
+fn test_ffi() {
+    use hashmap_mem::ffi::{
+        hashmap_mem_config, hashmap_mem_get_or_reserve_entry, hashmap_mem_init, hashmap_mem_lookup,
+        hashmap_mem_next_entry, hashmap_mem_remove,
+    };
+
+    let config = hashmap_mem_config(4, 4, 4, 4, 16);
+    let layout = Layout::from_size_align(config.total_size as usize, 8).unwrap();
+    let map_base = unsafe { alloc(layout) };
+    assert!(!map_base.is_null());
+
+    unsafe {
+        hashmap_mem_init(map_base, &config);
+        for key in 0..5u32 {
+            *hashmap_mem_get_or_reserve_entry(map_base, (&raw const key).cast::<u8>())
+                .cast::<u32>() = key * 2;
+        }
+        let key: u32 = 3;
+        assert!(hashmap_mem_remove(map_base, (&raw const key).cast::<u8>()));
+        assert!(hashmap_mem_lookup(map_base, (&raw const key).cast::<u8>()).is_null());
+
+        let mut sum = 0;
+        let mut entry = hashmap_mem_next_entry(map_base, 0);
+        while !entry.key.is_null() {
+            assert_eq!(*entry.value.cast::<u32>(), *entry.key.cast::<u32>() * 2);
+            sum += *entry.key.cast::<u32>();
+            entry = hashmap_mem_next_entry(map_base, entry.index + 1);
+        }
+        assert_eq!(sum, 1 + 2 + 4);
+    }
+}
+
+#[test]
+fn test_slot_handle() {
+    let (_, map_init) = layout(4, 4, 4, 4, 16);
+
+    let layout = Layout::from_size_align(map_init.total_size as usize, 8).unwrap();
+    let map_base = unsafe { alloc(layout) };
+    assert!(!map_base.is_null());
+
+    unsafe {
+        init(map_base, &map_init);
+
+        let slots: Vec<(u32, Handle)> = (0..6u32)
+            .map(|key| {
+                let value = key + 10;
+                let slot = insert_slot(
+                    map_base,
+                    (&raw const key).cast::<u8>(),
+                    (&raw const value).cast::<u8>(),
+                );
+                (key, slot.unwrap())
+            })
+            .collect();
+
+        for &(key, slot) in &slots {
+            assert_eq!(
+                *(get_by_slot(map_base, slot, (&raw const key).cast::<u8>()) as *const u32),
+                key + 10
+            );
+        }
+
+        // A handle that looks current still has to point at the same key
+        let other: u32 = 1;
+        assert!(get_by_slot(map_base, slots[0].1, (&raw const other).cast::<u8>()).is_null());
+
+        // Replacing a value keeps the slot
+        let replaced: u32 = 5;
+        let slot = insert_slot(
+            map_base,
+            (&raw const replaced).cast::<u8>(),
+            (&raw const replaced).cast::<u8>(),
+        )
+        .unwrap();
+        assert_eq!(slot, slots[5].1);
+        assert_eq!(
+            *(get_by_slot(map_base, slot, (&raw const replaced).cast::<u8>()) as *const u32),
+            replaced
+        );
+
+        // A removal invalidates every slot, even when the key comes back to the same bucket
+        let removed: u32 = 2;
+        remove(map_base, (&raw const removed).cast::<u8>());
+        let value = 20u32;
+        let reinserted = insert_slot(
+            map_base,
+            (&raw const removed).cast::<u8>(),
+            (&raw const value).cast::<u8>(),
+        )
+        .unwrap();
+        assert_eq!(reinserted.index, slots[2].1.index);
+        for &(key, slot) in &slots {
+            assert!(get_by_slot(map_base, slot, (&raw const key).cast::<u8>()).is_null());
+        }
+        assert_eq!(
+            *(get_by_slot(map_base, reinserted, (&raw const removed).cast::<u8>()) as *const u32),
+            20
+        );
+
+        let out_of_range = Handle {
+            index: 16,
+            generation: reinserted.generation,
+        };
+        assert!(get_by_slot(map_base, out_of_range, (&raw const removed).cast::<u8>()).is_null());
+    }
+}
+
+#[test]
+fn test_retain() {
+    for strategy in [
+        ProbeStrategy::Linear,
+        ProbeStrategy::RobinHood,
+        ProbeStrategy::Quadratic,
+    ] {
+        let (_, map_init) = layout(4, 4, 4, 4, 32);
+        let map_init = map_init.with_probe_strategy(strategy);
+
+        let layout = Layout::from_size_align(map_init.total_size as usize, 8).unwrap();
+        let map_base = unsafe { alloc(layout) };
+        assert!(!map_base.is_null());
+
+        unsafe {
+            init(map_base, &map_init);
+            for key in 0..30u32 {
+                *get_or_reserve_entry(map_base, (&raw const key).cast::<u8>()).cast::<u32>() = key;
+            }
+
+            let mut visited = Vec::new();
+            retain(map_base, |key_ptr, value_ptr| {
+                let key = *key_ptr.cast::<u32>();
+                visited.push(key);
+                *value_ptr.cast::<u32>() += 100;
+                key % 3 == 0
+            });
+
+            visited.sort_unstable();
+            assert_eq!(visited, (0..30).collect::<Vec<u32>>());
+            assert_eq!((*(map_base as *const MapHeader)).element_count, 10);
+            for key in 0..30u32 {
+                let found_ptr = lookup(map_base, (&raw const key).cast::<u8>());
+                if key % 3 == 0 {
+                    assert_eq!(*(found_ptr as *const u32), key + 100);
+                } else {
+                    assert!(found_ptr.is_null());
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_retain_full_map() {
+    // A full linear map has no empty bucket, so its clusters wrap around the buckets
+    for modulus in 2..8u32 {
+        let (_, map_init) = layout(4, 4, 4, 4, 16);
+        let size = map_init.total_size as usize;
+        let map_base = unsafe { alloc(alloc_layout(&map_init)) };
+        assert!(!map_base.is_null());
+
+        unsafe {
+            init(map_base, &map_init);
+            for key in 0..16u32 {
+                *get_or_reserve_entry(map_base, (&raw const key).cast::<u8>()).cast::<u32>() = key;
+            }
+            assert!(is_full(map_base));
+
+            let mut visited = Vec::new();
+            retain(map_base, |key_ptr, _| {
+                let key = *key_ptr.cast::<u32>();
+                visited.push(key);
+                key % modulus == 0
+            });
+
+            visited.sort_unstable();
+            assert_eq!(visited, (0..16).collect::<Vec<u32>>());
+            assert_eq!(validate(map_base, size), Ok(()));
+            assert_eq!(stats(map_base).tombstone_count, 0);
+            for key in 0..16u32 {
+                let found_ptr = lookup(map_base, (&raw const key).cast::<u8>());
+                if key % modulus == 0 {
+                    assert_eq!(*(found_ptr as *const u32), key);
+                } else {
+                    assert!(found_ptr.is_null());
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_quadratic_probing() {
+    for layout_kind in [LayoutKind::Interleaved, LayoutKind::ControlBytes] {
+        let (_, map_init) = layout(4, 4, 4, 4, 64);
+        let map_init = map_init
+            .with_probe_strategy(ProbeStrategy::Quadratic)
+            .with_probe_limit(64)
+            .with_layout_kind(layout_kind);
+
+        let map_base = unsafe { alloc(alloc_layout(&map_init)) };
+        assert!(!map_base.is_null());
+
+        unsafe {
+            init(map_base, &map_init);
+            assert_eq!(validate(map_base, map_init.total_size as usize), Ok(()));
+
+            // The triangular probe sequence reaches every bucket, so the map fills up
+            for key in 0..64u32 {
+                let value_ptr = get_or_reserve_entry(map_base, (&raw const key).cast::<u8>());
+                assert!(!value_ptr.is_null());
+                *value_ptr.cast::<u32>() = key * 10;
+            }
+            assert!(is_full(map_base));
+
+            for key in (1..64u32).step_by(2) {
+                assert!(remove(map_base, (&raw const key).cast::<u8>()));
+            }
+            assert_eq!(stats(map_base).tombstone_count, 32);
+
+            rehash_in_place(map_base);
+            assert_eq!(stats(map_base).tombstone_count, 0);
+            assert_eq!(validate(map_base, map_init.total_size as usize), Ok(()));
+            for key in 0..64u32 {
+                let found_ptr = lookup(map_base, (&raw const key).cast::<u8>());
+                if key % 2 == 0 {
+                    assert_eq!(*(found_ptr as *const u32), key * 10);
+                } else {
+                    assert!(found_ptr.is_null());
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_content_hash() {
+    let (_, small_init) = layout(4, 4, 4, 4, 16);
+    let (_, large_init) = layout(4, 4, 4, 4, 64);
+    let large_init = large_init
+        .with_seed(99)
+        .with_hash_strategy(HashStrategy::WyHash);
+
+    let small_layout = Layout::from_size_align(small_init.total_size as usize, 8).unwrap();
+    let large_layout = Layout::from_size_align(large_init.total_size as usize, 8).unwrap();
+    let small_base = unsafe { alloc(small_layout) };
+    let large_base = unsafe { alloc(large_layout) };
+    assert!(!small_base.is_null() && !large_base.is_null());
+
+    unsafe {
+        init(small_base, &small_init);
+        init(large_base, &large_init);
+        assert_eq!(content_hash(small_base), content_hash(large_base));
+
+        for key in 0..10u32 {
+            *get_or_reserve_entry(small_base, (&raw const key).cast::<u8>()).cast::<u32>() = key;
+        }
+        for key in (0..12u32).rev() {
+            *get_or_reserve_entry(large_base, (&raw const key).cast::<u8>()).cast::<u32>() = key;
+        }
+        assert_ne!(content_hash(small_base), content_hash(large_base));
+
+        for key in [10u32, 11] {
+            remove(large_base, (&raw const key).cast::<u8>());
+        }
+        assert_eq!(content_hash(small_base), content_hash(large_base));
+
+        let key: u32 = 4;
+        *lookup(large_base, (&raw const key).cast::<u8>()).cast::<u32>() = 5;
+        assert_ne!(content_hash(small_base), content_hash(large_base));
+    }
+}
+
+#[test]
+fn test_equals() {
+    let (_, a_init) = layout(4, 4, 4, 4, 16);
+    let (_, b_init) = layout(4, 4, 4, 4, 128);
+    let (_, other_init) = layout(4, 4, 8, 8, 16);
+
+    let a_layout = Layout::from_size_align(a_init.total_size as usize, 8).unwrap();
+    let b_layout = Layout::from_size_align(b_init.total_size as usize, 8).unwrap();
+    let other_layout = Layout::from_size_align(other_init.total_size as usize, 8).unwrap();
+    let a_base = unsafe { alloc(a_layout) };
+    let b_base = unsafe { alloc(b_layout) };
+    let other_base = unsafe { alloc(other_layout) };
+    assert!(!a_base.is_null() && !b_base.is_null() && !other_base.is_null());
+
+    unsafe {
+        init(a_base, &a_init);
+        init(b_base, &b_init);
+        init(other_base, &other_init);
+        assert!(equals(a_base, b_base));
+        assert!(!equals(a_base, other_base));
+
+        for key in 0..8u32 {
+            *get_or_reserve_entry(a_base, (&raw const key).cast::<u8>()).cast::<u32>() = key;
+        }
+        // Tombstones in `b` do not matter
+        for key in (0..12u32).rev() {
+            *get_or_reserve_entry(b_base, (&raw const key).cast::<u8>()).cast::<u32>() = key;
+        }
+        for key in 8..12u32 {
+            remove(b_base, (&raw const key).cast::<u8>());
+        }
+        assert!(equals(a_base, b_base));
+        assert!(equals(b_base, a_base));
+
+        let key: u32 = 3;
+        *lookup(b_base, (&raw const key).cast::<u8>()).cast::<u32>() = 4;
+        assert!(!equals(a_base, b_base));
+    }
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn test_diff() {
+    let (_, map_init) = layout(4, 4, 4, 4, 32);
+
+    let layout = Layout::from_size_align(map_init.total_size as usize, 8).unwrap();
+    let old_base = unsafe { alloc(layout) };
+    let new_base = unsafe { alloc(layout) };
+    assert!(!old_base.is_null() && !new_base.is_null());
+
+    unsafe {
+        init(old_base, &map_init);
+        init(new_base, &map_init);
+        for key in 0..20u32 {
+            *get_or_reserve_entry(old_base, (&raw const key).cast::<u8>()).cast::<u32>() = key;
+        }
+        // Remove 0..5, change 10..20 and add 20..25
+        for key in 5..25u32 {
+            let value = if key < 10 { key } else { key * 2 };
+            *get_or_reserve_entry(new_base, (&raw const key).cast::<u8>()).cast::<u32>() = value;
+        }
+
+        let mut patch = Vec::new();
+        diff(old_base, new_base, &mut patch);
+        // Five removed keys, ten changed and five added entries
+        assert_eq!(patch.len(), 8 + 5 * 5 + 15 * 9);
+
+        assert_eq!(apply_diff(old_base, &patch), Ok(()));
+        assert!(equals(old_base, new_base));
+
+        assert_eq!(
+            apply_diff(old_base, &patch[..patch.len() - 1]),
+            Err(PatchError::Truncated)
+        );
+        let mut other_patch = patch.clone();
+        other_patch[..4].copy_from_slice(&8u32.to_le_bytes());
+        assert_eq!(
+            apply_diff(old_base, &other_patch),
+            Err(PatchError::SizeMismatch)
+        );
+    }
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn test_serialize() {
+    let (_, a_init) = layout(4, 4, 2, 2, 16);
+    let (_, b_init) = layout(4, 4, 2, 2, 64);
+    let b_init = b_init.with_seed(7);
+
+    let a_layout = Layout::from_size_align(a_init.total_size as usize, 8).unwrap();
+    let b_layout = Layout::from_size_align(b_init.total_size as usize, 8).unwrap();
+    let a_base = unsafe { alloc(a_layout) };
+    let b_base = unsafe { alloc(b_layout) };
+    assert!(!a_base.is_null() && !b_base.is_null());
+
+    unsafe {
+        init(a_base, &a_init);
+        init(b_base, &b_init);
+        for key in 0..10u32 {
+            *get_or_reserve_entry(a_base, (&raw const key).cast::<u8>()).cast::<u16>() = key as u16;
+        }
+        for key in (0..12u32).rev() {
+            *get_or_reserve_entry(b_base, (&raw const key).cast::<u8>()).cast::<u16>() = key as u16;
+        }
+        for key in 10..12u32 {
+            remove(b_base, (&raw const key).cast::<u8>());
+        }
+
+        let mut a_stream = Vec::new();
+        let mut b_stream = Vec::new();
+        serialize(a_base, &mut a_stream);
+        serialize(b_base, &mut b_stream);
+        assert_eq!(a_stream.len(), 12 + 10 * 6);
+        assert_eq!(a_stream, b_stream);
+
+        // Existing entries of the target are replaced
+        let key: u32 = 99;
+        get_or_reserve_entry(b_base, (&raw const key).cast::<u8>());
+        let (_, small_init) = layout(4, 4, 2, 2, 4);
+        let small_base = alloc(a_layout);
+        init(small_base, &small_init);
+        assert_eq!(
+            deserialize_into(small_base, &a_stream),
+            Err(SnapshotError::Full)
+        );
+
+        init(a_base, &a_init);
+        assert_eq!(deserialize_into(a_base, &b_stream), Ok(()));
+        assert_eq!(deserialize_into(b_base, &b_stream), Ok(()));
+        assert!(equals(a_base, b_base));
+        assert_eq!(
+            deserialize_into(a_base, &b_stream[..b_stream.len() - 1]),
+            Err(SnapshotError::Truncated)
+        );
+    }
+}
+
+#[test]
+fn test_le_image() {
+    let (_, map_init) = layout(4, 4, 8, 8, 16);
+    let map_init = map_init
+        .with_store_hash(true)
+        .with_seed(0x0123_4567_89ab_cdef);
+    let size = map_init.total_size as usize;
+
+    let layout = Layout::from_size_align(size, 8).unwrap();
+    let map_base = unsafe { alloc(layout) };
+    let loaded_base = unsafe { alloc(layout) };
+    assert!(!map_base.is_null() && !loaded_base.is_null());
+
+    unsafe {
+        init(map_base, &map_init);
+        for key in 0..10u32 {
+            *get_or_reserve_entry(map_base, (&raw const key).cast::<u8>()).cast::<u64>() =
+                u64::from(key) * 3;
+        }
+
+        // Odd offset, the image does not need to be aligned
+        let mut buffer = vec![0u8; size + 1];
+        let image = &mut buffer[1..];
+        assert_eq!(write_le_image(map_base, image), size);
+
+        let header = &*map_base.cast::<MapHeader>();
+        assert_eq!(image[..2], header.capacity.to_le_bytes());
+        assert_eq!(image[2..4], header.element_count.to_le_bytes());
+        assert_eq!(image[16..20], header.bucket_size.to_le_bytes());
+
+        assert_eq!(load_le_image(loaded_base, size, image), Ok(()));
+        assert!(equals(map_base, loaded_base));
+        for key in 0..10u32 {
+            let value_ptr = lookup(loaded_base, (&raw const key).cast::<u8>());
+            assert_eq!(*value_ptr.cast::<u64>(), u64::from(key) * 3);
+        }
+
+        assert_eq!(
+            load_le_image(loaded_base, size, &image[..size - 1]),
+            Err(ValidationError::BufferTooSmall)
+        );
+        assert_eq!(
+            load_le_image(loaded_base, size - 1, image),
+            Err(ValidationError::BufferTooSmall)
+        );
+        image[0] ^= 0xff;
+        assert_eq!(
+            load_le_image(loaded_base, size, image),
+            Err(ValidationError::InvalidCapacity)
+        );
+    }
+}
+
+#[test]
+fn test_format_version() {
+    let (_, map_init) = layout(4, 4, 4, 4, 16);
+    let size = map_init.total_size as usize;
+    let buckets_offset = size_of::<MapHeader>();
+
+    let layout = Layout::from_size_align(size, 8).unwrap();
+    let map_base = unsafe { alloc(layout) };
+    let old_base = unsafe { alloc(layout) };
+    assert!(!map_base.is_null() && !old_base.is_null());
+
+    unsafe {
+        init(map_base, &map_init);
+        for key in 0..10u32 {
+            *get_or_reserve_entry(map_base, (&raw const key).cast::<u8>()).cast::<u32>() = key + 1;
+        }
+        assert_eq!(
+            (*map_base.cast::<MapHeader>()).format_version,
+            FORMAT_VERSION
+        );
+
+        (*map_base.cast::<MapHeader>()).format_version = FORMAT_VERSION + 1;
+        assert_eq!(
+            validate(map_base, size),
+            Err(ValidationError::UnsupportedFormatVersion)
+        );
+        (*map_base.cast::<MapHeader>()).format_version = FORMAT_VERSION;
+
+        // Version 1 maps have their buckets right after the original 24 byte header,
+        // and tombstones that are not counted in it
+        std::ptr::write_bytes(old_base, 0xaa, size);
+        std::ptr::copy_nonoverlapping(map_base, old_base, 24);
+        std::ptr::copy_nonoverlapping(
+            map_base.add(buckets_offset),
+            old_base.add(24),
+            size - buckets_offset,
+        );
+        let bucket_size = (*map_base.cast::<MapHeader>()).bucket_size as usize;
+        let empty_index = (0..16)
+            .find(|index| *map_base.add(buckets_offset + index * bucket_size) == 0)
+            .unwrap();
+        *old_base.add(24 + empty_index * bucket_size) = 1;
+        assert_eq!(
+            migrate_v1_to_v3(old_base, size - 1),
+            Err(ValidationError::BufferTooSmall)
+        );
+        assert_eq!(migrate_v1_to_v3(old_base, size), Ok(()));
+        assert!(equals(map_base, old_base));
+
+        let header = ptr::read(old_base.cast::<MapHeader>());
+        assert_eq!(header.format_version, FORMAT_VERSION);
+        assert_eq!(header.tombstone_count, 1);
+        assert_eq!(header.probe_limit, 32);
+        let key: u32 = 3;
+        assert_eq!(
+            *lookup(old_base, (&raw const key).cast::<u8>()).cast::<u32>(),
+            4
+        );
+
+        // Linear maps only get tombstones from older versions, rehashing clears them
+        rehash_in_place(old_base);
+        assert_eq!((*old_base.cast::<MapHeader>()).tombstone_count, 0);
+        assert!(equals(map_base, old_base));
+    }
+}
+
+#[test]
+fn test_stats() {
+    let (_, map_init) = layout(4, 4, 4, 4, 16);
+
+    let map_layout = Layout::from_size_align(map_init.total_size as usize, 8).unwrap();
+    let map_base = unsafe { alloc(map_layout) };
+    let scratch_base = unsafe { alloc(map_layout) };
+    assert!(!map_base.is_null() && !scratch_base.is_null());
+
+    unsafe {
+        let home = home_of(scratch_base, &map_init, 0);
+        let keys: Vec<u32> = (0..)
+            .filter(|&key| home_of(scratch_base, &map_init, key) == home)
+            .take(3)
+            .collect();
+
+        init(map_base, &map_init);
+        let empty = stats(map_base);
+        assert_eq!(empty.element_count, 0);
+        assert_eq!(empty.avg_probe_distance, 0.0);
+        assert_eq!(empty.longest_cluster, 0);
+
+        for key in &keys {
+            get_or_reserve_entry(map_base, std::ptr::from_ref(key).cast::<u8>());
+        }
+        let map_stats = stats(map_base);
+        assert_eq!(map_stats.element_count, 3);
+        assert_eq!(map_stats.capacity, map_init.capacity);
+        assert_eq!(map_stats.load_factor, 3.0 / map_init.capacity as f32);
+        assert_eq!(map_stats.max_probe_distance, 2);
+        assert_eq!(map_stats.avg_probe_distance, 1.0);
+        assert_eq!(map_stats.longest_cluster, 3);
+        assert_eq!(map_stats.distance_histogram[..4], [1, 1, 1, 0]);
+
+        // The last key shifts back into the freed bucket
+        remove(map_base, std::ptr::from_ref(&keys[1]).cast::<u8>());
+        let map_stats = stats(map_base);
+        assert_eq!(map_stats.element_count, 2);
+        assert_eq!(map_stats.tombstone_count, 0);
+        assert_eq!(map_stats.max_probe_distance, 1);
+        assert_eq!(map_stats.longest_cluster, 2);
+        assert_eq!(map_stats.distance_histogram[..4], [1, 1, 0, 0]);
+    }
+}
+
+#[test]
+fn test_accessors() {
+    let (_, map_init) = layout(4, 4, 4, 4, 10);
+
+    let map_layout = Layout::from_size_align(map_init.total_size as usize, 8).unwrap();
+    let map_base = unsafe { alloc(map_layout) };
+    assert!(!map_base.is_null());
+
+    unsafe {
+        init(map_base, &map_init);
+        assert_eq!(len(map_base), 0);
+        assert!(is_empty(map_base));
+        assert_eq!(capacity(map_base), map_init.capacity);
+        assert_eq!(logical_limit(map_base), 10);
+        assert_eq!(remaining(map_base), 10);
+        assert!(!is_full(map_base));
+
+        for key in 0..10u32 {
+            get_or_reserve_entry(map_base, (&raw const key).cast::<u8>());
+        }
+        assert_eq!(len(map_base), 10);
+        assert!(!is_empty(map_base));
+        assert_eq!(remaining(map_base), 0);
+        assert!(is_full(map_base));
+
+        let key: u32 = 4;
+        remove(map_base, (&raw const key).cast::<u8>());
+        assert_eq!(len(map_base), 9);
+        assert_eq!(remaining(map_base), 1);
+        assert!(!is_full(map_base));
+    }
+}
+
+#[test]
+fn test_try_init() {
+    let (_, map_init) = layout(4, 4, 8, 8, 16);
+    let size = map_init.total_size as usize;
+
+    let map_layout = Layout::from_size_align(size + 8, 8).unwrap();
+    let map_base = unsafe { alloc(map_layout) };
+    assert!(!map_base.is_null());
+
+    unsafe {
+        assert_eq!(try_init(map_base, &map_init, size), Ok(()));
+        assert_eq!(validate(map_base, size), Ok(()));
+        assert_eq!(
+            try_init(map_base, &map_init, size - 1),
+            Err(InitError::BufferTooSmall)
+        );
+        assert_eq!(
+            try_init(map_base.add(4), &map_init, size),
+            Err(InitError::Misaligned)
+        );
+
+        let check = |f: &dyn Fn(&mut MapInit)| {
+            let (_, mut config) = layout(4, 4, 8, 8, 16);
+            f(&mut config);
+            try_init(map_base, &config, size)
+        };
+        assert_eq!(check(&|c| c.key_size = 0), Err(InitError::KeySizeZero));
+        assert_eq!(
+            check(&|c| c.key_alignment = 3),
+            Err(InitError::InvalidAlignment)
+        );
+        assert_eq!(check(&|c| c.capacity = 12), Err(InitError::InvalidCapacity));
+        assert_eq!(
+            check(&|c| c.logical_limit = 17),
+            Err(InitError::LogicalLimitExceedsCapacity)
+        );
+        assert_eq!(
+            check(&|c| c.probe_limit = 0),
+            Err(InitError::ProbeLimitZero)
+        );
+        assert_eq!(
+            check(&|c| c.key_size = u32::MAX),
+            Err(InitError::LayoutOverflow)
+        );
+        assert_eq!(
+            check(&|c| {
+                c.key_size = 1 << 20;
+                c.capacity = 1 << 15;
+            }),
+            Err(InitError::LayoutOverflow)
+        );
+
+        // Nothing is written when the configuration is rejected
+        let key: u32 = 5;
+        try_init(map_base, &map_init, size).unwrap();
+        get_or_reserve_entry(map_base, (&raw const key).cast::<u8>());
+        assert_eq!(
+            check(&|c| c.probe_limit = 0),
+            Err(InitError::ProbeLimitZero)
+        );
+        assert!(has(map_base, (&raw const key).cast::<u8>()));
+    }
+}
+
+#[test]
+fn test_layout_checked() {
+    let (checked_bucket_layout, checked_init) = layout_checked(4, 4, 8, 8, 100).unwrap();
+    let (bucket_layout, map_init) = layout(4, 4, 8, 8, 100);
+    assert_eq!(checked_bucket_layout.bucket_size, bucket_layout.bucket_size);
+    assert_eq!(checked_bucket_layout.key_offset, bucket_layout.key_offset);
+    assert_eq!(
+        checked_bucket_layout.value_offset,
+        bucket_layout.value_offset
+    );
+    assert_eq!(checked_init.capacity, map_init.capacity);
+    assert_eq!(checked_init.total_size, map_init.total_size);
+
+    assert_eq!(
+        layout_checked(4, 3, 8, 8, 16).err(),
+        Some(LayoutError::InvalidAlignment)
+    );
+    assert_eq!(
+        layout_checked(4, 4, 8, 8, u32::MAX).err(),
+        Some(LayoutError::CapacityOverflow)
+    );
+    assert_eq!(
+        layout_checked(u32::MAX, 4, 8, 8, 16).err(),
+        Some(LayoutError::BucketSizeOverflow)
+    );
+    assert_eq!(
+        layout_checked(1 << 20, 4, 8, 8, 1 << 15).err(),
+        Some(LayoutError::TotalSizeOverflow)
+    );
+    assert!(layout_checked(4, 128, 0, 0, 16).is_ok());
+}
+
+#[test]
+fn test_wide_key_offset() {
+    let (bucket_layout, _) = layout(16, 128, 4, 4, 16);
+    assert_eq!(bucket_layout.key_offset, 128);
+
+    let (_, map_init) = layout(4, 4, 8, 8, 16);
+    let size = map_init.total_size as usize;
+    let map_layout = Layout::from_size_align(size, 8).unwrap();
+    let map_base = unsafe { alloc(map_layout) };
+    assert!(!map_base.is_null());
+
+    unsafe {
+        init(map_base, &map_init);
+        let header = &mut *map_base.cast::<MapHeader>();
+        assert_eq!(header.key_offset_wide, u32::from(header.key_offset));
+
+        // A key offset that does not fit in the byte field is read from the wide field
+        header.key_offset = 0;
+        assert_eq!(validate(map_base, size), Ok(()));
+        for key in 0..10u32 {
+            *get_or_reserve_entry(map_base, (&raw const key).cast::<u8>()).cast::<u64>() =
+                u64::from(key);
+        }
+        for key in 0..10u32 {
+            let value_ptr = lookup(map_base, (&raw const key).cast::<u8>());
+            assert_eq!(*value_ptr.cast::<u64>(), u64::from(key));
+        }
+        let mut keys: Vec<u32> = entries(map_base)
+            .map(|(key, _)| u32::from_ne_bytes(key.try_into().unwrap()))
+            .collect();
+        keys.sort_unstable();
+        assert_eq!(keys, (0..10).collect::<Vec<_>>());
+
+        (*map_base.cast::<MapHeader>()).key_offset_wide = 0;
+        assert_eq!(
+            validate(map_base, size),
+            Err(ValidationError::InvalidBucketLayout)
+        );
+    }
+}
+
+#[test]
+fn test_large_capacity() {
+    let (_, map_init) = layout(4, 4, 4, 4, 200_000);
+    // Quadratic maps leave tombstones, more than fit the low half of the count
+    let map_init = map_init.with_probe_strategy(ProbeStrategy::Quadratic);
+    assert_eq!(map_init.capacity, 1 << 18);
+    let size = map_init.total_size as usize;
+
+    let map_layout = Layout::from_size_align(size, 8).unwrap();
+    let map_base = unsafe { alloc(map_layout) };
+    assert!(!map_base.is_null());
+
+    unsafe {
+        init(map_base, &map_init);
+        let header = ptr::read(map_base.cast::<MapHeader>());
+        assert_eq!((header.capacity, header.capacity_high), (0, 4));
+        assert_eq!(capacity(map_base), 1 << 18);
+        assert_eq!(logical_limit(map_base), 200_000);
+
+        for key in 0..200_000u32 {
+            *get_or_reserve_entry(map_base, (&raw const key).cast::<u8>()).cast::<u32>() = !key;
+        }
+        assert_eq!(len(map_base), 200_000);
+        assert!(is_full(map_base));
+        assert_eq!(validate(map_base, size), Ok(()));
+
+        for key in (0..200_000u32).step_by(2) {
+            assert!(remove(map_base, (&raw const key).cast::<u8>()));
+        }
+        assert_eq!(len(map_base), 100_000);
+        assert_eq!(stats(map_base).tombstone_count, 100_000);
+        assert_eq!(validate(map_base, size), Ok(()));
+        for key in 0..200_000u32 {
+            let value_ptr = lookup(map_base, (&raw const key).cast::<u8>());
+            if key % 2 == 0 {
+                assert!(value_ptr.is_null());
+            } else {
+                assert_eq!(*value_ptr.cast::<u32>(), !key);
+            }
+        }
+
+        // Buckets beyond the first 65536 are reached by the cursor
+        assert_eq!(entries(map_base).count(), 100_000);
+        let (key_ptr, _, index) = find_next_valid_entry(map_base, (1 << 18) - 1);
+        if key_ptr.is_null() {
+            assert_eq!(index, u32::MAX);
+        } else {
+            assert_eq!(index, (1 << 18) - 1);
+        }
+
+        clear(map_base);
+        assert!(is_empty(map_base));
+        assert_eq!(validate(map_base, size), Ok(()));
+    }
+}
+
+#[test]
+fn test_alloc_layout() {
+    assert_eq!(required_alignment(1, 1), 8);
+    assert_eq!(required_alignment(16, 4), 16);
+    assert_eq!(required_alignment(4, 64), 64);
+
+    let (_, map_init) = layout(16, 16, 4, 4, 16);
+    let map_layout = alloc_layout(&map_init);
+    assert_eq!(map_layout.size(), map_init.total_size as usize);
+    assert_eq!(map_layout.align(), 16);
+
+    let map_base = unsafe { alloc(map_layout) };
+    assert!(!map_base.is_null());
+
+    unsafe {
+        assert_eq!(try_init(map_base, &map_init, map_layout.size()), Ok(()));
+        let key = 7u128;
+        *get_or_reserve_entry(map_base, (&raw const key).cast::<u8>()).cast::<u32>() = 70;
+        assert_eq!(
+            *lookup(map_base, (&raw const key).cast::<u8>()).cast::<u32>(),
+            70
+        );
+        let view =
+            MapView::<u128, u32>::new(std::slice::from_raw_parts(map_base, map_layout.size()))
+                .unwrap();
+        assert_eq!(view.get(&key), Some(&70));
+    }
+}
+
+#[test]
+fn test_init_in_slice() {
+    let (_, map_init) = layout(4, 4, 4, 4, 16);
+    let size = map_init.total_size as usize;
+
+    // u64 storage keeps the bytes aligned for the header
+    let mut storage = vec![0u64; size.div_ceil(8) + 1];
+    let (_, bytes, _) = unsafe { storage.align_to_mut::<u8>() };
+
+    assert_eq!(
+        init_in_slice(&mut bytes[..size - 1], &map_init),
+        Err(InitError::BufferTooSmall)
+    );
+    assert_eq!(
+        init_in_slice(&mut bytes[4..size + 4], &map_init),
+        Err(InitError::Misaligned)
+    );
+    assert_eq!(init_in_slice(&mut bytes[..size], &map_init), Ok(()));
+
+    let mut view = MapViewMut::<u32, u32>::new(&mut bytes[..size]).unwrap();
+    assert_eq!(view.insert(3, 30), Ok(None));
+    assert_eq!(view.get(&3), Some(&30));
+}
+
+#[test]
+fn test_entry() {
+    for strategy in [
+        ProbeStrategy::Linear,
+        ProbeStrategy::RobinHood,
+        ProbeStrategy::Quadratic,
+    ] {
+        let (_, map_init) = layout(4, 4, 4, 4, 32);
+        let map_init = map_init.with_probe_strategy(strategy);
+
+        let layout = Layout::from_size_align(map_init.total_size as usize, 8).unwrap();
+        let map_base = unsafe { alloc(layout) };
+        assert!(!map_base.is_null());
+
+        unsafe {
+            init(map_base, &map_init);
+
+            // Count occurrences, inserting a starting count for new keys
+            for key in [1u32, 2, 1, 3, 1, 2] {
+                match entry(map_base, (&raw const key).cast::<u8>()).unwrap() {
+                    Entry::Occupied(occupied) => *occupied.value_ptr().cast::<u32>() += 1,
+                    Entry::Vacant(vacant) => {
+                        let one: u32 = 1;
+                        vacant.insert((&raw const one).cast::<u8>());
+                    }
+                }
+            }
+            assert_eq!(len(map_base), 3);
+            for (key, count) in [(1u32, 3u32), (2, 2), (3, 1)] {
+                assert_eq!(
+                    *lookup(map_base, (&raw const key).cast::<u8>()).cast::<u32>(),
+                    count
+                );
+            }
+
+            let key: u32 = 2;
+            let Some(Entry::Occupied(occupied)) = entry(map_base, (&raw const key).cast::<u8>())
+            else {
+                panic!("key 2 should be occupied");
+            };
+            assert_eq!(*occupied.key_ptr().cast::<u32>(), 2);
+            occupied.remove();
+            assert!(!has(map_base, (&raw const key).cast::<u8>()));
+            assert_eq!(len(map_base), 2);
+
+            // Fill the map, after which absent keys have no entry
+            for key in 10..100u32 {
+                if let Some(Entry::Vacant(vacant)) = entry(map_base, (&raw const key).cast::<u8>())
+                {
+                    vacant.insert((&raw const key).cast::<u8>());
+                }
+            }
+            assert!(is_full(map_base));
+            let absent: u32 = 1000;
+            assert!(entry(map_base, (&raw const absent).cast::<u8>()).is_none());
+            for key in 10..100u32 {
+                let found_ptr = lookup(map_base, (&raw const key).cast::<u8>());
+                assert!(found_ptr.is_null() || *found_ptr.cast::<u32>() == key);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_get_or_insert_with() {
+    let (_, map_init) = layout(4, 4, 8, 8, 16);
+    let layout = Layout::from_size_align(map_init.total_size as usize, 8).unwrap();
+    let map_base = unsafe { alloc(layout) };
+    assert!(!map_base.is_null());
+
+    unsafe {
+        // Garbage in the buckets must not leak into new values
+        map_base.write_bytes(0xcd, map_init.total_size as usize);
+        init(map_base, &map_init);
+
+        let key: u32 = 7;
+        let value_ptr = get_or_insert_zeroed(map_base, (&raw const key).cast::<u8>());
+        assert_eq!(*value_ptr.cast::<u64>(), 0);
+        *value_ptr.cast::<u64>() = 70;
+        assert_eq!(
+            *get_or_insert_zeroed(map_base, (&raw const key).cast::<u8>()).cast::<u64>(),
+            70
+        );
+
+        let mut calls = 0;
+        for _ in 0..2 {
+            let other: u32 = 8;
+            let value_ptr = get_or_insert_with(map_base, (&raw const other).cast::<u8>(), |p| {
+                calls += 1;
+                *p.cast::<u64>() = 80;
+            });
+            assert_eq!(*value_ptr.cast::<u64>(), 80);
+        }
+        assert_eq!(calls, 1);
+        assert_eq!(len(map_base), 2);
+    }
+}
+
+#[test]
+fn test_insert_batch() {
+    let (_, map_init) = layout(4, 4, 8, 8, 64);
+    let layout = Layout::from_size_align(map_init.total_size as usize, 8).unwrap();
+    let map_base = unsafe { alloc(layout) };
+    assert!(!map_base.is_null());
+
+    let keys: Vec<u32> = (0..20).collect();
+    let values: Vec<u64> = keys.iter().map(|&key| u64::from(key) * 10).collect();
+
+    unsafe {
+        init(map_base, &map_init);
+        let inserted = insert_batch(
+            map_base,
+            keys.as_ptr().cast::<u8>(),
+            values.as_ptr().cast::<u8>(),
+            keys.len(),
+        );
+        assert_eq!(inserted, 20);
+        assert_eq!(len(map_base), 20);
+        for key in &keys {
+            let value_ptr = lookup(map_base, (&raw const *key).cast::<u8>());
+            assert_eq!(*value_ptr.cast::<u64>(), u64::from(*key) * 10);
+        }
+
+        // Stops at the first key that does not fit
+        let capacity = capacity(map_base) as usize;
+        let many: Vec<u32> = (0..capacity as u32 + 10).collect();
+        let many_values = vec![0u64; many.len()];
+        let inserted = insert_batch(
+            map_base,
+            many.as_ptr().cast::<u8>(),
+            many_values.as_ptr().cast::<u8>(),
+            many.len(),
+        );
+        assert!(inserted < many.len());
+        assert_eq!(len(map_base) as usize, inserted);
+        assert!(!has(map_base, (&raw const many[inserted]).cast::<u8>()));
+    }
+}
+
+#[test]
+fn test_lookup_many() {
+    for logical_limit in [4, 64] {
+        let (_, map_init) = layout(4, 4, 4, 4, logical_limit);
+        let layout = Layout::from_size_align(map_init.total_size as usize, 8).unwrap();
+        let map_base = unsafe { alloc(layout) };
+        assert!(!map_base.is_null());
+
+        unsafe {
+            init(map_base, &map_init);
+            let inserted = logical_limit / 2;
+            for key in 0..inserted {
+                let value = key + 100;
+                insert(
+                    map_base,
+                    (&raw const key).cast::<u8>(),
+                    (&raw const value).cast::<u8>(),
+                );
+            }
+
+            // Every other key is missing
+            let keys: Vec<u32> = (0..logical_limit).step_by(2).collect();
+            let mut out_ptrs = vec![ptr::null_mut::<u8>(); keys.len()];
+            let found = lookup_many(
+                map_base,
+                keys.as_ptr().cast::<u8>(),
+                keys.len(),
+                out_ptrs.as_mut_ptr(),
+            );
+            assert_eq!(found as u32, inserted / 2);
+            for (key, value_ptr) in keys.iter().zip(&out_ptrs) {
+                if *key < inserted {
+                    assert_eq!(*value_ptr.cast::<u32>(), key + 100);
+                } else {
+                    assert!(value_ptr.is_null());
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_key_compare_lengths() {
+    for key_size in [1u32, 3, 7, 8, 12, 16, 17, 24, 31, 32, 33, 48, 64, 70] {
+        // Tiny maps compare the key against every entry
+        let (_, map_init) = layout(key_size, 1, 4, 4, 4);
+        let layout = Layout::from_size_align(map_init.total_size as usize, 8).unwrap();
+        let map_base = unsafe { alloc(layout) };
+        assert!(!map_base.is_null());
+
+        let key: Vec<u8> = (0..key_size as u8).map(|i| i.wrapping_mul(37)).collect();
+        unsafe {
+            init(map_base, &map_init);
+            let value: u32 = key_size;
+            insert(map_base, key.as_ptr(), (&raw const value).cast::<u8>());
+            assert_eq!(*lookup(map_base, key.as_ptr()).cast::<u32>(), key_size);
+
+            // A difference in any byte, including the last chunk, must be noticed
+            for position in 0..key.len() {
+                let mut other = key.clone();
+                other[position] ^= 0x40;
+                assert!(
+                    !has(map_base, other.as_ptr()),
+                    "{key_size} bytes, at {position}"
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn test_control_bytes_layout() {
+    let (interleaved, _) = layout(4, 4, 4, 4, 64);
+    for strategy in [
+        ProbeStrategy::Linear,
+        ProbeStrategy::RobinHood,
+        ProbeStrategy::Quadratic,
+    ] {
+        for fragments in [false, true] {
+            let (_, map_init) = layout(4, 4, 4, 4, 64);
+            let map_init = map_init
+                .with_layout_kind(LayoutKind::ControlBytes)
+                .with_probe_strategy(strategy)
+                .with_hash_fragments(fragments);
+            // No status byte in the buckets
+            assert!(bucket_layout(&map_init).bucket_size < interleaved.bucket_size);
+
+            let size = map_init.total_size as usize;
+            let layout = Layout::from_size_align(size, 8).unwrap();
+            let map_base = unsafe { alloc(layout) };
+            assert!(!map_base.is_null());
+
+            unsafe {
+                init(map_base, &map_init);
+                assert_eq!(layout_kind(map_base), LayoutKind::ControlBytes);
+
+                let mut inserted = Vec::new();
+                for key in 0..56u32 {
+                    let value = key * 3;
+                    let result = insert(
+                        map_base,
+                        (&raw const key).cast::<u8>(),
+                        (&raw const value).cast::<u8>(),
+                    );
+                    if result == InsertResult::Inserted {
+                        inserted.push(key);
+                    }
+                }
+                assert!(inserted.len() > 48);
+                assert_eq!(validate(map_base, size), Ok(()));
+
+                for key in 0..100u32 {
+                    let found_ptr = lookup(map_base, (&raw const key).cast::<u8>());
+                    if inserted.contains(&key) {
+                        assert_eq!(*found_ptr.cast::<u32>(), key * 3);
+                    } else {
+                        assert!(found_ptr.is_null());
+                    }
+                }
+
+                // Remove every other key, the rest must stay reachable
+                for key in inserted.iter().step_by(2) {
+                    assert!(remove(map_base, (&raw const *key).cast::<u8>()));
+                }
+                for (position, key) in inserted.iter().enumerate() {
+                    assert_eq!(
+                        has(map_base, (&raw const *key).cast::<u8>()),
+                        position % 2 == 1
+                    );
+                }
+                assert_eq!(validate(map_base, size), Ok(()));
+
+                rehash_in_place(map_base);
+                assert_eq!(validate(map_base, size), Ok(()));
+                let mut keys: Vec<u32> = entries(map_base)
+                    .map(|(key, _)| u32::from_ne_bytes(key.try_into().unwrap()))
+                    .collect();
+                keys.sort_unstable();
+                let expected: Vec<u32> = inserted.iter().copied().skip(1).step_by(2).collect();
+                assert_eq!(keys, expected);
+
+                clear(map_base);
+                assert!(is_empty(map_base));
+                assert_eq!(validate(map_base, size), Ok(()));
+            }
+        }
+    }
+}
+
+#[test]
+fn test_integer_hash() {
+    for key_size in [4u32, 8] {
+        let (_, map_init) = layout(key_size, 4, 4, 4, 1024);
+        let map_init = map_init.with_hash_strategy(HashStrategy::Integer);
+        let size = map_init.total_size as usize;
+        let layout = Layout::from_size_align(size, 8).unwrap();
+        let map_base = unsafe { alloc(layout) };
+        assert!(!map_base.is_null());
+
+        unsafe {
+            init(map_base, &map_init);
+            // Sequential handles spread out evenly
+            // Keys are the first `key_size` bytes of the little-endian handle
+            for handle in 0..768u64 {
+                let key = handle.to_le_bytes();
+                let value = handle as u32;
+                let result = insert(map_base, key.as_ptr(), (&raw const value).cast::<u8>());
+                assert_eq!(result, InsertResult::Inserted);
+            }
+            assert_eq!(validate(map_base, size), Ok(()));
+            assert!(avg_lookup_probes(map_base) < 2.0);
+
+            for handle in 0..768u64 {
+                let found_ptr = lookup(map_base, handle.to_le_bytes().as_ptr());
+                assert_eq!(*found_ptr.cast::<u32>(), handle as u32);
+            }
+        }
+    }
+
+    // Other key sizes hash like Fx
+    let (_, integer_init) = layout(3, 1, 4, 4, 16);
+    let integer_init = integer_init.with_hash_strategy(HashStrategy::Integer);
+    let (_, fx_init) = layout(3, 1, 4, 4, 16);
+    let layout = Layout::from_size_align(fx_init.total_size as usize, 8).unwrap();
+    let integer_base = unsafe { alloc(layout) };
+    let fx_base = unsafe { alloc(layout) };
+    assert!(!integer_base.is_null() && !fx_base.is_null());
+
+    unsafe {
+        init(integer_base, &integer_init);
+        init(fx_base, &fx_init);
+        let key = [1u8, 2, 3];
+        assert_eq!(
+            key_hash(integer_base, key.as_ptr()),
+            key_hash(fx_base, key.as_ptr())
+        );
+    }
+}
+
+#[test]
+fn test_fibonacci_index() {
+    let (_, map_init) = layout(4, 4, 4, 4, 512);
+    let map_init = map_init.with_fibonacci_index(true);
+    let size = map_init.total_size as usize;
+    let layout = Layout::from_size_align(size, 8).unwrap();
+    let map_base = unsafe { alloc(layout) };
+    assert!(!map_base.is_null());
+
+    unsafe {
+        init(map_base, &map_init);
+
+        // A lone key is stored in its home bucket
+        let key = 77u32;
+        let hash = key_hash(map_base, (&raw const key).cast::<u8>());
+        let handle = reserve_handle(map_base, (&raw const key).cast::<u8>()).unwrap();
+        assert_eq!(home_bucket(map_base, hash), handle.index);
+        assert!(remove(map_base, (&raw const key).cast::<u8>()));
+
+        // Hashes that only differ in their low bits still get different home buckets
+        assert_ne!(home_bucket(map_base, 1), home_bucket(map_base, 2));
+
+        for key in 0..400u32 {
+            let value = key * 3;
+            let result = insert(
+                map_base,
+                (&raw const key).cast::<u8>(),
+                (&raw const value).cast::<u8>(),
+            );
+            assert_eq!(result, InsertResult::Inserted);
+        }
+        assert_eq!(validate(map_base, size), Ok(()));
+
+        for key in (0..400u32).step_by(2) {
+            assert!(remove(map_base, (&raw const key).cast::<u8>()));
+        }
+        for key in 0..400u32 {
+            let found_ptr = lookup(map_base, (&raw const key).cast::<u8>());
+            if key % 2 == 0 {
+                assert!(found_ptr.is_null());
+            } else {
+                assert_eq!(*found_ptr.cast::<u32>(), key * 3);
+            }
+        }
+        assert_eq!(validate(map_base, size), Ok(()));
+    }
+}
+
+#[test]
+fn test_spill_region() {
+    for layout_kind in [
+        LayoutKind::Interleaved,
+        LayoutKind::ControlBytes,
+        LayoutKind::StructOfArrays,
+    ] {
+        let (_, map_init) = layout(4, 4, 4, 4, 16);
+        let map_init = map_init
+            .with_probe_limit(1)
+            .with_store_hash(true)
+            .with_layout_kind(layout_kind)
+            .with_spill_capacity(16);
+        let size = map_init.total_size as usize;
+        let layout = Layout::from_size_align(size, 8).unwrap();
+        let map_base = unsafe { alloc(layout) };
+        let plain_base = unsafe { alloc(layout) };
+        let image_base = unsafe { alloc(layout) };
+        assert!(!map_base.is_null() && !plain_base.is_null() && !image_base.is_null());
+
+        unsafe {
+            init(map_base, &map_init);
+            init(plain_base, &map_init.with_spill_capacity(0));
+
+            // With a probe limit of one, every key that misses its home bucket spills
+            let mut plain_full = 0;
+            for key in 0..16u32 {
+                let value = key + 100;
+                let key_ptr = (&raw const key).cast::<u8>();
+                let value_ptr = (&raw const value).cast::<u8>();
+                assert_eq!(insert(map_base, key_ptr, value_ptr), InsertResult::Inserted);
+                if insert(plain_base, key_ptr, value_ptr) == InsertResult::Full {
+                    plain_full += 1;
+                }
+            }
+            assert_ne!(plain_full, 0);
+            assert_eq!(len(map_base), 16);
+            assert_eq!(entries(map_base).count(), 16);
+            assert_eq!(validate(map_base, size), Ok(()));
+            assert!(stats(map_base).max_probe_distance >= 1);
+
+            for key in 0..16u32 {
+                let key_ptr = (&raw const key).cast::<u8>();
+                assert_eq!(*lookup(map_base, key_ptr).cast::<u32>(), key + 100);
+
+                // Handles of spilled entries resolve too
+                let handle = reserve_handle(map_base, key_ptr).unwrap();
+                assert_eq!(resolve(map_base, handle), Some(lookup(map_base, key_ptr)));
+            }
+
+            // Spilled entries survive a little-endian image
+            let mut image = vec![0u8; size];
+            assert_eq!(write_le_image(map_base, &mut image), size);
+            assert_eq!(load_le_image(image_base, size, &image), Ok(()));
+            assert!(equals(map_base, image_base));
+
+            for key in (0..16u32).step_by(2) {
+                assert!(remove(map_base, (&raw const key).cast::<u8>()));
+            }
+            retain(map_base, |key_ptr, _| *key_ptr.cast::<u32>() % 4 != 1);
+            rehash_in_place(map_base);
+            assert_eq!(validate(map_base, size), Ok(()));
+            for key in 0..16u32 {
+                let found_ptr = lookup(map_base, (&raw const key).cast::<u8>());
+                if key % 4 == 3 {
+                    assert_eq!(*found_ptr.cast::<u32>(), key + 100);
+                } else {
+                    assert!(found_ptr.is_null());
+                }
+            }
+
+            clear(map_base);
+            assert!(is_empty(map_base));
+            assert_eq!(entries(map_base).count(), 0);
+            assert_eq!(validate(map_base, size), Ok(()));
+        }
+    }
+}
+
+#[test]
+fn test_replace_with() {
+    let (_, source_init) = layout(4, 4, 4, 4, 8);
+    let (_, target_init) = layout(4, 4, 4, 4, 16);
+    let (_, small_init) = layout(4, 4, 4, 4, 2);
+    let source_layout = Layout::from_size_align(source_init.total_size as usize, 8).unwrap();
+    let target_layout = Layout::from_size_align(target_init.total_size as usize, 8).unwrap();
+    let small_layout = Layout::from_size_align(small_init.total_size as usize, 8).unwrap();
+    let source_base = unsafe { alloc(source_layout) };
+    let target_base = unsafe { alloc(target_layout) };
+    let small_base = unsafe { alloc(small_layout) };
+    assert!(!source_base.is_null() && !target_base.is_null() && !small_base.is_null());
+
+    unsafe {
+        init(source_base, &source_init);
+        init(target_base, &target_init);
+        init(small_base, &small_init);
+
+        for key in 0..3u32 {
+            let value = key * 100;
+            insert(
+                source_base,
+                (&raw const key).cast::<u8>(),
+                (&raw const value).cast::<u8>(),
+            );
+        }
+        for key in 2..6u32 {
+            let value = 1;
+            insert(
+                target_base,
+                (&raw const key).cast::<u8>(),
+                (&raw const value).cast::<u8>(),
+            );
+        }
+
+        assert_eq!(replace_with(target_base, source_base), Ok(()));
+        assert!(equals(target_base, source_base));
+        assert_eq!(len(target_base), 3);
+
+        // A source that does not fit leaves the target as it was
+        let key = 9u32;
+        insert(
+            small_base,
+            (&raw const key).cast::<u8>(),
+            (&raw const key).cast::<u8>(),
+        );
+        assert_eq!(
+            replace_with(small_base, source_base),
+            Err(GrowError::InsufficientCapacity)
+        );
+        assert_eq!(
+            *lookup(small_base, (&raw const key).cast::<u8>()).cast::<u32>(),
+            9
+        );
+    }
+}
+
+#[test]
+fn test_overwrite_between_layouts() {
+    // Tightly packed source, padded target with stored hashes
+    let (_, source_init) = layout(2, 2, 8, 4, 8);
+    let (_, target_init) = layout(2, 2, 8, 8, 16);
+    let target_init = target_init.with_store_hash(true);
+    let source_layout = Layout::from_size_align(source_init.total_size as usize, 8).unwrap();
+    let target_layout = Layout::from_size_align(target_init.total_size as usize, 8).unwrap();
+    let source_base = unsafe { alloc(source_layout) };
+    let target_base = unsafe { alloc(target_layout) };
+    assert!(!source_base.is_null() && !target_base.is_null());
+
+    unsafe {
+        init(source_base, &source_init);
+        init(target_base, &target_init);
+        let source_header = &*source_base.cast::<MapHeader>();
+        let target_header = &*target_base.cast::<MapHeader>();
+        assert_ne!(source_header.bucket_size, target_header.bucket_size);
+        assert_ne!(source_header.value_offset, target_header.value_offset);
+
+        for key in 0..6u16 {
+            let value = [u32::from(key), u32::from(key) * 10];
+            insert(
+                source_base,
+                (&raw const key).cast::<u8>(),
+                value.as_ptr().cast::<u8>(),
+            );
+        }
+
+        assert!(overwrite(target_base, source_base));
+        assert!(equals(target_base, source_base));
+        assert_eq!(
+            validate(target_base, target_init.total_size as usize),
+            Ok(())
+        );
+        let key = 5u16;
+        let found_ptr = lookup(target_base, (&raw const key).cast::<u8>());
+        assert_eq!(*found_ptr.cast::<[u32; 2]>(), [5, 50]);
+    }
+}
+
+#[test]
+fn test_merge() {
+    let (_, map_init) = layout(4, 4, 4, 4, 16);
+    let (_, small_init) = layout(4, 4, 4, 4, 4);
+    let map_layout = Layout::from_size_align(map_init.total_size as usize, 8).unwrap();
+    let small_layout = Layout::from_size_align(small_init.total_size as usize, 8).unwrap();
+    let target_base = unsafe { alloc(map_layout) };
+    let source_base = unsafe { alloc(map_layout) };
+    let small_base = unsafe { alloc(small_layout) };
+    assert!(!target_base.is_null() && !source_base.is_null() && !small_base.is_null());
+
+    let value_of = |base: *mut u8, key: u32| unsafe {
+        *lookup(base, (&raw const key).cast::<u8>()).cast::<u32>()
+    };
+
+    unsafe {
+        init(target_base, &map_init);
+        init(source_base, &map_init);
+        for key in 0..4u32 {
+            let value = 1;
+            insert(
+                target_base,
+                (&raw const key).cast::<u8>(),
+                (&raw const value).cast::<u8>(),
+            );
+        }
+        for key in 2..6u32 {
+            let value = 10;
+            insert(
+                source_base,
+                (&raw const key).cast::<u8>(),
+                (&raw const value).cast::<u8>(),
+            );
+        }
+
+        assert_eq!(
+            merge(target_base, source_base, MergePolicy::KeepTarget),
+            Ok(())
+        );
+        assert_eq!(len(target_base), 6);
+        assert_eq!(value_of(target_base, 2), 1);
+        assert_eq!(value_of(target_base, 5), 10);
+
+        assert_eq!(
+            merge(target_base, source_base, MergePolicy::KeepSource),
+            Ok(())
+        );
+        assert_eq!(value_of(target_base, 2), 10);
+        assert_eq!(value_of(target_base, 0), 1);
+
+        let mut sum = |target_ptr: *mut u8, source_ptr: *const u8| {
+            *target_ptr.cast::<u32>() += *source_ptr.cast::<u32>();
+        };
+        assert_eq!(
+            merge(target_base, source_base, MergePolicy::Combine(&mut sum)),
+            Ok(())
+        );
+        assert_eq!(value_of(target_base, 3), 20);
+        assert_eq!(value_of(target_base, 1), 1);
+        assert_eq!(len(target_base), 6);
+
+        // The target stops at its logical limit
+        init(small_base, &small_init);
+        assert_eq!(
+            merge(small_base, target_base, MergePolicy::KeepTarget),
+            Err(GrowError::InsufficientCapacity)
+        );
+        assert_eq!(len(small_base), 4);
+    }
+}
+
+#[test]
+fn test_for_each_missing_and_common() {
+    let (_, a_init) = layout(4, 4, 4, 4, 16);
+    let (_, b_init) = layout(4, 4, 2, 2, 32);
+    let a_layout = Layout::from_size_align(a_init.total_size as usize, 8).unwrap();
+    let b_layout = Layout::from_size_align(b_init.total_size as usize, 8).unwrap();
+    let a_base = unsafe { alloc(a_layout) };
+    let b_base = unsafe { alloc(b_layout) };
+    assert!(!a_base.is_null() && !b_base.is_null());
+
+    unsafe {
+        init(a_base, &a_init);
+        init(b_base, &b_init);
+        for key in 0..8u32 {
+            let value = key * 10;
+            insert(
+                a_base,
+                (&raw const key).cast::<u8>(),
+                (&raw const value).cast::<u8>(),
+            );
+        }
+        for key in 5..12u32 {
+            let value = key as u16;
+            insert(
+                b_base,
+                (&raw const key).cast::<u8>(),
+                (&raw const value).cast::<u8>(),
+            );
+        }
+
+        let mut missing = Vec::new();
+        for_each_missing_in(a_base, b_base, |key_ptr, value_ptr| {
+            missing.push((*key_ptr.cast::<u32>(), *value_ptr.cast::<u32>()));
+        });
+        missing.sort_unstable();
+        assert_eq!(missing, [(0, 0), (1, 10), (2, 20), (3, 30), (4, 40)]);
+
+        let mut common = Vec::new();
+        for_each_common(a_base, b_base, |key_ptr, a_value_ptr, b_value_ptr| {
+            common.push((
+                *key_ptr.cast::<u32>(),
+                *a_value_ptr.cast::<u32>(),
+                *b_value_ptr.cast::<u16>(),
+            ));
+        });
+        common.sort_unstable();
+        assert_eq!(common, [(5, 50, 5), (6, 60, 6), (7, 70, 7)]);
+    }
+}
+
+#[test]
+fn test_journal() {
+    let (_, map_init) = layout(4, 4, 4, 4, 16);
+    let map_layout = Layout::from_size_align(map_init.total_size as usize, 8).unwrap();
+    let map_base = unsafe { alloc(map_layout) };
+    let replica_base = unsafe { alloc(map_layout) };
+    assert!(!map_base.is_null() && !replica_base.is_null());
+
+    unsafe {
+        init(map_base, &map_init);
+        init(replica_base, &map_init);
+
+        let mut buffer = [0u8; 256];
+        let mut journal = Journal::new(map_base, &mut buffer);
+        assert!(journal.is_empty());
+
+        for key in 0..6u32 {
+            let value = key * 3;
+            let result = insert_journaled(
+                map_base,
+                (&raw const key).cast::<u8>(),
+                (&raw const value).cast::<u8>(),
+                &mut journal,
+            );
+            assert_eq!(result, InsertResult::Inserted);
+        }
+        let key = 2u32;
+        let value = 99u32;
+        insert_journaled(
+            map_base,
+            (&raw const key).cast::<u8>(),
+            (&raw const value).cast::<u8>(),
+            &mut journal,
+        );
+        let key = 4u32;
+        assert!(remove_journaled(
+            map_base,
+            (&raw const key).cast::<u8>(),
+            &mut journal
+        ));
+        let key = 40u32;
+        assert!(!remove_journaled(
+            map_base,
+            (&raw const key).cast::<u8>(),
+            &mut journal
+        ));
+
+        // 8 header bytes, 7 insert records and 1 remove record
+        assert_eq!(journal.as_bytes().len(), 8 + 7 * 9 + 5);
+        assert!(!journal.overflowed());
+        assert_eq!(apply_journal(replica_base, journal.as_bytes()), Ok(()));
+        assert!(equals(map_base, replica_base));
+
+        // Only the mutations after a clear are replayed
+        journal.clear();
+        let key = 0u32;
+        remove_journaled(map_base, (&raw const key).cast::<u8>(), &mut journal);
+        assert_eq!(apply_journal(replica_base, journal.as_bytes()), Ok(()));
+        assert!(equals(map_base, replica_base));
+
+        // A full buffer stops recording
+        let mut small_buffer = [0u8; 20];
+        let mut small_journal = Journal::new(map_base, &mut small_buffer);
+        for key in 10..12u32 {
+            insert_journaled(
+                map_base,
+                (&raw const key).cast::<u8>(),
+                (&raw const key).cast::<u8>(),
+                &mut small_journal,
+            );
+        }
+        assert!(small_journal.overflowed());
+        assert_eq!(small_journal.as_bytes().len(), 8 + 9);
+    }
+}
+
+#[test]
+fn test_dirty_flags() {
+    for probe_strategy in [
+        ProbeStrategy::Linear,
+        ProbeStrategy::RobinHood,
+        ProbeStrategy::Quadratic,
+    ] {
+        let (_, map_init) = layout(4, 4, 4, 4, 16);
+        let map_init = map_init
+            .with_probe_strategy(probe_strategy)
+            .with_probe_limit(2)
+            .with_spill_capacity(8);
+        let clean_size = map_init.total_size as usize;
+        let map_init = map_init.with_dirty_flags(true);
+        let size = map_init.total_size as usize;
+        assert_eq!(size, clean_size + 3);
+        let layout = Layout::from_size_align(size, 8).unwrap();
+        let map_base = unsafe { alloc(layout) };
+        assert!(!map_base.is_null());
+
+        let dirty_keys = |map_base: *const u8| {
+            let mut keys = Vec::new();
+            unsafe {
+                for_each_dirty(map_base, |key_ptr, value_ptr| {
+                    assert_eq!(*value_ptr.cast::<u32>(), *key_ptr.cast::<u32>() + 100);
+                    keys.push(*key_ptr.cast::<u32>());
+                });
+            }
+            keys.sort_unstable();
+            keys
+        };
+
+        unsafe {
+            init(map_base, &map_init);
+            assert_eq!(validate(map_base, size), Ok(()));
+
+            // Plain inserts are not tracked
+            for key in 0..12u32 {
+                let value = key + 100;
+                insert(
+                    map_base,
+                    (&raw const key).cast::<u8>(),
+                    (&raw const value).cast::<u8>(),
+                );
+            }
+            assert!(dirty_keys(map_base).is_empty());
+
+            for key in [3u32, 14, 15] {
+                let value = key + 100;
+                insert_dirty(
+                    map_base,
+                    (&raw const key).cast::<u8>(),
+                    (&raw const value).cast::<u8>(),
+                );
+            }
+            let key = 7u32;
+            let value_ptr = lookup(map_base, (&raw const key).cast::<u8>());
+            mark_dirty(map_base, value_ptr);
+            assert_eq!(dirty_keys(map_base), [3, 7, 14, 15]);
+
+            // Flags move with their entries, removed entries are not reported
+            for key in [0u32, 1, 2, 14] {
+                remove(map_base, (&raw const key).cast::<u8>());
+            }
+            rehash_in_place(map_base);
+            assert_eq!(validate(map_base, size), Ok(()));
+            assert_eq!(dirty_keys(map_base), [3, 7, 15]);
+
+            // A removed entry does not pass its flag on to the next one in its bucket
+            let key = 14u32;
+            let value = key + 100;
+            insert(
+                map_base,
+                (&raw const key).cast::<u8>(),
+                (&raw const value).cast::<u8>(),
+            );
+            assert_eq!(dirty_keys(map_base), [3, 7, 15]);
+
+            clear_dirty_flags(map_base);
+            assert!(dirty_keys(map_base).is_empty());
+        }
+    }
+}
+
+#[test]
+fn test_mutation_generation() {
+    let (_, map_init) = layout(4, 4, 4, 4, 16);
+    let map_init = map_init.with_spill_capacity(4).with_dirty_flags(true);
+    let size = map_init.total_size as usize;
+    let layout = Layout::from_size_align(size, 8).unwrap();
+    let map_base = unsafe { alloc(layout) };
+    let old_base = unsafe { alloc(layout) };
+    assert!(!map_base.is_null() && !old_base.is_null());
+
+    unsafe {
+        init(map_base, &map_init);
+        assert_eq!(generation(map_base), 0);
+
+        for key in 0..8u32 {
+            let value = key + 100;
+            insert(
+                map_base,
+                (&raw const key).cast::<u8>(),
+                (&raw const value).cast::<u8>(),
+            );
+        }
+        assert_eq!(generation(map_base), 8);
+
+        // Replacing a value leaves the entries where they are
+        let key = 3u32;
+        let value = 7u32;
+        insert(
+            map_base,
+            (&raw const key).cast::<u8>(),
+            (&raw const value).cast::<u8>(),
+        );
+        assert_eq!(generation(map_base), 8);
+
+        assert!(remove(map_base, (&raw const key).cast::<u8>()));
+        assert!(!remove(map_base, (&raw const key).cast::<u8>()));
+        assert_eq!(generation(map_base), 9);
+
+        // A version 2 map has a 64 byte header, with everything after it moved down
+        let header_len = size_of::<MapHeader>();
+        std::ptr::copy_nonoverlapping(map_base, old_base, 64);
+        std::ptr::copy_nonoverlapping(
+            map_base.add(header_len),
+            old_base.add(64),
+            size - header_len,
+        );
+        (*old_base.cast::<MapHeader>()).format_version = 2;
+        assert_eq!(
+            validate(old_base, size),
+            Err(ValidationError::UnsupportedFormatVersion)
+        );
+        assert_eq!(
+            migrate_v2_to_v3(old_base, size - 1),
+            Err(ValidationError::BufferTooSmall)
+        );
+        assert_eq!(migrate_v2_to_v3(old_base, size), Ok(()));
+        assert!(equals(map_base, old_base));
+        assert_eq!(generation(old_base), 0);
+        assert_eq!(
+            migrate_v2_to_v3(old_base, size),
+            Err(ValidationError::UnsupportedFormatVersion)
+        );
+
+        clear(map_base);
+        assert_eq!(generation(map_base), 10);
+    }
+
+    // Debug builds catch iterators that outlive a change to the map
+    #[cfg(debug_assertions)]
+    {
+        let result = std::panic::catch_unwind(|| unsafe {
+            let key = 1u32;
+            insert(
+                map_base,
+                (&raw const key).cast::<u8>(),
+                (&raw const key).cast::<u8>(),
+            );
+            let mut iter = entries(map_base);
+            let key = 2u32;
+            insert(
+                map_base,
+                (&raw const key).cast::<u8>(),
+                (&raw const key).cast::<u8>(),
+            );
+            iter.next();
+        });
+        assert!(result.is_err());
+    }
+}
+
+#[test]
+fn test_overlay() {
+    let (_, base_init) = layout(4, 4, 4, 4, 16);
+    let (_, overlay_init) = overlay::layout(4, 4, 4, 4, 8);
+    let base_layout = Layout::from_size_align(base_init.total_size as usize, 8).unwrap();
+    let overlay_layout = Layout::from_size_align(overlay_init.total_size as usize, 8).unwrap();
+    let base = unsafe { alloc(base_layout) };
+    let overlay_base = unsafe { alloc(overlay_layout) };
+    assert!(!base.is_null() && !overlay_base.is_null());
+
+    let lookup_value = |key: u32| unsafe {
+        let value_ptr = overlay::lookup_overlaid(overlay_base, base, (&raw const key).cast::<u8>());
+        (!value_ptr.is_null()).then(|| *value_ptr.cast::<u32>())
+    };
+
+    unsafe {
+        init(base, &base_init);
+        init(overlay_base, &overlay_init);
+        for key in 0..4u32 {
+            let value = key + 100;
+            insert(
+                base,
+                (&raw const key).cast::<u8>(),
+                (&raw const value).cast::<u8>(),
+            );
+        }
+
+        // Speculative changes hide and replace base entries without touching the base
+        let speculate = || {
+            for (key, value) in [(1u32, 7u32), (9, 9)] {
+                assert!(overlay::insert_overlaid(
+                    overlay_base,
+                    (&raw const key).cast::<u8>(),
+                    (&raw const value).cast::<u8>(),
+                ));
+            }
+            for key in [2u32, 9] {
+                assert!(overlay::remove_overlaid(
+                    overlay_base,
+                    base,
+                    (&raw const key).cast::<u8>()
+                ));
+            }
+            let key = 2u32;
+            assert!(!overlay::remove_overlaid(
+                overlay_base,
+                base,
+                (&raw const key).cast::<u8>()
+            ));
+        };
+        speculate();
+        assert_eq!(lookup_value(0), Some(100));
+        assert_eq!(lookup_value(1), Some(7));
+        assert_eq!(lookup_value(2), None);
+        assert_eq!(lookup_value(9), None);
+        assert_eq!(len(base), 4);
+        assert_eq!(len(overlay_base), 2);
+
+        overlay::discard(overlay_base);
+        assert_eq!(lookup_value(1), Some(101));
+        assert_eq!(lookup_value(2), Some(102));
+
+        speculate();
+        assert_eq!(overlay::commit(overlay_base, base), Ok(()));
+        assert!(is_empty(overlay_base));
+        assert_eq!(len(base), 3);
+        assert_eq!(
+            *lookup(base, std::ptr::from_ref(&1u32).cast::<u8>()).cast::<u32>(),
+            7
+        );
+        assert!(!has(base, std::ptr::from_ref(&2u32).cast::<u8>()));
+        assert_eq!(lookup_value(3), Some(103));
+
+        // A base map that is logically full keeps the overlay for another try
+        let (_, small_init) = layout(4, 4, 4, 4, 3);
+        let small_layout = Layout::from_size_align(small_init.total_size as usize, 8).unwrap();
+        let small_base = alloc(small_layout);
+        init(small_base, &small_init);
+        for key in 0..4u32 {
+            overlay::insert_overlaid(
+                overlay_base,
+                (&raw const key).cast::<u8>(),
+                (&raw const key).cast::<u8>(),
+            );
+        }
+        assert_eq!(
+            overlay::commit(overlay_base, small_base),
+            Err(GrowError::InsufficientCapacity)
+        );
+        assert_eq!(len(overlay_base), 4);
+        assert_eq!(
+            overlay::commit(overlay_base, overlay_base),
+            Err(GrowError::ValueSizeMismatch)
+        );
+    }
+}
+
+#[test]
+fn test_staging() {
+    for probe_strategy in [
+        ProbeStrategy::Linear,
+        ProbeStrategy::RobinHood,
+        ProbeStrategy::Quadratic,
+    ] {
+        let (_, base_init) = layout(4, 4, 4, 4, 6);
+        let base_init = base_init.with_probe_strategy(probe_strategy);
+        let (_, scratch_init) = overlay::layout(4, 4, 4, 4, 8);
+        let base_size = base_init.total_size as usize;
+        let base_layout = Layout::from_size_align(base_size, 8).unwrap();
+        let scratch_layout = Layout::from_size_align(scratch_init.total_size as usize, 8).unwrap();
+        let base = unsafe { alloc(base_layout) };
+        let copy_base = unsafe { alloc(base_layout) };
+        let scratch = unsafe { alloc(scratch_layout) };
+        assert!(!base.is_null() && !copy_base.is_null() && !scratch.is_null());
+
+        unsafe {
+            init(base, &base_init);
+            init(copy_base, &base_init);
+            init(scratch, &scratch_init);
+            for key in 0..4u32 {
+                let value = key + 100;
+                insert(
+                    base,
+                    (&raw const key).cast::<u8>(),
+                    (&raw const value).cast::<u8>(),
+                );
+            }
+            overwrite(copy_base, base);
+
+            // Three new keys do not fit under a logical limit of six, so nothing changes
+            let mut staging = begin_staging(base, scratch);
+            for key in [0u32, 10, 11, 12] {
+                assert!(
+                    staging.insert((&raw const key).cast::<u8>(), (&raw const key).cast::<u8>())
+                );
+            }
+            assert!(staging.remove(std::ptr::from_ref(&1u32).cast::<u8>()));
+            assert_eq!(
+                *staging
+                    .lookup(std::ptr::from_ref(&10u32).cast::<u8>())
+                    .cast::<u32>(),
+                10
+            );
+            assert!(lookup(base, std::ptr::from_ref(&10u32).cast::<u8>()).is_null());
+            assert_eq!(staging.commit(), Err(GrowError::InsufficientCapacity));
+            assert!(equals(base, copy_base));
+            assert_eq!(validate(base, base_size), Ok(()));
+            assert!(is_empty(scratch));
+
+            let mut staging = begin_staging(base, scratch);
+            staging.remove(std::ptr::from_ref(&1u32).cast::<u8>());
+            staging.abort();
+            assert!(equals(base, copy_base));
+
+            let mut staging = begin_staging(base, scratch);
+            for key in [0u32, 10] {
+                staging.insert((&raw const key).cast::<u8>(), (&raw const key).cast::<u8>());
+            }
+            staging.remove(std::ptr::from_ref(&1u32).cast::<u8>());
+            assert_eq!(staging.commit(), Ok(()));
+            assert_eq!(len(base), 4);
+            assert_eq!(
+                *lookup(base, std::ptr::from_ref(&0u32).cast::<u8>()).cast::<u32>(),
+                0
+            );
+            assert_eq!(
+                *lookup(base, std::ptr::from_ref(&10u32).cast::<u8>()).cast::<u32>(),
+                10
+            );
+            assert!(!has(base, std::ptr::from_ref(&1u32).cast::<u8>()));
+        }
+    }
+}
+
+#[test]
+fn test_freeze() {
+    let (_, map_init) = layout(4, 4, 4, 4, 16);
+    let size = map_init.total_size as usize;
+    let layout = Layout::from_size_align(size, 8).unwrap();
+    let map_base = unsafe { alloc(layout) };
+    assert!(!map_base.is_null());
+
+    unsafe {
+        init(map_base, &map_init);
+        let key = 1u32;
+        insert(
+            map_base,
+            (&raw const key).cast::<u8>(),
+            (&raw const key).cast::<u8>(),
+        );
+
+        freeze(map_base);
+        assert!(is_frozen(map_base));
+        assert_eq!(validate(map_base, size), Ok(()));
+        assert_eq!(
+            *lookup(map_base, (&raw const key).cast::<u8>()).cast::<u32>(),
+            1
+        );
+
+        thaw(map_base);
+        assert!(!is_frozen(map_base));
+        assert!(remove(map_base, (&raw const key).cast::<u8>()));
+        freeze(map_base);
+    }
+
+    #[cfg(debug_assertions)]
+    {
+        let key = 2u32;
+        let changes: [&(dyn Fn() + std::panic::RefUnwindSafe); 3] = [
+            &|| unsafe {
+                get_or_reserve_entry(map_base, (&raw const key).cast::<u8>());
+            },
+            &|| unsafe {
+                remove(map_base, (&raw const key).cast::<u8>());
+            },
+            &|| unsafe { clear(map_base) },
+        ];
+        for change in changes {
+            assert!(std::panic::catch_unwind(change).is_err());
+        }
+    }
+}
+
+#[test]
+fn test_for_each() {
+    let (_, map_init) = layout(4, 4, 4, 4, 32);
+    let map_init = map_init.with_probe_limit(1).with_spill_capacity(8);
+    let layout = Layout::from_size_align(map_init.total_size as usize, 8).unwrap();
+    let map_base = unsafe { alloc(layout) };
+    assert!(!map_base.is_null());
+
+    unsafe {
+        init(map_base, &map_init);
+        for key in 0..20u32 {
+            let value = key + 100;
+            insert(
+                map_base,
+                (&raw const key).cast::<u8>(),
+                (&raw const value).cast::<u8>(),
+            );
+        }
+
+        let mut keys = Vec::new();
+        for_each(map_base, |key_ptr, value_ptr| {
+            let key = *key_ptr.cast::<u32>();
+            assert_eq!(*value_ptr.cast::<u32>(), key + 100);
+            *value_ptr.cast::<u32>() = key * 2;
+            keys.push(key);
+        });
+        keys.sort_unstable();
+        assert_eq!(keys, (0..20).collect::<Vec<_>>());
+        assert_eq!(
+            *lookup(map_base, (&raw const keys[7]).cast::<u8>()).cast::<u32>(),
+            14
+        );
+
+        // Stops at the first entry that breaks, with the same order as `for_each`
+        let mut visited = 0;
+        let found = try_for_each(map_base, |key_ptr, _| {
+            visited += 1;
+            if *key_ptr.cast::<u32>() >= 10 {
+                ControlFlow::Break(*key_ptr.cast::<u32>())
+            } else {
+                ControlFlow::Continue(())
+            }
+        });
+        let mut order = Vec::new();
+        for_each(map_base, |key_ptr, _| order.push(*key_ptr.cast::<u32>()));
+        let first = order.iter().position(|&key| key >= 10).unwrap();
+        assert_eq!(found, ControlFlow::Break(order[first]));
+        assert_eq!(visited, first + 1);
+        assert_eq!(
+            try_for_each(map_base, |_, _| ControlFlow::<()>::Continue(())),
+            ControlFlow::Continue(())
+        );
+    }
+}
+
+#[test]
+fn test_next_page() {
+    let (_, map_init) = layout(4, 4, 2, 2, 64);
+    let layout = Layout::from_size_align(map_init.total_size as usize, 8).unwrap();
+    let map_base = unsafe { alloc(layout) };
+    assert!(!map_base.is_null());
+
+    unsafe {
+        init(map_base, &map_init);
+        for key in 0..40u32 {
+            let value = key as u16 + 100;
+            insert(
+                map_base,
+                (&raw const key).cast::<u8>(),
+                (&raw const value).cast::<u8>(),
+            );
+        }
+
+        // Pages of up to 8 entries, limited to 5 by the size of the buffer
+        let mut out = [0u8; 5 * 6 + 3];
+        let mut cursor = Cursor::START;
+        let mut keys = Vec::new();
+        let mut pages = 0;
+        while !cursor.is_done() {
+            let (count, next) = next_page(map_base, cursor, 8, &mut out);
+            assert!(count <= 5);
+            for entry in out[..count * 6].chunks_exact(6) {
+                let key = u32::from_ne_bytes(entry[..4].try_into().unwrap());
+                assert_eq!(
+                    u16::from_ne_bytes(entry[4..].try_into().unwrap()),
+                    key as u16 + 100
+                );
+                keys.push(key);
+            }
+            cursor = next;
+            pages += 1;
+
+            // Removing entries that were already copied between pages
+            if pages == 2 {
+                for key in keys.clone() {
+                    remove(map_base, (&raw const key).cast::<u8>());
+                }
+                assert_ne!(cursor.generation, generation(map_base));
+            }
+        }
+        assert_eq!(pages, 8);
+        keys.sort_unstable();
+        assert_eq!(keys, (0..40).collect::<Vec<_>>());
+
+        let (count, cursor) = next_page(map_base, cursor, 8, &mut out);
+        assert_eq!(count, 0);
+        assert!(cursor.is_done());
+    }
+}
+
+#[test]
+fn test_next_page_after_removal() {
+    // Two keys whose probe chain wraps from the last bucket around to the first
+    let (_, map_init) = layout(4, 4, 2, 2, 16);
+    let scratch_base = unsafe { alloc(alloc_layout(&map_init)) };
+    assert!(!scratch_base.is_null());
+    let last = map_init.capacity - 1;
+    let mut wrapping =
+        (0..).filter(|&key| unsafe { home_of(scratch_base, &map_init, key) } == last);
+    let (first, wrapped) = (wrapping.next().unwrap(), wrapping.next().unwrap());
+    let others: Vec<u32> = (0..)
+        .filter(|&key| (2..last - 1).contains(&unsafe { home_of(scratch_base, &map_init, key) }))
+        .take(4)
+        .collect();
+
+    for strategy in [ProbeStrategy::Linear, ProbeStrategy::Quadratic] {
+        let (_, map_init) = layout(4, 4, 2, 2, 16);
+        let map_init = map_init.with_probe_strategy(strategy);
+        let map_base = unsafe { alloc(alloc_layout(&map_init)) };
+        assert!(!map_base.is_null());
+
+        unsafe {
+            init(map_base, &map_init);
+            for &key in [first, wrapped].iter().chain(&others) {
+                let value = key as u16;
+                insert(
+                    map_base,
+                    (&raw const key).cast::<u8>(),
+                    (&raw const value).cast::<u8>(),
+                );
+            }
+
+            // The first page copies the wrapped key out of bucket 0
+            let mut out = [0u8; 6];
+            let (count, mut cursor) = next_page(map_base, Cursor::START, 1, &mut out);
+            assert_eq!(count, 1);
+            assert_eq!(u32::from_ne_bytes(out[..4].try_into().unwrap()), wrapped);
+
+            assert!(remove(map_base, (&raw const first).cast::<u8>()));
+            assert_ne!(cursor.generation, generation(map_base));
+
+            let mut keys = vec![wrapped];
+            while !cursor.is_done() {
+                let (count, next) = next_page(map_base, cursor, 1, &mut out);
+                if count != 0 {
+                    keys.push(u32::from_ne_bytes(out[..4].try_into().unwrap()));
+                }
+                cursor = next;
+            }
+
+            // A linear removal shifts the wrapped key back into the last bucket, ahead
+            // of the cursor, while a quadratic one leaves a tombstone
+            let copies = keys.iter().filter(|&&key| key == wrapped).count();
+            assert_eq!(
+                copies,
+                if strategy == ProbeStrategy::Linear {
+                    2
+                } else {
+                    1
+                }
+            );
+            assert_eq!(keys.len(), others.len() + copies);
+        }
+    }
+}
+
+#[test]
+fn test_find_by_value() {
+    let (_, map_init) = layout(4, 4, 8, 8, 16);
+    let layout = Layout::from_size_align(map_init.total_size as usize, 8).unwrap();
+    let map_base = unsafe { alloc(layout) };
+    assert!(!map_base.is_null());
+
+    unsafe {
+        init(map_base, &map_init);
+        for key in 0..10u32 {
+            let value = u64::from(key) * 1000;
+            insert(
+                map_base,
+                (&raw const key).cast::<u8>(),
+                (&raw const value).cast::<u8>(),
+            );
+        }
+
+        let value = 7000u64;
+        let (key_ptr, index) = find_by_value(map_base, (&raw const value).cast::<u8>());
+        assert_eq!(*key_ptr.cast::<u32>(), 7);
+        let handle = Handle {
+            index,
+            generation: (*map_base.cast::<MapHeader>()).generation,
+        };
+        let value_ptr = get_by_slot(map_base, handle, key_ptr);
+        assert_eq!(*value_ptr.cast::<u64>(), 7000);
+
+        let value = 7001u64;
+        let (key_ptr, index) = find_by_value(map_base, (&raw const value).cast::<u8>());
+        assert!(key_ptr.is_null());
+        assert_eq!(index, u32::MAX);
+    }
+}
+
+#[test]
+fn test_sample() {
+    let (_, map_init) = layout(4, 4, 4, 4, 64);
+    let layout = Layout::from_size_align(map_init.total_size as usize, 8).unwrap();
+    let map_base = unsafe { alloc(layout) };
+    assert!(!map_base.is_null());
+
+    unsafe {
+        init(map_base, &map_init);
+        assert!(sample(map_base, 12345).0.is_null());
+
+        for key in 0..10u32 {
+            let value = key + 100;
+            insert(
+                map_base,
+                (&raw const key).cast::<u8>(),
+                (&raw const value).cast::<u8>(),
+            );
+        }
+
+        // Evenly spread random numbers pick every entry equally often
+        let mut picks = [0u32; 10];
+        for step in 0..1000u32 {
+            let random = (u64::from(step) << 32).div_ceil(1000) as u32;
+            let (key_ptr, value_ptr) = sample(map_base, random);
+            let key = *key_ptr.cast::<u32>();
+            assert_eq!(*value_ptr.cast::<u32>(), key + 100);
+            picks[key as usize] += 1;
+        }
+        assert!(picks.iter().all(|&count| count == 100), "{picks:?}");
+        assert!(!sample(map_base, u32::MAX).0.is_null());
+    }
+}
+
+#[test]
+fn test_drain() {
+    // Quadratic maps leave tombstones for the drain to clear
+    let (_, map_init) = layout(4, 4, 4, 4, 16);
+    let map_init = map_init.with_probe_strategy(ProbeStrategy::Quadratic);
+    let size = map_init.total_size as usize;
+    let layout = Layout::from_size_align(size, 8).unwrap();
+    let map_base = unsafe { alloc(layout) };
+    assert!(!map_base.is_null());
+
+    unsafe {
+        init(map_base, &map_init);
+        for key in 0..12u32 {
+            let value = key + 100;
+            insert(
+                map_base,
+                (&raw const key).cast::<u8>(),
+                (&raw const value).cast::<u8>(),
+            );
+        }
+        for key in 0..3u32 {
+            remove(map_base, (&raw const key).cast::<u8>());
+        }
+        assert_eq!(stats(map_base).tombstone_count, 3);
+
+        let mut drained = Vec::new();
+        drain(map_base, |key_ptr, value_ptr| {
+            drained.push((*key_ptr.cast::<u32>(), *value_ptr.cast::<u32>()));
+        });
+        drained.sort_unstable();
+        assert_eq!(
+            drained,
+            (3..12u32).map(|key| (key, key + 100)).collect::<Vec<_>>()
+        );
+
+        // A clean slate, without tombstones
+        assert!(is_empty(map_base));
+        assert_eq!(stats(map_base).tombstone_count, 0);
+        assert_eq!(validate(map_base, size), Ok(()));
+    }
+}
+
+#[test]
+fn test_scrub_removed() {
+    let (_, map_init) = layout(4, 4, 4, 4, 16);
+    let map_init = map_init
+        .with_probe_limit(1)
+        .with_spill_capacity(8)
+        .with_scrub_removed(Some(POISON_BYTE));
+    let size = map_init.total_size as usize;
+    let layout = Layout::from_size_align(size, 8).unwrap();
+    // Zeroed, so that stale heap bytes can not look like old entries
+    let map_base = unsafe { alloc_zeroed(layout) };
+    assert!(!map_base.is_null());
+
+    unsafe {
+        init(map_base, &map_init);
+        let mut value_ptrs = Vec::new();
+        for key in 0..12u32 {
+            let value = key + 100;
+            insert(
+                map_base,
+                (&raw const key).cast::<u8>(),
+                (&raw const value).cast::<u8>(),
+            );
+        }
+        for key in 0..12u32 {
+            value_ptrs.push(lookup(map_base, (&raw const key).cast::<u8>()));
+        }
+
+        // The removed value reads as poison through a stale pointer
+        let key = (0..12u32)
+            .find(|key| {
+                reserve_handle(map_base, std::ptr::from_ref(key).cast::<u8>())
+                    .unwrap()
+                    .index
+                    < 16
+            })
+            .unwrap();
+        assert!(remove(map_base, (&raw const key).cast::<u8>()));
+        assert_eq!(*value_ptrs[key as usize].cast::<u32>(), 0xdddd_dddd);
+        assert_eq!(validate(map_base, size), Ok(()));
+
+        // Nothing of the old entries is left in the buffer once the map is cleared
+        clear(map_base);
+        let bytes = std::slice::from_raw_parts(map_base, size);
+        for key in 0..12u32 {
+            let value = (key + 100).to_ne_bytes();
+            assert!(!bytes.windows(4).any(|window| window == value));
+        }
+    }
+}
+
+#[test]
+fn test_constant_time_keys() {
+    for layout_kind in [
+        LayoutKind::Interleaved,
+        LayoutKind::ControlBytes,
+        LayoutKind::StructOfArrays,
+    ] {
+        let (_, map_init) = layout(32, 1, 4, 4, 32);
+        let map_init = map_init
+            .with_layout_kind(layout_kind)
+            .with_spill_capacity(4)
+            .with_constant_time_keys(true);
+        let size = map_init.total_size as usize;
+        let layout = Layout::from_size_align(size, 8).unwrap();
+        let map_base = unsafe { alloc(layout) };
+        assert!(!map_base.is_null());
+
+        let token = |seed: u32| {
+            let mut token = [0u8; 32];
+            token[31] = seed as u8;
+            token[0] = (seed * 7) as u8;
+            token
+        };
+
+        unsafe {
+            init(map_base, &map_init);
+            assert_ne!(
+                (*map_base.cast::<MapHeader>()).flags & FLAG_CONSTANT_TIME_KEYS,
+                0
+            );
+            for seed in 0..20u32 {
+                let key = token(seed);
+                assert_eq!(
+                    insert(map_base, key.as_ptr(), (&raw const seed).cast::<u8>()),
+                    InsertResult::Inserted
+                );
+            }
+            assert_eq!(validate(map_base, size), Ok(()));
+
+            for seed in 0..20u32 {
+                let key = token(seed);
+                assert_eq!(*lookup(map_base, key.as_ptr()).cast::<u32>(), seed);
+            }
+            // Keys that only differ in their last byte are still told apart
+            let mut key = token(3);
+            key[31] = 200;
+            assert!(lookup(map_base, key.as_ptr()).is_null());
+            assert!(remove(map_base, token(3).as_ptr()));
+            assert!(!has(map_base, token(3).as_ptr()));
+        }
+    }
+}
+
+#[test]
+fn test_cache_line_buckets() {
+    for layout_kind in [LayoutKind::Interleaved, LayoutKind::ControlBytes] {
+        let (_, map_init) = layout(40, 4, 12, 4, 32);
+        let map_init = map_init.with_layout_kind(layout_kind);
+        let packed_size = map_init.total_size;
+        let map_init = map_init.with_cache_line_buckets(true);
+        assert!(map_init.total_size > packed_size);
+
+        let map_layout = alloc_layout(&map_init);
+        assert_eq!(map_layout.align(), CACHE_LINE_SIZE as usize);
+        let map_base = unsafe { alloc(map_layout) };
+        assert!(!map_base.is_null());
+
+        unsafe {
+            assert_eq!(try_init(map_base, &map_init, map_layout.size()), Ok(()));
+            let header = &*map_base.cast::<MapHeader>();
+            assert_eq!(header.bucket_size, CACHE_LINE_SIZE);
+            assert_ne!(header.flags & FLAG_CACHE_LINE_BUCKETS, 0);
+
+            for index in 0..32u32 {
+                let key = [index; 10];
+                let value_ptr = get_or_reserve_entry(map_base, key.as_ptr().cast::<u8>());
+                assert!(!value_ptr.is_null());
+                // Every value sits inside the cache line of its bucket
+                let line_offset = value_ptr as usize % CACHE_LINE_SIZE as usize;
+                assert!(line_offset + 12 <= CACHE_LINE_SIZE as usize);
+                *value_ptr.cast::<u32>() = index * 3;
+            }
+            for index in 0..32u32 {
+                let key = [index; 10];
+                assert_eq!(
+                    *lookup(map_base, key.as_ptr().cast::<u8>()).cast::<u32>(),
+                    index * 3
+                );
+            }
+            assert_eq!(validate(map_base, map_layout.size()), Ok(()));
+        }
+    }
+}
+
+#[test]
+fn test_struct_of_arrays() {
+    for probe_strategy in [
+        ProbeStrategy::Linear,
+        ProbeStrategy::RobinHood,
+        ProbeStrategy::Quadratic,
+    ] {
+        let (_, map_init) = layout(4, 4, 8, 8, 64);
+        let map_init = map_init
+            .with_probe_strategy(probe_strategy)
+            .with_layout_kind(LayoutKind::StructOfArrays)
+            .with_probe_limit(4)
+            .with_spill_capacity(8)
+            .with_dirty_flags(true);
+        let bucket_layout = bucket_layout(&map_init);
+        assert_eq!(bucket_layout.bucket_size, 4);
+        assert_eq!(bucket_layout.value_stride, 8);
+
+        let map_layout = alloc_layout(&map_init);
+        let size = map_layout.size();
+        let map_base = unsafe { alloc(map_layout) };
+        let (_, packed_init) = layout(4, 4, 8, 8, 128);
+        let packed_layout = alloc_layout(&packed_init);
+        let packed_base = unsafe { alloc(packed_layout) };
+        assert!(!map_base.is_null() && !packed_base.is_null());
+
+        unsafe {
+            assert_eq!(try_init(map_base, &map_init, size), Ok(()));
+            assert_eq!(layout_kind(map_base), LayoutKind::StructOfArrays);
+            for key in 0..64u32 {
+                let value = u64::from(key) * 10;
+                assert_eq!(
+                    insert(
+                        map_base,
+                        (&raw const key).cast::<u8>(),
+                        (&raw const value).cast::<u8>()
+                    ),
+                    InsertResult::Inserted
+                );
+            }
+            assert_eq!(validate(map_base, size), Ok(()));
+
+            // The values of neighbouring buckets follow each other
+            let (_, first_value_ptr, first_index) = find_next_valid_entry(map_base, 0);
+            let (_, next_value_ptr, next_index) = find_next_valid_entry(map_base, first_index + 1);
+            assert_eq!(
+                next_value_ptr as usize - first_value_ptr as usize,
+                (next_index - first_index) as usize * 8
+            );
+
+            let mut sum = 0;
+            for_each(map_base, |_, value_ptr| sum += *value_ptr.cast::<u64>());
+            assert_eq!(sum, (0..64).sum::<u64>() * 10);
+
+            for key in (0..64u32).step_by(3) {
+                let key_ptr = (&raw const key).cast::<u8>();
+                let handle = reserve_handle(map_base, key_ptr).unwrap();
+                assert_eq!(resolve(map_base, handle), Some(lookup(map_base, key_ptr)));
+                mark_dirty(map_base, lookup(map_base, key_ptr));
+            }
+            let mut dirty = 0;
+            for_each_dirty(map_base, |key_ptr, value_ptr| {
+                assert_eq!(
+                    *value_ptr.cast::<u64>(),
+                    u64::from(*key_ptr.cast::<u32>()) * 10
+                );
+                dirty += 1;
+            });
+            assert_eq!(dirty, 22);
+
+            for key in (0..64u32).step_by(2) {
+                assert!(remove(map_base, (&raw const key).cast::<u8>()));
+            }
+            rehash_in_place(map_base);
+            assert_eq!(validate(map_base, size), Ok(()));
+            for key in 0..64u32 {
+                let value_ptr = lookup(map_base, (&raw const key).cast::<u8>());
+                if key % 2 == 0 {
+                    assert!(value_ptr.is_null());
+                } else {
+                    assert_eq!(*value_ptr.cast::<u64>(), u64::from(key) * 10);
+                }
+            }
+
+            // Entries move between layouts with their values
+            init(packed_base, &packed_init);
+            assert_eq!(grow_into(packed_base, map_base), Ok(()));
+            assert!(equals(packed_base, map_base));
+            assert_eq!(content_hash(packed_base), content_hash(map_base));
+
+            let view =
+                MapView::<u32, u64>::new(std::slice::from_raw_parts(map_base, size)).unwrap();
+            assert_eq!(view.get(&7), Some(&70));
+        }
+    }
+}